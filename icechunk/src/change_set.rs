@@ -9,9 +9,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
-        manifest::{ChunkInfo, ManifestExtents, ManifestRef},
+        manifest::{ChunkInfo, ManifestRef},
         snapshot::{NodeData, NodeSnapshot, UserAttributesSnapshot},
-        ManifestId, NodeId,
+        NodeId,
     },
     metadata::UserAttributes,
     repository::{ChunkIndices, ChunkPayload, Path, RepositoryResult, ZarrArrayMetadata},
@@ -116,6 +116,17 @@ impl ChangeSet {
         }
     }
 
+    /// True if the change set deletes any nodes or chunks
+    pub fn has_deletions(&self) -> bool {
+        !self.deleted_groups.is_empty()
+            || !self.deleted_arrays.is_empty()
+            || self
+                .set_chunks
+                .values()
+                .flat_map(|chunks| chunks.values())
+                .any(Option::is_none)
+    }
+
     pub fn is_deleted(&self, path: &Path) -> bool {
         self.deleted_groups.contains(path)
             || self.deleted_arrays.contains(path)
@@ -198,6 +209,7 @@ impl ChangeSet {
                             node: *node_id,
                             coord: coords.clone(),
                             payload: p.clone(),
+                            uncompressed_size: None,
                         },
                     )
                 })
@@ -209,6 +221,59 @@ impl ChangeSet {
         self.new_groups.keys().chain(self.new_arrays.keys())
     }
 
+    pub fn deleted_nodes(&self) -> impl Iterator<Item = &Path> {
+        self.deleted_groups.iter().chain(self.deleted_arrays.iter())
+    }
+
+    /// The nodes with updated metadata or user attributes
+    pub fn updated_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.updated_arrays.keys().chain(
+            self.updated_attributes
+                .keys()
+                .filter(|node_id| !self.updated_arrays.contains_key(node_id)),
+        )
+    }
+
+    /// The coordinates of every chunk set or deleted, with its node
+    pub fn chunk_changes(&self) -> impl Iterator<Item = (&NodeId, &ChunkIndices)> {
+        self.set_chunks.iter().flat_map(|(node_id, chunks)| {
+            chunks.keys().map(move |coord| (node_id, coord))
+        })
+    }
+
+    /// Give the nodes created in this change set consecutive ids, starting at `first_id`
+    ///
+    /// Returns the number of new nodes.
+    pub fn renumber_new_nodes(&mut self, first_id: NodeId) -> usize {
+        let mut new_ids: Vec<_> = self
+            .new_groups
+            .values_mut()
+            .chain(self.new_arrays.values_mut().map(|(node_id, _)| node_id))
+            .collect();
+        new_ids.sort();
+        let renumbered: HashMap<NodeId, NodeId> = new_ids
+            .into_iter()
+            .zip(first_id..)
+            .map(|(node_id, new_id)| (std::mem::replace(node_id, new_id), new_id))
+            .collect();
+
+        let renumber =
+            |node_id: NodeId| renumbered.get(&node_id).copied().unwrap_or(node_id);
+        self.updated_arrays = take(&mut self.updated_arrays)
+            .into_iter()
+            .map(|(node_id, metadata)| (renumber(node_id), metadata))
+            .collect();
+        self.updated_attributes = take(&mut self.updated_attributes)
+            .into_iter()
+            .map(|(node_id, atts)| (renumber(node_id), atts))
+            .collect();
+        self.set_chunks = take(&mut self.set_chunks)
+            .into_iter()
+            .map(|(node_id, chunks)| (renumber(node_id), chunks))
+            .collect();
+        renumbered.len()
+    }
+
     pub fn take_chunks(
         &mut self,
     ) -> HashMap<NodeId, HashMap<ChunkIndices, Option<ChunkPayload>>> {
@@ -279,7 +344,12 @@ impl ChangeSet {
         chunks.filter_map(move |chunk| match self.get_chunk_ref(node, &chunk.coord) {
             None => Some(chunk),
             Some(new_payload) => {
-                new_payload.clone().map(|pl| ChunkInfo { payload: pl, ..chunk })
+                // the size of the old chunk doesn't apply to the new payload
+                new_payload.clone().map(|pl| ChunkInfo {
+                    payload: pl,
+                    uncompressed_size: None,
+                    ..chunk
+                })
             }
         })
     }
@@ -317,7 +387,7 @@ impl ChangeSet {
 
     pub fn new_nodes_iterator<'a>(
         &'a self,
-        manifest_id: Option<&'a ManifestId>,
+        manifest_refs: &'a [ManifestRef],
     ) -> impl Iterator<Item = NodeSnapshot> + 'a {
         self.new_nodes().filter_map(move |path| {
             if self.is_deleted(path) {
@@ -329,20 +399,10 @@ impl ChangeSet {
             let node = self.get_new_node(path).expect("Bug in new_nodes implementation");
             match node.node_data {
                 NodeData::Group => Some(node),
-                NodeData::Array(meta, _no_manifests_yet) => {
-                    let new_manifests = manifest_id
-                        .map(|mid| {
-                            vec![ManifestRef {
-                                object_id: mid.clone(),
-                                extents: ManifestExtents(vec![]),
-                            }]
-                        })
-                        .unwrap_or_default();
-                    Some(NodeSnapshot {
-                        node_data: NodeData::Array(meta, new_manifests),
-                        ..node
-                    })
-                }
+                NodeData::Array(meta, _no_manifests_yet) => Some(NodeSnapshot {
+                    node_data: NodeData::Array(meta, manifest_refs.to_vec()),
+                    ..node
+                }),
             }
         })
     }
@@ -1,10 +1,15 @@
 use futures::{pin_mut, Stream, TryStreamExt};
 use itertools::Itertools;
-use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    ops::{Bound, Range},
+    sync::Arc,
+};
 use thiserror::Error;
 
 use bytes::Bytes;
-use serde::{Deserialize, Serialize};
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 
 use super::{
     format_constants, ChunkId, ChunkIndices, ChunkLength, ChunkOffset,
@@ -14,6 +19,27 @@ use super::{
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestExtents(pub Vec<ChunkIndices>);
 
+impl ManifestExtents {
+    /// The coordinates from `first` to `last`, both included, in coordinate order
+    pub fn new(first: ChunkIndices, last: ChunkIndices) -> Self {
+        Self(vec![first, last])
+    }
+
+    /// False only if the manifest cannot hold a chunk at `coord`
+    ///
+    /// Empty extents are unknown, they contain every coordinate.
+    pub fn contains(&self, coord: &ChunkIndices) -> bool {
+        match self.0.as_slice() {
+            [first, last] => first <= coord && coord <= last,
+            _ => true,
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestRef {
     pub object_id: ManifestId,
@@ -100,13 +126,26 @@ pub struct ChunkInfo {
     pub node: NodeId,
     pub coord: ChunkIndices,
     pub payload: ChunkPayload,
+    /// Size of the chunk once decoded, if it was known when the chunk was written
+    pub uncompressed_size: Option<u64>,
+}
+
+impl ChunkInfo {
+    /// Size of the chunk once decoded, as opposed to the length of its stored bytes
+    ///
+    /// Readers can use it to allocate decode buffers of the right size. It's `None` for chunks
+    /// written without it.
+    pub fn uncompressed_size(&self) -> Option<u64> {
+        self.uncompressed_size
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+#[derive(Debug, PartialEq, Default)]
 pub struct Manifest {
     pub icechunk_manifest_format_version: IcechunkFormatVersion,
     pub icechunk_manifest_format_flags: BTreeMap<String, rmpv::Value>,
     chunks: BTreeMap<(NodeId, ChunkIndices), ChunkPayload>,
+    uncompressed_sizes: BTreeMap<(NodeId, ChunkIndices), u64>,
 }
 
 impl Manifest {
@@ -121,6 +160,59 @@ impl Manifest {
         })
     }
 
+    pub fn get_chunk_info(
+        &self,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> Option<ChunkInfo> {
+        self.chunks.get(&(node, coord.clone())).map(|payload| ChunkInfo {
+            node,
+            coord: coord.clone(),
+            payload: payload.clone(),
+            uncompressed_size: self.uncompressed_size(node, coord),
+        })
+    }
+
+    /// Size of the chunk once decoded, if it was recorded, see [`ChunkInfo::uncompressed_size`]
+    pub fn uncompressed_size(&self, node: NodeId, coord: &ChunkIndices) -> Option<u64> {
+        self.uncompressed_sizes.get(&(node, coord.clone())).copied()
+    }
+
+    /// A manifest with only the entries of `node`
+    pub fn node_manifest(&self, node: NodeId) -> Manifest {
+        let chunks = self
+            .chunks
+            .range((node, ChunkIndices(vec![]))..)
+            .take_while(|((chunk_node, _), _)| *chunk_node == node)
+            .map(|(key, payload)| (key.clone(), payload.clone()))
+            .collect();
+        let uncompressed_sizes = self
+            .uncompressed_sizes
+            .range((node, ChunkIndices(vec![]))..)
+            .take_while(|((chunk_node, _), _)| *chunk_node == node)
+            .map(|(key, size)| (key.clone(), *size))
+            .collect();
+        Manifest {
+            chunks,
+            uncompressed_sizes,
+            icechunk_manifest_format_version: self.icechunk_manifest_format_version,
+            icechunk_manifest_format_flags: self.icechunk_manifest_format_flags.clone(),
+        }
+    }
+
+    /// The entries of `node`, sorted by coordinates
+    pub fn node_chunk_infos(&self, node: NodeId) -> impl Iterator<Item = ChunkInfo> + '_ {
+        self.chunks
+            .range((node, ChunkIndices(vec![]))..)
+            .take_while(move |((chunk_node, _), _)| *chunk_node == node)
+            .map(move |((_, coord), payload)| ChunkInfo {
+                node,
+                coord: coord.clone(),
+                payload: payload.clone(),
+                uncompressed_size: self.uncompressed_size(node, coord),
+            })
+    }
+
     pub fn iter(
         self: Arc<Self>,
         node: &NodeId,
@@ -131,6 +223,7 @@ impl Manifest {
     pub fn new(chunks: BTreeMap<(NodeId, ChunkIndices), ChunkPayload>) -> Self {
         Self {
             chunks,
+            uncompressed_sizes: Default::default(),
             icechunk_manifest_format_version:
                 format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
             icechunk_manifest_format_flags: Default::default(),
@@ -140,12 +233,20 @@ impl Manifest {
     pub async fn from_stream<E>(
         chunks: impl Stream<Item = Result<ChunkInfo, E>>,
     ) -> Result<Self, E> {
-        let mut chunk_map = BTreeMap::new();
+        let mut builder = ManifestBuilder::new();
         pin_mut!(chunks);
         while let Some(chunk) = chunks.try_next().await? {
-            chunk_map.insert((chunk.node, chunk.coord), chunk.payload);
+            builder.add_chunk(chunk);
         }
-        Ok(Self::new(chunk_map))
+        Ok(builder.build())
+    }
+
+    /// True if identical inline payloads are stored once when the manifest is serialized
+    ///
+    /// See [`ManifestBuilder::with_interned_inline_payloads`]
+    pub fn interns_inline_payloads(&self) -> bool {
+        self.icechunk_manifest_format_flags.get(INTERN_INLINE_PAYLOADS_FLAG)
+            == Some(&rmpv::Value::Boolean(true))
     }
 
     pub fn chunks(&self) -> &BTreeMap<(NodeId, ChunkIndices), ChunkPayload> {
@@ -162,13 +263,464 @@ impl Manifest {
     }
 }
 
-impl FromIterator<ChunkInfo> for Manifest {
-    fn from_iter<T: IntoIterator<Item = ChunkInfo>>(iter: T) -> Self {
-        let chunks = iter
+const INTERN_INLINE_PAYLOADS_FLAG: &str = "intern_inline_payloads";
+
+/// Incrementally build a [`Manifest`]
+#[derive(Debug, Default)]
+pub struct ManifestBuilder {
+    chunks: BTreeMap<(NodeId, ChunkIndices), ChunkPayload>,
+    uncompressed_sizes: BTreeMap<(NodeId, ChunkIndices), u64>,
+    interned: Option<HashMap<Bytes, Bytes>>,
+}
+
+impl ManifestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store each distinct inline payload only once
+    ///
+    /// Useful for arrays with many identical small chunks, like constant or fill value
+    /// regions. Chunks share the bytes in memory, and the serialized manifest stores repeated
+    /// payloads in a table, referencing them by position. Readers resolve the references
+    /// transparently.
+    pub fn with_interned_inline_payloads(mut self, intern: bool) -> Self {
+        self.interned = intern.then(HashMap::new);
+        self
+    }
+
+    pub fn add_chunk(&mut self, chunk: ChunkInfo) -> &mut Self {
+        let payload = match (chunk.payload, self.interned.as_mut()) {
+            (ChunkPayload::Inline(bytes), Some(interned)) => ChunkPayload::Inline(
+                interned.entry(bytes.clone()).or_insert(bytes).clone(),
+            ),
+            (payload, _) => payload,
+        };
+        let key = (chunk.node, chunk.coord);
+        match chunk.uncompressed_size {
+            Some(size) => self.uncompressed_sizes.insert(key.clone(), size),
+            None => self.uncompressed_sizes.remove(&key),
+        };
+        self.chunks.insert(key, payload);
+        self
+    }
+
+    pub fn build(self) -> Manifest {
+        let mut manifest = Manifest::new(self.chunks);
+        manifest.uncompressed_sizes = self.uncompressed_sizes;
+        if self.interned.is_some() {
+            manifest.icechunk_manifest_format_flags.insert(
+                INTERN_INLINE_PAYLOADS_FLAG.to_string(),
+                rmpv::Value::Boolean(true),
+            );
+        }
+        manifest
+    }
+}
+
+impl Extend<ChunkInfo> for ManifestBuilder {
+    fn extend<T: IntoIterator<Item = ChunkInfo>>(&mut self, iter: T) {
+        for chunk in iter {
+            self.add_chunk(chunk);
+        }
+    }
+}
+
+/// Serialized form of a [`ChunkPayload`]
+///
+/// Besides the [`ChunkPayload`] variants, it can point to an interned inline payload, or add
+/// the uncompressed size of the chunk. The variants shared with [`ChunkPayload`] must keep their
+/// order, so both types have the same encoding.
+#[derive(Serialize)]
+#[serde(rename = "ChunkPayload")]
+enum SerializedPayload<'a> {
+    Inline(&'a Bytes),
+    Virtual(&'a VirtualChunkRef),
+    Ref(&'a ChunkRef),
+    Interned(u32),
+    WithUncompressedSize(Box<SerializedPayload<'a>>, u64),
+}
+
+impl<'a> SerializedPayload<'a> {
+    fn new(
+        payload: &'a ChunkPayload,
+        uncompressed_size: Option<u64>,
+        positions: &HashMap<&'a Bytes, u32>,
+    ) -> Self {
+        let payload = match payload {
+            ChunkPayload::Inline(bytes) => positions
+                .get(bytes)
+                .map_or(Self::Inline(bytes), |position| Self::Interned(*position)),
+            ChunkPayload::Virtual(virtual_ref) => Self::Virtual(virtual_ref),
+            ChunkPayload::Ref(chunk_ref) => Self::Ref(chunk_ref),
+        };
+        match uncompressed_size {
+            Some(size) => Self::WithUncompressedSize(Box::new(payload), size),
+            None => payload,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "ChunkPayload")]
+enum DeserializedPayload {
+    Inline(Bytes),
+    Virtual(VirtualChunkRef),
+    Ref(ChunkRef),
+    Interned(u32),
+    WithUncompressedSize(Box<DeserializedPayload>, u64),
+}
+
+impl DeserializedPayload {
+    /// The payload, with interned payloads resolved, and the uncompressed size of the chunk
+    fn resolve(
+        self,
+        inline_payloads: &[Bytes],
+    ) -> Result<(ChunkPayload, Option<u64>), String> {
+        match self {
+            Self::Inline(bytes) => Ok((ChunkPayload::Inline(bytes), None)),
+            Self::Virtual(virtual_ref) => Ok((ChunkPayload::Virtual(virtual_ref), None)),
+            Self::Ref(chunk_ref) => Ok((ChunkPayload::Ref(chunk_ref), None)),
+            Self::Interned(position) => inline_payloads
+                .get(position as usize)
+                .map(|bytes| (ChunkPayload::Inline(bytes.clone()), None))
+                .ok_or_else(|| format!("unknown interned inline payload {position}")),
+            Self::WithUncompressedSize(payload, size) => {
+                let (payload, _) = payload.resolve(inline_payloads)?;
+                Ok((payload, Some(size)))
+            }
+        }
+    }
+}
+
+/// A [`Manifest`] ready to serialize, interning its inline payloads or not
+struct SerializedManifest<'a> {
+    manifest: &'a Manifest,
+    intern: bool,
+}
+
+impl Serialize for SerializedManifest<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let manifest = self.manifest;
+        // only payloads that repeat are worth storing in the table
+        let mut counts: HashMap<&Bytes, usize> = HashMap::new();
+        if self.intern {
+            for payload in manifest.chunks.values() {
+                if let ChunkPayload::Inline(bytes) = payload {
+                    *counts.entry(bytes).or_default() += 1;
+                }
+            }
+        }
+        let mut table = Vec::new();
+        let mut positions: HashMap<&Bytes, u32> = HashMap::new();
+        for payload in manifest.chunks.values() {
+            if let ChunkPayload::Inline(bytes) = payload {
+                if counts.get(bytes).is_some_and(|count| *count > 1)
+                    && !positions.contains_key(bytes)
+                {
+                    positions.insert(bytes, table.len() as u32);
+                    table.push(bytes);
+                }
+            }
+        }
+
+        // the table goes last, so manifests without it have the same encoding as before
+        let mut state =
+            serializer.serialize_struct("Manifest", if self.intern { 4 } else { 3 })?;
+        state.serialize_field(
+            "icechunk_manifest_format_version",
+            &manifest.icechunk_manifest_format_version,
+        )?;
+        state.serialize_field(
+            "icechunk_manifest_format_flags",
+            &manifest.icechunk_manifest_format_flags,
+        )?;
+        state.serialize_field(
+            "chunks",
+            &SerializedChunks { manifest, positions: &positions },
+        )?;
+        if self.intern {
+            state.serialize_field("inline_payloads", &table)?;
+        }
+        state.end()
+    }
+}
+
+/// The chunks map of a [`SerializedManifest`], with interned payloads replaced by their position
+struct SerializedChunks<'a> {
+    manifest: &'a Manifest,
+    positions: &'a HashMap<&'a Bytes, u32>,
+}
+
+impl Serialize for SerializedChunks<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.manifest.chunks.iter().map(|(key, payload)| {
+            let uncompressed_size = self.manifest.uncompressed_sizes.get(key).copied();
+            (key, SerializedPayload::new(payload, uncompressed_size, self.positions))
+        }))
+    }
+}
+
+impl Serialize for Manifest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedManifest { manifest: self, intern: self.interns_inline_payloads() }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Manifest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Manifest")]
+        struct Stored {
+            icechunk_manifest_format_version: IcechunkFormatVersion,
+            icechunk_manifest_format_flags: BTreeMap<String, rmpv::Value>,
+            chunks: BTreeMap<(NodeId, ChunkIndices), DeserializedPayload>,
+            #[serde(default)]
+            inline_payloads: Vec<Bytes>,
+        }
+
+        let stored = Stored::deserialize(deserializer)?;
+        let mut chunks = BTreeMap::new();
+        let mut uncompressed_sizes = BTreeMap::new();
+        for (key, payload) in stored.chunks {
+            let (payload, size) =
+                payload.resolve(&stored.inline_payloads).map_err(de::Error::custom)?;
+            if let Some(size) = size {
+                uncompressed_sizes.insert(key.clone(), size);
+            }
+            chunks.insert(key, payload);
+        }
+        Ok(Manifest {
+            icechunk_manifest_format_version: stored.icechunk_manifest_format_version,
+            icechunk_manifest_format_flags: stored.icechunk_manifest_format_flags,
+            chunks,
+            uncompressed_sizes,
+        })
+    }
+}
+
+/// A small sidecar object that locates chunk entries within a serialized [`Manifest`]
+///
+/// Entries are grouped in blocks of consecutive keys, the index stores the first key of every
+/// block and the byte offset where the block starts. Looking up a single chunk requires fetching
+/// the index and then only the bytes of the block that can contain the chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestIndex {
+    blocks: Vec<((NodeId, ChunkIndices), ChunkOffset)>,
+    manifest_size: ChunkLength,
+}
+
+impl ManifestIndex {
+    /// Serialize `manifest` and build the index for the resulting bytes
+    ///
+    /// Blocks must be readable on their own, so inline payloads are never interned here.
+    pub fn serialize_with_index(
+        manifest: &Manifest,
+        entries_per_block: usize,
+    ) -> Result<(Vec<u8>, ManifestIndex), rmp_serde::encode::Error> {
+        let bytes = rmp_serde::to_vec(&SerializedManifest { manifest, intern: false })?;
+        let entries_per_block = entries_per_block.max(1);
+
+        // The chunks map is the last field in the serialized manifest, so its entries take the
+        // tail of the buffer. MessagePack encoding of an entry doesn't depend on its context, so
+        // we can find the entry offsets by serializing them independently.
+        let mut entries = Vec::with_capacity(bytes.len());
+        let mut block_starts = Vec::with_capacity(manifest.len() / entries_per_block + 1);
+        for (ix, (key, payload)) in manifest.chunks.iter().enumerate() {
+            if ix % entries_per_block == 0 {
+                block_starts.push((key.clone(), entries.len() as ChunkOffset));
+            }
+            let uncompressed_size = manifest.uncompressed_sizes.get(key).copied();
+            rmp_serde::encode::write(&mut entries, key)?;
+            rmp_serde::encode::write(
+                &mut entries,
+                &SerializedPayload::new(payload, uncompressed_size, &HashMap::new()),
+            )?;
+        }
+        if !bytes.ends_with(entries.as_slice()) {
+            // the offsets of the index would point to the wrong bytes
+            return Err(rmp_serde::encode::Error::Syntax(
+                "manifest entries are not at the end of the serialized manifest"
+                    .to_string(),
+            ));
+        }
+
+        let entries_start = (bytes.len() - entries.len()) as ChunkOffset;
+        let blocks = block_starts
             .into_iter()
-            .map(|chunk| ((chunk.node, chunk.coord), chunk.payload))
+            .map(|(key, offset)| (key, entries_start + offset))
             .collect();
-        Self::new(chunks)
+        let index = ManifestIndex { blocks, manifest_size: bytes.len() as ChunkLength };
+        Ok((bytes, index))
+    }
+
+    /// The byte range of the serialized manifest that contains the chunk, if it's present
+    pub fn block_range(
+        &self,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> Option<Range<ChunkOffset>> {
+        let key = (node, coord.clone());
+        // index of the first block that starts after the key
+        let next = self.blocks.partition_point(|(first, _)| first <= &key);
+        let (_, start) = self.blocks.get(next.checked_sub(1)?)?;
+        let end = self.blocks.get(next).map_or(self.manifest_size, |(_, offset)| *offset);
+        Some(*start..end)
+    }
+
+    pub fn manifest_size(&self) -> ChunkLength {
+        self.manifest_size
+    }
+
+    /// The byte ranges of the serialized manifest that can contain entries of `node`, in order
+    pub fn node_block_ranges(&self, node: NodeId) -> Vec<Range<ChunkOffset>> {
+        // the block before the first one starting with the node can hold its first entries
+        let first = self.blocks.partition_point(|((first, _), _)| *first < node);
+        let end = self.blocks.partition_point(|((first, _), _)| *first <= node);
+        (first.saturating_sub(1)..end)
+            .map(|ix| {
+                let (_, start) = &self.blocks[ix];
+                let end = self
+                    .blocks
+                    .get(ix + 1)
+                    .map_or(self.manifest_size, |(_, offset)| *offset);
+                *start..end
+            })
+            .collect()
+    }
+
+    /// Search for a chunk in the bytes of a block returned by [`ManifestIndex::block_range`]
+    pub fn find_in_block(
+        block: &[u8],
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> Result<Option<ChunkInfo>, rmp_serde::decode::Error> {
+        for entry in block_entries(block) {
+            let entry = entry?;
+            if entry.node == node && &entry.coord == coord {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The entries of `node` in the bytes of a block returned by
+    /// [`ManifestIndex::node_block_ranges`]
+    pub fn node_chunks_in_block(
+        block: &[u8],
+        node: NodeId,
+    ) -> Result<Vec<ChunkInfo>, rmp_serde::decode::Error> {
+        block_entries(block).filter_ok(|entry| entry.node == node).collect()
+    }
+}
+
+/// Decode the entries in the bytes of a block of an indexed manifest
+fn block_entries(
+    block: &[u8],
+) -> impl Iterator<Item = Result<ChunkInfo, rmp_serde::decode::Error>> + '_ {
+    let mut de = rmp_serde::Deserializer::new(Cursor::new(block));
+    std::iter::from_fn(move || {
+        if de.position() as usize >= block.len() {
+            return None;
+        }
+        let mut entry = || {
+            let (node, coord) = <(NodeId, ChunkIndices)>::deserialize(&mut de)?;
+            // indexed manifests don't intern payloads
+            let (payload, uncompressed_size) = DeserializedPayload::deserialize(&mut de)?
+                .resolve(&[])
+                .map_err(rmp_serde::decode::Error::Syntax)?;
+            Ok(ChunkInfo { node, coord, payload, uncompressed_size })
+        };
+        Some(entry())
+    })
+}
+
+#[cfg(feature = "arrow")]
+impl Manifest {
+    /// Export the manifest as an Arrow [`RecordBatch`](arrow::record_batch::RecordBatch)
+    ///
+    /// There is one row per chunk, with columns:
+    /// - `node_id`
+    /// - `coord`: a list of the chunk indices, arrays can have any number of dimensions
+    /// - `payload_kind`: one of `inline`, `virtual`, or `ref`
+    /// - `offset` and `length`: the bytes of the chunk in its object, `offset` is 0 for inline
+    ///   chunks
+    /// - `chunk_id`: the id of the chunk object, only for `ref` chunks
+    /// - `location`: the location of virtual chunks
+    pub fn to_record_batch(
+        &self,
+    ) -> Result<arrow::record_batch::RecordBatch, arrow::error::ArrowError> {
+        use arrow::{
+            array::{
+                ArrayRef, ListBuilder, StringArray, UInt32Array, UInt32Builder,
+                UInt64Array,
+            },
+            datatypes::{DataType, Field, Schema},
+            record_batch::RecordBatch,
+        };
+
+        let mut node_ids = Vec::with_capacity(self.len());
+        let mut coords = ListBuilder::new(UInt32Builder::new());
+        let mut kinds = Vec::with_capacity(self.len());
+        let mut offsets = Vec::with_capacity(self.len());
+        let mut lengths = Vec::with_capacity(self.len());
+        let mut chunk_ids = Vec::with_capacity(self.len());
+        let mut locations = Vec::with_capacity(self.len());
+        for ((node, coord), payload) in self.chunks.iter() {
+            node_ids.push(*node);
+            coords.append_value(coord.0.iter().copied().map(Some));
+            let (kind, offset, length, chunk_id, location) = match payload {
+                ChunkPayload::Inline(bytes) => {
+                    ("inline", 0, bytes.len() as ChunkLength, None, None)
+                }
+                ChunkPayload::Virtual(VirtualChunkRef {
+                    location: VirtualChunkLocation::Absolute(location),
+                    offset,
+                    length,
+                }) => ("virtual", *offset, *length, None, Some(location.clone())),
+                ChunkPayload::Ref(ChunkRef { id, offset, length }) => {
+                    ("ref", *offset, *length, Some(id.to_string()), None)
+                }
+            };
+            kinds.push(kind);
+            offsets.push(offset);
+            lengths.push(length);
+            chunk_ids.push(chunk_id);
+            locations.push(location);
+        }
+
+        let schema = Schema::new(vec![
+            Field::new("node_id", DataType::UInt32, false),
+            Field::new(
+                "coord",
+                DataType::List(Arc::new(Field::new("item", DataType::UInt32, true))),
+                false,
+            ),
+            Field::new("payload_kind", DataType::Utf8, false),
+            Field::new("offset", DataType::UInt64, false),
+            Field::new("length", DataType::UInt64, false),
+            Field::new("chunk_id", DataType::Utf8, true),
+            Field::new("location", DataType::Utf8, true),
+        ]);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt32Array::from(node_ids)),
+            Arc::new(coords.finish()),
+            Arc::new(StringArray::from(kinds)),
+            Arc::new(UInt64Array::from(offsets)),
+            Arc::new(UInt64Array::from(lengths)),
+            Arc::new(StringArray::from(chunk_ids)),
+            Arc::new(StringArray::from(locations)),
+        ];
+        RecordBatch::try_new(Arc::new(schema), columns)
+    }
+}
+
+impl FromIterator<ChunkInfo> for Manifest {
+    fn from_iter<T: IntoIterator<Item = ChunkInfo>>(iter: T) -> Self {
+        let mut builder = ManifestBuilder::new();
+        builder.extend(iter);
+        builder.build()
     }
 }
 
@@ -182,36 +734,252 @@ impl Iterator for PayloadIterator {
     type Item = (ChunkIndices, ChunkPayload);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &self.last_key {
-            None => {
-                if let Some((k @ (_, coord), payload)) = self
-                    .manifest
-                    .chunks
-                    .range((
-                        Bound::Included((self.for_node, ChunkIndices(vec![]))),
-                        Bound::Unbounded,
-                    ))
-                    .next()
-                {
-                    self.last_key = Some(k.clone());
-                    Some((coord.clone(), payload.clone()))
-                } else {
-                    None
-                }
-            }
-            Some(last_key) => {
-                if let Some((k @ (_, coord), payload)) = self
-                    .manifest
-                    .chunks
-                    .range((Bound::Excluded(last_key), Bound::Unbounded))
-                    .next()
-                {
-                    self.last_key = Some(k.clone());
-                    Some((coord.clone(), payload.clone()))
-                } else {
-                    None
-                }
-            }
+        let start = match &self.last_key {
+            None => Bound::Included((self.for_node, ChunkIndices(vec![]))),
+            Some(last_key) => Bound::Excluded(last_key.clone()),
+        };
+        // entries are sorted by node, the first entry of another node ends the iteration
+        let (k @ (node, coord), payload) =
+            self.manifest.chunks.range((start, Bound::Unbounded)).next()?;
+        if *node != self.for_node {
+            return None;
         }
+        self.last_key = Some(k.clone());
+        Some((coord.clone(), payload.clone()))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_manifest_extents_contains() {
+        let extents =
+            ManifestExtents::new(ChunkIndices(vec![1, 5]), ChunkIndices(vec![3, 0]));
+        assert!(extents.contains(&ChunkIndices(vec![1, 5])));
+        assert!(extents.contains(&ChunkIndices(vec![2, 9])));
+        assert!(extents.contains(&ChunkIndices(vec![3, 0])));
+        assert!(!extents.contains(&ChunkIndices(vec![1, 4])));
+        assert!(!extents.contains(&ChunkIndices(vec![3, 1])));
+        assert!(ManifestExtents(vec![]).contains(&ChunkIndices(vec![7, 7])));
+    }
+
+    #[test]
+    fn test_iter_yields_only_the_node_chunks() {
+        let manifest: Arc<Manifest> = Arc::new(
+            [(1, 0), (1, 1), (2, 0), (3, 5)]
+                .into_iter()
+                .map(|(node, i)| ChunkInfo {
+                    node,
+                    coord: ChunkIndices(vec![i]),
+                    payload: ChunkPayload::Inline(format!("{node} {i}").into()),
+                    uncompressed_size: None,
+                })
+                .collect(),
+        );
+        let coords = |node| -> Vec<_> {
+            Arc::clone(&manifest).iter(&node).map(|(coord, _)| coord).collect()
+        };
+        assert_eq!(coords(1), vec![ChunkIndices(vec![0]), ChunkIndices(vec![1])]);
+        assert_eq!(coords(2), vec![ChunkIndices(vec![0])]);
+        assert_eq!(coords(4), vec![]);
+    }
+
+    #[test]
+    fn test_manifest_index_finds_every_chunk() -> Result<(), Box<dyn std::error::Error>> {
+        let manifest: Manifest = (0..3)
+            .flat_map(|node| {
+                (0..50).map(move |i| ChunkInfo {
+                    node,
+                    coord: ChunkIndices(vec![i, i % 7]),
+                    payload: ChunkPayload::Inline(Bytes::from(format!("{node}-{i}"))),
+                    uncompressed_size: None,
+                })
+            })
+            .collect();
+        let (bytes, index) = ManifestIndex::serialize_with_index(&manifest, 16)?;
+        assert_eq!(index.manifest_size(), bytes.len() as u64);
+        assert_eq!(rmp_serde::from_slice::<Manifest>(&bytes)?, manifest);
+
+        for ((node, coord), payload) in manifest.chunks() {
+            let range = index.block_range(*node, coord).unwrap();
+            let block = &bytes[range.start as usize..range.end as usize];
+            let found = ManifestIndex::find_in_block(block, *node, coord)?.unwrap();
+            assert_eq!(&found.payload, payload);
+        }
+
+        let missing = ChunkIndices(vec![1000, 0]);
+        let range = index.block_range(2, &missing).unwrap();
+        let block = &bytes[range.start as usize..range.end as usize];
+        assert_eq!(ManifestIndex::find_in_block(block, 2, &missing)?, None);
+        // keys before the first block are never present
+        assert_eq!(index.block_range(0, &ChunkIndices(vec![])), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interned_inline_payloads() -> Result<(), Box<dyn std::error::Error>> {
+        let fill = Bytes::from(vec![0u8; 64]);
+        let chunks = || {
+            (0..1000)
+                .map(|i| ChunkInfo {
+                    node: 1,
+                    coord: ChunkIndices(vec![i]),
+                    payload: ChunkPayload::Inline(fill.clone()),
+                    uncompressed_size: None,
+                })
+                .chain(std::iter::once(ChunkInfo {
+                    node: 2,
+                    coord: ChunkIndices(vec![0]),
+                    payload: ChunkPayload::Inline(Bytes::from_static(b"unique")),
+                    uncompressed_size: None,
+                }))
+        };
+        let mut plain = ManifestBuilder::new();
+        plain.extend(chunks());
+        let plain = plain.build();
+        let mut interned = ManifestBuilder::new().with_interned_inline_payloads(true);
+        interned.extend(chunks());
+        let interned = interned.build();
+        assert!(!plain.interns_inline_payloads());
+        assert!(interned.interns_inline_payloads());
+
+        let plain_bytes = rmp_serde::to_vec(&plain)?;
+        let interned_bytes = rmp_serde::to_vec(&interned)?;
+        // the fill value is stored once, instead of once per chunk
+        assert!(plain_bytes.len() > 1000 * fill.len());
+        assert!(plain_bytes.len() - interned_bytes.len() > 900 * fill.len());
+
+        let read: Manifest = rmp_serde::from_slice(&interned_bytes)?;
+        assert_eq!(read, interned);
+        assert_eq!(read.chunks(), plain.chunks());
+        assert_eq!(
+            read.get_chunk_payload(1, ChunkIndices(vec![500]))?,
+            &ChunkPayload::Inline(fill.clone())
+        );
+        assert_eq!(
+            read.get_chunk_payload(2, ChunkIndices(vec![0]))?,
+            &ChunkPayload::Inline(Bytes::from_static(b"unique"))
+        );
+
+        // plain manifests keep their encoding
+        assert_eq!(rmp_serde::from_slice::<Manifest>(&plain_bytes)?, plain);
+        let (indexed_bytes, _) = ManifestIndex::serialize_with_index(&plain, 16)?;
+        assert_eq!(indexed_bytes, plain_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncompressed_size_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let sized = ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![0]),
+            payload: ChunkPayload::Ref(ChunkRef {
+                id: ChunkId::random(),
+                offset: 0,
+                length: 100,
+            }),
+            uncompressed_size: Some(4096),
+        };
+        let unsized_chunk = ChunkInfo {
+            coord: ChunkIndices(vec![1]),
+            payload: ChunkPayload::Inline(Bytes::from_static(b"hello")),
+            uncompressed_size: None,
+            ..sized.clone()
+        };
+        assert_eq!(sized.uncompressed_size(), Some(4096));
+        assert_eq!(unsized_chunk.uncompressed_size(), None);
+
+        let manifest: Manifest =
+            vec![sized.clone(), unsized_chunk.clone()].into_iter().collect();
+        let bytes = rmp_serde::to_vec(&manifest)?;
+        let read: Manifest = rmp_serde::from_slice(&bytes)?;
+        assert_eq!(read, manifest);
+        assert_eq!(read.get_chunk_info(1, &sized.coord), Some(sized.clone()));
+        assert_eq!(
+            read.get_chunk_info(1, &unsized_chunk.coord),
+            Some(unsized_chunk.clone())
+        );
+
+        // sizes are also found through the index
+        let (bytes, index) = ManifestIndex::serialize_with_index(&manifest, 1)?;
+        for chunk in [sized, unsized_chunk] {
+            let range = index.block_range(1, &chunk.coord).unwrap();
+            let block = &bytes[range.start as usize..range.end as usize];
+            assert_eq!(
+                ManifestIndex::find_in_block(block, 1, &chunk.coord)?,
+                Some(chunk)
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn test_manifest_to_record_batch() -> Result<(), Box<dyn std::error::Error>> {
+        use arrow::array::{Array, AsArray};
+        use arrow::datatypes::{UInt32Type, UInt64Type};
+
+        let chunk_id = ChunkId::random();
+        let manifest: Manifest = vec![
+            ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![0, 1]),
+                payload: ChunkPayload::Inline(Bytes::from_static(b"hello")),
+                uncompressed_size: None,
+            },
+            ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![0, 2]),
+                payload: ChunkPayload::Ref(ChunkRef {
+                    id: chunk_id.clone(),
+                    offset: 10,
+                    length: 20,
+                }),
+                uncompressed_size: None,
+            },
+            ChunkInfo {
+                node: 2,
+                coord: ChunkIndices(vec![3]),
+                payload: ChunkPayload::Virtual(VirtualChunkRef {
+                    location: VirtualChunkLocation::Absolute(
+                        "s3://bucket/key".to_string(),
+                    ),
+                    offset: 0,
+                    length: 100,
+                }),
+                uncompressed_size: None,
+            },
+        ]
+        .into_iter()
+        .collect();
+
+        let batch = manifest.to_record_batch()?;
+        assert_eq!(batch.num_rows(), 3);
+        let node_ids: Vec<_> =
+            batch["node_id"].as_primitive::<UInt32Type>().values().to_vec();
+        assert_eq!(node_ids, vec![1, 1, 2]);
+        let coords = batch["coord"].as_list::<i32>();
+        let coords: Vec<Vec<u32>> = (0..3)
+            .map(|i| coords.value(i).as_primitive::<UInt32Type>().values().to_vec())
+            .collect();
+        assert_eq!(coords, vec![vec![0, 1], vec![0, 2], vec![3]]);
+        let kinds: Vec<_> = batch["payload_kind"].as_string::<i32>().iter().collect();
+        assert_eq!(kinds, vec![Some("inline"), Some("ref"), Some("virtual")]);
+        let offsets: Vec<_> =
+            batch["offset"].as_primitive::<UInt64Type>().values().to_vec();
+        assert_eq!(offsets, vec![0, 10, 0]);
+        let lengths: Vec<_> =
+            batch["length"].as_primitive::<UInt64Type>().values().to_vec();
+        assert_eq!(lengths, vec![5, 20, 100]);
+        let chunk_ids: Vec<_> = batch["chunk_id"].as_string::<i32>().iter().collect();
+        assert_eq!(chunk_ids, vec![None, Some(chunk_id.to_string().as_str()), None]);
+        let locations = batch["location"].as_string::<i32>();
+        assert_eq!(locations.null_count(), 2);
+        assert_eq!(locations.value(2), "s3://bucket/key");
+        Ok(())
     }
 }
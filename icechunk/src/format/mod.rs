@@ -170,6 +170,10 @@ impl ByteRange {
         (start..end).into()
     }
 
+    pub fn last(n: ChunkLength) -> Self {
+        Self::Last(n)
+    }
+
     pub const ALL: Self = Self::From(0);
 
     pub fn slice(&self, bytes: Bytes) -> Bytes {
@@ -178,7 +182,10 @@ impl ByteRange {
                 bytes.slice(range.start as usize..range.end as usize)
             }
             ByteRange::From(from) => bytes.slice(*from as usize..),
-            ByteRange::Last(n) => bytes.slice(bytes.len() - *n as usize..bytes.len()),
+            // like in HTTP, asking for more bytes than the object has returns all of them
+            ByteRange::Last(n) => {
+                bytes.slice(bytes.len().saturating_sub(*n as usize)..bytes.len())
+            }
         }
     }
 }
@@ -223,6 +230,8 @@ pub mod format_constants {
     pub const LATEST_ICECHUNK_SNAPSHOT_FORMAT: IcechunkFormatVersion = 0;
     pub const LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE: &str = "application/msgpack";
     pub const LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY: &str = "ic-sna-fmt-ver";
+
+    pub const LATEST_ICECHUNK_ATTRIBUTES_FORMAT: IcechunkFormatVersion = 0;
 }
 
 impl Display for Path {
@@ -13,8 +13,12 @@ use crate::metadata::{
     StorageTransformer, UserAttributes,
 };
 
+use crate::storage::{Storage, StorageResult};
+
 use super::{
-    format_constants, manifest::ManifestRef, AttributesId, IcechunkFormatError,
+    format_constants,
+    manifest::{ChunkPayload, ManifestRef},
+    AttributesId, ByteRange, ChunkId, ChunkIndices, IcechunkFormatError,
     IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId, ObjectId, Path,
     SnapshotId, TableOffset,
 };
@@ -49,6 +53,23 @@ pub struct ZarrArrayMetadata {
     pub dimension_names: Option<DimensionNames>,
 }
 
+impl ZarrArrayMetadata {
+    /// Number of chunks along each dimension
+    pub fn num_chunks(&self) -> Vec<u64> {
+        self.shape
+            .iter()
+            .zip(self.chunk_shape.0.iter())
+            .map(|(size, chunk)| size.div_ceil(chunk.get()))
+            .collect()
+    }
+
+    /// If `coords` are the coordinates of a chunk inside the array
+    pub fn contains_chunk(&self, coords: &ChunkIndices) -> bool {
+        coords.0.len() == self.shape.len()
+            && coords.0.iter().zip(self.num_chunks()).all(|(c, n)| (*c as u64) < n)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NodeData {
     Array(ZarrArrayMetadata, Vec<ManifestRef>),
@@ -110,6 +131,43 @@ pub struct Snapshot {
     pub started_at: DateTime<Utc>,
     pub properties: SnapshotProperties,
     nodes: BTreeMap<Path, NodeSnapshot>,
+    // appended field, older snapshots don't have stats
+    #[serde(default)]
+    node_stats: BTreeMap<NodeId, ChunkId>,
+}
+
+/// Summary of the chunks of an array, so it can be displayed without loading its manifests
+///
+/// Commits can store it as a small sidecar object, see [`Snapshot::node_stats`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeStats {
+    /// Number of chunks written, including virtual chunks
+    pub chunk_count: u64,
+    /// Sum of the stored length of all the chunks, in bytes
+    pub total_size: u64,
+    /// Fraction of the chunk grid that has been written
+    pub populated_fraction: f64,
+}
+
+impl NodeStats {
+    pub fn from_chunks<'a>(
+        metadata: &ZarrArrayMetadata,
+        chunks: impl IntoIterator<Item = &'a ChunkPayload>,
+    ) -> Self {
+        let (chunk_count, total_size) =
+            chunks.into_iter().fold((0, 0), |(count, size), payload| {
+                let len = match payload {
+                    ChunkPayload::Inline(bytes) => bytes.len() as u64,
+                    ChunkPayload::Ref(chunk_ref) => chunk_ref.length,
+                    ChunkPayload::Virtual(chunk_ref) => chunk_ref.length,
+                };
+                (count + 1, size + len)
+            });
+        let grid_size: u64 = metadata.num_chunks().iter().product();
+        let populated_fraction =
+            if grid_size == 0 { 0.0 } else { chunk_count as f64 / grid_size as f64 };
+        Self { chunk_count, total_size, populated_fraction }
+    }
 }
 
 impl Default for SnapshotMetadata {
@@ -156,6 +214,7 @@ impl Snapshot {
             started_at,
             properties,
             nodes,
+            node_stats: BTreeMap::new(),
         }
     }
 
@@ -199,10 +258,84 @@ impl Snapshot {
         self.nodes.values()
     }
 
+    /// Iterate over `root` and all the nodes under it, in path order
+    pub fn iter_subtree<'a>(
+        &'a self,
+        root: &'a Path,
+    ) -> impl Iterator<Item = &'a NodeSnapshot> + 'a {
+        // paths sort by component, so all the descendants of a node follow it contiguously
+        self.nodes
+            .range(root..)
+            .take_while(move |(path, _)| path.starts_with(root))
+            .map(|(_, node)| node)
+    }
+
+    /// A copy of this snapshot that only keeps the nodes under `root`, and their ancestors
+    ///
+    /// Everything else, including the id, history and the list of manifest files, is kept.
+    pub fn subtree(&self, root: &Path) -> Snapshot {
+        let ancestors = root
+            .ancestors()
+            .skip(1)
+            .filter_map(|path| self.nodes.get(&path))
+            .map(|node| (node.path.clone(), node.clone()));
+        let nodes: BTreeMap<_, _> = self
+            .iter_subtree(root)
+            .map(|node| (node.path.clone(), node.clone()))
+            .chain(ancestors)
+            .collect();
+        Self {
+            icechunk_snapshot_format_version: self.icechunk_snapshot_format_version,
+            icechunk_snapshot_format_flags: self.icechunk_snapshot_format_flags.clone(),
+            manifest_files: self.manifest_files.clone(),
+            attribute_files: self.attribute_files.clone(),
+            total_parents: self.total_parents,
+            short_term_parents: self.short_term_parents,
+            short_term_history: self.short_term_history.clone(),
+            metadata: self.metadata.clone(),
+            started_at: self.started_at,
+            properties: self.properties.clone(),
+            node_stats: self
+                .node_stats
+                .iter()
+                .filter(|(id, _)| nodes.values().any(|node| node.id == **id))
+                .map(|(id, stats)| (*id, stats.clone()))
+                .collect(),
+            nodes,
+        }
+    }
+
+    /// The ids of the sidecar objects with the [`NodeStats`] of each array, stored as chunks
+    pub fn node_stats_ids(&self) -> &BTreeMap<NodeId, ChunkId> {
+        &self.node_stats
+    }
+
+    pub fn set_node_stats_ids(&mut self, ids: BTreeMap<NodeId, ChunkId>) {
+        self.node_stats = ids;
+    }
+
+    /// Fetch the stats of `node`, they are `None` if the commit didn't compute them
+    pub async fn node_stats(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        node: NodeId,
+    ) -> StorageResult<Option<NodeStats>> {
+        let Some(id) = self.node_stats.get(&node) else {
+            return Ok(None);
+        };
+        let bytes = storage.fetch_chunk(id, &ByteRange::ALL).await?;
+        Ok(Some(rmp_serde::from_slice(&bytes)?))
+    }
+
     pub fn iter_arc(self: Arc<Self>) -> impl Iterator<Item = NodeSnapshot> {
         NodeIterator { table: self, last_key: None }
     }
 
+    /// The id of the snapshot this one was committed on top of, `None` for the root snapshot
+    pub fn parent_id(&self) -> Option<&SnapshotId> {
+        self.short_term_history.front().map(|parent| &parent.id)
+    }
+
     pub fn local_ancestry(self: Arc<Self>) -> impl Iterator<Item = SnapshotMetadata> {
         (0..self.short_term_history.len())
             .map(move |ix| self.short_term_history[ix].clone())
@@ -392,6 +525,21 @@ mod tests {
             })
         );
 
+        let subtree = st.subtree(&"/b".try_into().unwrap());
+        assert_eq!(subtree.metadata, st.metadata);
+        assert_eq!(
+            subtree.iter().map(|node| node.id).collect::<Vec<_>>(),
+            // the root group is kept as an ancestor, "/a" and "/array2" are outside
+            vec![1, 3, 5, 7, 4]
+        );
+        assert_eq!(
+            st.iter_subtree(&"/b/array1".try_into().unwrap())
+                .map(|node| node.id)
+                .collect::<Vec<_>>(),
+            vec![5]
+        );
+        assert_eq!(st.subtree(&"/nonexistent".try_into().unwrap()).iter().count(), 1);
+
         let node = st.get_node(&"/b/c".try_into().unwrap());
         assert_eq!(
             node,
@@ -20,11 +20,14 @@
 pub mod change_set;
 pub mod format;
 pub mod metadata;
+pub mod ops;
 pub mod refs;
 pub mod repository;
 pub mod storage;
 #[cfg(test)]
 pub mod strategies;
+pub mod vfs;
+pub mod view;
 pub mod zarr;
 
 pub use repository::{Repository, RepositoryBuilder, RepositoryConfig, SnapshotMetadata};
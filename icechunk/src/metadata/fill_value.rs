@@ -213,6 +213,34 @@ impl FillValue {
         }
     }
 
+    /// The bytes of one element with this value, little-endian like the zarr `bytes` codec
+    ///
+    /// Returns `None` for variable length types, that don't have a fixed size encoding.
+    pub fn to_le_bytes(&self) -> Option<Vec<u8>> {
+        let bytes = match self {
+            FillValue::Bool(v) => vec![*v as u8],
+            FillValue::Int8(v) => v.to_le_bytes().to_vec(),
+            FillValue::Int16(v) => v.to_le_bytes().to_vec(),
+            FillValue::Int32(v) => v.to_le_bytes().to_vec(),
+            FillValue::Int64(v) => v.to_le_bytes().to_vec(),
+            FillValue::UInt8(v) => v.to_le_bytes().to_vec(),
+            FillValue::UInt16(v) => v.to_le_bytes().to_vec(),
+            FillValue::UInt32(v) => v.to_le_bytes().to_vec(),
+            FillValue::UInt64(v) => v.to_le_bytes().to_vec(),
+            FillValue::Float16(v) => f32_to_f16_bits(*v).to_le_bytes().to_vec(),
+            FillValue::Float32(v) => v.to_le_bytes().to_vec(),
+            FillValue::Float64(v) => v.to_le_bytes().to_vec(),
+            FillValue::Complex64(re, im) => {
+                re.to_le_bytes().into_iter().chain(im.to_le_bytes()).collect()
+            }
+            FillValue::Complex128(re, im) => {
+                re.to_le_bytes().into_iter().chain(im.to_le_bytes()).collect()
+            }
+            FillValue::String(_) | FillValue::Bytes(_) => return None,
+        };
+        Some(bytes)
+    }
+
     pub fn get_data_type(&self) -> DataType {
         match self {
             FillValue::Bool(_) => DataType::Bool,
@@ -235,11 +263,72 @@ impl FillValue {
     }
 }
 
+/// Convert to half precision, rounding to nearest even
+fn f32_to_f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // infinity stays infinity, NaN stays a quiet NaN
+        return sign | 0x7c00 | if mantissa == 0 { 0 } else { 0x200 };
+    }
+
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exp <= 0 {
+        // subnormal, or too small even for that
+        if exp < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exp) as u32;
+        let half = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = rest > halfway || (rest == halfway && half & 1 == 1);
+        return sign | (half + round_up as u32) as u16;
+    }
+
+    let half = ((exp as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    // a carry out of the mantissa correctly bumps the exponent, up to infinity
+    let round_up = rest > 0x1000 || (rest == 0x1000 && half & 1 == 1);
+    sign | (half + round_up as u32) as u16
+}
+
 #[cfg(test)]
 #[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_fill_value_bytes() {
+        let cases = [
+            (FillValue::Bool(true), Some(vec![1])),
+            (FillValue::Int16(-2), Some(vec![0xfe, 0xff])),
+            (FillValue::UInt32(1), Some(vec![1, 0, 0, 0])),
+            (FillValue::Float16(1.0), Some(vec![0x00, 0x3c])),
+            (FillValue::Float16(-2.0), Some(vec![0x00, 0xc0])),
+            (FillValue::Float16(65504.0), Some(vec![0xff, 0x7b])),
+            (FillValue::Float16(1e6), Some(vec![0x00, 0x7c])),
+            (FillValue::Float16(f32::NAN), Some(vec![0x00, 0x7e])),
+            (FillValue::Float16(6e-8), Some(vec![0x01, 0x00])),
+            (FillValue::Float32(1.5), Some(1.5f32.to_le_bytes().to_vec())),
+            (
+                FillValue::Complex64(1.0, -1.0),
+                Some(vec![0, 0, 0x80, 0x3f, 0, 0, 0x80, 0xbf]),
+            ),
+            (FillValue::String("a".to_string()), None),
+            (FillValue::Bytes(vec![1, 2]), None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(value.to_le_bytes(), expected, "{value:?}");
+        }
+    }
+
     #[test]
     fn test_nan_inf_parsing() {
         assert_eq!(
@@ -0,0 +1,130 @@
+//! Write many chunks with bounded concurrency
+//!
+//! Committing a large array can require writing millions of chunks, [`write_chunks`] keeps a
+//! bounded number of writes in flight and reports the outcome of all of them.
+use bytes::Bytes;
+use futures::{pin_mut, Stream, StreamExt};
+
+use crate::{
+    format::ChunkId,
+    storage::{StorageError, StorageResult},
+    Storage,
+};
+
+/// The outcome of [`write_chunks`]
+#[derive(Debug, Default)]
+pub struct WriteSummary {
+    /// Chunks written successfully
+    pub written: usize,
+    /// Total size of the chunks written successfully
+    pub bytes: u64,
+    /// Chunks that couldn't be written, there is at most one when failing fast
+    pub errors: Vec<(ChunkId, StorageError)>,
+}
+
+impl WriteSummary {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Write a stream of chunks, with at most `concurrency` writes in flight
+///
+/// Chunks are written in no particular order, and the stream is only consumed as writes
+/// complete. With `fail_fast` no new writes are started after the first error, and writes still
+/// in flight are abandoned. Otherwise every chunk is attempted, and all the errors are collected
+/// in the summary. Write errors don't fail the call, check [`WriteSummary::is_ok`].
+pub async fn write_chunks(
+    storage: &(dyn Storage + Send + Sync),
+    chunks: impl Stream<Item = (ChunkId, Bytes)>,
+    concurrency: usize,
+    fail_fast: bool,
+) -> StorageResult<WriteSummary> {
+    let results = chunks
+        .map(|(id, bytes)| async move {
+            let len = bytes.len() as u64;
+            let res = storage.write_chunk(id.clone(), bytes).await;
+            (id, len, res)
+        })
+        .buffer_unordered(concurrency.max(1));
+    pin_mut!(results);
+
+    let mut summary = WriteSummary::default();
+    while let Some((id, len, res)) = results.next().await {
+        match res {
+            Ok(()) => {
+                summary.written += 1;
+                summary.bytes += len;
+            }
+            Err(err) => {
+                summary.errors.push((id, err));
+                if fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+    use crate::{
+        format::ByteRange,
+        ops::tests::{flaky_storage, WriteQuota},
+        ObjectStorage,
+    };
+
+    fn chunks(n: usize) -> Vec<(ChunkId, Bytes)> {
+        (0..n).map(|i| (ChunkId::random(), Bytes::from(vec![i as u8; i + 1]))).collect()
+    }
+
+    #[tokio::test]
+    async fn test_write_chunks() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let chunks = chunks(100);
+        let summary =
+            write_chunks(&storage, stream::iter(chunks.clone()), 8, true).await?;
+        assert!(summary.is_ok());
+        assert_eq!(summary.written, 100);
+        assert_eq!(summary.bytes, (1..=100).sum::<u64>());
+        for (id, bytes) in chunks {
+            assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, bytes);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_chunks_with_failures() -> Result<(), Box<dyn std::error::Error>> {
+        let chunks = chunks(10);
+
+        // one write at a time, so the quota runs out at a known chunk
+        let storage = flaky_storage(WriteQuota::new(5));
+        let summary =
+            write_chunks(&storage, stream::iter(chunks.clone()), 1, true).await?;
+        assert_eq!(summary.written, 5);
+        assert_eq!(summary.bytes, (1..=5).sum::<u64>());
+        let failed: Vec<_> = summary.errors.iter().map(|(id, _)| id.clone()).collect();
+        assert_eq!(failed, vec![chunks[5].0.clone()]);
+
+        let storage = flaky_storage(WriteQuota::new(5));
+        let summary =
+            write_chunks(&storage, stream::iter(chunks.clone()), 1, false).await?;
+        assert!(!summary.is_ok());
+        assert_eq!(summary.written, 5);
+        let failed: Vec<_> = summary.errors.iter().map(|(id, _)| id.clone()).collect();
+        let expected: Vec<_> = chunks[5..].iter().map(|(id, _)| id.clone()).collect();
+        assert_eq!(failed, expected);
+
+        // with concurrency every chunk is still accounted for
+        let storage = flaky_storage(WriteQuota::new(5));
+        let summary = write_chunks(&storage, stream::iter(chunks), 4, false).await?;
+        assert_eq!(summary.written, 5);
+        assert_eq!(summary.errors.len(), 5);
+        Ok(())
+    }
+}
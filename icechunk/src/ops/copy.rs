@@ -0,0 +1,231 @@
+//! Copy a repository version between storage instances
+//!
+//! Copies can be large, so they can be cancelled by dropping the returned future, and resumed
+//! later from a [`CopyCheckpoint`].
+use std::{
+    collections::BTreeSet,
+    future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use futures::{stream, StreamExt};
+
+use super::snapshot_closure;
+use crate::{
+    format::{ByteRange, SnapshotId},
+    storage::{AnyObjectId, ObjectKind, StorageResult},
+    Storage,
+};
+
+/// How many objects are copied concurrently
+const COPY_CONCURRENCY: usize = 16;
+
+/// Records the objects already copied by [`copy_snapshot_closure`]
+///
+/// A checkpoint can be serialized with [`CopyCheckpoint::save_checkpoint`] and restored with
+/// [`CopyCheckpoint::from_bytes`] to resume an interrupted copy in a different process.
+#[derive(Debug, Default)]
+pub struct CopyCheckpoint {
+    copied: Mutex<BTreeSet<AnyObjectId>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CopySummary {
+    /// Objects written to the destination by this call
+    pub copied: usize,
+    /// Objects found in the checkpoint and in the destination, that didn't need copying
+    pub skipped: usize,
+}
+
+impl CopyCheckpoint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> StorageResult<Self> {
+        let copied: BTreeSet<AnyObjectId> = rmp_serde::from_slice(bytes)?;
+        Ok(Self { copied: Mutex::new(copied) })
+    }
+
+    #[allow(clippy::expect_used)]
+    pub fn save_checkpoint(&self) -> StorageResult<Vec<u8>> {
+        let copied = self.copied.lock().expect("poison lock");
+        Ok(rmp_serde::to_vec(&*copied)?)
+    }
+
+    #[allow(clippy::expect_used)]
+    pub fn contains(&self, id: &AnyObjectId) -> bool {
+        self.copied.lock().expect("poison lock").contains(id)
+    }
+
+    #[allow(clippy::expect_used)]
+    pub fn len(&self) -> usize {
+        self.copied.lock().expect("poison lock").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(clippy::expect_used)]
+    fn record(&self, id: AnyObjectId) {
+        self.copied.lock().expect("poison lock").insert(id);
+    }
+}
+
+/// Copy a snapshot and every object reachable from it to a different storage
+///
+/// Objects are written in dependency order, so the snapshot is only present in the destination
+/// once all its objects have been copied. Refs are not copied.
+///
+/// If a `checkpoint` is passed, every successfully copied object is recorded in it. Objects
+/// already in the checkpoint are skipped, as long as they are still present in the destination.
+/// This allows resuming a copy that failed or was cancelled by dropping the future.
+pub async fn copy_snapshot_closure(
+    from: &(dyn Storage + Send + Sync),
+    to: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+    checkpoint: Option<&CopyCheckpoint>,
+) -> StorageResult<CopySummary> {
+    let closure = snapshot_closure(from, snapshot_id).await?;
+    let (leaves, rest): (Vec<_>, Vec<_>) = closure
+        .into_iter()
+        .partition(|id| matches!(id.kind(), ObjectKind::Chunk | ObjectKind::Attributes));
+    let (manifests, snapshots): (Vec<_>, Vec<_>) =
+        rest.into_iter().partition(|id| id.kind() == ObjectKind::Manifest);
+
+    let mut summary = CopySummary::default();
+    // each phase must complete before the next one starts, so that objects are never
+    // written before the objects they reference
+    for phase in [leaves, manifests, snapshots] {
+        // after a failure we stop starting new copies, but we wait for the ones in flight,
+        // so they get recorded in the checkpoint
+        let failed = AtomicBool::new(false);
+        let mut results = stream::iter(phase)
+            .take_while(|_| future::ready(!failed.load(Ordering::Relaxed)))
+            .map(|id| copy_object(from, to, id, checkpoint))
+            .buffer_unordered(COPY_CONCURRENCY);
+        let mut error = None;
+        while let Some(res) = results.next().await {
+            match res {
+                Ok(true) => summary.copied += 1,
+                Ok(false) => summary.skipped += 1,
+                Err(err) => {
+                    failed.store(true, Ordering::Relaxed);
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        if let Some(err) = error {
+            return Err(err);
+        }
+    }
+    Ok(summary)
+}
+
+/// Returns false if the object was skipped
+async fn copy_object(
+    from: &(dyn Storage + Send + Sync),
+    to: &(dyn Storage + Send + Sync),
+    id: AnyObjectId,
+    checkpoint: Option<&CopyCheckpoint>,
+) -> StorageResult<bool> {
+    if let Some(checkpoint) = checkpoint {
+        if checkpoint.contains(&id) && to.exists(&id).await? {
+            return Ok(false);
+        }
+    }
+
+    match &id {
+        AnyObjectId::Snapshot(id) => {
+            to.write_snapshot(id.clone(), from.fetch_snapshot(id).await?).await?
+        }
+        AnyObjectId::Manifest(id) => {
+            to.write_manifests(id.clone(), from.fetch_manifests(id).await?).await?
+        }
+        AnyObjectId::Attributes(id) => {
+            to.write_attributes(id.clone(), from.fetch_attributes(id).await?).await?
+        }
+        AnyObjectId::Chunk(id) => {
+            to.write_chunk(id.clone(), from.fetch_chunk(id, &ByteRange::ALL).await?)
+                .await?
+        }
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.record(id);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::*;
+    use crate::{
+        ops::tests::{flaky_storage, write_source, WriteQuota},
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_snapshot_closure_puts_the_snapshot_last() {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let (snapshot_id, expected) = write_source(&storage).await;
+        let closure = snapshot_closure(&storage, &snapshot_id).await.unwrap();
+        assert_eq!(closure.iter().cloned().collect::<BTreeSet<_>>(), expected);
+        assert_eq!(closure.len(), expected.len());
+        assert_eq!(closure.last(), Some(&AnyObjectId::Snapshot(snapshot_id)));
+        assert_eq!(closure[closure.len() - 2].kind(), ObjectKind::Manifest);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_copy_resumes_from_checkpoint() {
+        let source = ObjectStorage::new_in_memory_store(None);
+        let (snapshot_id, closure) = write_source(&source).await;
+
+        let quota = WriteQuota::new(4);
+        let dest = flaky_storage(Arc::clone(&quota));
+        let checkpoint = CopyCheckpoint::new();
+        let res =
+            copy_snapshot_closure(&source, &dest, &snapshot_id, Some(&checkpoint)).await;
+        assert!(res.is_err());
+        let first_writes = quota.take_writes();
+        assert_eq!(first_writes.len(), 4);
+        assert_eq!(checkpoint.len(), 4);
+        // nothing that references missing objects was written
+        assert!(first_writes.iter().all(|id| id.kind() == ObjectKind::Chunk));
+
+        // resume in a new process, from the serialized checkpoint
+        let checkpoint =
+            CopyCheckpoint::from_bytes(&checkpoint.save_checkpoint().unwrap()).unwrap();
+        quota.allow_writes(usize::MAX);
+        let summary =
+            copy_snapshot_closure(&source, &dest, &snapshot_id, Some(&checkpoint))
+                .await
+                .unwrap();
+        assert_eq!(summary, CopySummary { copied: closure.len() - 4, skipped: 4 });
+
+        let second_writes = quota.take_writes();
+        let mut counts: HashMap<&AnyObjectId, usize> = HashMap::new();
+        for id in first_writes.iter().chain(second_writes.iter()) {
+            *counts.entry(id).or_default() += 1;
+        }
+        assert!(counts.values().all(|n| *n == 1));
+        assert_eq!(
+            counts.keys().map(|id| (*id).clone()).collect::<BTreeSet<_>>(),
+            closure
+        );
+        assert_eq!(
+            second_writes.last(),
+            Some(&AnyObjectId::Snapshot(snapshot_id.clone()))
+        );
+
+        let copied = dest.fetch_snapshot(&snapshot_id).await.unwrap();
+        assert_eq!(copied, source.fetch_snapshot(&snapshot_id).await.unwrap());
+    }
+}
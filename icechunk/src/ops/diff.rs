@@ -0,0 +1,230 @@
+//! Compare two versions of a repository
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    format::{
+        manifest::ChunkPayload,
+        snapshot::{NodeData, NodeSnapshot},
+        ChunkIndices, Path, SnapshotId,
+    },
+    storage::StorageResult,
+    Storage,
+};
+
+/// What changed between two snapshots
+///
+/// Nodes are identified by their path, a node deleted and created again at the same path is
+/// reported as modified.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SnapshotDiff {
+    pub added_nodes: BTreeSet<Path>,
+    pub removed_nodes: BTreeSet<Path>,
+    /// Nodes in both snapshots with different type, metadata, attributes or chunks
+    pub modified_nodes: BTreeSet<Path>,
+    /// For every array with changed chunks, the coordinates of the chunks written, deleted, or
+    /// with a different payload
+    ///
+    /// The chunks of added and removed arrays are all included.
+    pub changed_chunks: BTreeMap<Path, BTreeSet<ChunkIndices>>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.modified_nodes.is_empty()
+    }
+}
+
+/// Find the nodes and chunks that changed from snapshot `from` to snapshot `to`
+///
+/// Chunk payloads are compared as they are stored in the manifests, a chunk that moved between
+/// inline and referenced storage is changed even if its bytes are the same.
+pub async fn diff_snapshots(
+    storage: &(dyn Storage + Send + Sync),
+    from: &SnapshotId,
+    to: &SnapshotId,
+) -> StorageResult<SnapshotDiff> {
+    let from = storage.fetch_snapshot(from).await?;
+    let to = storage.fetch_snapshot(to).await?;
+    let mut diff = SnapshotDiff::default();
+
+    for old in from.iter() {
+        if to.get_node(&old.path).is_err() {
+            diff.removed_nodes.insert(old.path.clone());
+            let chunks = node_chunks(storage, old).await?;
+            if !chunks.is_empty() {
+                diff.changed_chunks
+                    .insert(old.path.clone(), chunks.into_keys().collect());
+            }
+        }
+    }
+
+    for new in to.iter() {
+        let new_chunks = node_chunks(storage, new).await?;
+        let Ok(old) = from.get_node(&new.path) else {
+            diff.added_nodes.insert(new.path.clone());
+            if !new_chunks.is_empty() {
+                diff.changed_chunks
+                    .insert(new.path.clone(), new_chunks.into_keys().collect());
+            }
+            continue;
+        };
+
+        let old_chunks = node_chunks(storage, old).await?;
+        let changed: BTreeSet<_> = old_chunks
+            .keys()
+            .chain(new_chunks.keys())
+            .filter(|coord| old_chunks.get(*coord) != new_chunks.get(*coord))
+            .cloned()
+            .collect();
+        if !changed.is_empty() || node_changed(old, new) {
+            diff.modified_nodes.insert(new.path.clone());
+        }
+        if !changed.is_empty() {
+            diff.changed_chunks.insert(new.path.clone(), changed);
+        }
+    }
+    Ok(diff)
+}
+
+/// Compare everything but the chunks, manifest refs change with every commit
+pub(crate) fn node_changed(old: &NodeSnapshot, new: &NodeSnapshot) -> bool {
+    let metadata_changed = match (&old.node_data, &new.node_data) {
+        (NodeData::Group, NodeData::Group) => false,
+        (NodeData::Array(old, _), NodeData::Array(new, _)) => old != new,
+        _ => true,
+    };
+    metadata_changed || old.user_attributes != new.user_attributes
+}
+
+/// The chunks of an array, empty for groups
+///
+/// When a chunk is in several manifests the first one wins, like when reading the chunk.
+async fn node_chunks(
+    storage: &(dyn Storage + Send + Sync),
+    node: &NodeSnapshot,
+) -> StorageResult<BTreeMap<ChunkIndices, ChunkPayload>> {
+    let mut chunks = BTreeMap::new();
+    if let NodeData::Array(_, manifests) = &node.node_data {
+        for manifest_ref in manifests {
+            let manifest =
+                storage.fetch_node_chunks(&manifest_ref.object_id, node.id).await?;
+            for ((_, coord), payload) in manifest.chunks() {
+                chunks.entry(coord.clone()).or_insert_with(|| payload.clone());
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{num::NonZeroU64, sync::Arc};
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkRef, ObjectId},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        ObjectStorage, Repository,
+    };
+
+    fn array_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    fn coords(indices: &[u32]) -> BTreeSet<ChunkIndices> {
+        indices.iter().map(|i| ChunkIndices(vec![*i])).collect()
+    }
+
+    #[tokio::test]
+    async fn test_diff_snapshots() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into()?;
+        let group: Path = "/group".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_group(group.clone()).await?;
+        repo.add_array(array.clone(), array_metadata()).await?;
+        for i in 0..3 {
+            repo.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![i]),
+                Some(ChunkPayload::Inline(format!("chunk {i}").into())),
+            )
+            .await?;
+        }
+        let first = repo.commit("main", "first", None).await?;
+
+        // chunk 0 moves to a referenced payload, 1 is removed, 2 is unchanged, 3 is added
+        repo.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Ref(ChunkRef {
+                id: ObjectId::random(),
+                offset: 0,
+                length: 7,
+            })),
+        )
+        .await?;
+        repo.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), None).await?;
+        repo.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline("chunk 3".into())),
+        )
+        .await?;
+        let new_array: Path = "/new".try_into()?;
+        repo.add_array(new_array.clone(), array_metadata()).await?;
+        repo.set_chunk_ref(
+            new_array.clone(),
+            ChunkIndices(vec![5]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+        repo.delete_group(group.clone()).await?;
+        let second = repo.commit("main", "second", None).await?;
+
+        let diff = diff_snapshots(storage.as_ref(), &first, &second).await?;
+        assert_eq!(diff.added_nodes, BTreeSet::from([new_array.clone()]));
+        assert_eq!(diff.removed_nodes, BTreeSet::from([group]));
+        assert_eq!(diff.modified_nodes, BTreeSet::from([array.clone()]));
+        assert_eq!(
+            diff.changed_chunks,
+            BTreeMap::from([
+                (array.clone(), coords(&[0, 1, 3])),
+                (new_array, coords(&[5]))
+            ])
+        );
+
+        // the reverse diff swaps added and removed nodes
+        let reverse = diff_snapshots(storage.as_ref(), &second, &first).await?;
+        assert_eq!(reverse.added_nodes, diff.removed_nodes);
+        assert_eq!(reverse.removed_nodes, diff.added_nodes);
+        assert_eq!(reverse.changed_chunks, diff.changed_chunks);
+
+        // writing a chunk with the same payload doesn't change it
+        repo.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![2]),
+            Some(ChunkPayload::Inline("chunk 2".into())),
+        )
+        .await?;
+        let third = repo.commit("main", "third", None).await?;
+        assert!(diff_snapshots(storage.as_ref(), &second, &third).await?.is_empty());
+        assert!(diff_snapshots(storage.as_ref(), &third, &third).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,566 @@
+//! Maintenance operations that work on all the objects of a repository version
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter,
+};
+
+use futures::{stream, Future, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    format::{
+        manifest::ChunkPayload,
+        snapshot::{NodeData, NodeSnapshot, Snapshot, UserAttributesSnapshot},
+        AttributesId, ByteRange, ChunkLength, ManifestId, Path, SnapshotId,
+    },
+    storage::{AnyObjectId, StorageError, StorageResult},
+    Storage,
+};
+
+pub mod bulk;
+pub mod copy;
+pub mod diff;
+
+/// Find all the objects reachable from a snapshot
+///
+/// The result includes the snapshot itself, its manifests and attribute files, and all chunks
+/// referenced from the manifests. Parent snapshots and virtual chunks are not included.
+///
+/// Objects are returned in dependency order: chunks and attributes first, then manifests and the
+/// snapshot last. Writing the objects in this order makes sure no object is visible before the
+/// objects it references.
+pub async fn snapshot_closure(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> StorageResult<Vec<AnyObjectId>> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let (manifests, attributes) = referenced_files(&snapshot);
+
+    let mut chunks = BTreeSet::new();
+    for manifest_id in manifests.iter() {
+        let manifest = storage.fetch_manifests(manifest_id).await?;
+        chunks.extend(manifest.chunks().values().filter_map(|payload| match payload {
+            ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+            _ => None,
+        }));
+    }
+    chunks.extend(snapshot.node_stats_ids().values().cloned());
+
+    Ok(chunks
+        .into_iter()
+        .map(AnyObjectId::Chunk)
+        .chain(attributes.into_iter().map(AnyObjectId::Attributes))
+        .chain(manifests.into_iter().map(AnyObjectId::Manifest))
+        .chain(iter::once(AnyObjectId::Snapshot(snapshot_id.clone())))
+        .collect())
+}
+
+/// The manifests and attribute files referenced by a snapshot
+fn referenced_files(
+    snapshot: &Snapshot,
+) -> (BTreeSet<ManifestId>, BTreeSet<AttributesId>) {
+    let mut manifests: BTreeSet<_> =
+        snapshot.manifest_files.iter().map(|info| info.id.clone()).collect();
+    let mut attributes: BTreeSet<_> =
+        snapshot.attribute_files.iter().map(|info| info.id.clone()).collect();
+    for node in snapshot.iter() {
+        if let Some(UserAttributesSnapshot::Ref(atts)) = &node.user_attributes {
+            attributes.insert(atts.object_id.clone());
+        }
+        if let NodeData::Array(_, manifest_refs) = &node.node_data {
+            manifests.extend(manifest_refs.iter().map(|mref| mref.object_id.clone()));
+        }
+    }
+    (manifests, attributes)
+}
+
+/// Iterate over all the nodes in a snapshot, in path order
+///
+/// An empty snapshot, like the initial commit of a repository, yields no nodes.
+pub async fn iter_nodes(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> StorageResult<impl Iterator<Item = NodeSnapshot>> {
+    Ok(storage.fetch_snapshot(snapshot_id).await?.iter_arc())
+}
+
+/// Find the node at `path` in a snapshot, `None` if there is no such node
+pub async fn resolve_node(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+    path: &Path,
+) -> StorageResult<Option<NodeSnapshot>> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    Ok(snapshot.get_node(path).ok().cloned())
+}
+
+/// The storage used by the objects of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SnapshotFootprint {
+    pub nodes: usize,
+    pub manifests: usize,
+    pub attributes: usize,
+    /// Materialized chunks, inline and virtual chunks are not counted
+    pub chunks: usize,
+    /// Total size of the materialized chunks
+    pub chunk_bytes: u64,
+}
+
+/// Compute how much storage a snapshot uses
+///
+/// Chunks are counted once, even if referenced multiple times. Objects shared with other
+/// snapshots are included.
+pub async fn snapshot_footprint(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> StorageResult<SnapshotFootprint> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let mut footprint = SnapshotFootprint { nodes: snapshot.len(), ..Default::default() };
+    let mut chunk_sizes: BTreeMap<_, ChunkLength> = BTreeMap::new();
+    for id in snapshot_closure(storage, snapshot_id).await? {
+        match id {
+            AnyObjectId::Manifest(id) => {
+                footprint.manifests += 1;
+                let manifest = storage.fetch_manifests(&id).await?;
+                for payload in manifest.chunks().values() {
+                    if let ChunkPayload::Ref(chunk_ref) = payload {
+                        let size = chunk_sizes.entry(chunk_ref.id.clone()).or_default();
+                        // chunks can be referenced in pieces, we take the furthest byte used
+                        *size = (*size).max(chunk_ref.offset + chunk_ref.length);
+                    }
+                }
+            }
+            AnyObjectId::Attributes(_) => footprint.attributes += 1,
+            AnyObjectId::Chunk(_) | AnyObjectId::Snapshot(_) => {}
+        }
+    }
+    footprint.chunks = chunk_sizes.len();
+    footprint.chunk_bytes = chunk_sizes.values().sum();
+    Ok(footprint)
+}
+
+/// Check that every object reachable from a snapshot is present in storage
+///
+/// Returns the missing objects, an empty vector if the snapshot is complete.
+pub async fn verify_reachable(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> StorageResult<Vec<AnyObjectId>> {
+    let closure = snapshot_closure(storage, snapshot_id).await?;
+    stream::iter(closure)
+        .map(|id| async move { Ok((storage.exists(&id).await?, id)) })
+        .buffered(16)
+        .try_filter_map(|(exists, id)| async move { Ok((!exists).then_some(id)) })
+        .try_collect()
+        .await
+}
+
+/// A hash identifying all the objects reachable from a snapshot
+///
+/// Objects are immutable and their ids are unique, so two closures with the same fingerprint
+/// hold the same objects, and any differing object changes the fingerprint. Comparing the
+/// fingerprint of a replica with the source's checks they reference the same objects, use
+/// [`verify_reachable`] to also check the objects are present.
+pub async fn closure_fingerprint(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> StorageResult<[u8; 32]> {
+    let mut closure = snapshot_closure(storage, snapshot_id).await?;
+    closure.sort();
+    let mut hasher = Sha256::new();
+    for id in closure {
+        hasher.update([id.kind() as u8]);
+        hasher.update(id.as_bytes());
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// The result of [`verify_content_addresses`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IntegrityReport {
+    /// Number of objects checked, including missing and corrupted ones
+    pub checked: usize,
+    pub missing: Vec<AnyObjectId>,
+    /// Objects that cannot be decoded, or whose contents don't match how they are referenced
+    pub corrupted: Vec<AnyObjectId>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// Fetch and check every object reachable from a snapshot, like `git fsck --full`
+///
+/// Object ids are random, not derived from the contents, so they cannot be recomputed. Instead,
+/// each object is checked against what is recorded about it elsewhere:
+///
+/// * snapshots must decode and contain their own id,
+/// * manifests and attribute files must decode,
+/// * chunks must be long enough for every reference to them.
+///
+/// Objects that cannot be read because they reference corrupted objects are not checked.
+/// Storage errors other than decoding failures abort the verification.
+pub async fn verify_content_addresses(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> StorageResult<IntegrityReport> {
+    let mut report = IntegrityReport::default();
+    let snapshot_oid = AnyObjectId::Snapshot(snapshot_id.clone());
+    let snapshot = match fetch_checked(
+        storage,
+        &snapshot_oid,
+        storage.fetch_snapshot(snapshot_id),
+        &mut report,
+    )
+    .await?
+    {
+        Some(snapshot) if &snapshot.metadata.id == snapshot_id => snapshot,
+        Some(_) => {
+            report.corrupted.push(snapshot_oid);
+            return Ok(report);
+        }
+        None => return Ok(report),
+    };
+
+    let (manifests, attributes) = referenced_files(&snapshot);
+    for id in attributes {
+        let oid = AnyObjectId::Attributes(id.clone());
+        fetch_checked(storage, &oid, storage.fetch_attributes(&id), &mut report).await?;
+    }
+
+    // the furthest byte used in each chunk
+    let mut chunk_sizes: BTreeMap<_, ChunkLength> = BTreeMap::new();
+    for id in manifests {
+        let oid = AnyObjectId::Manifest(id.clone());
+        if let Some(manifest) =
+            fetch_checked(storage, &oid, storage.fetch_manifests(&id), &mut report)
+                .await?
+        {
+            for payload in manifest.chunks().values() {
+                if let ChunkPayload::Ref(chunk_ref) = payload {
+                    let size = chunk_sizes.entry(chunk_ref.id.clone()).or_default();
+                    *size = (*size).max(chunk_ref.offset + chunk_ref.length);
+                }
+            }
+        }
+    }
+
+    // chunks are the bulk of the objects, we check them concurrently
+    let chunk_results: Vec<_> = stream::iter(chunk_sizes)
+        .map(|(id, size)| async move {
+            let oid = AnyObjectId::Chunk(id.clone());
+            if !storage.exists(&oid).await? {
+                return Ok((oid, false, false));
+            }
+            let bytes = storage.fetch_chunk(&id, &ByteRange::ALL).await?;
+            Ok::<_, StorageError>((oid, true, bytes.len() as u64 >= size))
+        })
+        .buffered(16)
+        .try_collect()
+        .await?;
+    for (oid, exists, complete) in chunk_results {
+        report.checked += 1;
+        if !exists {
+            report.missing.push(oid);
+        } else if !complete {
+            report.corrupted.push(oid);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Fetch an object, recording it in the report as missing or corrupted if needed
+async fn fetch_checked<T>(
+    storage: &(dyn Storage + Send + Sync),
+    id: &AnyObjectId,
+    fetch: impl Future<Output = StorageResult<T>>,
+    report: &mut IntegrityReport,
+) -> StorageResult<Option<T>> {
+    report.checked += 1;
+    if !storage.exists(id).await? {
+        report.missing.push(id.clone());
+        return Ok(None);
+    }
+    match fetch.await {
+        Ok(res) => Ok(Some(res)),
+        Err(StorageError::MsgPackDecodeError(_)) => {
+            report.corrupted.push(id.clone());
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        num::NonZeroU64,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        format::{
+            format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+            manifest::{ChunkInfo, ChunkRef, Manifest, ManifestExtents, ManifestRef},
+            snapshot::{ManifestFileInfo, ZarrArrayMetadata},
+            ChunkId, ChunkIndices, ObjectId,
+        },
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::fetch_branch_tip,
+        storage::faulty::{Fault, FaultyStorage},
+        ObjectStorage, Repository,
+    };
+
+    /// How many more objects [`flaky_storage`] writes, with the ids of the objects it wrote
+    #[derive(Debug)]
+    pub(super) struct WriteQuota {
+        remaining: AtomicUsize,
+        writes: Mutex<Vec<AnyObjectId>>,
+    }
+
+    impl WriteQuota {
+        pub(super) fn new(remaining_writes: usize) -> Arc<Self> {
+            Arc::new(Self {
+                remaining: AtomicUsize::new(remaining_writes),
+                writes: Mutex::new(Vec::new()),
+            })
+        }
+
+        pub(super) fn allow_writes(&self, n: usize) {
+            self.remaining.store(n, Ordering::SeqCst);
+        }
+
+        pub(super) fn take_writes(&self) -> Vec<AnyObjectId> {
+            std::mem::take(&mut *self.writes.lock().unwrap())
+        }
+
+        fn check(&self, id: &AnyObjectId) -> Fault {
+            match self
+                .remaining
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => {
+                    self.writes.lock().unwrap().push(id.clone());
+                    Fault::Pass
+                }
+                Err(_) => {
+                    Fault::Error(StorageError::Other("write quota exhausted".to_string()))
+                }
+            }
+        }
+    }
+
+    /// An in memory storage failing all object writes once `quota` is exhausted
+    pub(super) fn flaky_storage(quota: Arc<WriteQuota>) -> FaultyStorage {
+        FaultyStorage::in_memory().on_all(move |call| match &call.object {
+            Some(id) if call.method.starts_with("write_") => quota.check(id),
+            _ => Fault::Pass,
+        })
+    }
+
+    async fn genesis() -> (Arc<dyn Storage + Send + Sync>, SnapshotId) {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        Repository::init(Arc::clone(&storage), false).await.unwrap();
+        let snapshot_id =
+            fetch_branch_tip(storage.as_ref(), "main").await.unwrap().snapshot;
+        (storage, snapshot_id)
+    }
+
+    pub(super) fn array_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    /// Write a snapshot with one array of 10 chunks, returns its id and closure
+    pub(super) async fn write_source(
+        storage: &(dyn Storage + Send + Sync),
+    ) -> (SnapshotId, BTreeSet<AnyObjectId>) {
+        let mut closure = BTreeSet::new();
+        let mut chunks = Vec::new();
+        for i in 0..10 {
+            let id: ChunkId = ObjectId::random();
+            storage.write_chunk(id.clone(), Bytes::from(vec![i as u8; 8])).await.unwrap();
+            closure.insert(AnyObjectId::Chunk(id.clone()));
+            chunks.push(ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![i]),
+                payload: ChunkPayload::Ref(ChunkRef { id, offset: 0, length: 8 }),
+                uncompressed_size: None,
+            });
+        }
+        // virtual and inline chunks are not part of the closure
+        chunks.push(ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![10]),
+            payload: ChunkPayload::Inline("hello".into()),
+            uncompressed_size: None,
+        });
+
+        let manifest_id: ManifestId = ObjectId::random();
+        let manifest: Manifest = chunks.into_iter().collect();
+        storage.write_manifests(manifest_id.clone(), Arc::new(manifest)).await.unwrap();
+        closure.insert(AnyObjectId::Manifest(manifest_id.clone()));
+
+        let node = NodeSnapshot {
+            id: 1,
+            path: Path::root(),
+            user_attributes: None,
+            node_data: NodeData::Array(
+                array_metadata(),
+                vec![ManifestRef {
+                    object_id: manifest_id.clone(),
+                    extents: ManifestExtents(vec![]),
+                }],
+            ),
+        };
+        let snapshot = Snapshot::from_iter(
+            &Snapshot::empty(),
+            None,
+            vec![ManifestFileInfo {
+                id: manifest_id,
+                format_version: LATEST_ICECHUNK_MANIFEST_FORMAT,
+            }],
+            vec![],
+            [node],
+        );
+        let snapshot_id = snapshot.metadata.id.clone();
+        storage.write_snapshot(snapshot_id.clone(), Arc::new(snapshot)).await.unwrap();
+        closure.insert(AnyObjectId::Snapshot(snapshot_id.clone()));
+
+        (snapshot_id, closure)
+    }
+
+    #[tokio::test]
+    async fn test_read_helpers_on_empty_snapshot() {
+        let (storage, snapshot_id) = genesis().await;
+
+        assert_eq!(iter_nodes(storage.as_ref(), &snapshot_id).await.unwrap().count(), 0);
+        assert_eq!(
+            resolve_node(storage.as_ref(), &snapshot_id, &Path::root()).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            snapshot_footprint(storage.as_ref(), &snapshot_id).await.unwrap(),
+            SnapshotFootprint::default()
+        );
+        assert_eq!(
+            snapshot_closure(storage.as_ref(), &snapshot_id).await.unwrap(),
+            vec![AnyObjectId::Snapshot(snapshot_id.clone())]
+        );
+        assert_eq!(
+            verify_reachable(storage.as_ref(), &snapshot_id).await.unwrap(),
+            vec![]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_content_addresses_detects_tampering() {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let (snapshot_id, closure) = write_source(&storage).await;
+
+        let report = verify_content_addresses(&storage, &snapshot_id).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, closure.len());
+
+        // truncate one of the chunks
+        let chunk_id = closure
+            .iter()
+            .find_map(|id| match id {
+                AnyObjectId::Chunk(id) => Some(id.clone()),
+                _ => None,
+            })
+            .unwrap();
+        storage.write_chunk(chunk_id.clone(), Bytes::from_static(b"abc")).await.unwrap();
+        let report = verify_content_addresses(&storage, &snapshot_id).await.unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupted, vec![AnyObjectId::Chunk(chunk_id)]);
+        assert_eq!(report.missing, vec![]);
+
+        // replace the snapshot with a different one
+        storage
+            .write_snapshot(snapshot_id.clone(), Arc::new(Snapshot::empty()))
+            .await
+            .unwrap();
+        let report = verify_content_addresses(&storage, &snapshot_id).await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.corrupted, vec![AnyObjectId::Snapshot(snapshot_id.clone())]);
+
+        let report =
+            verify_content_addresses(&storage, &SnapshotId::random()).await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.missing.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_closure_fingerprint() {
+        let source = ObjectStorage::new_in_memory_store(None);
+        let (snapshot_id, closure) = write_source(&source).await;
+        let replica = ObjectStorage::new_in_memory_store(None);
+        copy::copy_snapshot_closure(&source, &replica, &snapshot_id, None).await.unwrap();
+
+        let fingerprint = closure_fingerprint(&source, &snapshot_id).await.unwrap();
+        assert_eq!(
+            closure_fingerprint(&replica, &snapshot_id).await.unwrap(),
+            fingerprint
+        );
+        assert_eq!(
+            closure_fingerprint(&source, &snapshot_id).await.unwrap(),
+            fingerprint
+        );
+
+        // the replica's manifest points to a different chunk
+        let manifest_id = closure
+            .iter()
+            .find_map(|id| match id {
+                AnyObjectId::Manifest(id) => Some(id.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let manifest = replica.fetch_manifests(&manifest_id).await.unwrap();
+        let mut replaced = false;
+        let chunks = manifest.chunks().iter().map(|((node, coord), payload)| {
+            let payload = match payload {
+                ChunkPayload::Ref(chunk_ref) if !replaced => {
+                    replaced = true;
+                    ChunkPayload::Ref(ChunkRef {
+                        id: ChunkId::random(),
+                        ..chunk_ref.clone()
+                    })
+                }
+                other => other.clone(),
+            };
+            ChunkInfo {
+                node: *node,
+                coord: coord.clone(),
+                payload,
+                uncompressed_size: None,
+            }
+        });
+        let manifest: Manifest = chunks.collect();
+        replica.write_manifests(manifest_id, Arc::new(manifest)).await.unwrap();
+
+        assert_ne!(
+            closure_fingerprint(&replica, &snapshot_id).await.unwrap(),
+            fingerprint
+        );
+    }
+}
@@ -1,11 +1,15 @@
 use async_recursion::async_recursion;
 use bytes::Bytes;
-use futures::{Stream, TryStreamExt};
+use futures::{future::ready, stream, Stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{format::SnapshotId, Storage, StorageError};
+use crate::{
+    format::SnapshotId,
+    storage::{RECORDED_REF_VERSION_PREFIX, REF_FETCH_CONCURRENCY},
+    Storage, StorageError,
+};
 
 fn crock_encode_int(n: u64) -> String {
     // skip the first 3 bytes (zeroes)
@@ -44,6 +48,9 @@ pub enum RefError {
 
     #[error("branch update conflict: `({expected_parent:?}) != ({actual_parent:?})`")]
     Conflict { expected_parent: Option<SnapshotId>, actual_parent: Option<SnapshotId> },
+
+    #[error("commit conflict, the branch moved to `{current_target:?}`")]
+    CommitConflict { current_target: Option<SnapshotId> },
 }
 
 pub type RefResult<A> = Result<A, RefError>;
@@ -190,23 +197,135 @@ pub async fn update_branch(
     }
 }
 
+/// Move a branch from `expected_parent` to `new_snapshot`
+///
+/// This is the safe way to commit to a branch. The new branch version is created with
+/// [`Storage::compare_and_swap_ref`], so a concurrent update is never overwritten. If a
+/// concurrent writer creates the version first, the operation is retried up to `max_retries`
+/// times, as long as the branch still points to `expected_parent`.
+///
+/// Fails with [`RefError::CommitConflict`] if the branch points somewhere else, or if it
+/// couldn't be updated after all the retries. `current_target` is the last branch tip seen.
+pub async fn advance_ref(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+    expected_parent: Option<&SnapshotId>,
+    new_snapshot: SnapshotId,
+    max_retries: usize,
+) -> RefResult<BranchVersion> {
+    let data = RefData { snapshot: new_snapshot };
+    let content = Bytes::from(serde_json::to_vec(&data)?);
+    let mut retries = 0;
+    loop {
+        let (new_version, current_target) = match last_branch_version(storage, name).await
+        {
+            Ok(version) => {
+                let current = fetch_branch(storage, name, &version).await?;
+                (version.inc(), Some(current.snapshot))
+            }
+            Err(RefError::RefNotFound(_)) => (BranchVersion::initial(), None),
+            Err(err) => return Err(err),
+        };
+        if current_target.as_ref() != expected_parent {
+            return Err(RefError::CommitConflict { current_target });
+        }
+
+        let key = new_version.to_path(name)?;
+        if storage.compare_and_swap_ref(key.as_str(), None, content.clone()).await? {
+            return Ok(new_version);
+        }
+        if retries >= max_retries {
+            return Err(RefError::CommitConflict { current_target });
+        }
+        retries += 1;
+    }
+}
+
+/// Record a snapshot in the history of a branch, without changing the branch tip
+///
+/// Returns the id of the recorded version, which can be made the branch tip later with
+/// [`promote_branch_version`].
+pub async fn record_branch_version(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+    snapshot: SnapshotId,
+) -> RefResult<String> {
+    let key = branch_root(name)?;
+    let data = RefData { snapshot };
+    let content = serde_json::to_vec(&data)?;
+    Ok(storage.record_ref_version(key.as_str(), Bytes::copy_from_slice(&content)).await?)
+}
+
+/// Update the tip of a branch to the snapshot of a version recorded with
+/// [`record_branch_version`]
+///
+/// The recorded version stays in the history, the branch gets a new regular version.
+pub async fn promote_branch_version(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+    version_id: &str,
+    current_snapshot: Option<&SnapshotId>,
+    overwrite_refs: bool,
+) -> RefResult<BranchVersion> {
+    let key = format!("{}/{}", branch_root(name)?, version_id);
+    let data: RefData = match storage.get_ref(key.as_str()).await {
+        Ok(data) => serde_json::from_slice(data.as_ref())?,
+        Err(StorageError::RefNotFound(..)) => {
+            return Err(RefError::RefNotFound(key));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    update_branch(storage, name, data.snapshot, current_snapshot, overwrite_refs).await
+}
+
 pub async fn list_refs(storage: &(dyn Storage + Send + Sync)) -> RefResult<Vec<Ref>> {
     let all = storage.ref_names().await?;
     all.iter().map(|path| Ref::from_path(path.as_str())).try_collect()
 }
 
+/// List every ref with the snapshot it points to, in the order of [`list_refs`]
+///
+/// The targets are fetched concurrently, at most [`REF_FETCH_CONCURRENCY`] at the same time.
+/// Refs deleted after being listed are skipped.
+pub async fn resolved_refs(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<(Ref, SnapshotId)>> {
+    let resolved: Vec<Option<(Ref, SnapshotId)>> =
+        stream::iter(list_refs(storage).await?)
+            .map(|r| async move {
+                let data = match &r {
+                    Ref::Tag(name) => fetch_tag(storage, name).await,
+                    Ref::Branch(name) => fetch_branch_tip(storage, name).await,
+                };
+                match data {
+                    Ok(data) => Ok(Some((r, data.snapshot))),
+                    Err(RefError::RefNotFound(_)) => Ok(None),
+                    Err(err) => Err(err),
+                }
+            })
+            .buffered(REF_FETCH_CONCURRENCY)
+            .try_collect()
+            .await?;
+    Ok(resolved.into_iter().flatten().collect())
+}
+
 async fn branch_history<'a, 'b>(
     storage: &'a (dyn Storage + Send + Sync),
     branch: &'b str,
 ) -> RefResult<impl Stream<Item = RefResult<BranchVersion>> + 'a> {
     let key = branch_root(branch)?;
     let all = storage.ref_versions(key.as_str()).await?;
-    Ok(all.map_err(|e| e.into()).and_then(move |version_id| async move {
-        let version = version_id
-            .strip_suffix(".json")
-            .ok_or(RefError::InvalidRefName(version_id.clone()))?;
-        BranchVersion::decode(version)
-    }))
+    Ok(all
+        .try_filter(|version_id| {
+            ready(!version_id.starts_with(RECORDED_REF_VERSION_PREFIX))
+        })
+        .map_err(|e| e.into())
+        .and_then(move |version_id| async move {
+            let version = version_id
+                .strip_suffix(".json")
+                .ok_or(RefError::InvalidRefName(version_id.clone()))?;
+            BranchVersion::decode(version)
+        }))
 }
 
 async fn last_branch_version(
@@ -272,14 +391,24 @@ pub async fn fetch_ref(
 #[cfg(test)]
 #[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
-    use std::{iter::once, sync::Arc};
+    use std::{
+        collections::HashMap,
+        iter::once,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
     use futures::Future;
     use pretty_assertions::assert_eq;
     use rand::distributions::{Alphanumeric, DistString};
     use tempfile::{tempdir, TempDir};
 
-    use crate::ObjectStorage;
+    use crate::{
+        storage::faulty::{Fault, FaultyStorage},
+        ObjectStorage,
+    };
 
     use super::*;
 
@@ -493,4 +622,181 @@ mod tests {
         res2?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_recorded_versions_dont_move_the_tip(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ((_, res1), (_, res2, _)) = with_test_storages::<
+            Result<(), Box<dyn std::error::Error>>,
+            _,
+            _,
+        >(|storage| async move {
+            let s1 = SnapshotId::random();
+            let s2 = SnapshotId::random();
+            let s3 = SnapshotId::random();
+            update_branch(storage.as_ref(), "main", s1.clone(), None, false).await?;
+
+            let v2 = record_branch_version(storage.as_ref(), "main", s2.clone()).await?;
+            let v3 = record_branch_version(storage.as_ref(), "main", s3.clone()).await?;
+            assert_ne!(v2, v3);
+
+            let versions: Vec<_> =
+                storage.ref_versions("branch.main").await?.try_collect().await?;
+            assert_eq!(versions.len(), 3);
+            assert_eq!(versions[0], BranchVersion(0).encode() + ".json");
+            assert!(versions.contains(&v2));
+            assert!(versions.contains(&v3));
+
+            // the tip doesn't change
+            assert_eq!(
+                branch_history(storage.as_ref(), "main")
+                    .await?
+                    .try_collect::<Vec<_>>()
+                    .await?,
+                vec![BranchVersion(0)]
+            );
+            assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, s1);
+
+            // until the version is promoted
+            let version =
+                promote_branch_version(storage.as_ref(), "main", &v2, Some(&s1), false)
+                    .await?;
+            assert_eq!(version, BranchVersion(1));
+            assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, s2);
+
+            assert!(matches!(
+                promote_branch_version(
+                    storage.as_ref(),
+                    "main",
+                    "recorded.bad.json",
+                    Some(&s2),
+                    false
+                )
+                .await,
+                Err(RefError::RefNotFound(_))
+            ));
+            Ok(())
+        })
+        .await;
+        res1?;
+        res2?;
+        Ok(())
+    }
+
+    /// Fails the first `lost_races` compare and swap operations, as if another writer won
+    /// the race
+    fn contended_storage(lost_races: Arc<AtomicUsize>) -> FaultyStorage {
+        FaultyStorage::in_memory().on("compare_and_swap_ref", move |_| {
+            let lost = lost_races
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if lost {
+                Fault::Absent
+            } else {
+                Fault::Pass
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_advance_ref() -> Result<(), Box<dyn std::error::Error>> {
+        let ((_,res1),(_,res2,_)) = with_test_storages::<Result<(), Box<dyn std::error::Error>>, _, _>(|storage|  async move {
+            let s1 = SnapshotId::random();
+            let s2 = SnapshotId::random();
+            let s3 = SnapshotId::random();
+
+            assert_eq!(
+                advance_ref(storage.as_ref(), "main", None, s1.clone(), 0).await?,
+                BranchVersion(0)
+            );
+            assert_eq!(
+                advance_ref(storage.as_ref(), "main", Some(&s1), s2.clone(), 0).await?,
+                BranchVersion(1)
+            );
+            assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, s2);
+
+            // somebody else advanced the branch from s1 to s2
+            let res = advance_ref(storage.as_ref(), "main", Some(&s1), s3.clone(), 5).await;
+            assert!(matches!(res,
+                Err(RefError::CommitConflict { current_target }) if current_target == Some(s2.clone())
+            ));
+            let res = advance_ref(storage.as_ref(), "new-branch", Some(&s1), s3.clone(), 5).await;
+            assert!(matches!(res,
+                Err(RefError::CommitConflict { current_target: None })
+            ));
+            assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, s2);
+            Ok(())
+        }).await;
+        res1?;
+        res2?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_advance_ref_retries() -> Result<(), Box<dyn std::error::Error>> {
+        let s1 = SnapshotId::random();
+        let s2 = SnapshotId::random();
+        let lost_races = Arc::new(AtomicUsize::new(0));
+        let storage = contended_storage(Arc::clone(&lost_races));
+        advance_ref(&storage, "main", None, s1.clone(), 0).await?;
+
+        lost_races.store(2, Ordering::SeqCst);
+        assert!(matches!(
+            advance_ref(&storage, "main", Some(&s1), s2.clone(), 1).await,
+            Err(RefError::CommitConflict { current_target }) if current_target == Some(s1.clone())
+        ));
+
+        lost_races.store(2, Ordering::SeqCst);
+        assert_eq!(
+            advance_ref(&storage, "main", Some(&s1), s2.clone(), 2).await?,
+            BranchVersion(1)
+        );
+        assert_eq!(fetch_branch_tip(&storage, "main").await?.snapshot, s2);
+        Ok(())
+    }
+
+    /// Lists the refs in `vanished`, but doesn't find them, as if they were deleted right after
+    /// being listed
+    fn vanishing_refs_storage(vanished: &[&'static str]) -> FaultyStorage {
+        let vanished = vanished.to_vec();
+        FaultyStorage::in_memory().on_all(move |call| {
+            let ref_key = call.ref_key.as_deref().unwrap_or_default();
+            let gone = matches!(call.method, "get_ref" | "ref_versions")
+                && vanished.iter().any(|name| {
+                    ref_key == *name || ref_key.starts_with(format!("{name}/").as_str())
+                });
+            if gone {
+                Fault::Absent
+            } else {
+                Fault::Pass
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_resolved_refs() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = vanishing_refs_storage(&["branch.deleted", "tag.deleted"]);
+        assert_eq!(resolved_refs(&storage).await?, vec![]);
+
+        let mut expected = HashMap::new();
+        for i in 0..20 {
+            let (s1, s2) = (SnapshotId::random(), SnapshotId::random());
+            let branch = format!("branch{i}");
+            update_branch(&storage, &branch, s1.clone(), None, false).await?;
+            update_branch(&storage, &branch, s2.clone(), Some(&s1), false).await?;
+            expected.insert(Ref::Branch(branch), s2);
+            let tag = format!("tag{i}");
+            create_tag(&storage, &tag, s1.clone(), false).await?;
+            expected.insert(Ref::Tag(tag), s1);
+        }
+        // deleted refs are listed, but skipped
+        let snapshot = SnapshotId::random();
+        update_branch(&storage, "deleted", snapshot.clone(), None, false).await?;
+        create_tag(&storage, "deleted", snapshot, false).await?;
+
+        let resolved = resolved_refs(&storage).await?;
+        assert_eq!(list_refs(&storage).await?.len(), resolved.len() + 2);
+        assert_eq!(resolved.into_iter().collect::<HashMap<_, _>>(), expected);
+        Ok(())
+    }
 }
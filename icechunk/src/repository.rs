@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     iter::{self},
     pin::Pin,
     sync::Arc,
@@ -18,10 +18,7 @@ pub use crate::{
     },
 };
 use crate::{
-    format::{
-        manifest::VirtualReferenceError, snapshot::ManifestFileInfo, ManifestId,
-        SnapshotId,
-    },
+    format::{manifest::VirtualReferenceError, snapshot::ManifestFileInfo, SnapshotId},
     storage::virtual_ref::{
         construct_valid_byte_range, ObjectStoreVirtualChunkResolverConfig,
         VirtualChunkResolver,
@@ -30,42 +27,67 @@ use crate::{
 use bytes::Bytes;
 use chrono::Utc;
 use futures::{future::ready, Future, FutureExt, Stream, StreamExt, TryStreamExt};
-use itertools::Either;
+use itertools::{Either, Itertools};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 use crate::{
     format::{
+        format_constants,
         manifest::{
-            ChunkInfo, ChunkRef, Manifest, ManifestExtents, ManifestRef, VirtualChunkRef,
+            ChunkInfo, ChunkRef, Manifest, ManifestBuilder, ManifestExtents, ManifestRef,
+            VirtualChunkRef,
         },
         snapshot::{
-            NodeData, NodeSnapshot, NodeType, Snapshot, SnapshotProperties,
+            NodeData, NodeSnapshot, NodeStats, NodeType, Snapshot, SnapshotProperties,
             UserAttributesSnapshot,
         },
-        ByteRange, IcechunkFormatError, NodeId, ObjectId,
+        ByteRange, ChunkId, IcechunkFormatError, ManifestId, NodeId, ObjectId,
     },
+    ops::diff::{self, diff_snapshots},
     refs::{
         create_tag, fetch_branch_tip, fetch_tag, update_branch, BranchVersion, Ref,
         RefError,
     },
-    storage::virtual_ref::ObjectStoreVirtualChunkResolver,
+    storage::{virtual_ref::ObjectStoreVirtualChunkResolver, AnyObjectId},
     MemCachingStorage, Storage, StorageError,
 };
 
 #[derive(Clone, Debug)]
 pub struct RepositoryConfig {
-    // Chunks smaller than this will be stored inline in the manifst
+    // Chunks of this size or smaller are stored inline in the manifest, larger chunks are
+    // written as separate objects. Zero stores every chunk, even empty ones, as an object.
     pub inline_chunk_threshold_bytes: u16,
     // Unsafely overwrite refs on write. This is not recommended, users should only use it at their
     // own risk in object stores for which we don't support write-object-if-not-exists. There is
     // the possibility of race conditions if this variable is set to true and there are concurrent
     // commit attempts.
     pub unsafe_overwrite_refs: bool,
+    // Commits append the chunks they write to a small delta manifest, instead of rewriting the
+    // full manifest, until there are this many deltas. The next commit then compacts the base
+    // manifest and all the deltas into a single manifest. This makes small commits much
+    // cheaper, at the cost of reading more manifests. Zero disables deltas.
+    pub max_manifest_deltas: u16,
+    // Commits compute the NodeStats of every array and store them as sidecar objects
+    // referenced from the snapshot. This makes commits read all the manifests.
+    pub compute_node_stats: bool,
+    // Commits that write a full manifest split the chunks of every array with more than this
+    // many chunks into shards of at most this many chunks, each shard in its own manifest. The
+    // array references only its shards, with the coordinates each one covers, so reading a
+    // chunk fetches a single shard. Manifest deltas and `Repository::compact_manifests` don't
+    // split. Zero disables sharding.
+    pub manifest_shard_size: u32,
 }
 
 impl Default for RepositoryConfig {
     fn default() -> Self {
-        Self { inline_chunk_threshold_bytes: 512, unsafe_overwrite_refs: false }
+        Self {
+            inline_chunk_threshold_bytes: 512,
+            unsafe_overwrite_refs: false,
+            max_manifest_deltas: 0,
+            compute_node_stats: false,
+            manifest_shard_size: 0,
+        }
     }
 }
 
@@ -86,6 +108,7 @@ pub struct RepositoryBuilder {
     snapshot_id: SnapshotId,
     change_set: Option<ChangeSet>,
     virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+    virtual_resolver: Option<Arc<dyn VirtualChunkResolver + Send + Sync>>,
 }
 
 impl RepositoryBuilder {
@@ -96,6 +119,7 @@ impl RepositoryBuilder {
             storage,
             change_set: None,
             virtual_ref_config: None,
+            virtual_resolver: None,
         }
     }
 
@@ -109,6 +133,21 @@ impl RepositoryBuilder {
         self
     }
 
+    pub fn with_max_manifest_deltas(&mut self, max_deltas: u16) -> &mut Self {
+        self.config.max_manifest_deltas = max_deltas;
+        self
+    }
+
+    pub fn with_node_stats(&mut self, value: bool) -> &mut Self {
+        self.config.compute_node_stats = value;
+        self
+    }
+
+    pub fn with_manifest_shard_size(&mut self, max_chunks: u32) -> &mut Self {
+        self.config.manifest_shard_size = max_chunks;
+        self
+    }
+
     pub fn with_config(&mut self, config: RepositoryConfig) -> &mut Self {
         self.config = config;
         self
@@ -122,18 +161,32 @@ impl RepositoryBuilder {
         self
     }
 
+    /// Read virtual chunks with `resolver`, instead of one built from the virtual ref config
+    pub fn with_virtual_resolver(
+        &mut self,
+        resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+    ) -> &mut Self {
+        self.virtual_resolver = Some(resolver);
+        self
+    }
+
     pub fn with_change_set(&mut self, change_set_bytes: ChangeSet) -> &mut Self {
         self.change_set = Some(change_set_bytes);
         self
     }
 
     pub fn build(&self) -> Repository {
+        let virtual_resolver = self.virtual_resolver.clone().unwrap_or_else(|| {
+            Arc::new(ObjectStoreVirtualChunkResolver::new(
+                self.virtual_ref_config.clone(),
+            ))
+        });
         Repository::new(
             self.config.clone(),
             self.storage.clone(),
             self.snapshot_id.clone(),
             self.change_set.clone(),
-            self.virtual_ref_config.clone(),
+            virtual_resolver,
         )
     }
 }
@@ -153,8 +206,14 @@ pub enum RepositoryError {
     NotAGroup { node: NodeSnapshot, message: String },
     #[error("node already exists at `{node:?}`: {message}")]
     AlreadyExists { node: NodeSnapshot, message: String },
+    #[error("chunk not found at `{path}` coordinates `{coords:?}`")]
+    ChunkNotFound { path: Path, coords: ChunkIndices },
+    #[error("cannot build a chunk from the fill value of `{path}`, its data type has no fixed size")]
+    UnsupportedFillValue { path: Path },
     #[error("cannot commit, no changes made to the repository")]
     NoChangesToCommit,
+    #[error("cannot compact manifests with uncommitted changes")]
+    UncommittedChanges,
     #[error("unknown flush error")]
     OtherFlushError,
     #[error("ref error: `{0}`")]
@@ -163,6 +222,8 @@ pub enum RepositoryError {
     Tag(String),
     #[error("branch update conflict: `({expected_parent:?}) != ({actual_parent:?})`")]
     Conflict { expected_parent: Option<SnapshotId>, actual_parent: Option<SnapshotId> },
+    #[error("changes conflict with the commits they would be rebased on: `{0:?}`")]
+    CommitConflict(CommitConflict),
     #[error("the repository has been initialized already (default branch exists)")]
     AlreadyInitialized,
     #[error("error when handling virtual reference {0}")]
@@ -175,6 +236,22 @@ pub enum RepositoryError {
 
 pub type RepositoryResult<T> = Result<T, RepositoryError>;
 
+/// The changes of a session that overlap with the commits it's rebased on, see
+/// [`Repository::rebase`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CommitConflict {
+    /// Nodes both sides created, deleted or updated, not counting chunk writes
+    pub nodes: BTreeSet<Path>,
+    /// Chunks both sides wrote or deleted
+    pub chunks: BTreeSet<(Path, ChunkIndices)>,
+}
+
+impl CommitConflict {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.chunks.is_empty()
+    }
+}
+
 /// FIXME: what do we want to do with implicit groups?
 ///
 impl Repository {
@@ -213,7 +290,8 @@ impl Repository {
             return Err(RepositoryError::AlreadyInitialized);
         }
         let new_snapshot = Snapshot::empty();
-        let new_snapshot_id = ObjectId::random();
+        // stored under its own id, like every other snapshot
+        let new_snapshot_id = new_snapshot.metadata.id.clone();
         storage.write_snapshot(new_snapshot_id.clone(), Arc::new(new_snapshot)).await?;
         update_branch(
             storage.as_ref(),
@@ -251,7 +329,7 @@ impl Repository {
         storage: Arc<dyn Storage + Send + Sync>,
         snapshot_id: SnapshotId,
         change_set: Option<ChangeSet>,
-        virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+        virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
     ) -> Self {
         Repository {
             snapshot_id,
@@ -259,9 +337,7 @@ impl Repository {
             storage,
             last_node_id: None,
             change_set: change_set.unwrap_or_default(),
-            virtual_resolver: Arc::new(ObjectStoreVirtualChunkResolver::new(
-                virtual_ref_config,
-            )),
+            virtual_resolver,
         }
     }
 
@@ -570,6 +646,93 @@ impl Repository {
         }
     }
 
+    /// Read the bytes of a chunk
+    ///
+    /// With `fill_missing` set, a chunk that was never written, but is inside the array, reads as
+    /// a chunk full of the array fill value. As in Zarr, this allows not storing chunks that only
+    /// contain the fill value. The synthesized bytes use the little-endian layout of the zarr
+    /// `bytes` codec, no other array codecs are applied to them.
+    ///
+    /// Without `fill_missing`, reading a chunk that was never written fails with
+    /// [`RepositoryError::ChunkNotFound`].
+    pub async fn read_chunk(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        byte_range: &ByteRange,
+        fill_missing: bool,
+    ) -> RepositoryResult<Bytes> {
+        if let Some(reader) = self.get_chunk_reader(path, coords, byte_range).await? {
+            return reader.await;
+        }
+        let not_found = || RepositoryError::ChunkNotFound {
+            path: path.clone(),
+            coords: coords.clone(),
+        };
+        if !fill_missing {
+            return Err(not_found());
+        }
+        let NodeData::Array(metadata, _) = self.get_array(path).await?.node_data else {
+            return Err(not_found());
+        };
+        if !metadata.contains_chunk(coords) {
+            return Err(not_found());
+        }
+        Ok(byte_range.slice(fill_chunk(path, &metadata)?))
+    }
+
+    /// Read all the chunks of an array, in the order of their coordinates
+    ///
+    /// With `fill_missing` set, chunks that were never written are synthesized from the fill
+    /// value, as in [`Repository::read_chunk`], otherwise they are skipped.
+    pub async fn scan_array(
+        &self,
+        path: &Path,
+        fill_missing: bool,
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<(ChunkIndices, Bytes)>> + '_>
+    {
+        let NodeData::Array(metadata, _) = self.get_array(path).await?.node_data else {
+            return Err(RepositoryError::NodeNotFound {
+                path: path.clone(),
+                message: "scanning an array".to_string(),
+            });
+        };
+        // built once, the error is only returned if some chunk is actually missing
+        let fill = fill_missing.then(|| fill_chunk(path, &metadata).ok());
+        let grid = metadata
+            .num_chunks()
+            .into_iter()
+            .map(|n| 0..n as u32)
+            .multi_cartesian_product()
+            .map(ChunkIndices);
+        // a scalar array has a single chunk, with no coordinates
+        let grid: Box<dyn Iterator<Item = ChunkIndices> + Send> =
+            if metadata.shape.is_empty() {
+                Box::new(iter::once(ChunkIndices(vec![])))
+            } else {
+                Box::new(grid)
+            };
+        let path = path.clone();
+        Ok(futures::stream::iter(grid)
+            .then(move |coords| {
+                let path = path.clone();
+                let fill = fill.clone();
+                async move {
+                    match self.get_chunk_reader(&path, &coords, &ByteRange::ALL).await? {
+                        Some(reader) => Ok(Some((coords, reader.await?))),
+                        None => match fill {
+                            Some(Some(bytes)) => Ok(Some((coords, bytes))),
+                            Some(None) => {
+                                Err(RepositoryError::UnsupportedFillValue { path })
+                            }
+                            None => Ok(None),
+                        },
+                    }
+                }
+            })
+            .try_filter_map(|chunk| ready(Ok(chunk))))
+    }
+
     /// Returns a function that can be used to asynchronously write chunk bytes to object store
     ///
     /// The reason to use this design, instead of simple pass the [`Bytes`] is to avoid holding a
@@ -594,7 +757,7 @@ impl Repository {
         let storage = Arc::clone(&self.storage);
         move |data: Bytes| {
             async move {
-                let payload = if data.len() > threshold {
+                let payload = if threshold == 0 || data.len() > threshold {
                     new_materialized_chunk(storage.as_ref(), data).await?
                 } else {
                     new_inline_chunk(data)
@@ -624,8 +787,7 @@ impl Repository {
         manifests: &[ManifestRef],
         coords: &ChunkIndices,
     ) -> RepositoryResult<Option<ChunkPayload>> {
-        // FIXME: use manifest extents
-        for manifest in manifests {
+        for manifest in manifests.iter().filter(|m| m.extents.contains(coords)) {
             let manifest_structure =
                 self.storage.fetch_manifests(&manifest.object_id).await?;
             match manifest_structure.get_chunk_payload(node, coords.clone()) {
@@ -642,7 +804,7 @@ impl Repository {
     pub async fn list_nodes(
         &self,
     ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + '_> {
-        updated_nodes(self.storage.as_ref(), &self.change_set, &self.snapshot_id, None)
+        updated_nodes(self.storage.as_ref(), &self.change_set, &self.snapshot_id, &[])
             .await
     }
 
@@ -667,6 +829,9 @@ impl Repository {
             self.snapshot_id(),
             message,
             properties,
+            self.config.max_manifest_deltas,
+            self.config.manifest_shard_size,
+            self.config.compute_node_stats,
         )
         .await?;
 
@@ -675,6 +840,35 @@ impl Repository {
         Ok(new_snapshot_id)
     }
 
+    /// Rewrite all the manifests of the current snapshot into a single one
+    ///
+    /// Useful after many commits appended manifest deltas, see
+    /// [`RepositoryConfig::max_manifest_deltas`]. Like [`Repository::flush`], returns the id of the
+    /// new snapshot, and it's the callers responsibility to commit it.
+    ///
+    /// The compacted manifest gets an id derived from the snapshot it compacts, so concurrent
+    /// compactions of the same snapshot detect each other's manifest and reuse it, instead of
+    /// writing one each.
+    pub async fn compact_manifests(
+        &mut self,
+        message: &str,
+        properties: SnapshotProperties,
+    ) -> RepositoryResult<SnapshotId> {
+        if self.has_uncommitted_changes() {
+            return Err(RepositoryError::UncommittedChanges);
+        }
+        let new_snapshot_id = compact_manifests(
+            self.storage.as_ref(),
+            self.snapshot_id(),
+            message,
+            properties,
+            self.config.compute_node_stats,
+        )
+        .await?;
+        self.snapshot_id = new_snapshot_id.clone();
+        Ok(new_snapshot_id)
+    }
+
     /// After changes to the repository have been made, this generates and writes to `Storage` the updated datastructures.
     ///
     /// After calling this, changes are reset and the [`Repository`] can continue to be used for further
@@ -739,6 +933,63 @@ impl Repository {
         }
     }
 
+    /// Commit to a branch, rebasing the changes if other commits moved the branch
+    ///
+    /// Unlike [`Repository::commit`], this doesn't fail if the branch tip is not the snapshot
+    /// the session started from. The changes are rebased on the new tip, see
+    /// [`Repository::rebase`], and committed there, as many times as needed to win the race to
+    /// update the branch.
+    ///
+    /// Fails with [`RepositoryError::CommitConflict`] if the changes overlap with the new
+    /// commits, the session keeps its snapshot and changes then.
+    pub async fn try_commit(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<SnapshotId> {
+        loop {
+            let parent = self.snapshot_id.clone();
+            let change_set = self.change_set.clone();
+            match self.commit(update_branch_name, message, properties.clone()).await {
+                Err(RepositoryError::Conflict { actual_parent: Some(tip), .. }) => {
+                    // the conflict could be detected after flushing, which resets the session
+                    self.snapshot_id = parent;
+                    self.change_set = change_set;
+                    self.rebase(&tip).await?;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Move the uncommitted changes on top of `snapshot_id`
+    ///
+    /// The changes are compared with what changed from the session snapshot to `snapshot_id`,
+    /// so this is meant for descendants of the session snapshot, like a branch tip that moved.
+    /// Fails with [`RepositoryError::CommitConflict`] if both sides changed the same chunks, or
+    /// the same nodes, without modifying the session. Writing different chunks of an array, or
+    /// creating different nodes, doesn't conflict.
+    pub async fn rebase(&mut self, snapshot_id: &SnapshotId) -> RepositoryResult<()> {
+        let conflict = commit_conflicts(
+            self.storage.as_ref(),
+            &self.change_set,
+            &self.snapshot_id,
+            snapshot_id,
+        )
+        .await?;
+        if !conflict.is_empty() {
+            return Err(RepositoryError::CommitConflict(conflict));
+        }
+
+        self.snapshot_id = snapshot_id.clone();
+        // ids are sequential, nodes created by the other commits could have the same ids
+        let last_node_id = self.compute_last_node_id().await?;
+        let new_nodes = self.change_set.renumber_new_nodes(last_node_id + 1);
+        self.last_node_id = Some(last_node_id + new_nodes as NodeId);
+        Ok(())
+    }
+
     async fn do_distributed_commit<I: IntoIterator<Item = ChangeSet>>(
         &mut self,
         update_branch_name: &str,
@@ -819,15 +1070,25 @@ async fn new_materialized_chunk(
     storage: &(dyn Storage + Send + Sync),
     data: Bytes,
 ) -> RepositoryResult<ChunkPayload> {
-    let new_id = ObjectId::random();
-    storage.write_chunk(new_id.clone(), data.clone()).await?;
-    Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length: data.len() as u64 }))
+    let length = data.len() as u64;
+    let new_id = storage.write_new_chunk(data).await?;
+    Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length }))
 }
 
 fn new_inline_chunk(data: Bytes) -> ChunkPayload {
     ChunkPayload::Inline(data)
 }
 
+/// A chunk where every element is the fill value of the array
+fn fill_chunk(path: &Path, metadata: &ZarrArrayMetadata) -> RepositoryResult<Bytes> {
+    let element = metadata
+        .fill_value
+        .to_le_bytes()
+        .ok_or_else(|| RepositoryError::UnsupportedFillValue { path: path.clone() })?;
+    let len: u64 = metadata.chunk_shape.0.iter().map(|n| n.get()).product();
+    Ok(element.repeat(len as usize).into())
+}
+
 pub async fn get_chunk(
     reader: Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
 ) -> RepositoryResult<Option<Bytes>> {
@@ -841,16 +1102,13 @@ async fn updated_existing_nodes<'a>(
     storage: &(dyn Storage + Send + Sync),
     change_set: &'a ChangeSet,
     parent_id: &SnapshotId,
-    manifest_id: Option<&'a ManifestId>,
+    manifest_refs: &'a [ManifestRef],
 ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
-    let manifest_refs = manifest_id.map(|mid| {
-        vec![ManifestRef { object_id: mid.clone(), extents: ManifestExtents(vec![]) }]
-    });
     let updated_nodes =
         storage.fetch_snapshot(parent_id).await?.iter_arc().filter_map(move |node| {
             let new_manifests = if node.node_type() == NodeType::Array {
                 //FIXME: it could be none for empty arrays
-                manifest_refs.clone()
+                Some(manifest_refs.to_vec())
             } else {
                 None
             };
@@ -864,11 +1122,11 @@ async fn updated_nodes<'a>(
     storage: &(dyn Storage + Send + Sync),
     change_set: &'a ChangeSet,
     parent_id: &SnapshotId,
-    manifest_id: Option<&'a ManifestId>,
+    manifest_refs: &'a [ManifestRef],
 ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
-    Ok(updated_existing_nodes(storage, change_set, parent_id, manifest_id)
+    Ok(updated_existing_nodes(storage, change_set, parent_id, manifest_refs)
         .await?
-        .chain(change_set.new_nodes_iterator(manifest_id)))
+        .chain(change_set.new_nodes_iterator(manifest_refs)))
 }
 
 async fn get_node<'a>(
@@ -940,12 +1198,83 @@ async fn get_existing_node<'a>(
     }
 }
 
+/// The changes in `change_set`, done on top of `from`, that overlap with the changes from `from`
+/// to `to`
+async fn commit_conflicts(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    from: &SnapshotId,
+    to: &SnapshotId,
+) -> RepositoryResult<CommitConflict> {
+    let diff = diff_snapshots(storage, from, to).await?;
+    let from = storage.fetch_snapshot(from).await?;
+    let to = storage.fetch_snapshot(to).await?;
+    let paths: HashMap<NodeId, &Path> =
+        from.iter().map(|node| (node.id, &node.path)).collect();
+    // the node was replaced, or its metadata or attributes changed, chunk changes don't count
+    let node_changed = |path: &Path| match (from.get_node(path), to.get_node(path)) {
+        (Ok(old), Ok(new)) => old.id != new.id || diff::node_changed(old, new),
+        (Err(_), Err(_)) => false,
+        _ => true,
+    };
+    let mut conflict = CommitConflict::default();
+
+    for path in change_set.new_nodes() {
+        if node_changed(path)
+            || path.ancestors().any(|parent| diff.removed_nodes.contains(&parent))
+        {
+            conflict.nodes.insert(path.clone());
+        }
+    }
+    for path in change_set.deleted_nodes() {
+        // deleting a node deletes its children too
+        if diff
+            .added_nodes
+            .iter()
+            .chain(diff.modified_nodes.iter())
+            .any(|changed| changed.starts_with(path))
+        {
+            conflict.nodes.insert(path.clone());
+        }
+    }
+    for node_id in change_set.updated_nodes() {
+        // nodes created by the session are not in `paths`
+        if let Some(path) = paths.get(node_id) {
+            if node_changed(path) {
+                conflict.nodes.insert((*path).clone());
+            }
+        }
+    }
+    for (node_id, coord) in change_set.chunk_changes() {
+        let Some(path) = paths.get(node_id) else { continue };
+        match to.get_node(path) {
+            Ok(node) if node.id == *node_id => {
+                if diff
+                    .changed_chunks
+                    .get(*path)
+                    .is_some_and(|chunks| chunks.contains(coord))
+                {
+                    conflict.chunks.insert(((*path).clone(), coord.clone()));
+                }
+            }
+            _ => {
+                conflict.nodes.insert((*path).clone());
+            }
+        }
+    }
+    Ok(conflict)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
     storage: &(dyn Storage + Send + Sync),
     change_sets: I,
     parent_id: &SnapshotId,
     message: &str,
     properties: SnapshotProperties,
+    max_manifest_deltas: u16,
+    manifest_shard_size: u32,
+    compute_node_stats: bool,
 ) -> RepositoryResult<SnapshotId> {
     let mut change_set = ChangeSet::default();
     change_set.merge_many(change_sets);
@@ -953,38 +1282,151 @@ async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
         return Err(RepositoryError::NoChangesToCommit);
     }
 
-    let chunks = all_chunks(storage, &change_set, parent_id)
-        .await?
-        .map_ok(|(_path, chunk_info)| chunk_info);
+    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
+    // shards don't count as deltas
+    let shard_count = manifest_shards(old_snapshot.as_ref()).len();
+    // Deletions cannot be expressed in a delta, and the ids of deleted nodes could be reused by
+    // new nodes, so they always compact the manifests
+    let append_delta = max_manifest_deltas > 0
+        && !old_snapshot.manifest_files.is_empty()
+        && old_snapshot.manifest_files.len() - shard_count
+            <= max_manifest_deltas as usize
+        && !change_set.has_deletions();
+    let (manifest_files, node_manifests) = if append_delta {
+        write_manifest_delta(storage, &change_set, old_snapshot.as_ref()).await?
+    } else {
+        write_compacted_manifest(storage, &change_set, parent_id, manifest_shard_size)
+            .await?
+    };
+    write_flushed_snapshot(
+        storage,
+        &change_set,
+        old_snapshot.as_ref(),
+        parent_id,
+        manifest_files,
+        node_manifests,
+        message,
+        properties,
+        compute_node_stats,
+    )
+    .await
+}
 
-    let new_manifest = Arc::new(Manifest::from_stream(chunks).await?);
-    let new_manifest_id = if new_manifest.len() > 0 {
-        let id = ObjectId::random();
-        storage.write_manifests(id.clone(), Arc::clone(&new_manifest)).await?;
-        Some(id)
+async fn compact_manifests(
+    storage: &(dyn Storage + Send + Sync),
+    parent_id: &SnapshotId,
+    message: &str,
+    properties: SnapshotProperties,
+    compute_node_stats: bool,
+) -> RepositoryResult<SnapshotId> {
+    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
+    let change_set = ChangeSet::default();
+    let id = compaction_id(parent_id);
+    let manifest_files = if storage.exists(&AnyObjectId::Manifest(id.clone())).await? {
+        vec![ManifestFileInfo {
+            id,
+            format_version: format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+        }]
     } else {
-        None
+        let chunks = all_chunks(storage, &change_set, parent_id)
+            .await?
+            .map_ok(|(_path, chunk_info)| chunk_info);
+        let manifest = Arc::new(Manifest::from_stream(chunks).await?);
+        if manifest.is_empty() {
+            vec![]
+        } else {
+            // if another compaction wrote the manifest since we checked, its contents are the
+            // same as ours
+            storage
+                .write_manifests_if_not_exists(id.clone(), Arc::clone(&manifest))
+                .await?;
+            vec![ManifestFileInfo {
+                id,
+                format_version: manifest.icechunk_manifest_format_version,
+            }]
+        }
     };
+    write_flushed_snapshot(
+        storage,
+        &change_set,
+        old_snapshot.as_ref(),
+        parent_id,
+        manifest_files,
+        HashMap::new(),
+        message,
+        properties,
+        compute_node_stats,
+    )
+    .await
+}
 
-    let all_nodes =
-        updated_nodes(storage, &change_set, parent_id, new_manifest_id.as_ref()).await?;
+/// The id of the manifest that compacts the manifests of a snapshot
+fn compaction_id(snapshot_id: &SnapshotId) -> ManifestId {
+    let mut hasher = Sha256::new();
+    hasher.update(b"compaction");
+    hasher.update(snapshot_id.0);
+    let mut bytes = [0; 12];
+    bytes.copy_from_slice(&hasher.finalize()[..12]);
+    ManifestId::new(bytes)
+}
+
+/// Write the snapshot that results of applying `change_set` to the parent, with the given
+/// manifests
+///
+/// Arrays in `node_manifests` reference those manifests, every other array references all the
+/// manifest files that are not shards.
+#[allow(clippy::too_many_arguments)]
+async fn write_flushed_snapshot(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    old_snapshot: &Snapshot,
+    parent_id: &SnapshotId,
+    manifest_files: Vec<ManifestFileInfo>,
+    node_manifests: HashMap<NodeId, Vec<ManifestRef>>,
+    message: &str,
+    properties: SnapshotProperties,
+    compute_node_stats: bool,
+) -> RepositoryResult<SnapshotId> {
+    let shards: HashSet<_> = node_manifests
+        .values()
+        .flatten()
+        .filter(|manifest_ref| !manifest_ref.extents.is_unknown())
+        .map(|manifest_ref| &manifest_ref.object_id)
+        .collect();
+    // newest manifests first, so their chunks take precedence
+    let manifest_refs: Vec<_> = manifest_files
+        .iter()
+        .filter(|info| !shards.contains(&info.id))
+        .map(|info| ManifestRef {
+            object_id: info.id.clone(),
+            extents: ManifestExtents(vec![]),
+        })
+        .collect();
+
+    let all_nodes: Vec<_> = updated_nodes(storage, change_set, parent_id, &manifest_refs)
+        .await?
+        .map(|node| match (node.node_data, node_manifests.get(&node.id)) {
+            (NodeData::Array(metadata, _), Some(manifests)) => NodeSnapshot {
+                node_data: NodeData::Array(metadata, manifests.clone()),
+                ..node
+            },
+            (node_data, _) => NodeSnapshot { node_data, ..node },
+        })
+        .collect();
+    let node_stats = if compute_node_stats {
+        write_node_stats(storage, &all_nodes).await?
+    } else {
+        BTreeMap::new()
+    };
 
-    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
     let mut new_snapshot = Snapshot::from_iter(
-        old_snapshot.as_ref(),
+        old_snapshot,
         Some(properties),
-        new_manifest_id
-            .as_ref()
-            .map(|mid| {
-                vec![ManifestFileInfo {
-                    id: mid.clone(),
-                    format_version: new_manifest.icechunk_manifest_format_version,
-                }]
-            })
-            .unwrap_or_default(),
+        manifest_files,
         vec![],
         all_nodes,
     );
+    new_snapshot.set_node_stats_ids(node_stats);
     new_snapshot.metadata.message = message.to_string();
     new_snapshot.metadata.written_at = Utc::now();
 
@@ -995,6 +1437,197 @@ async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
     Ok(new_snapshot_id.clone())
 }
 
+/// Compute the stats of every array and write them, returns the ids of the sidecar objects
+///
+/// Ids are derived from the contents, so arrays with unchanged stats reuse the same object.
+async fn write_node_stats(
+    storage: &(dyn Storage + Send + Sync),
+    nodes: &[NodeSnapshot],
+) -> RepositoryResult<BTreeMap<NodeId, ChunkId>> {
+    futures::stream::iter(nodes.iter().filter_map(|node| match &node.node_data {
+        NodeData::Array(metadata, manifests) => Some((node.id, metadata, manifests)),
+        NodeData::Group => None,
+    }))
+    .then(|(node, metadata, manifests)| async move {
+        // newest manifests first, so only the first payload for each coordinate counts
+        let mut chunks = HashMap::new();
+        for manifest in manifests {
+            let manifest = storage.fetch_node_chunks(&manifest.object_id, node).await?;
+            for ((_, coord), payload) in manifest.chunks() {
+                chunks.entry(coord.clone()).or_insert_with(|| payload.clone());
+            }
+        }
+        let stats = NodeStats::from_chunks(metadata, chunks.values());
+        let bytes = Bytes::from(rmp_serde::to_vec(&stats).map_err(StorageError::from)?);
+        let id = node_stats_id(&bytes);
+        storage.write_chunk_if_absent(id.clone(), bytes).await?;
+        Ok((node, id))
+    })
+    .try_collect()
+    .await
+}
+
+fn node_stats_id(bytes: &[u8]) -> ChunkId {
+    let mut hasher = Sha256::new();
+    hasher.update(b"node stats");
+    hasher.update(bytes);
+    let mut id = [0; 12];
+    id.copy_from_slice(&hasher.finalize()[..12]);
+    ChunkId::new(id)
+}
+
+/// Write a single manifest with all the chunks, except for arrays with more than `shard_size`
+/// chunks that are split into shards, zero doesn't split
+///
+/// Returns the new list of manifest files, and the shards of every split array.
+async fn write_compacted_manifest(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    parent_id: &SnapshotId,
+    shard_size: u32,
+) -> RepositoryResult<(Vec<ManifestFileInfo>, HashMap<NodeId, Vec<ManifestRef>>)> {
+    let chunks = all_chunks(storage, change_set, parent_id)
+        .await?
+        .map_ok(|(_path, chunk_info)| chunk_info);
+    let new_manifest = Manifest::from_stream(chunks).await?;
+
+    let shard_size = shard_size as usize;
+    let large_nodes: HashSet<NodeId> = if shard_size == 0 {
+        HashSet::new()
+    } else {
+        new_manifest
+            .chunks()
+            .keys()
+            .map(|(node, _)| *node)
+            .dedup_with_count()
+            .filter(|(count, _)| *count > shard_size)
+            .map(|(_, node)| node)
+            .collect()
+    };
+    if large_nodes.is_empty() {
+        let files = write_new_manifest(storage, Arc::new(new_manifest)).await?;
+        return Ok((files.into_iter().collect(), HashMap::new()));
+    }
+
+    let interned = new_manifest.interns_inline_payloads();
+    let mut manifest_files = Vec::new();
+    let mut node_manifests = HashMap::new();
+    for node in large_nodes.iter().sorted() {
+        let chunks: Vec<_> = new_manifest.node_chunk_infos(*node).collect();
+        let mut shards = Vec::new();
+        // node_chunk_infos is sorted by coordinates, so shards cover disjoint extents
+        for shard in chunks.chunks(shard_size) {
+            let (Some(first), Some(last)) = (shard.first(), shard.last()) else {
+                continue;
+            };
+            let extents = ManifestExtents::new(first.coord.clone(), last.coord.clone());
+            let mut builder =
+                ManifestBuilder::new().with_interned_inline_payloads(interned);
+            builder.extend(shard.iter().cloned());
+            if let Some(info) =
+                write_new_manifest(storage, Arc::new(builder.build())).await?
+            {
+                shards.push(ManifestRef { object_id: info.id.clone(), extents });
+                manifest_files.push(info);
+            }
+        }
+        node_manifests.insert(*node, shards);
+    }
+
+    let mut builder = ManifestBuilder::new().with_interned_inline_payloads(interned);
+    builder.extend(
+        new_manifest
+            .chunks()
+            .keys()
+            .map(|(node, _)| *node)
+            .dedup()
+            .filter(|node| !large_nodes.contains(node))
+            .flat_map(|node| new_manifest.node_chunk_infos(node)),
+    );
+    let rest = write_new_manifest(storage, Arc::new(builder.build())).await?;
+    manifest_files.splice(0..0, rest);
+    Ok((manifest_files, node_manifests))
+}
+
+/// The ids of the manifests that hold shards of an array, see
+/// [`RepositoryConfig::manifest_shard_size`]
+fn manifest_shards(snapshot: &Snapshot) -> HashSet<ManifestId> {
+    snapshot
+        .iter()
+        .flat_map(|node| match &node.node_data {
+            NodeData::Array(_, manifests) => manifests.clone(),
+            NodeData::Group => vec![],
+        })
+        .filter(|manifest_ref| !manifest_ref.extents.is_unknown())
+        .map(|manifest_ref| manifest_ref.object_id)
+        .collect()
+}
+
+/// Write a manifest with only the chunks set in `change_set`, in front of the parent's manifests
+///
+/// Returns the new list of manifest files, and the manifests of the sharded arrays, that
+/// reference the delta in front of their shards. Deleted chunks are not supported.
+async fn write_manifest_delta(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    parent: &Snapshot,
+) -> RepositoryResult<(Vec<ManifestFileInfo>, HashMap<NodeId, Vec<ManifestRef>>)> {
+    let existing_array_chunks = parent.iter().flat_map(|node| {
+        change_set.array_chunks_iterator(node.id, &node.path).filter_map(
+            |(coord, payload)| {
+                payload.as_ref().map(|payload| ChunkInfo {
+                    node: node.id,
+                    coord: coord.clone(),
+                    payload: payload.clone(),
+                    uncompressed_size: None,
+                })
+            },
+        )
+    });
+    let new_array_chunks =
+        change_set.new_arrays_chunk_iterator().map(|(_path, chunk_info)| chunk_info);
+    let delta: Manifest = existing_array_chunks.chain(new_array_chunks).collect();
+
+    let delta = write_new_manifest(storage, Arc::new(delta)).await?;
+    let delta_ref = delta.iter().map(|info| ManifestRef {
+        object_id: info.id.clone(),
+        extents: ManifestExtents(vec![]),
+    });
+    let node_manifests = parent
+        .iter()
+        .filter_map(|node| match &node.node_data {
+            NodeData::Array(_, manifests)
+                if manifests.iter().any(|manifest| !manifest.extents.is_unknown()) =>
+            {
+                Some((
+                    node.id,
+                    delta_ref.clone().chain(manifests.iter().cloned()).collect(),
+                ))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut manifest_files: Vec<_> = delta.into_iter().collect();
+    manifest_files.extend(parent.manifest_files.iter().cloned());
+    Ok((manifest_files, node_manifests))
+}
+
+async fn write_new_manifest(
+    storage: &(dyn Storage + Send + Sync),
+    manifest: Arc<Manifest>,
+) -> RepositoryResult<Option<ManifestFileInfo>> {
+    if manifest.is_empty() {
+        return Ok(None);
+    }
+    let id = ObjectId::random();
+    storage.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+    Ok(Some(ManifestFileInfo {
+        id,
+        format_version: manifest.icechunk_manifest_format_version,
+    }))
+}
+
 /// Warning: The presence of a single error may mean multiple missing items
 async fn updated_chunk_iterator<'a>(
     storage: &'a (dyn Storage + Send + Sync),
@@ -1051,47 +1684,64 @@ async fn verified_node_chunk_iterator<'a>(
                             node: node.id,
                             coord: idx.clone(),
                             payload: payload.clone(),
+                            uncompressed_size: None,
                         })
                     })
                 });
 
+            let last_manifest = manifests.len().saturating_sub(1);
             futures::future::Either::Right(
                 futures::stream::iter(new_chunks).chain(
-                    futures::stream::iter(manifests)
-                        .then(move |manifest_ref| {
+                    futures::stream::iter(manifests.into_iter().enumerate())
+                        .then(move |(ix, manifest_ref)| async move {
+                            let manifest =
+                                storage.fetch_manifests(&manifest_ref.object_id).await;
+                            (ix == last_manifest, manifest)
+                        })
+                        // manifests are sorted newest first, chunks found in a manifest hide
+                        // the same chunks in older manifests
+                        .scan(HashSet::new(), move |hidden, (is_last, manifest)| {
                             let new_chunk_indices = new_chunk_indices.clone();
-                            async move {
-                                let manifest = storage
-                                    .fetch_manifests(&manifest_ref.object_id)
-                                    .await;
-                                match manifest {
-                                    Ok(manifest) => {
-                                        let old_chunks = manifest
-                                            .iter(&node.id)
-                                            .filter(move |(coord, _)| {
-                                                !new_chunk_indices.contains(coord)
-                                            })
-                                            .map(move |(coord, payload)| ChunkInfo {
-                                                node: node.id,
-                                                coord,
-                                                payload,
-                                            });
-
-                                        let old_chunks = change_set
-                                            .update_existing_chunks(node.id, old_chunks);
-                                        futures::future::Either::Left(
-                                            futures::stream::iter(old_chunks.map(Ok)),
-                                        )
+                            let res = match manifest {
+                                Ok(manifest) => {
+                                    let older_hidden = Arc::new(hidden.clone());
+                                    if !is_last {
+                                        hidden.extend(
+                                            Arc::clone(&manifest)
+                                                .iter(&node.id)
+                                                .map(|(coord, _)| coord),
+                                        );
                                     }
-                                    // if we cannot even fetch the manifest, we generate a
-                                    // single error value.
-                                    Err(err) => futures::future::Either::Right(
-                                        futures::stream::once(ready(Err(
-                                            RepositoryError::StorageError(err),
-                                        ))),
-                                    ),
+                                    let sizes = Arc::clone(&manifest);
+                                    let old_chunks = manifest
+                                        .iter(&node.id)
+                                        .filter(move |(coord, _)| {
+                                            !new_chunk_indices.contains(coord)
+                                                && !older_hidden.contains(coord)
+                                        })
+                                        .map(move |(coord, payload)| ChunkInfo {
+                                            node: node.id,
+                                            uncompressed_size: sizes
+                                                .uncompressed_size(node.id, &coord),
+                                            coord,
+                                            payload,
+                                        });
+
+                                    let old_chunks = change_set
+                                        .update_existing_chunks(node.id, old_chunks);
+                                    futures::future::Either::Left(futures::stream::iter(
+                                        old_chunks.map(Ok),
+                                    ))
                                 }
-                            }
+                                // if we cannot even fetch the manifest, we generate a
+                                // single error value.
+                                Err(err) => {
+                                    futures::future::Either::Right(futures::stream::once(
+                                        ready(Err(RepositoryError::StorageError(err))),
+                                    ))
+                                }
+                            };
+                            ready(Some(res))
                         })
                         .flatten(),
                 ),
@@ -1250,12 +1900,14 @@ mod tests {
                 offset: 0,
                 length: 4,
             }),
+            uncompressed_size: None,
         };
 
         let chunk2 = ChunkInfo {
             node: array_id,
             coord: ChunkIndices(vec![0, 0, 1]),
             payload: ChunkPayload::Inline("hello".into()),
+            uncompressed_size: None,
         };
 
         let manifest =
@@ -1525,6 +2177,7 @@ mod tests {
                         node: 2,
                         coord: ChunkIndices(vec![0]),
                         payload: ChunkPayload::Inline("baz1".into()),
+                        uncompressed_size: None,
                     },
                 ),
                 (
@@ -1533,6 +2186,7 @@ mod tests {
                         node: 2,
                         coord: ChunkIndices(vec![1]),
                         payload: ChunkPayload::Inline("baz2".into()),
+                        uncompressed_size: None,
                     },
                 ),
                 (
@@ -1541,6 +2195,7 @@ mod tests {
                         node: 1,
                         coord: ChunkIndices(vec![1, 0]),
                         payload: ChunkPayload::Inline("bar1".into()),
+                        uncompressed_size: None,
                     },
                 ),
                 (
@@ -1549,6 +2204,7 @@ mod tests {
                         node: 1,
                         coord: ChunkIndices(vec![1, 1]),
                         payload: ChunkPayload::Inline("bar2".into()),
+                        uncompressed_size: None,
                     },
                 ),
             ]
@@ -1997,6 +2653,614 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_try_commit_rebases_disjoint_changes() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let inline =
+            |s: &str| Some(ChunkPayload::Inline(Bytes::copy_from_slice(s.as_bytes())));
+        let array: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), zarr_meta.clone()).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), inline("base")).await?;
+        let base = ds.commit("main", "base", None).await?;
+
+        // both writers change different chunks and create different nodes
+        let mut ds1 = Repository::update(Arc::clone(&storage), base.clone()).build();
+        let mut ds2 = Repository::update(Arc::clone(&storage), base.clone()).build();
+        ds1.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), inline("ds1")).await?;
+        ds1.add_array("/ds1".try_into()?, zarr_meta.clone()).await?;
+        ds1.set_chunk_ref("/ds1".try_into()?, ChunkIndices(vec![0]), inline("ds1"))
+            .await?;
+        let first = ds1.commit("main", "first", None).await?;
+
+        ds2.set_chunk_ref(array.clone(), ChunkIndices(vec![2]), inline("ds2")).await?;
+        ds2.add_array("/ds2".try_into()?, zarr_meta.clone()).await?;
+        ds2.set_chunk_ref("/ds2".try_into()?, ChunkIndices(vec![0]), inline("ds2"))
+            .await?;
+        ds2.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"writer":2}"#).unwrap()),
+        )
+        .await?;
+        assert!(matches!(
+            ds2.commit("main", "second", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+        let second = ds2.try_commit("main", "second", None).await?;
+
+        let snapshot = storage.fetch_snapshot(&second).await?;
+        assert_eq!(snapshot.metadata.message, "second");
+        assert_eq!(snapshot.short_term_history[0].id, first);
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, second);
+
+        let ds = Repository::update(Arc::clone(&storage), second).build();
+        for (path, coord, payload) in [
+            (array.clone(), 0, "base"),
+            (array.clone(), 1, "ds1"),
+            (array.clone(), 2, "ds2"),
+            ("/ds1".try_into()?, 0, "ds1"),
+            ("/ds2".try_into()?, 0, "ds2"),
+        ] {
+            assert_eq!(
+                ds.get_chunk_ref(&path, &ChunkIndices(vec![coord])).await?,
+                inline(payload)
+            );
+        }
+        // the new nodes don't share ids, even if both sessions reserved the same
+        let ds1_node = ds.get_node(&"/ds1".try_into()?).await?;
+        let ds2_node = ds.get_node(&"/ds2".try_into()?).await?;
+        assert_ne!(ds1_node.id, ds2_node.id);
+        assert!(ds.get_node(&Path::root()).await?.user_attributes.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_try_commit_conflicts() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let inline =
+            |s: &str| Some(ChunkPayload::Inline(Bytes::copy_from_slice(s.as_bytes())));
+        let array: Path = "/array".try_into()?;
+        let other: Path = "/other".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), zarr_meta.clone()).await?;
+        ds.add_array(other.clone(), zarr_meta.clone()).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), inline("base")).await?;
+        let base = ds.commit("main", "base", None).await?;
+
+        let mut ds1 = Repository::update(Arc::clone(&storage), base.clone()).build();
+        ds1.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), inline("ds1")).await?;
+        ds1.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), inline("ds1")).await?;
+        ds1.delete_array(other.clone()).await?;
+        let first = ds1.commit("main", "first", None).await?;
+
+        // the same chunks, even deleted, conflict, and so do changes to a deleted node
+        let mut ds2 = Repository::update(Arc::clone(&storage), base.clone()).build();
+        ds2.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), inline("ds2")).await?;
+        ds2.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), None).await?;
+        ds2.set_chunk_ref(array.clone(), ChunkIndices(vec![2]), inline("ds2")).await?;
+        ds2.set_chunk_ref(other.clone(), ChunkIndices(vec![0]), inline("ds2")).await?;
+        let res = ds2.try_commit("main", "second", None).await;
+        let Err(RepositoryError::CommitConflict(conflict)) = res else {
+            panic!("expected a conflict, got {res:?}");
+        };
+        assert_eq!(
+            conflict,
+            CommitConflict {
+                nodes: BTreeSet::from([other.clone()]),
+                chunks: BTreeSet::from([
+                    (array.clone(), ChunkIndices(vec![0])),
+                    (array.clone(), ChunkIndices(vec![1]))
+                ]),
+            }
+        );
+
+        // the session is left as it was, and the branch doesn't move
+        assert_eq!(ds2.snapshot_id(), &base);
+        assert_eq!(
+            ds2.get_chunk_ref(&array, &ChunkIndices(vec![2])).await?,
+            inline("ds2")
+        );
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, first);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_shards() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_manifest_shard_size(2)
+            .with_max_manifest_deltas(1)
+            .build();
+
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let large: Path = "/large".try_into()?;
+        let small: Path = "/small".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(large.clone(), zarr_meta.clone()).await?;
+        ds.add_array(small.clone(), zarr_meta).await?;
+
+        let inline =
+            |s: String| Some(ChunkPayload::Inline(Bytes::copy_from_slice(s.as_bytes())));
+        for i in 0..5 {
+            ds.set_chunk_ref(
+                large.clone(),
+                ChunkIndices(vec![i]),
+                inline(format!("a{i}")),
+            )
+            .await?;
+        }
+        ds.set_chunk_ref(small.clone(), ChunkIndices(vec![0]), inline("s".to_string()))
+            .await?;
+        ds.commit("main", "sharded", None).await?;
+
+        async fn manifests(ds: &Repository, path: &Path) -> Vec<ManifestRef> {
+            match ds.get_node(path).await.unwrap().node_data {
+                NodeData::Array(_, manifests) => manifests,
+                NodeData::Group => panic!("must be an array"),
+            }
+        }
+        let extents = |from: u32, to: u32| {
+            ManifestExtents::new(ChunkIndices(vec![from]), ChunkIndices(vec![to]))
+        };
+
+        // the large array is split in 3 shards, the small one stays in a shared manifest
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(snapshot.manifest_files.len(), 4);
+        let shards = manifests(&ds, &large).await;
+        assert_eq!(
+            shards.iter().map(|shard| shard.extents.clone()).collect::<Vec<_>>(),
+            vec![extents(0, 1), extents(2, 3), extents(4, 4)]
+        );
+        let small_manifests = manifests(&ds, &small).await;
+        assert_eq!(small_manifests.len(), 1);
+        assert!(small_manifests[0].extents.is_unknown());
+        let large_id = ds.get_node(&large).await?.id;
+        for shard in shards.iter() {
+            let manifest = storage.fetch_manifests(&shard.object_id).await?;
+            assert!(manifest.len() <= 2);
+            assert!(manifest
+                .chunks()
+                .keys()
+                .all(|(node, coord)| *node == large_id && shard.extents.contains(coord)));
+        }
+
+        // all chunks can be read, from a single shard
+        let ds2 =
+            Repository::update(Arc::clone(&storage), ds.snapshot_id().clone()).build();
+        for i in 0..5 {
+            let fetched = logging.fetch_operations().len();
+            assert_eq!(
+                ds2.get_chunk_ref(&large, &ChunkIndices(vec![i])).await?,
+                inline(format!("a{i}"))
+            );
+            let manifest_fetches: Vec<_> = logging.fetch_operations()[fetched..]
+                .iter()
+                .filter(|(op, _)| op == "fetch_manifests")
+                .map(|(_, id)| id.clone())
+                .collect();
+            assert_eq!(
+                manifest_fetches,
+                vec![shards[i as usize / 2].object_id.0.to_vec()]
+            );
+        }
+        assert_eq!(
+            ds2.get_chunk_ref(&small, &ChunkIndices(vec![0])).await?,
+            inline("s".to_string())
+        );
+        assert_eq!(ds2.all_chunks().await?.try_collect::<Vec<_>>().await?.len(), 6);
+
+        // a delta goes in front of the shards
+        ds.set_chunk_ref(large.clone(), ChunkIndices(vec![3]), inline("b3".to_string()))
+            .await?;
+        ds.commit("main", "delta", None).await?;
+        let delta_manifests = manifests(&ds, &large).await;
+        assert_eq!(delta_manifests.len(), 4);
+        assert!(delta_manifests[0].extents.is_unknown());
+        assert_eq!(delta_manifests[1..], shards[..]);
+        assert_eq!(manifests(&ds, &small).await.len(), 2);
+        assert_eq!(
+            ds.get_chunk_ref(&large, &ChunkIndices(vec![3])).await?,
+            inline("b3".to_string())
+        );
+        let chunks: Vec<_> = ds
+            .all_chunks()
+            .await?
+            .map_ok(|(path, chunk)| (path, chunk.coord, chunk.payload))
+            .try_collect()
+            .await?;
+        assert_eq!(chunks.len(), 6);
+        assert!(chunks.contains(&(
+            large.clone(),
+            ChunkIndices(vec![3]),
+            inline("b3".to_string()).unwrap()
+        )));
+
+        // deletions compact, and split again
+        ds.set_chunk_ref(large.clone(), ChunkIndices(vec![0]), None).await?;
+        ds.commit("main", "compaction", None).await?;
+        let shards = manifests(&ds, &large).await;
+        assert_eq!(
+            shards.iter().map(|shard| shard.extents.clone()).collect::<Vec<_>>(),
+            vec![extents(1, 2), extents(3, 4)]
+        );
+        assert_eq!(
+            storage.fetch_snapshot(ds.snapshot_id()).await?.manifest_files.len(),
+            3
+        );
+        for i in 1..5 {
+            let expected = if i == 3 { "b3".to_string() } else { format!("a{i}") };
+            assert_eq!(
+                ds.get_chunk_ref(&large, &ChunkIndices(vec![i])).await?,
+                inline(expected)
+            );
+        }
+        assert_eq!(ds.get_chunk_ref(&large, &ChunkIndices(vec![0])).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sharded_manifests_without_deltas() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_manifest_shard_size(2)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+        let inline =
+            |s: String| Some(ChunkPayload::Inline(Bytes::copy_from_slice(s.as_bytes())));
+        for i in 0..4 {
+            ds.set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![i]),
+                inline(format!("a{i}")),
+            )
+            .await?;
+        }
+        ds.commit("main", "sharded", None).await?;
+        // the parent only has shards, no deltas
+        assert_eq!(
+            storage.fetch_snapshot(ds.snapshot_id()).await?.manifest_files.len(),
+            2
+        );
+
+        // without deltas allowed, every commit compacts and splits again
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![4]), inline("a4".to_string()))
+            .await?;
+        ds.commit("main", "no delta", None).await?;
+        let NodeData::Array(_, manifests) = ds.get_node(&path).await?.node_data else {
+            panic!("must be an array");
+        };
+        assert_eq!(manifests.len(), 3);
+        assert!(manifests.iter().all(|manifest| !manifest.extents.is_unknown()));
+        for i in 0..5 {
+            assert_eq!(
+                ds.get_chunk_ref(&path, &ChunkIndices(vec![i])).await?,
+                inline(format!("a{i}"))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_deltas_and_compaction() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_max_manifest_deltas(2)
+            .build();
+
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+
+        let inline =
+            |s: &str| Some(ChunkPayload::Inline(Bytes::copy_from_slice(s.as_bytes())));
+        async fn manifest_count(ds: &Repository) -> usize {
+            ds.storage()
+                .fetch_snapshot(ds.snapshot_id())
+                .await
+                .unwrap()
+                .manifest_files
+                .len()
+        }
+        async fn all_chunks(ds: &Repository) -> Vec<(ChunkIndices, ChunkPayload)> {
+            ds.all_chunks()
+                .await
+                .unwrap()
+                .map_ok(|(_, chunk)| (chunk.coord, chunk.payload))
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+                .into_iter()
+                .sorted()
+                .collect()
+        }
+
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), inline("a0")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), inline("a1")).await?;
+        ds.commit("main", "base", None).await?;
+        assert_eq!(manifest_count(&ds).await, 1);
+
+        // two commits go to deltas, overwriting and adding chunks
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), inline("b0")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), inline("b2")).await?;
+        ds.commit("main", "delta 1", None).await?;
+        assert_eq!(manifest_count(&ds).await, 2);
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), inline("c2")).await?;
+        ds.commit("main", "delta 2", None).await?;
+        assert_eq!(manifest_count(&ds).await, 3);
+
+        // the delta only holds the chunks written by its commit
+        let snapshot = ds.storage().fetch_snapshot(ds.snapshot_id()).await?;
+        let delta = ds.storage().fetch_manifests(&snapshot.manifest_files[0].id).await?;
+        assert_eq!(delta.len(), 1);
+
+        let expected = vec![
+            (ChunkIndices(vec![0]), inline("b0").unwrap()),
+            (ChunkIndices(vec![1]), inline("a1").unwrap()),
+            (ChunkIndices(vec![2]), inline("c2").unwrap()),
+        ];
+        assert_eq!(all_chunks(&ds).await, expected);
+        for (coord, payload) in expected.iter() {
+            assert_eq!(ds.get_chunk_ref(&path, coord).await?, Some(payload.clone()));
+        }
+
+        // a new session reads the same merged chunks
+        let ds2 =
+            Repository::update(Arc::clone(&storage), ds.snapshot_id().clone()).build();
+        assert_eq!(all_chunks(&ds2).await, expected);
+
+        // going over the limit compacts everything into a single manifest
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![3]), inline("d3")).await?;
+        ds.commit("main", "compaction", None).await?;
+        assert_eq!(manifest_count(&ds).await, 1);
+        let mut expected = expected;
+        expected.push((ChunkIndices(vec![3]), inline("d3").unwrap()));
+        assert_eq!(all_chunks(&ds).await, expected);
+
+        // deletions always compact
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![4]), inline("e4")).await?;
+        ds.commit("main", "delta 3", None).await?;
+        assert_eq!(manifest_count(&ds).await, 2);
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), None).await?;
+        ds.commit("main", "delete", None).await?;
+        assert_eq!(manifest_count(&ds).await, 1);
+        assert_eq!(ds.get_chunk_ref(&path, &ChunkIndices(vec![0])).await?, None);
+        assert_eq!(all_chunks(&ds).await.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_stats_match_manifest_scan() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_max_manifest_deltas(5)
+            .with_node_stats(true)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![4, 5],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let array: Path = "/array".try_into()?;
+        let empty: Path = "/empty".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), zarr_meta.clone()).await?;
+        ds.add_array(empty.clone(), zarr_meta).await?;
+        let large = ds.get_chunk_writer()(Bytes::from(vec![1; 1_000])).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0, 0]), Some(large)).await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1, 2]),
+            Some(ChunkPayload::Inline("small".into())),
+        )
+        .await?;
+        ds.commit("main", "first", None).await?;
+        // overwrite a chunk in a manifest delta, the stats must only count the new one
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1, 2]),
+            Some(ChunkPayload::Inline("bigger".into())),
+        )
+        .await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0, 1]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+        let snapshot_id = ds.commit("main", "second", None).await?;
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        assert_eq!(snapshot.manifest_files.len(), 2);
+
+        let chunks: Vec<_> = ds.all_chunks().await?.try_collect().await?;
+        let mut sizes = Vec::new();
+        for (path, chunk) in chunks {
+            assert_eq!(path, array);
+            sizes.push(match chunk.payload {
+                ChunkPayload::Inline(bytes) => bytes.len() as u64,
+                ChunkPayload::Ref(chunk_ref) => chunk_ref.length,
+                ChunkPayload::Virtual(chunk_ref) => chunk_ref.length,
+            });
+        }
+        let array_id = snapshot.get_node(&array)?.id;
+        assert_eq!(
+            snapshot.node_stats(storage.as_ref(), array_id).await?,
+            Some(NodeStats {
+                chunk_count: sizes.len() as u64,
+                total_size: sizes.iter().sum(),
+                populated_fraction: sizes.len() as f64 / 6.0,
+            })
+        );
+        assert_eq!(sizes.len(), 3);
+        let empty_id = snapshot.get_node(&empty)?.id;
+        assert_eq!(
+            snapshot.node_stats(storage.as_ref(), empty_id).await?,
+            Some(NodeStats { chunk_count: 0, total_size: 0, populated_fraction: 0.0 })
+        );
+        let root_id = snapshot.get_node(&Path::root())?.id;
+        assert_eq!(snapshot.node_stats(storage.as_ref(), root_id).await?, None);
+
+        // without the option commits don't store stats
+        let mut ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        ds.set_chunk_ref(array, ChunkIndices(vec![1, 1]), None).await?;
+        let snapshot_id = ds.commit("main", "no stats", None).await?;
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        assert!(snapshot.node_stats_ids().is_empty());
+        assert_eq!(snapshot.node_stats(storage.as_ref(), array_id).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_compactions_share_the_manifest() -> Result<(), Box<dyn Error>>
+    {
+        use ::object_store::{memory::InMemory, path::Path as ObjectPath, ObjectStore};
+
+        let store = Arc::new(InMemory::new());
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::from_object_store(
+                Arc::clone(&store) as Arc<dyn ObjectStore>,
+                "repo".to_string(),
+            ));
+        let manifest_objects = || async {
+            store
+                .list(Some(&ObjectPath::from("repo/manifests")))
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+                .len()
+        };
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_max_manifest_deltas(5)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+        for i in 0..3 {
+            let payload = Some(ChunkPayload::Inline(Bytes::from(format!("chunk {i}"))));
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![i]), payload).await?;
+            ds.commit("main", "write", None).await?;
+        }
+        assert_eq!(manifest_objects().await, 3);
+
+        let parent = ds.snapshot_id().clone();
+        let mut ds1 = Repository::update(Arc::clone(&storage), parent.clone()).build();
+        let mut ds2 = Repository::update(Arc::clone(&storage), parent.clone()).build();
+        let (res1, res2) = futures::join!(
+            ds1.compact_manifests("compaction 1", Default::default()),
+            ds2.compact_manifests("compaction 2", Default::default()),
+        );
+        let (snapshot1, snapshot2) = (res1?, res2?);
+
+        // both compactions point to the same, single, new manifest
+        assert_eq!(manifest_objects().await, 4);
+        let files1 = storage.fetch_snapshot(&snapshot1).await?.manifest_files.clone();
+        let files2 = storage.fetch_snapshot(&snapshot2).await?.manifest_files.clone();
+        assert_eq!(files1.len(), 1);
+        assert_eq!(files1, files2);
+        for coord in 0..3 {
+            let payload =
+                Some(ChunkPayload::Inline(Bytes::from(format!("chunk {coord}"))));
+            let coord = ChunkIndices(vec![coord]);
+            assert_eq!(ds1.get_chunk_ref(&path, &coord).await?, payload);
+            assert_eq!(ds2.get_chunk_ref(&path, &coord).await?, payload);
+        }
+
+        // a later compaction of the same snapshot reuses it too
+        let mut ds3 = Repository::update(Arc::clone(&storage), parent).build();
+        ds3.compact_manifests("compaction 3", Default::default()).await?;
+        assert_eq!(manifest_objects().await, 4);
+
+        ds3.set_chunk_ref(path.clone(), ChunkIndices(vec![5]), None).await?;
+        assert!(matches!(
+            ds3.compact_manifests("dirty", Default::default()).await,
+            Err(RepositoryError::UncommittedChanges)
+        ));
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_all_chunks_iterator() -> Result<(), Box<dyn Error>> {
         let storage: Arc<dyn Storage + Send + Sync> =
@@ -2061,6 +3325,111 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_missing_chunks_read_as_fill_value() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![4, 3],
+            data_type: DataType::Int16,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int16(-2),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let path: Path = "/sparse".try_into().unwrap();
+        ds.add_array(path.clone(), zarr_meta.clone()).await?;
+        ds.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![1, 0]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        let snapshot_id = ds.flush("commit", SnapshotProperties::default()).await?;
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+
+        let fill = Bytes::from([0xfe, 0xff].repeat(4));
+        let absent = ChunkIndices(vec![0, 1]);
+        assert_eq!(ds.read_chunk(&path, &absent, &ByteRange::ALL, true).await?, fill);
+        assert_eq!(
+            ds.read_chunk(&path, &absent, &ByteRange::bounded(1, 3), true).await?,
+            fill.slice(1..3)
+        );
+        assert_eq!(
+            ds.read_chunk(&path, &ChunkIndices(vec![1, 0]), &ByteRange::ALL, true)
+                .await?,
+            Bytes::from("hello")
+        );
+        assert!(matches!(
+            ds.read_chunk(&path, &absent, &ByteRange::ALL, false).await,
+            Err(RepositoryError::ChunkNotFound { coords, .. }) if coords == absent
+        ));
+        // outside of the array there is nothing to fill
+        assert!(matches!(
+            ds.read_chunk(&path, &ChunkIndices(vec![2, 0]), &ByteRange::ALL, true).await,
+            Err(RepositoryError::ChunkNotFound { .. })
+        ));
+
+        let scanned: Vec<_> = ds.scan_array(&path, true).await?.try_collect().await?;
+        assert_eq!(
+            scanned,
+            vec![
+                (ChunkIndices(vec![0, 0]), fill.clone()),
+                (ChunkIndices(vec![0, 1]), fill.clone()),
+                (ChunkIndices(vec![1, 0]), Bytes::from("hello")),
+                (ChunkIndices(vec![1, 1]), fill.clone()),
+            ]
+        );
+        let scanned: Vec<_> = ds.scan_array(&path, false).await?.try_collect().await?;
+        assert_eq!(scanned, vec![(ChunkIndices(vec![1, 0]), Bytes::from("hello"))]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inline_chunk_threshold() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(4)
+            .build();
+        let write = |len: usize| ds.get_chunk_writer()(Bytes::from(vec![1; len]));
+
+        assert!(
+            matches!(write(3).await?, ChunkPayload::Inline(bytes) if bytes.len() == 3)
+        );
+        assert!(
+            matches!(write(4).await?, ChunkPayload::Inline(bytes) if bytes.len() == 4)
+        );
+        let ChunkPayload::Ref(chunk_ref) = write(5).await? else {
+            panic!("chunk above the threshold stored inline");
+        };
+        assert_eq!(chunk_ref.length, 5);
+        assert_eq!(
+            storage.fetch_chunk(&chunk_ref.id, &ByteRange::ALL).await?,
+            Bytes::from(vec![1; 5])
+        );
+
+        // with a zero threshold nothing is inline
+        let ds = Repository::update(Arc::clone(&storage), ds.snapshot_id().clone())
+            .with_inline_threshold_bytes(0)
+            .build();
+        for len in [0, 1] {
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![1; len])).await?;
+            assert!(
+                matches!(payload, ChunkPayload::Ref(chunk_ref) if chunk_ref.length == len as u64)
+            );
+        }
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_commit_and_refs() -> Result<(), Box<dyn Error>> {
         let storage: Arc<dyn Storage + Send + Sync> =
@@ -1,296 +1,2578 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use async_trait::async_trait;
-use bytes::Bytes;
-use futures::stream::BoxStream;
-use quick_cache::sync::Cache;
+use bytes::{Bytes, BytesMut};
+use futures::{future::try_join_all, stream, stream::BoxStream, StreamExt, TryStreamExt};
+use quick_cache::{
+    sync::{Cache, PlaceholderGuard},
+    DefaultHashBuilder, Lifecycle, Weighter,
+};
 
 use crate::{
     format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ChunkLength, ChunkOffset,
+        ManifestId, NodeId, SnapshotId,
     },
     private,
 };
 
-use super::{Storage, StorageError, StorageResult};
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageError, StorageResult};
 
-#[derive(Debug)]
-pub struct MemCachingStorage {
-    backend: Arc<dyn Storage + Send + Sync>,
-    snapshot_cache: Cache<SnapshotId, Arc<Snapshot>>,
-    manifest_cache: Cache<ManifestId, Arc<Manifest>>,
-    attributes_cache: Cache<AttributesId, Arc<AttributesTable>>,
-    chunk_cache: Cache<(ChunkId, ByteRange), Bytes>,
+// rough in memory sizes, used to weigh cached objects without walking them
+const SNAPSHOT_BASE_SIZE: u64 = 512;
+const SNAPSHOT_NODE_SIZE: u64 = 256;
+const MANIFEST_BASE_SIZE: u64 = 128;
+const MANIFEST_ENTRY_SIZE: u64 = 128;
+const ATTRIBUTES_SIZE: u64 = 64;
+
+/// How many objects [`MemCachingStorage::warm_manifests`] and
+/// [`MemCachingStorage::warm_snapshots`] fetch at the same time
+pub const WARM_CONCURRENCY: usize = 16;
+
+/// The result of warming a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarmSummary {
+    /// Number of ids passed, including duplicates
+    pub requested: usize,
+    /// Number of distinct objects that were not cached, and were fetched from the backend
+    pub fetched: usize,
 }
 
-impl MemCachingStorage {
-    pub fn new(
-        backend: Arc<dyn Storage + Send + Sync>,
-        num_snapshots: u16,
-        num_manifests: u16,
-        num_attributes: u16,
-        num_chunks: u16,
-    ) -> Self {
-        MemCachingStorage {
-            backend,
-            snapshot_cache: Cache::new(num_snapshots as usize),
-            manifest_cache: Cache::new(num_manifests as usize),
-            attributes_cache: Cache::new(num_attributes as usize),
-            chunk_cache: Cache::new(num_chunks as usize),
+/// How [`MemCachingStorage::with_memory_budget_fractions`] splits the budget between caches
+///
+/// The fractions are relative to their sum, so they don't need to add up to one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBudgetFractions {
+    pub snapshots: f64,
+    pub manifests: f64,
+    /// The entries of single nodes, see [`Storage::fetch_node_chunks`]
+    pub node_manifests: f64,
+    pub attributes: f64,
+    pub chunks: f64,
+}
+
+impl Default for MemoryBudgetFractions {
+    fn default() -> Self {
+        Self {
+            snapshots: 0.1,
+            manifests: 0.3,
+            node_manifests: 0.1,
+            attributes: 0.05,
+            chunks: 0.45,
         }
     }
 }
 
-impl private::Sealed for MemCachingStorage {}
+/// A cheap estimate of the memory used by a cached value, in bytes
+trait CacheWeight {
+    fn cache_weight(&self) -> u64;
+}
 
-#[async_trait]
-impl Storage for MemCachingStorage {
-    async fn fetch_snapshot(
-        &self,
-        id: &SnapshotId,
-    ) -> Result<Arc<Snapshot>, StorageError> {
-        match self.snapshot_cache.get_value_or_guard_async(id).await {
-            Ok(snapshot) => Ok(snapshot),
-            Err(guard) => {
-                let snapshot = self.backend.fetch_snapshot(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&snapshot));
-                Ok(snapshot)
-            }
-        }
+impl CacheWeight for Arc<Snapshot> {
+    fn cache_weight(&self) -> u64 {
+        SNAPSHOT_BASE_SIZE + SNAPSHOT_NODE_SIZE * self.len() as u64
     }
+}
 
-    async fn fetch_attributes(
-        &self,
-        id: &AttributesId,
-    ) -> Result<Arc<AttributesTable>, StorageError> {
-        match self.attributes_cache.get_value_or_guard_async(id).await {
-            Ok(table) => Ok(table),
-            Err(guard) => {
-                let table = self.backend.fetch_attributes(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&table));
-                Ok(table)
-            }
-        }
+impl CacheWeight for Arc<Manifest> {
+    fn cache_weight(&self) -> u64 {
+        MANIFEST_BASE_SIZE + MANIFEST_ENTRY_SIZE * self.len() as u64
     }
+}
 
-    async fn fetch_manifests(
-        &self,
-        id: &ManifestId,
-    ) -> Result<Arc<Manifest>, StorageError> {
-        match self.manifest_cache.get_value_or_guard_async(id).await {
-            Ok(manifest) => Ok(manifest),
-            Err(guard) => {
-                let manifest = self.backend.fetch_manifests(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&manifest));
-                Ok(manifest)
-            }
+impl CacheWeight for Arc<AttributesTable> {
+    fn cache_weight(&self) -> u64 {
+        ATTRIBUTES_SIZE
+    }
+}
+
+impl CacheWeight for Bytes {
+    fn cache_weight(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+/// Limits caches by number of entries, or by their estimated size in bytes
+#[derive(Debug, Clone, Copy)]
+enum CacheWeighter {
+    Count,
+    Bytes,
+}
+
+impl<K, V: CacheWeight> Weighter<K, V> for CacheWeighter {
+    fn weight(&self, _key: &K, value: &V) -> u64 {
+        match self {
+            CacheWeighter::Count => 1,
+            // zero weight entries are never evicted
+            CacheWeighter::Bytes => value.cache_weight().max(1),
         }
     }
+}
 
-    async fn fetch_chunk(
-        &self,
-        id: &ChunkId,
-        range: &ByteRange,
-    ) -> Result<Bytes, StorageError> {
-        let key = (id.clone(), range.clone());
-        match self.chunk_cache.get_value_or_guard_async(&key).await {
-            Ok(bytes) => Ok(bytes),
-            Err(guard) => {
-                let bytes = self.backend.fetch_chunk(id, range).await?;
-                let _fail_is_ok = guard.insert(bytes.clone());
-                Ok(bytes)
+type WeightedCache<K, V> = Cache<K, V, CacheWeighter, DefaultHashBuilder, EvictionHook>;
+
+fn count_cache<K: CacheKey>(num_items: usize, hook: EvictionHook) -> ObjectCache<K> {
+    ObjectCache::Dedicated(Cache::with(
+        num_items,
+        num_items as u64,
+        CacheWeighter::Count,
+        DefaultHashBuilder::default(),
+        hook,
+    ))
+}
+
+fn bytes_cache<K: CacheKey>(
+    bytes: u64,
+    typical_size: u64,
+    hook: EvictionHook,
+) -> ObjectCache<K> {
+    ObjectCache::Dedicated(weighted_bytes_cache(bytes, typical_size, hook))
+}
+
+fn weighted_bytes_cache<K, V>(
+    bytes: u64,
+    typical_size: u64,
+    hook: EvictionHook,
+) -> WeightedCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+    V: Clone + CacheWeight,
+    EvictionHook: Lifecycle<K, V>,
+{
+    let estimated_items = (bytes / typical_size).max(1) as usize;
+    Cache::with(
+        estimated_items,
+        bytes,
+        CacheWeighter::Bytes,
+        DefaultHashBuilder::default(),
+        hook,
+    )
+}
+
+/// What [`MemCachingStorage`] remembers about cached chunk ranges and node manifests, on top
+/// of the caches themselves
+///
+/// Entries are added before the value they describe is cached, and removed when the value is
+/// evicted, see [`EvictionHook`], so they don't outlive the cached values.
+#[derive(Debug, Default)]
+struct CacheIndex {
+    /// The absolute start offset of every cached chunk range, and the range used as cache key
+    chunk_ranges: Mutex<HashMap<ChunkId, BTreeMap<ChunkOffset, ByteRange>>>,
+    /// Every range and node cached for a chunk or manifest, so deleting the object can
+    /// invalidate all of them
+    chunk_keys: Mutex<HashMap<ChunkId, HashSet<ByteRange>>>,
+    node_manifest_keys: Mutex<HashMap<ManifestId, HashSet<NodeId>>>,
+    /// The size of the chunks fetched to the end, used to resolve open-ended ranges, kept while
+    /// any range of the chunk is cached
+    chunk_sizes: Mutex<HashMap<ChunkId, ChunkLength>>,
+}
+
+impl CacheIndex {
+    fn forget(&self, key: &SharedKey) {
+        match key {
+            SharedKey::Chunk(id, range) => {
+                #[allow(clippy::expect_used)]
+                let mut keys = self.chunk_keys.lock().expect("poison lock");
+                let uncached = keys.get_mut(id).is_some_and(|ranges| {
+                    ranges.remove(range);
+                    ranges.is_empty()
+                });
+                if uncached {
+                    keys.remove(id);
+                    #[allow(clippy::expect_used)]
+                    self.chunk_sizes.lock().expect("poison lock").remove(id);
+                }
+                drop(keys);
+                #[allow(clippy::expect_used)]
+                let mut index = self.chunk_ranges.lock().expect("poison lock");
+                remove_nested(&mut index, id, |ranges| {
+                    ranges.retain(|_, indexed| indexed != range);
+                    ranges.is_empty()
+                });
+            }
+            SharedKey::NodeManifest(id, node) => {
+                #[allow(clippy::expect_used)]
+                let mut keys = self.node_manifest_keys.lock().expect("poison lock");
+                remove_nested(&mut keys, id, |nodes| {
+                    nodes.remove(node);
+                    nodes.is_empty()
+                });
             }
+            SharedKey::Snapshot(_)
+            | SharedKey::Manifest(_)
+            | SharedKey::Attributes(_) => {}
         }
     }
+}
 
-    async fn write_snapshot(
-        &self,
-        id: SnapshotId,
-        snapshot: Arc<Snapshot>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
-        self.snapshot_cache.insert(id, snapshot);
-        Ok(())
+/// Update the entry for `key`, `update` returns true if the entry should be dropped
+fn remove_nested<K: Eq + std::hash::Hash, V>(
+    map: &mut HashMap<K, V>,
+    key: &K,
+    update: impl FnOnce(&mut V) -> bool,
+) {
+    if map.get_mut(key).is_some_and(update) {
+        map.remove(key);
     }
+}
 
-    async fn write_attributes(
-        &self,
-        id: AttributesId,
-        table: Arc<AttributesTable>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_attributes(id.clone(), Arc::clone(&table)).await?;
-        self.attributes_cache.insert(id, table);
-        Ok(())
+/// Keeps the [`CacheIndex`] in sync with the evictions of a cache
+///
+/// The evicted keys are collected while the cache shard is locked, and forgotten once the
+/// insert that evicted them is done, so the index locks are never taken inside the cache's.
+/// A range evicted and cached again concurrently can be forgotten, losing its index entry.
+/// That only makes it invisible to [`Storage::delete_chunk`] and to range stitching.
+#[derive(Debug, Clone)]
+struct EvictionHook(Arc<CacheIndex>);
+
+impl EvictionHook {
+    fn forget_all(&self, keys: Vec<SharedKey>) {
+        for key in keys {
+            self.0.forget(&key);
+        }
     }
+}
 
-    async fn write_manifests(
-        &self,
-        id: ManifestId,
-        manifest: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
-        self.manifest_cache.insert(id, manifest);
-        Ok(())
+impl<K: CacheKey, V> Lifecycle<K, V> for EvictionHook {
+    type RequestState = Vec<SharedKey>;
+
+    fn begin_request(&self) -> Self::RequestState {
+        Vec::new()
     }
 
-    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
-        self.backend.write_chunk(id.clone(), bytes.clone()).await?;
-        // we don't pre-populate the chunk cache, there are too many of them for this to be useful
-        Ok(())
+    fn on_evict(&self, state: &mut Self::RequestState, key: K, _val: V) {
+        state.push(key.shared_key());
     }
 
-    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
-        self.backend.get_ref(ref_key).await
+    fn end_request(&self, state: Self::RequestState) {
+        self.forget_all(state)
     }
+}
 
-    async fn ref_names(&self) -> StorageResult<Vec<String>> {
-        self.backend.ref_names().await
+impl<V> Lifecycle<SharedKey, V> for EvictionHook {
+    type RequestState = Vec<SharedKey>;
+
+    fn begin_request(&self) -> Self::RequestState {
+        Vec::new()
     }
 
-    async fn write_ref(
-        &self,
-        ref_key: &str,
-        overwrite_refs: bool,
-        bytes: Bytes,
-    ) -> StorageResult<()> {
-        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    fn on_evict(&self, state: &mut Self::RequestState, key: SharedKey, _val: V) {
+        state.push(key);
     }
 
-    async fn ref_versions(
-        &self,
-        ref_name: &str,
-    ) -> StorageResult<BoxStream<StorageResult<String>>> {
-        self.backend.ref_versions(ref_name).await
+    fn end_request(&self, state: Self::RequestState) {
+        self.forget_all(state)
     }
 }
 
-#[cfg(test)]
-#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
-mod test {
-    use std::sync::Arc;
+/// The key of an entry in the cache shared by all object types, see
+/// [`MemCachingStorage::with_shared_memory_budget`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum SharedKey {
+    Snapshot(SnapshotId),
+    Manifest(ManifestId),
+    NodeManifest(ManifestId, NodeId),
+    Attributes(AttributesId),
+    Chunk(ChunkId, ByteRange),
+}
 
-    use itertools::Itertools;
+#[derive(Debug, Clone)]
+enum SharedValue {
+    Snapshot(Arc<Snapshot>),
+    Manifest(Arc<Manifest>),
+    Attributes(Arc<AttributesTable>),
+    Chunk(Bytes),
+}
 
-    use super::*;
-    use crate::{
-        format::manifest::ChunkInfo,
-        repository::{ChunkIndices, ChunkPayload},
-        storage::{logging::LoggingStorage, ObjectStorage, Storage},
-    };
+impl CacheWeight for SharedValue {
+    fn cache_weight(&self) -> u64 {
+        match self {
+            SharedValue::Snapshot(snapshot) => snapshot.cache_weight(),
+            SharedValue::Manifest(manifest) => manifest.cache_weight(),
+            SharedValue::Attributes(table) => table.cache_weight(),
+            SharedValue::Chunk(bytes) => bytes.cache_weight(),
+        }
+    }
+}
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_caching_storage_caches() -> Result<(), Box<dyn std::error::Error>> {
-        let backend: Arc<dyn Storage + Send + Sync> =
-            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+type SharedCache = WeightedCache<SharedKey, SharedValue>;
 
-        let ci1 = ChunkInfo {
-            node: 1,
-            coord: ChunkIndices(vec![]),
-            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
-        };
-        let ci2 = ChunkInfo {
-            node: 1,
-            coord: ChunkIndices(vec![]),
-            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"b")),
-        };
-        let pre_existing_id = ManifestId::random();
-        let pre_exiting_manifest = Arc::new(vec![ci1].into_iter().collect());
-        backend
-            .write_manifests(pre_existing_id.clone(), Arc::clone(&pre_exiting_manifest))
-            .await?;
+/// The keys of one of the caches, and how they are stored in the shared cache
+trait CacheKey: Eq + std::hash::Hash + Clone {
+    type Value: Clone + CacheWeight;
 
-        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
-        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
-        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 0, 2, 0, 0);
+    fn shared_key(&self) -> SharedKey;
+    fn wrap(value: Self::Value) -> SharedValue;
+    /// `None` if the value is of a different type, which cannot happen for the key's own entry
+    fn unwrap(value: SharedValue) -> Option<Self::Value>;
+}
 
-        let manifest = Arc::new(vec![ci2].into_iter().collect());
-        let id = ManifestId::random();
-        caching.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+impl CacheKey for SnapshotId {
+    type Value = Arc<Snapshot>;
 
-        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
-        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
-        // when we insert we cache, so no fetches
-        assert_eq!(logging.fetch_operations(), vec![]);
+    fn shared_key(&self) -> SharedKey {
+        SharedKey::Snapshot(self.clone())
+    }
 
-        // first time it sees an ID it calls the backend
-        assert_eq!(
-            caching.fetch_manifests(&pre_existing_id).await?,
-            pre_exiting_manifest
-        );
-        assert_eq!(
-            logging.fetch_operations(),
-            vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
-        );
+    fn wrap(value: Self::Value) -> SharedValue {
+        SharedValue::Snapshot(value)
+    }
 
-        // only calls backend once
-        assert_eq!(
-            caching.fetch_manifests(&pre_existing_id).await?,
-            pre_exiting_manifest
-        );
-        assert_eq!(
-            logging.fetch_operations(),
-            vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
-        );
+    fn unwrap(value: SharedValue) -> Option<Self::Value> {
+        match value {
+            SharedValue::Snapshot(snapshot) => Some(snapshot),
+            _ => None,
+        }
+    }
+}
 
-        // other walues still cached
-        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
-        assert_eq!(
-            logging.fetch_operations(),
-            vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
-        );
-        Ok(())
+impl CacheKey for ManifestId {
+    type Value = Arc<Manifest>;
+
+    fn shared_key(&self) -> SharedKey {
+        SharedKey::Manifest(self.clone())
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_caching_storage_has_limit() -> Result<(), Box<dyn std::error::Error>> {
-        let backend: Arc<dyn Storage + Send + Sync> =
-            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+    fn wrap(value: Self::Value) -> SharedValue {
+        SharedValue::Manifest(value)
+    }
 
-        let ci1 = ChunkInfo {
-            node: 1,
-            coord: ChunkIndices(vec![]),
-            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
-        };
-        let ci2 = ChunkInfo { node: 2, ..ci1.clone() };
-        let ci3 = ChunkInfo { node: 3, ..ci1.clone() };
-        let ci4 = ChunkInfo { node: 4, ..ci1.clone() };
-        let ci5 = ChunkInfo { node: 5, ..ci1.clone() };
-        let ci6 = ChunkInfo { node: 6, ..ci1.clone() };
-        let ci7 = ChunkInfo { node: 7, ..ci1.clone() };
-        let ci8 = ChunkInfo { node: 8, ..ci1.clone() };
-        let ci9 = ChunkInfo { node: 9, ..ci1.clone() };
+    fn unwrap(value: SharedValue) -> Option<Self::Value> {
+        match value {
+            SharedValue::Manifest(manifest) => Some(manifest),
+            _ => None,
+        }
+    }
+}
 
-        let id1 = ManifestId::random();
-        let table1 = Arc::new(vec![ci1, ci2, ci3].into_iter().collect());
-        backend.write_manifests(id1.clone(), Arc::clone(&table1)).await?;
-        let id2 = ManifestId::random();
-        let table2 = Arc::new(vec![ci4, ci5, ci6].into_iter().collect());
-        backend.write_manifests(id2.clone(), Arc::clone(&table2)).await?;
-        let id3 = ManifestId::random();
-        let table3 = Arc::new(vec![ci7, ci8, ci9].into_iter().collect());
-        backend.write_manifests(id3.clone(), Arc::clone(&table3)).await?;
+impl CacheKey for (ManifestId, NodeId) {
+    type Value = Arc<Manifest>;
 
-        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
-        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
-        let caching = MemCachingStorage::new(
-            Arc::clone(&logging_c),
-            // the cache can only fit 2 manifests.
-            0,
-            2,
-            0,
-            0,
-        );
+    fn shared_key(&self) -> SharedKey {
+        SharedKey::NodeManifest(self.0.clone(), self.1)
+    }
 
-        // we keep asking for all 3 items, but the cache can only fit 2
-        for _ in 0..20 {
-            assert_eq!(caching.fetch_manifests(&id1).await?, table1);
-            assert_eq!(caching.fetch_manifests(&id2).await?, table2);
-            assert_eq!(caching.fetch_manifests(&id3).await?, table3);
-        }
-        // after the initial warming requests, we only request the file that doesn't fit in the cache
-        assert_eq!(logging.fetch_operations()[10..].iter().unique().count(), 1);
+    fn wrap(value: Self::Value) -> SharedValue {
+        SharedValue::Manifest(value)
+    }
+
+    fn unwrap(value: SharedValue) -> Option<Self::Value> {
+        ManifestId::unwrap(value)
+    }
+}
+
+impl CacheKey for AttributesId {
+    type Value = Arc<AttributesTable>;
+
+    fn shared_key(&self) -> SharedKey {
+        SharedKey::Attributes(self.clone())
+    }
+
+    fn wrap(value: Self::Value) -> SharedValue {
+        SharedValue::Attributes(value)
+    }
+
+    fn unwrap(value: SharedValue) -> Option<Self::Value> {
+        match value {
+            SharedValue::Attributes(table) => Some(table),
+            _ => None,
+        }
+    }
+}
+
+impl CacheKey for (ChunkId, ByteRange) {
+    type Value = Bytes;
+
+    fn shared_key(&self) -> SharedKey {
+        SharedKey::Chunk(self.0.clone(), self.1.clone())
+    }
+
+    fn wrap(value: Self::Value) -> SharedValue {
+        SharedValue::Chunk(value)
+    }
+
+    fn unwrap(value: SharedValue) -> Option<Self::Value> {
+        match value {
+            SharedValue::Chunk(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// One of the caches of [`MemCachingStorage`], with its own capacity or a view of the cache
+/// shared by all object types
+#[derive(Debug)]
+enum ObjectCache<K: CacheKey> {
+    Dedicated(WeightedCache<K, K::Value>),
+    Shared(Arc<SharedCache>),
+}
+
+type DedicatedGuard<'a, K, V> =
+    PlaceholderGuard<'a, K, V, CacheWeighter, DefaultHashBuilder, EvictionHook>;
+
+/// A pending insert into an [`ObjectCache`], returned by a lookup that missed
+enum CacheGuard<'a, K: CacheKey> {
+    Dedicated(DedicatedGuard<'a, K, K::Value>),
+    Shared(DedicatedGuard<'a, SharedKey, SharedValue>),
+}
+
+impl<K: CacheKey> CacheGuard<'_, K> {
+    fn insert(self, value: K::Value) -> Result<(), K::Value> {
+        match self {
+            CacheGuard::Dedicated(guard) => guard.insert(value),
+            CacheGuard::Shared(guard) => {
+                guard.insert(K::wrap(value.clone())).map_err(|_| value)
+            }
+        }
+    }
+}
+
+impl<K: CacheKey> ObjectCache<K> {
+    fn get(&self, key: &K) -> Option<K::Value> {
+        match self {
+            ObjectCache::Dedicated(cache) => cache.get(key),
+            ObjectCache::Shared(cache) => {
+                cache.get(&key.shared_key()).and_then(K::unwrap)
+            }
+        }
+    }
+
+    fn peek(&self, key: &K) -> Option<K::Value> {
+        match self {
+            ObjectCache::Dedicated(cache) => cache.peek(key),
+            ObjectCache::Shared(cache) => {
+                cache.peek(&key.shared_key()).and_then(K::unwrap)
+            }
+        }
+    }
+
+    fn insert(&self, key: K, value: K::Value) {
+        match self {
+            ObjectCache::Dedicated(cache) => cache.insert(key, value),
+            ObjectCache::Shared(cache) => cache.insert(key.shared_key(), K::wrap(value)),
+        }
+    }
+
+    fn remove(&self, key: &K) {
+        match self {
+            ObjectCache::Dedicated(cache) => {
+                cache.remove(key);
+            }
+            ObjectCache::Shared(cache) => {
+                cache.remove(&key.shared_key());
+            }
+        }
+    }
+
+    async fn get_value_or_guard_async(
+        &self,
+        key: &K,
+    ) -> Result<K::Value, CacheGuard<'_, K>> {
+        match self {
+            ObjectCache::Dedicated(cache) => {
+                cache.get_value_or_guard_async(key).await.map_err(CacheGuard::Dedicated)
+            }
+            ObjectCache::Shared(cache) => {
+                match cache.get_value_or_guard_async(&key.shared_key()).await {
+                    // every key is stored with values of its own type
+                    #[allow(clippy::expect_used)]
+                    Ok(value) => Ok(K::unwrap(value).expect("shared cache value type")),
+                    Err(guard) => Err(CacheGuard::Shared(guard)),
+                }
+            }
+        }
+    }
+
+    /// For a view of the shared cache, these are the numbers of the whole shared cache
+    #[cfg(test)]
+    fn capacity(&self) -> u64 {
+        match self {
+            ObjectCache::Dedicated(cache) => cache.capacity(),
+            ObjectCache::Shared(cache) => cache.capacity(),
+        }
+    }
+
+    #[cfg(test)]
+    fn weight(&self) -> u64 {
+        match self {
+            ObjectCache::Dedicated(cache) => cache.weight(),
+            ObjectCache::Shared(cache) => cache.weight(),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        match self {
+            ObjectCache::Dedicated(cache) => cache.len(),
+            ObjectCache::Shared(cache) => cache.len(),
+        }
+    }
+}
+
+/// Hits and misses of one of the caches of [`MemCachingStorage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Hits and misses of the [`MemCachingStorage`] caches, see [`MemCachingStorage::stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub snapshots: CacheCounts,
+    pub manifests: CacheCounts,
+    /// See [`Storage::fetch_node_chunks`]
+    pub node_manifests: CacheCounts,
+    pub attributes: CacheCounts,
+    pub chunks: CacheCounts,
+}
+
+#[derive(Debug)]
+struct Counters {
+    /// The name of the cache, in the lookup events
+    cache: &'static str,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counters {
+    fn new(cache: &'static str) -> Self {
+        Self { cache, hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    fn record<T, G>(&self, lookup: &Result<T, G>) {
+        self.record_hit(lookup.is_ok())
+    }
+
+    fn record_hit(&self, hit: bool) {
+        tracing::debug!(cache = self.cache, hit, "storage cache lookup");
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> CacheCounts {
+        CacheCounts {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct StatsCounters {
+    snapshots: Counters,
+    manifests: Counters,
+    node_manifests: Counters,
+    attributes: Counters,
+    chunks: Counters,
+}
+
+impl Default for StatsCounters {
+    fn default() -> Self {
+        Self {
+            snapshots: Counters::new("snapshots"),
+            manifests: Counters::new("manifests"),
+            node_manifests: Counters::new("node_manifests"),
+            attributes: Counters::new("attributes"),
+            chunks: Counters::new("chunks"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MemCachingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    snapshot_cache: ObjectCache<SnapshotId>,
+    manifest_cache: ObjectCache<ManifestId>,
+    /// The entries of single nodes, see [`Storage::fetch_node_chunks`]
+    node_manifest_cache: ObjectCache<(ManifestId, NodeId)>,
+    attributes_cache: Arc<ObjectCache<AttributesId>>,
+    chunk_cache: ObjectCache<(ChunkId, ByteRange)>,
+    index: Arc<CacheIndex>,
+    eager_attributes: bool,
+    /// Written chunks are cached in full, see [`MemCachingStorage::with_cache_chunks_on_write`]
+    cache_chunks_on_write: bool,
+    /// Refs are mutable, so they are only cached for a short time, zero disables caching
+    ref_ttl: Duration,
+    /// Cached refs older than this are revalidated with the backend, using their etag
+    ref_grace_period: Option<Duration>,
+    ref_cache: Cache<String, (Instant, (Bytes, Option<String>))>,
+    ref_versions_cache: Cache<String, (Instant, Arc<Vec<String>>)>,
+    /// Objects and refs recently found missing are remembered for this long, zero disables it
+    not_found_ttl: Duration,
+    not_found_cache: Cache<MissingKey, Instant>,
+    /// Incremented by every write, a fetch that raced with a write doesn't remember a miss
+    write_epoch: AtomicU64,
+    stats: StatsCounters,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MissingKey {
+    Object(AnyObjectId),
+    Ref(String),
+}
+
+impl MissingKey {
+    fn not_found(self) -> StorageError {
+        match self {
+            MissingKey::Object(id) => StorageError::ObjectNotFound(id),
+            MissingKey::Ref(key) => StorageError::RefNotFound(key),
+        }
+    }
+}
+
+impl MemCachingStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        num_snapshots: u16,
+        num_manifests: u16,
+        num_attributes: u16,
+        num_chunks: u16,
+    ) -> Self {
+        let index = Arc::new(CacheIndex::default());
+        let hook = EvictionHook(Arc::clone(&index));
+        MemCachingStorage {
+            backend,
+            snapshot_cache: count_cache(num_snapshots as usize, hook.clone()),
+            manifest_cache: count_cache(num_manifests as usize, hook.clone()),
+            node_manifest_cache: count_cache(num_manifests as usize, hook.clone()),
+            attributes_cache: Arc::new(count_cache(
+                num_attributes as usize,
+                hook.clone(),
+            )),
+            chunk_cache: count_cache(num_chunks as usize, hook),
+            index,
+            eager_attributes: false,
+            cache_chunks_on_write: false,
+            ref_ttl: Duration::ZERO,
+            ref_grace_period: None,
+            ref_cache: Cache::new(0),
+            ref_versions_cache: Cache::new(0),
+            not_found_ttl: Duration::ZERO,
+            not_found_cache: Cache::new(0),
+            write_epoch: AtomicU64::new(0),
+            stats: StatsCounters::default(),
+        }
+    }
+
+    /// Limit the caches by the estimated memory used, instead of by number of objects
+    ///
+    /// The budget is split between the caches with the default [`MemoryBudgetFractions`].
+    pub fn with_memory_budget(
+        backend: Arc<dyn Storage + Send + Sync>,
+        bytes: usize,
+    ) -> Self {
+        Self::with_memory_budget_fractions(
+            backend,
+            bytes,
+            MemoryBudgetFractions::default(),
+        )
+    }
+
+    /// Like [`MemCachingStorage::with_memory_budget`], splitting the budget with `fractions`
+    ///
+    /// Sizes are estimated from the number of nodes in snapshots, the number of entries in
+    /// manifests, and the length of chunks. An object bigger than its cache's share of the
+    /// budget is not cached.
+    pub fn with_memory_budget_fractions(
+        backend: Arc<dyn Storage + Send + Sync>,
+        bytes: usize,
+        fractions: MemoryBudgetFractions,
+    ) -> Self {
+        let total = fractions.snapshots
+            + fractions.manifests
+            + fractions.node_manifests
+            + fractions.attributes
+            + fractions.chunks;
+        let share = |fraction: f64| {
+            if total > 0.0 {
+                (bytes as f64 * fraction.max(0.0) / total) as u64
+            } else {
+                0
+            }
+        };
+        let base = Self::new(backend, 0, 0, 0, 0);
+        let hook = base.eviction_hook();
+        MemCachingStorage {
+            snapshot_cache: bytes_cache(
+                share(fractions.snapshots),
+                64 * 1024,
+                hook.clone(),
+            ),
+            manifest_cache: bytes_cache(
+                share(fractions.manifests),
+                1024 * 1024,
+                hook.clone(),
+            ),
+            node_manifest_cache: bytes_cache(
+                share(fractions.node_manifests),
+                16 * 1024,
+                hook.clone(),
+            ),
+            attributes_cache: Arc::new(bytes_cache(
+                share(fractions.attributes),
+                ATTRIBUTES_SIZE,
+                hook.clone(),
+            )),
+            chunk_cache: bytes_cache(share(fractions.chunks), 1024 * 1024, hook),
+            ..base
+        }
+    }
+
+    /// Like [`MemCachingStorage::with_memory_budget`], with a single budget shared by all caches
+    ///
+    /// Objects of every type compete for the same memory, so the budget goes to what is being
+    /// used: under a chunk heavy workload chunks can take the memory that snapshots and
+    /// attributes don't need. Hits and misses are still counted per object type. An object
+    /// bigger than the whole budget is not cached.
+    pub fn with_shared_memory_budget(
+        backend: Arc<dyn Storage + Send + Sync>,
+        bytes: usize,
+    ) -> Self {
+        let base = Self::new(backend, 0, 0, 0, 0);
+        let shared: Arc<SharedCache> =
+            Arc::new(weighted_bytes_cache(bytes as u64, 64 * 1024, base.eviction_hook()));
+        MemCachingStorage {
+            snapshot_cache: ObjectCache::Shared(Arc::clone(&shared)),
+            manifest_cache: ObjectCache::Shared(Arc::clone(&shared)),
+            node_manifest_cache: ObjectCache::Shared(Arc::clone(&shared)),
+            attributes_cache: Arc::new(ObjectCache::Shared(Arc::clone(&shared))),
+            chunk_cache: ObjectCache::Shared(shared),
+            ..base
+        }
+    }
+
+    fn eviction_hook(&self) -> EvictionHook {
+        EvictionHook(Arc::clone(&self.index))
+    }
+
+    /// Hits and misses of the caches since creation, or since the last
+    /// [`MemCachingStorage::reset_stats`]
+    ///
+    /// Only fetches count, attributes prefetched in the background don't.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            snapshots: self.stats.snapshots.counts(),
+            manifests: self.stats.manifests.counts(),
+            node_manifests: self.stats.node_manifests.counts(),
+            attributes: self.stats.attributes.counts(),
+            chunks: self.stats.chunks.counts(),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.snapshots.reset();
+        self.stats.manifests.reset();
+        self.stats.node_manifests.reset();
+        self.stats.attributes.reset();
+        self.stats.chunks.reset();
+    }
+
+    /// Cache the entries of up to `num_nodes` nodes fetched with [`Storage::fetch_node_chunks`]
+    ///
+    /// By default as many nodes as full manifests are cached. With a shared memory budget, this
+    /// gives the node entries their own cache, out of the budget.
+    pub fn with_node_manifest_cache(mut self, num_nodes: u16) -> Self {
+        self.node_manifest_cache = count_cache(num_nodes as usize, self.eviction_hook());
+        self
+    }
+
+    /// Cache ref contents and ref version listings for up to `ttl`
+    ///
+    /// This makes resolving hot branches much cheaper, but a ref updated by somebody else can be
+    /// seen up to `ttl` late. Writes done through this storage invalidate the cached entries
+    /// immediately. Up to `num_refs` refs are cached.
+    pub fn with_ref_cache(mut self, num_refs: u16, ttl: Duration) -> Self {
+        self.ref_ttl = ttl;
+        self.ref_cache = Cache::new(num_refs as usize);
+        self.ref_versions_cache = Cache::new(num_refs as usize);
+        self
+    }
+
+    /// Remember for up to `ttl` the objects and refs the backend didn't find
+    ///
+    /// Probing again for a missing object or ref then fails without calling the backend. Writes
+    /// done through this storage forget the miss immediately, but an object or ref created by
+    /// somebody else can be reported missing for up to `ttl`. Up to `num_entries` misses are
+    /// remembered. Chunks, snapshots, manifests, attributes and refs are covered.
+    pub fn with_not_found_cache(mut self, num_entries: u16, ttl: Duration) -> Self {
+        self.not_found_ttl = ttl;
+        self.not_found_cache = Cache::new(num_entries as usize);
+        self
+    }
+
+    /// Revalidate cached refs older than `grace_period` before returning them
+    ///
+    /// Revalidation asks the backend for the ref only if it changed since it was cached, see
+    /// [`Storage::get_ref_if_changed`], which is cheaper than fetching it again. This bounds how
+    /// stale a cached ref can be, even with a long ttl. Backends without etags fall back to the
+    /// ttl. Requires a ref cache, see [`MemCachingStorage::with_ref_cache`].
+    pub fn with_ref_revalidation(mut self, grace_period: Duration) -> Self {
+        self.ref_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Fetch a ref from the backend, revalidating the cached version if there is one
+    async fn refresh_ref(
+        &self,
+        ref_key: &str,
+        cached: Option<(Bytes, Option<String>)>,
+    ) -> StorageResult<Bytes> {
+        let fresh = self.fetch_fresh_ref(ref_key, cached).await?;
+        self.ref_cache.insert(ref_key.to_string(), (Instant::now(), fresh.clone()));
+        Ok(fresh.0)
+    }
+
+    async fn fetch_fresh_ref(
+        &self,
+        ref_key: &str,
+        cached: Option<(Bytes, Option<String>)>,
+    ) -> StorageResult<(Bytes, Option<String>)> {
+        let (cached_bytes, etag) = cached.unzip();
+        let etag = etag.flatten();
+        let fresh = match (
+            self.backend.get_ref_if_changed(ref_key, etag.as_deref()).await?,
+            cached_bytes,
+        ) {
+            (RefFetch::Unchanged, Some(bytes)) => (bytes, etag),
+            (RefFetch::Changed { bytes, etag }, _) => (bytes, etag),
+            // we didn't send an etag, so the backend shouldn't answer unchanged
+            (RefFetch::Unchanged, None) => (self.backend.get_ref(ref_key).await?, None),
+        };
+        Ok(fresh)
+    }
+
+    fn fresh_ref<T: Clone>(
+        &self,
+        cache: &Cache<String, (Instant, T)>,
+        key: &str,
+    ) -> Option<T> {
+        cache
+            .get(key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ref_ttl)
+            .map(|(_, value)| value)
+    }
+
+    async fn get_cached_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        if self.ref_ttl.is_zero() && self.ref_grace_period.is_none() {
+            return self.backend.get_ref(ref_key).await;
+        }
+        // concurrent misses wait for a single fetch
+        let (cached_at, cached) =
+            match self.ref_cache.get_value_or_guard_async(ref_key).await {
+                Ok(cached) => cached,
+                Err(guard) => {
+                    let fresh = self.fetch_fresh_ref(ref_key, None).await?;
+                    let _fail_is_ok = guard.insert((Instant::now(), fresh.clone()));
+                    return Ok(fresh.0);
+                }
+            };
+        let age = cached_at.elapsed();
+        match self.ref_grace_period {
+            Some(grace_period) if age < grace_period.min(self.ref_ttl) => Ok(cached.0),
+            Some(_) if cached.1.is_some() => {
+                self.refresh_ref(ref_key, Some(cached)).await
+            }
+            _ if age < self.ref_ttl => Ok(cached.0),
+            _ => self.refresh_ref(ref_key, None).await,
+        }
+    }
+
+    /// Run `fetch`, unless `key` was recently found missing
+    async fn fetch_unless_missing<T>(
+        &self,
+        key: MissingKey,
+        fetch: impl Future<Output = StorageResult<T>>,
+    ) -> StorageResult<T> {
+        if self.not_found_ttl.is_zero() {
+            return fetch.await;
+        }
+        match self.not_found_cache.get(&key) {
+            Some(missing_at) if missing_at.elapsed() < self.not_found_ttl => {
+                return Err(key.not_found());
+            }
+            Some(_) => {
+                self.not_found_cache.remove(&key);
+            }
+            None => {}
+        }
+        let epoch = self.write_epoch.load(Ordering::SeqCst);
+        let res = fetch.await;
+        if res.as_ref().err().is_some_and(StorageError::is_not_found)
+            && self.write_epoch.load(Ordering::SeqCst) == epoch
+        {
+            self.not_found_cache.insert(key, Instant::now());
+        }
+        res
+    }
+
+    /// Called after writing `key`, fetches still in flight may have missed the write, so they
+    /// don't remember the miss
+    fn forget_missing(&self, key: MissingKey) {
+        if !self.not_found_ttl.is_zero() {
+            self.write_epoch.fetch_add(1, Ordering::SeqCst);
+            self.not_found_cache.remove(&key);
+        }
+    }
+
+    fn invalidate_ref(&self, ref_key: &str) {
+        self.forget_missing(MissingKey::Ref(ref_key.to_string()));
+        self.ref_cache.remove(ref_key);
+        // a new version makes the cached version listing stale
+        if let Some((ref_name, _)) = ref_key.rsplit_once('/') {
+            self.ref_versions_cache.remove(ref_name);
+        }
+    }
+
+    /// Prefetch the attribute files of every snapshot fetched from the backend
+    ///
+    /// The attribute files are fetched in the background, so the first attributes lookup for
+    /// the snapshot finds them cached. By default attributes are only fetched when requested.
+    pub fn with_eager_attributes(mut self, eager_attributes: bool) -> Self {
+        self.eager_attributes = eager_attributes;
+        self
+    }
+
+    /// Cache the full contents of written chunks, so reading them back doesn't go to the backend
+    ///
+    /// Useful when chunks are read right after they are written, for example to validate them.
+    /// By default written chunks are not cached, most workloads write many more chunks than the
+    /// cache can hold.
+    pub fn with_cache_chunks_on_write(mut self, cache_chunks_on_write: bool) -> Self {
+        self.cache_chunks_on_write = cache_chunks_on_write;
+        self
+    }
+
+    /// Fetch the manifests that are not cached yet, so later fetches find them in the cache
+    ///
+    /// At most [`WARM_CONCURRENCY`] manifests are fetched at the same time. Fails if any of
+    /// the manifests cannot be fetched, the ones fetched until then stay cached.
+    pub async fn warm_manifests(&self, ids: &[ManifestId]) -> StorageResult<WarmSummary> {
+        let missing: HashSet<_> =
+            ids.iter().filter(|id| self.manifest_cache.peek(*id).is_none()).collect();
+        let fetched = missing.len();
+        stream::iter(missing)
+            .map(|id| self.fetch_manifests(id))
+            .buffer_unordered(WARM_CONCURRENCY)
+            .try_for_each(|_| async { Ok(()) })
+            .await?;
+        Ok(WarmSummary { requested: ids.len(), fetched })
+    }
+
+    /// Like [`MemCachingStorage::warm_manifests`], for snapshots
+    pub async fn warm_snapshots(&self, ids: &[SnapshotId]) -> StorageResult<WarmSummary> {
+        let missing: HashSet<_> =
+            ids.iter().filter(|id| self.snapshot_cache.peek(*id).is_none()).collect();
+        let fetched = missing.len();
+        stream::iter(missing)
+            .map(|id| self.fetch_snapshot(id))
+            .buffer_unordered(WARM_CONCURRENCY)
+            .try_for_each(|_| async { Ok(()) })
+            .await?;
+        Ok(WarmSummary { requested: ids.len(), fetched })
+    }
+
+    fn prefetch_attributes(&self, snapshot: &Snapshot) {
+        for file in snapshot.attribute_files.iter() {
+            let id = file.id.clone();
+            let backend = Arc::clone(&self.backend);
+            let cache = Arc::clone(&self.attributes_cache);
+            tokio::spawn(async move {
+                if let Err(guard) = cache.get_value_or_guard_async(&id).await {
+                    // errors are ignored, the attributes will be fetched again on demand
+                    if let Ok(table) = backend.fetch_attributes(&id).await {
+                        let _fail_is_ok = guard.insert(table);
+                    }
+                }
+            });
+        }
+    }
+
+    fn cache_chunk(&self, id: &ChunkId, range: &ByteRange, bytes: Bytes) {
+        self.index_chunk(id, range, bytes.len());
+        self.chunk_cache.insert((id.clone(), range.clone()), bytes);
+    }
+
+    /// Index a chunk range about to be cached
+    ///
+    /// This goes before the insert, so a range evicted right away is also removed from the
+    /// index.
+    fn index_chunk(&self, id: &ChunkId, range: &ByteRange, len: usize) {
+        #[allow(clippy::expect_used)]
+        let mut keys = self.index.chunk_keys.lock().expect("poison lock");
+        keys.entry(id.clone()).or_default().insert(range.clone());
+        drop(keys);
+        self.index_chunk_range(id, range, len);
+    }
+
+    /// Remember the absolute position of a cached chunk range
+    fn index_chunk_range(&self, id: &ChunkId, range: &ByteRange, len: usize) {
+        let start = match range {
+            ByteRange::Bounded(range) => range.start,
+            ByteRange::From(offset) => {
+                #[allow(clippy::expect_used)]
+                self.index
+                    .chunk_sizes
+                    .lock()
+                    .expect("poison lock")
+                    .insert(id.clone(), offset + len as ChunkLength);
+                *offset
+            }
+            // without the size of the object, we don't know where this starts
+            ByteRange::Last(_) => match self.chunk_size(id) {
+                Some(size) => size.saturating_sub(len as ChunkLength),
+                None => return,
+            },
+        };
+        #[allow(clippy::expect_used)]
+        let mut index = self.index.chunk_ranges.lock().expect("poison lock");
+        let ranges = index.entry(id.clone()).or_default();
+        // for ranges with the same start, we only need to remember the longest one
+        let longer_cached = ranges
+            .get(&start)
+            .and_then(|existing| self.chunk_cache.peek(&(id.clone(), existing.clone())))
+            .is_some_and(|existing| existing.len() >= len);
+        if !longer_cached {
+            ranges.insert(start, range.clone());
+        }
+    }
+
+    fn chunk_size(&self, id: &ChunkId) -> Option<ChunkLength> {
+        #[allow(clippy::expect_used)]
+        self.index.chunk_sizes.lock().expect("poison lock").get(id).copied()
+    }
+
+    /// The absolute `start..end` of `range`, `None` for open-ended ranges of chunks with
+    /// unknown size
+    fn resolve_chunk_range(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> Option<(ChunkOffset, ChunkOffset)> {
+        match range {
+            ByteRange::Bounded(range) => Some((range.start, range.end)),
+            ByteRange::From(offset) => self
+                .chunk_size(id)
+                .filter(|size| offset <= size)
+                .map(|size| (*offset, size)),
+            ByteRange::Last(n) => {
+                self.chunk_size(id).map(|size| (size.saturating_sub(*n), size))
+            }
+        }
+    }
+
+    /// Find the cached ranges that overlap `start..end`
+    ///
+    /// Returns the absolute start offset and bytes of every range still in the cache, sorted by
+    /// start offset.
+    fn cached_overlaps(
+        &self,
+        id: &ChunkId,
+        start: ChunkOffset,
+        end: ChunkOffset,
+    ) -> Vec<(ChunkOffset, Bytes)> {
+        #[allow(clippy::expect_used)]
+        let mut index = self.index.chunk_ranges.lock().expect("poison lock");
+        let Some(ranges) = index.get_mut(id) else {
+            return vec![];
+        };
+        let mut res = Vec::new();
+        let mut evicted = Vec::new();
+        for (range_start, key) in ranges.range(..end) {
+            match self.chunk_cache.get(&(id.clone(), key.clone())) {
+                Some(bytes) if range_start + bytes.len() as ChunkOffset > start => {
+                    res.push((*range_start, bytes))
+                }
+                Some(_) => {}
+                None => evicted.push(*range_start),
+            }
+        }
+        for range_start in evicted {
+            ranges.remove(&range_start);
+        }
+        if ranges.is_empty() {
+            index.remove(id);
+        }
+        res
+    }
+
+    /// Build the bytes in `start..end` from the cached ranges, fetching only the missing pieces
+    async fn fetch_chunk_range(
+        &self,
+        id: &ChunkId,
+        start: ChunkOffset,
+        end: ChunkOffset,
+    ) -> StorageResult<Bytes> {
+        enum Piece {
+            Cached(Bytes),
+            Missing(ByteRange),
+        }
+
+        let mut pieces = Vec::new();
+        let mut pos = start;
+        for (range_start, bytes) in self.cached_overlaps(id, start, end) {
+            let range_end = (range_start + bytes.len() as ChunkOffset).min(end);
+            if range_end <= pos {
+                continue;
+            }
+            if range_start > pos {
+                pieces.push(Piece::Missing(ByteRange::bounded(pos, range_start)));
+                pos = range_start;
+            }
+            let from = (pos - range_start) as usize;
+            let to = (range_end - range_start) as usize;
+            pieces.push(Piece::Cached(bytes.slice(from..to)));
+            pos = range_end;
+        }
+        if pos < end {
+            pieces.push(Piece::Missing(ByteRange::bounded(pos, end)));
+        }
+
+        if let [Piece::Cached(bytes)] = pieces.as_slice() {
+            return Ok(bytes.clone());
+        }
+        let pieces = try_join_all(pieces.into_iter().map(|piece| async move {
+            match piece {
+                Piece::Cached(bytes) => Ok(bytes),
+                Piece::Missing(range) => self.backend.fetch_chunk(id, &range).await,
+            }
+        }))
+        .await?;
+        if let [bytes] = pieces.as_slice() {
+            return Ok(bytes.clone());
+        }
+        let mut res = BytesMut::with_capacity((end - start) as usize);
+        for piece in pieces {
+            res.extend_from_slice(&piece);
+        }
+        Ok(res.freeze())
+    }
+}
+
+impl private::Sealed for MemCachingStorage {}
+
+#[async_trait]
+impl Storage for MemCachingStorage {
+    async fn fetch_snapshot(
+        &self,
+        id: &SnapshotId,
+    ) -> Result<Arc<Snapshot>, StorageError> {
+        let lookup = self.snapshot_cache.get_value_or_guard_async(id).await;
+        self.stats.snapshots.record(&lookup);
+        match lookup {
+            Ok(snapshot) => Ok(snapshot),
+            Err(guard) => {
+                let key = MissingKey::Object(AnyObjectId::Snapshot(id.clone()));
+                let snapshot = self
+                    .fetch_unless_missing(key, self.backend.fetch_snapshot(id))
+                    .await?;
+                let _fail_is_ok = guard.insert(Arc::clone(&snapshot));
+                if self.eager_attributes {
+                    self.prefetch_attributes(&snapshot);
+                }
+                Ok(snapshot)
+            }
+        }
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> Result<Arc<AttributesTable>, StorageError> {
+        let lookup = self.attributes_cache.get_value_or_guard_async(id).await;
+        self.stats.attributes.record(&lookup);
+        match lookup {
+            Ok(table) => Ok(table),
+            Err(guard) => {
+                let key = MissingKey::Object(AnyObjectId::Attributes(id.clone()));
+                let table = self
+                    .fetch_unless_missing(key, self.backend.fetch_attributes(id))
+                    .await?;
+                let _fail_is_ok = guard.insert(Arc::clone(&table));
+                Ok(table)
+            }
+        }
+    }
+
+    async fn fetch_manifests(
+        &self,
+        id: &ManifestId,
+    ) -> Result<Arc<Manifest>, StorageError> {
+        let lookup = self.manifest_cache.get_value_or_guard_async(id).await;
+        self.stats.manifests.record(&lookup);
+        match lookup {
+            Ok(manifest) => Ok(manifest),
+            Err(guard) => {
+                let key = MissingKey::Object(AnyObjectId::Manifest(id.clone()));
+                let manifest = self
+                    .fetch_unless_missing(key, self.backend.fetch_manifests(id))
+                    .await?;
+                let _fail_is_ok = guard.insert(Arc::clone(&manifest));
+                Ok(manifest)
+            }
+        }
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        let key = (manifest_id.clone(), node);
+        let lookup = self.node_manifest_cache.get_value_or_guard_async(&key).await;
+        self.stats.node_manifests.record(&lookup);
+        match lookup {
+            Ok(manifest) => Ok(manifest),
+            Err(guard) => {
+                // only the node's entries are cached, not the full manifest
+                let manifest = match self.manifest_cache.get(manifest_id) {
+                    Some(manifest) => Arc::new(manifest.node_manifest(node)),
+                    None => self.backend.fetch_node_chunks(manifest_id, node).await?,
+                };
+                #[allow(clippy::expect_used)]
+                let mut keys = self.index.node_manifest_keys.lock().expect("poison lock");
+                keys.entry(manifest_id.clone()).or_default().insert(node);
+                drop(keys);
+                let _fail_is_ok = guard.insert(Arc::clone(&manifest));
+                Ok(manifest)
+            }
+        }
+    }
+
+    /// A cached manifest answers directly, otherwise the entries are streamed from the backend
+    /// without caching them, they could be too many to keep in memory
+    fn fetch_manifest_chunks<'a>(
+        &'a self,
+        manifest_id: &'a ManifestId,
+        node: NodeId,
+    ) -> BoxStream<'a, StorageResult<ChunkInfo>> {
+        let cached = self
+            .manifest_cache
+            .get(manifest_id)
+            .or_else(|| self.node_manifest_cache.get(&(manifest_id.clone(), node)));
+        match cached {
+            Some(manifest) => {
+                let chunks: Vec<_> = manifest.node_chunk_infos(node).map(Ok).collect();
+                stream::iter(chunks).boxed()
+            }
+            None => self.backend.fetch_manifest_chunks(manifest_id, node),
+        }
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        // a cached manifest answers directly, otherwise the backend may be able to find the chunk
+        // without fetching the full manifest
+        let cached = self
+            .manifest_cache
+            .get(manifest_id)
+            .or_else(|| self.node_manifest_cache.get(&(manifest_id.clone(), node)));
+        match cached {
+            Some(manifest) => Ok(manifest.get_chunk_info(node, coord)),
+            None => self.backend.fetch_chunk_info(manifest_id, node, coord).await,
+        }
+    }
+
+    async fn fetch_chunk(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> Result<Bytes, StorageError> {
+        let key = (id.clone(), range.clone());
+        let lookup = self.chunk_cache.get_value_or_guard_async(&key).await;
+        self.stats.chunks.record(&lookup);
+        match lookup {
+            Ok(bytes) => Ok(bytes),
+            Err(guard) => {
+                // bounded ranges, and open-ended ones once the chunk size is known, can be
+                // served, fully or partially, from other cached ranges
+                let fetch = async {
+                    match self.resolve_chunk_range(id, range) {
+                        Some((start, end)) => {
+                            self.fetch_chunk_range(id, start, end).await
+                        }
+                        None => self.backend.fetch_chunk(id, range).await,
+                    }
+                };
+                let key = MissingKey::Object(AnyObjectId::Chunk(id.clone()));
+                let bytes = self.fetch_unless_missing(key, fetch).await?;
+                self.index_chunk(id, range, bytes.len());
+                let _fail_is_ok = guard.insert(bytes.clone());
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Cached chunks are returned without waiting for the backend, the rest are fetched from the
+    /// backend in a single batch
+    ///
+    /// Unlike [`Storage::fetch_chunk`], missing bounded ranges are not built from other cached
+    /// ranges.
+    fn fetch_chunks<'a>(
+        &'a self,
+        requests: &'a [(ChunkId, ByteRange)],
+    ) -> BoxStream<'a, StorageResult<Bytes>> {
+        let cached: Vec<Option<Bytes>> = requests
+            .iter()
+            .map(|(id, range)| {
+                let hit = self.chunk_cache.get(&(id.clone(), range.clone()));
+                self.stats.chunks.record_hit(hit.is_some());
+                hit
+            })
+            .collect();
+        let misses: Vec<(ChunkId, ByteRange)> = requests
+            .iter()
+            .zip(cached.iter())
+            .filter(|(_, hit)| hit.is_none())
+            .map(|(request, _)| request.clone())
+            .collect();
+
+        async_stream::stream! {
+            let mut fetched = self.backend.fetch_chunks(&misses);
+            for ((id, range), hit) in requests.iter().zip(cached) {
+                if let Some(bytes) = hit {
+                    yield Ok(bytes);
+                    continue;
+                }
+                match fetched.next().await {
+                    Some(Ok(bytes)) => {
+                        self.cache_chunk(id, range, bytes.clone());
+                        yield Ok(bytes);
+                    }
+                    Some(Err(err)) => yield Err(err),
+                    None => {
+                        yield Err(StorageError::Other(
+                            "backend returned fewer chunks than requested".to_string(),
+                        ))
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        let cached = match id {
+            AnyObjectId::Snapshot(id) => self.snapshot_cache.peek(id).is_some(),
+            AnyObjectId::Manifest(id) => self.manifest_cache.peek(id).is_some(),
+            AnyObjectId::Attributes(id) => self.attributes_cache.peek(id).is_some(),
+            // chunks are cached by range, we don't know which ranges are present
+            AnyObjectId::Chunk(_) => false,
+        };
+        if cached {
+            Ok(true)
+        } else {
+            self.backend.exists(id).await
+        }
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> Result<(), StorageError> {
+        let res = self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)).await;
+        self.forget_missing(MissingKey::Object(AnyObjectId::Snapshot(id.clone())));
+        res?;
+        self.snapshot_cache.insert(id, snapshot);
+        Ok(())
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> Result<(), StorageError> {
+        let res = self.backend.write_attributes(id.clone(), Arc::clone(&table)).await;
+        self.forget_missing(MissingKey::Object(AnyObjectId::Attributes(id.clone())));
+        res?;
+        self.attributes_cache.insert(id, table);
+        Ok(())
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> Result<(), StorageError> {
+        let res = self.backend.write_manifests(id.clone(), Arc::clone(&manifest)).await;
+        self.forget_missing(MissingKey::Object(AnyObjectId::Manifest(id.clone())));
+        res?;
+        self.manifest_cache.insert(id, manifest);
+        Ok(())
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
+        let res = self.backend.write_chunk(id.clone(), bytes.clone()).await;
+        self.forget_missing(MissingKey::Object(AnyObjectId::Chunk(id.clone())));
+        res?;
+        // by default we don't pre-populate the chunk cache, there are too many of them for this
+        // to be useful
+        if self.cache_chunks_on_write {
+            self.cache_chunk(&id, &ByteRange::ALL, bytes);
+        }
+        Ok(())
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        let res = self.backend.write_chunk_if_absent(id.clone(), bytes.clone()).await;
+        self.forget_missing(MissingKey::Object(AnyObjectId::Chunk(id.clone())));
+        let written = res?;
+        if written && self.cache_chunks_on_write {
+            self.cache_chunk(&id, &ByteRange::ALL, bytes);
+        }
+        Ok(written)
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        let id = self.backend.write_new_chunk(bytes.clone()).await?;
+        self.forget_missing(MissingKey::Object(AnyObjectId::Chunk(id.clone())));
+        if self.cache_chunks_on_write {
+            self.cache_chunk(&id, &ByteRange::ALL, bytes);
+        }
+        Ok(id)
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        let key = MissingKey::Ref(ref_key.to_string());
+        self.fetch_unless_missing(key, self.get_cached_ref(ref_key)).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.backend.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.backend.list_modified(kind, from, to).await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.backend.list_objects(kind).await
+    }
+
+    // the backend goes first, so a fetch that misses while we invalidate the cache can't
+    // populate it with the deleted object
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.backend.delete_chunk(id).await?;
+        #[allow(clippy::expect_used)]
+        let ranges = self.index.chunk_keys.lock().expect("poison lock").remove(id);
+        for range in ranges.into_iter().flatten() {
+            self.chunk_cache.remove(&(id.clone(), range));
+        }
+        #[allow(clippy::expect_used)]
+        self.index.chunk_ranges.lock().expect("poison lock").remove(id);
+        #[allow(clippy::expect_used)]
+        self.index.chunk_sizes.lock().expect("poison lock").remove(id);
+        Ok(())
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.backend.delete_manifest(id).await?;
+        self.manifest_cache.remove(id);
+        #[allow(clippy::expect_used)]
+        let nodes = self.index.node_manifest_keys.lock().expect("poison lock").remove(id);
+        for node in nodes.into_iter().flatten() {
+            self.node_manifest_cache.remove(&(id.clone(), node));
+        }
+        Ok(())
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.backend.delete_snapshot(id).await?;
+        self.snapshot_cache.remove(id);
+        Ok(())
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.backend.delete_attributes(id).await?;
+        self.attributes_cache.remove(id);
+        Ok(())
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.delete_ref_version(ref_name, version_id).await?;
+        self.invalidate_ref(format!("{ref_name}/{version_id}").as_str());
+        Ok(())
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        // we invalidate even if the write fails, it probably failed because the ref changed
+        let res = self.backend.write_ref(ref_key, overwrite_refs, bytes).await;
+        self.invalidate_ref(ref_key);
+        res
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        let res = self.backend.compare_and_swap_ref(ref_key, expected, new).await;
+        self.invalidate_ref(ref_key);
+        res
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.backend.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.backend.ping().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        if self.ref_ttl.is_zero() {
+            return self.backend.ref_versions(ref_name).await;
+        }
+        let versions = match self.fresh_ref(&self.ref_versions_cache, ref_name) {
+            Some(versions) => versions,
+            None => {
+                let versions: Arc<Vec<String>> = Arc::new(
+                    self.backend.ref_versions(ref_name).await?.try_collect().await?,
+                );
+                self.ref_versions_cache.insert(
+                    ref_name.to_string(),
+                    (Instant::now(), Arc::clone(&versions)),
+                );
+                versions
+            }
+        };
+        Ok(stream::iter(versions.as_ref().clone().into_iter().map(Ok)).boxed())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod test {
+    use std::sync::Arc;
+
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkInfo, snapshot::AttributeFileInfo},
+        refs::{fetch_branch_tip, update_branch, RefError},
+        repository::{ChunkIndices, ChunkPayload},
+        storage::{
+            faulty::{Fault, FaultyStorage},
+            logging::LoggingStorage,
+            serializing::SerializingStorage,
+            ObjectStorage, Storage,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_caches() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+
+        let ci1 = ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![]),
+            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
+            uncompressed_size: None,
+        };
+        let ci2 = ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![]),
+            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"b")),
+            uncompressed_size: None,
+        };
+        let pre_existing_id = ManifestId::random();
+        let pre_exiting_manifest = Arc::new(vec![ci1].into_iter().collect());
+        backend
+            .write_manifests(pre_existing_id.clone(), Arc::clone(&pre_exiting_manifest))
+            .await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 0, 2, 0, 0);
+
+        let manifest = Arc::new(vec![ci2].into_iter().collect());
+        let id = ManifestId::random();
+        caching.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+
+        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
+        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
+        // when we insert we cache, so no fetches
+        assert_eq!(logging.fetch_operations(), vec![]);
+
+        // first time it sees an ID it calls the backend
+        assert_eq!(
+            caching.fetch_manifests(&pre_existing_id).await?,
+            pre_exiting_manifest
+        );
+        assert_eq!(
+            logging.fetch_operations(),
+            vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
+        );
+
+        // only calls backend once
+        assert_eq!(
+            caching.fetch_manifests(&pre_existing_id).await?,
+            pre_exiting_manifest
+        );
+        assert_eq!(
+            logging.fetch_operations(),
+            vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
+        );
+
+        // other walues still cached
+        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
+        assert_eq!(
+            logging.fetch_operations(),
+            vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
+        );
+        assert_eq!(
+            caching.stats(),
+            CacheStats {
+                manifests: CacheCounts { hits: 4, misses: 1 },
+                ..CacheStats::default()
+            }
+        );
+        caching.reset_stats();
+        assert_eq!(caching.stats(), CacheStats::default());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_has_limit() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+
+        let ci1 = ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![]),
+            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
+            uncompressed_size: None,
+        };
+        let ci2 = ChunkInfo { node: 2, ..ci1.clone() };
+        let ci3 = ChunkInfo { node: 3, ..ci1.clone() };
+        let ci4 = ChunkInfo { node: 4, ..ci1.clone() };
+        let ci5 = ChunkInfo { node: 5, ..ci1.clone() };
+        let ci6 = ChunkInfo { node: 6, ..ci1.clone() };
+        let ci7 = ChunkInfo { node: 7, ..ci1.clone() };
+        let ci8 = ChunkInfo { node: 8, ..ci1.clone() };
+        let ci9 = ChunkInfo { node: 9, ..ci1.clone() };
+
+        let id1 = ManifestId::random();
+        let table1 = Arc::new(vec![ci1, ci2, ci3].into_iter().collect());
+        backend.write_manifests(id1.clone(), Arc::clone(&table1)).await?;
+        let id2 = ManifestId::random();
+        let table2 = Arc::new(vec![ci4, ci5, ci6].into_iter().collect());
+        backend.write_manifests(id2.clone(), Arc::clone(&table2)).await?;
+        let id3 = ManifestId::random();
+        let table3 = Arc::new(vec![ci7, ci8, ci9].into_iter().collect());
+        backend.write_manifests(id3.clone(), Arc::clone(&table3)).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(
+            Arc::clone(&logging_c),
+            // the cache can only fit 2 manifests.
+            0,
+            2,
+            0,
+            0,
+        );
+
+        // we keep asking for all 3 items, but the cache can only fit 2
+        for _ in 0..20 {
+            assert_eq!(caching.fetch_manifests(&id1).await?, table1);
+            assert_eq!(caching.fetch_manifests(&id2).await?, table2);
+            assert_eq!(caching.fetch_manifests(&id3).await?, table3);
+        }
+        // after the initial warming requests, we only request the file that doesn't fit in the cache
+        assert_eq!(logging.fetch_operations()[10..].iter().unique().count(), 1);
+
+        // every miss went to the backend, everything else was a hit
+        let stats = caching.stats();
+        let misses = logging.fetch_operations().len() as u64;
+        assert_eq!(stats.manifests, CacheCounts { hits: 60 - misses, misses });
+        assert!(stats.manifests.hits >= 20);
+        assert_eq!(stats.snapshots, CacheCounts::default());
+
+        Ok(())
+    }
+
+    fn indexed_chunk_ranges(caching: &MemCachingStorage) -> (usize, usize) {
+        let keys = caching.index.chunk_keys.lock().unwrap();
+        let ranges = caching.index.chunk_ranges.lock().unwrap();
+        (keys.values().map(HashSet::len).sum(), ranges.values().map(BTreeMap::len).sum())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_index_shrinks_on_eviction(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut chunk_ids = Vec::new();
+        for i in 0..50u8 {
+            let id = ChunkId::random();
+            backend.write_chunk(id.clone(), Bytes::from(vec![i; 100])).await?;
+            chunk_ids.push(id);
+        }
+        let manifest: Manifest = (0..50)
+            .map(|node| ChunkInfo {
+                node,
+                coord: ChunkIndices(vec![]),
+                payload: ChunkPayload::Inline(Bytes::from(vec![node as u8])),
+                uncompressed_size: None,
+            })
+            .collect();
+        let manifest_id = ManifestId::random();
+        backend.write_manifests(manifest_id.clone(), Arc::new(manifest)).await?;
+
+        let caching = MemCachingStorage::new(Arc::clone(&backend), 0, 4, 0, 4);
+        for id in chunk_ids.iter() {
+            caching.fetch_chunk(id, &ByteRange::ALL).await?;
+            caching.fetch_chunk(id, &ByteRange::bounded(10, 20)).await?;
+        }
+        for node in 0..50 {
+            caching.fetch_node_chunks(&manifest_id, node).await?;
+        }
+        // only what is still cached is indexed
+        let (keys, ranges) = indexed_chunk_ranges(&caching);
+        assert_eq!(keys, caching.chunk_cache.len());
+        assert!(keys <= 4);
+        assert!(ranges <= keys);
+        // and sizes are only known for chunks with cached ranges
+        let cached_ids: HashSet<ChunkId> =
+            caching.index.chunk_keys.lock().unwrap().keys().cloned().collect();
+        let sized_ids: HashSet<ChunkId> =
+            caching.index.chunk_sizes.lock().unwrap().keys().cloned().collect();
+        assert!(!sized_ids.is_empty());
+        assert!(sized_ids.is_subset(&cached_ids));
+        let nodes = caching.index.node_manifest_keys.lock().unwrap().clone();
+        assert_eq!(
+            nodes.values().map(HashSet::len).sum::<usize>(),
+            caching.node_manifest_cache.len()
+        );
+        assert!(caching.node_manifest_cache.len() <= 4);
+
+        // the shared cache evicts objects of every type through the same index
+        let caching =
+            MemCachingStorage::with_shared_memory_budget(Arc::clone(&backend), 1000);
+        for id in chunk_ids.iter() {
+            caching.fetch_chunk(id, &ByteRange::ALL).await?;
+        }
+        let (keys, ranges) = indexed_chunk_ranges(&caching);
+        assert_eq!(keys, caching.chunk_cache.len());
+        assert!(keys <= 10);
+        assert_eq!(ranges, keys);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_delete_invalidates(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(logging_c, 2, 2, 2, 10);
+
+        let chunk_id = ChunkId::random();
+        caching.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello world")).await?;
+        caching.fetch_chunk(&chunk_id, &ByteRange::ALL).await?;
+        caching.fetch_chunk(&chunk_id, &ByteRange::bounded(0, 5)).await?;
+        caching.fetch_chunk(&chunk_id, &ByteRange::Last(5)).await?;
+        let manifest_id = ManifestId::random();
+        let manifest: Arc<Manifest> = Arc::new(
+            vec![ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![]),
+                payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
+                uncompressed_size: None,
+            }]
+            .into_iter()
+            .collect(),
+        );
+        backend.write_manifests(manifest_id.clone(), Arc::clone(&manifest)).await?;
+        caching.fetch_manifests(&manifest_id).await?;
+        caching.fetch_node_chunks(&manifest_id, 1).await?;
+        let snapshot_id = SnapshotId::random();
+        caching.write_snapshot(snapshot_id.clone(), Arc::new(Snapshot::empty())).await?;
+        let fetches = logging.fetch_operations().len();
+
+        caching.delete_chunk(&chunk_id).await?;
+        caching.delete_manifest(&manifest_id).await?;
+        caching.delete_snapshot(&snapshot_id).await?;
+
+        // every cached entry is gone, fetches go to the backend and find nothing
+        for range in [ByteRange::ALL, ByteRange::bounded(0, 5), ByteRange::Last(5)] {
+            assert!(matches!(
+                caching.fetch_chunk(&chunk_id, &range).await,
+                Err(StorageError::ObjectStore(object_store::Error::NotFound { .. }))
+            ));
+        }
+        assert!(caching.fetch_manifests(&manifest_id).await.is_err());
+        assert!(caching.fetch_node_chunks(&manifest_id, 1).await.is_err());
+        assert!(caching.fetch_snapshot(&snapshot_id).await.is_err());
+        assert!(!caching.exists(&AnyObjectId::Snapshot(snapshot_id)).await?);
+        assert_eq!(logging.fetch_operations().len(), fetches + 6);
+
+        // deleting a missing object is fine
+        caching.delete_chunk(&chunk_id).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_memory_budget() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let manifest = |num_chunks: u32| -> Arc<Manifest> {
+            Arc::new(
+                (0..num_chunks)
+                    .map(|i| ChunkInfo {
+                        node: 1,
+                        coord: ChunkIndices(vec![i]),
+                        payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
+                        uncompressed_size: None,
+                    })
+                    .collect(),
+            )
+        };
+        let mut ids = Vec::new();
+        for num_chunks in [1, 200, 2, 300, 1, 250, 3, 400, 1, 200] {
+            let id = ManifestId::random();
+            backend.write_manifests(id.clone(), manifest(num_chunks)).await?;
+            ids.push(id);
+        }
+
+        let budget = 100_000;
+        let caching = MemCachingStorage::with_memory_budget_fractions(
+            Arc::clone(&backend),
+            budget,
+            MemoryBudgetFractions {
+                snapshots: 0.0,
+                manifests: 1.0,
+                node_manifests: 0.0,
+                attributes: 0.0,
+                chunks: 0.0,
+            },
+        );
+        assert_eq!(caching.manifest_cache.capacity(), budget as u64);
+        assert_eq!(caching.chunk_cache.capacity(), 0);
+        for _ in 0..3 {
+            for id in ids.iter() {
+                caching.fetch_manifests(id).await?;
+                assert!(caching.manifest_cache.weight() <= budget as u64);
+            }
+        }
+        // the large manifests don't fit together
+        assert!(caching.manifest_cache.len() < ids.len());
+
+        // the default split never goes over the total budget
+        let caching = MemCachingStorage::with_memory_budget(backend, budget);
+        let capacity = caching.snapshot_cache.capacity()
+            + caching.manifest_cache.capacity()
+            + caching.node_manifest_cache.capacity()
+            + caching.attributes_cache.capacity()
+            + caching.chunk_cache.capacity();
+        assert!(capacity <= budget as u64);
+        assert!(capacity > budget as u64 * 9 / 10);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_shared_memory_budget(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let snapshot_id = SnapshotId::random();
+        backend.write_snapshot(snapshot_id.clone(), Arc::new(Snapshot::empty())).await?;
+        let mut chunk_ids = Vec::new();
+        for _ in 0..60 {
+            let id = ChunkId::random();
+            backend.write_chunk(id.clone(), Bytes::from(vec![0; 10_000])).await?;
+            chunk_ids.push(id);
+        }
+
+        // reads the snapshot once and all the chunks twice, returns the chunk hits
+        let chunk_heavy = |caching: MemCachingStorage| {
+            let snapshot_id = snapshot_id.clone();
+            let chunk_ids = chunk_ids.clone();
+            async move {
+                caching.fetch_snapshot(&snapshot_id).await?;
+                for _ in 0..2 {
+                    for id in chunk_ids.iter() {
+                        caching.fetch_chunk(id, &ByteRange::ALL).await?;
+                    }
+                }
+                assert!(caching.chunk_cache.weight() <= caching.chunk_cache.capacity());
+                Ok::<_, StorageError>((caching.stats().chunks.hits, caching))
+            }
+        };
+
+        // the chunks fit in the budget, but not in the chunk share of a split budget
+        let budget = 1_000_000;
+        let (split_hits, _) = chunk_heavy(MemCachingStorage::with_memory_budget(
+            Arc::clone(&backend),
+            budget,
+        ))
+        .await?;
+        let (fixed_hits, _) =
+            chunk_heavy(MemCachingStorage::new(Arc::clone(&backend), 10, 10, 10, 40))
+                .await?;
+        let (shared_hits, caching) = chunk_heavy(
+            MemCachingStorage::with_shared_memory_budget(Arc::clone(&backend), budget),
+        )
+        .await?;
+        assert_eq!(shared_hits, chunk_ids.len() as u64);
+        assert!(shared_hits > split_hits);
+        assert!(shared_hits > fixed_hits);
+
+        // the snapshot is in the same cache, and is still there
+        caching.fetch_snapshot(&snapshot_id).await?;
+        assert_eq!(caching.stats().snapshots, CacheCounts { hits: 1, misses: 1 });
+        assert_eq!(caching.chunk_cache.len(), chunk_ids.len() + 1);
+        Ok(())
+    }
+
+    /// Writes a chunk, caches `0..100` of it, and then replaces it in the backend with
+    /// different bytes, so we can tell which bytes come from the cache
+    async fn chunk_with_cached_prefix() -> Result<
+        (Arc<LoggingStorage>, MemCachingStorage, ChunkId),
+        Box<dyn std::error::Error>,
+    > {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 0, 0, 0, 10);
+
+        let id = ChunkId::random();
+        backend.write_chunk(id.clone(), Bytes::from(vec![1; 1000])).await?;
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(0, 100)).await?,
+            Bytes::from(vec![1; 100])
+        );
+        backend.write_chunk(id.clone(), Bytes::from(vec![2; 1000])).await?;
+        assert_eq!(logging.fetch_operations().len(), 1);
+        Ok((logging, caching, id))
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_slices_cached_superset(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (logging, caching, id) = chunk_with_cached_prefix().await?;
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(10, 20)).await?,
+            Bytes::from(vec![1; 10])
+        );
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(0, 100)).await?,
+            Bytes::from(vec![1; 100])
+        );
+        assert_eq!(logging.fetch_operations().len(), 1);
+
+        // a cached full object serves any bounded range
+        caching.fetch_chunk(&id, &ByteRange::ALL).await?;
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(500, 510)).await?,
+            Bytes::from(vec![2; 10])
+        );
+        assert_eq!(logging.fetch_operations().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_fetches_only_missing_part_of_range(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (logging, caching, id) = chunk_with_cached_prefix().await?;
+        let expected: Vec<u8> = [vec![1; 50], vec![2; 100]].concat();
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(50, 200)).await?,
+            Bytes::from(expected.clone())
+        );
+        assert_eq!(logging.fetch_operations().len(), 2);
+
+        // the stitched range is cached too
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(50, 200)).await?,
+            Bytes::from(expected)
+        );
+        assert_eq!(logging.fetch_operations().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_disjoint_range_misses(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (logging, caching, id) = chunk_with_cached_prefix().await?;
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(100, 150)).await?,
+            Bytes::from(vec![2; 50])
+        );
+        assert_eq!(logging.fetch_operations().len(), 2);
+        // ranges are not shared across chunks
+        let other = ChunkId::random();
+        assert!(caching.fetch_chunk(&other, &ByteRange::bounded(10, 20)).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_resolves_open_ended_ranges(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (logging, caching, id) = chunk_with_cached_prefix().await?;
+        // the size of the chunk is not known yet, so suffixes go to the backend
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::last(3)).await?,
+            Bytes::from(vec![2; 3])
+        );
+        assert_eq!(logging.fetch_operations().len(), 2);
+
+        caching.fetch_chunk(&id, &ByteRange::ALL).await?;
+        assert_eq!(logging.fetch_operations().len(), 3);
+
+        // once it is, open-ended ranges are served from the cache as their bounded equivalent
+        let tail = caching.fetch_chunk(&id, &ByteRange::bounded(990, 1000)).await?;
+        assert_eq!(tail, Bytes::from(vec![2; 10]));
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::from_offset(990)).await?, tail);
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::last(10)).await?, tail);
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::last(5000)).await?.len(), 1000);
+        assert!(caching
+            .fetch_chunk(&id, &ByteRange::from_offset(1000))
+            .await?
+            .is_empty());
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::to_offset(100)).await?,
+            Bytes::from(vec![1; 100])
+        );
+        assert_eq!(logging.fetch_operations().len(), 3);
+
+        // deleting the chunk forgets its size
+        caching.delete_chunk(&id).await?;
+        assert!(caching.fetch_chunk(&id, &ByteRange::last(10)).await.is_err());
+        Ok(())
+    }
+
+    async fn snapshot_with_attributes(
+        backend: &(dyn Storage + Send + Sync),
+    ) -> Result<(SnapshotId, AttributesId), Box<dyn std::error::Error>> {
+        let attributes_id = AttributesId::random();
+        backend
+            .write_attributes(attributes_id.clone(), Arc::new(AttributesTable {}))
+            .await?;
+        let snapshot = Snapshot::from_iter(
+            &Snapshot::empty(),
+            None,
+            vec![],
+            vec![AttributeFileInfo { id: attributes_id.clone(), format_version: 0 }],
+            [],
+        );
+        let snapshot_id = snapshot.metadata.id.clone();
+        backend.write_snapshot(snapshot_id.clone(), Arc::new(snapshot)).await?;
+        Ok((snapshot_id, attributes_id))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_eager_attributes(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let (snapshot_id, attributes_id) =
+            snapshot_with_attributes(backend.as_ref()).await?;
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 2, 2, 2, 0)
+            .with_eager_attributes(true);
+
+        caching.fetch_snapshot(&snapshot_id).await?;
+        let attributes_fetch = ("fetch_attributes".to_string(), attributes_id.0.to_vec());
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !logging.fetch_operations().contains(&attributes_fetch) {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+        })
+        .await?;
+
+        caching.fetch_attributes(&attributes_id).await?;
+        assert_eq!(
+            logging.fetch_operations(),
+            vec![
+                ("fetch_snapshot".to_string(), snapshot_id.0.to_vec()),
+                attributes_fetch
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_caches_chunks_on_write(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let bytes = Bytes::from_static(b"hello world");
+
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 2, 2, 2, 10)
+            .with_cache_chunks_on_write(true);
+        let id = ChunkId::random();
+        caching.write_chunk(id.clone(), bytes.clone()).await?;
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::ALL).await?, bytes);
+        // ranges are served from the cached chunk too
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::bounded(0, 5)).await?, "hello");
+        let id = ChunkId::random();
+        assert!(caching.write_chunk_if_absent(id.clone(), bytes.clone()).await?);
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::ALL).await?, bytes);
+        assert_eq!(logging.fetch_operations(), vec![]);
+
+        // by default written chunks are fetched from the backend
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 2, 2, 2, 10);
+        let id = ChunkId::random();
+        caching.write_chunk(id.clone(), bytes.clone()).await?;
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::ALL).await?, bytes);
+        assert_eq!(
+            logging.fetch_operations(),
+            vec![("fetch_chunk".to_string(), id.0.to_vec())]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_lazy_attributes(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let (snapshot_id, attributes_id) =
+            snapshot_with_attributes(backend.as_ref()).await?;
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 2, 2, 2, 0);
+
+        caching.fetch_snapshot(&snapshot_id).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(logging.fetch_operations().len(), 1);
+
+        caching.fetch_attributes(&attributes_id).await?;
+        assert_eq!(logging.fetch_operations().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_fetch_chunks() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(logging_c, 0, 0, 0, 10);
+
+        let mut requests = Vec::new();
+        for i in 0..4u8 {
+            let id = ChunkId::random();
+            backend.write_chunk(id.clone(), Bytes::from(vec![i; 10])).await?;
+            requests.push((id, ByteRange::ALL));
+        }
+        requests.push((requests[1].0.clone(), ByteRange::bounded(2, 4)));
+        for (id, range) in [&requests[0], &requests[2]] {
+            caching.fetch_chunk(id, range).await?;
+        }
+        let warm_up = logging.fetch_operations().len();
+        caching.reset_stats();
+
+        let fetched: Vec<Bytes> = caching.fetch_chunks(&requests).try_collect().await?;
+        assert_eq!(
+            fetched,
+            vec![
+                Bytes::from(vec![0; 10]),
+                Bytes::from(vec![1; 10]),
+                Bytes::from(vec![2; 10]),
+                Bytes::from(vec![3; 10]),
+                Bytes::from(vec![1; 2]),
+            ]
+        );
+        // only the misses hit the backend
+        let fetched_ids: HashSet<_> = logging.fetch_operations()[warm_up..]
+            .iter()
+            .map(|(_, id)| id.clone())
+            .collect();
+        assert_eq!(
+            fetched_ids,
+            [&requests[1].0, &requests[3].0]
+                .into_iter()
+                .map(|id| id.0.to_vec())
+                .collect()
+        );
+        assert_eq!(caching.stats().chunks, CacheCounts { hits: 2, misses: 3 });
+
+        // fetched chunks are cached
+        let again: Vec<Bytes> = caching.fetch_chunks(&requests).try_collect().await?;
+        assert_eq!(again, fetched);
+        assert_eq!(logging.fetch_operations().len(), warm_up + 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_not_found_cache(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        // nothing is cached positively, so every successful fetch goes to the backend
+        let caching = MemCachingStorage::new(logging_c, 0, 0, 0, 0)
+            .with_not_found_cache(10, Duration::from_secs(60));
+
+        let id = ManifestId::random();
+        for _ in 0..2 {
+            let res = caching.fetch_manifests(&id).await;
+            assert!(res.is_err_and(|err| err.is_not_found()));
+        }
+        assert_eq!(logging.fetch_operations().len(), 1);
+
+        let manifest = Arc::new(Manifest::default());
+        caching.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+        assert_eq!(caching.fetch_manifests(&id).await?, manifest);
+        assert_eq!(logging.fetch_operations().len(), 2);
+
+        for _ in 0..2 {
+            assert!(matches!(
+                caching.get_ref("branch.main/0").await,
+                Err(StorageError::RefNotFound(_))
+            ));
+        }
+        caching.write_ref("branch.main/0", false, Bytes::from("ref")).await?;
+        assert_eq!(caching.get_ref("branch.main/0").await?, Bytes::from("ref"));
+
+        // misses are forgotten after the ttl
+        let caching = MemCachingStorage::new(logging.clone(), 0, 0, 0, 0)
+            .with_not_found_cache(10, Duration::from_millis(50));
+        let missing = SnapshotId::random();
+        assert!(caching.fetch_snapshot(&missing).await.is_err());
+        assert!(caching.fetch_snapshot(&missing).await.is_err());
+        assert_eq!(logging.fetch_operations().len(), 3);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(caching.fetch_snapshot(&missing).await.is_err());
+        assert_eq!(logging.fetch_operations().len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_warm() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(logging_c, 10, 10, 0, 0);
+
+        let manifests: Vec<_> = (0..3).map(|_| ManifestId::random()).collect();
+        for id in manifests.iter() {
+            backend.write_manifests(id.clone(), Arc::new(Manifest::default())).await?;
+        }
+        let snapshots: Vec<_> = (0..2).map(|_| SnapshotId::random()).collect();
+        for id in snapshots.iter() {
+            backend.write_snapshot(id.clone(), Arc::new(Snapshot::empty())).await?;
+        }
+        caching.fetch_manifests(&manifests[0]).await?;
+
+        let mut ids = manifests.clone();
+        ids.push(manifests[1].clone());
+        assert_eq!(
+            caching.warm_manifests(&ids).await?,
+            WarmSummary { requested: 4, fetched: 2 }
+        );
+        assert_eq!(
+            caching.warm_snapshots(&snapshots).await?,
+            WarmSummary { requested: 2, fetched: 2 }
+        );
+        assert_eq!(logging.fetch_operations().len(), 5);
+
+        // once warm, nothing goes to the backend
+        for id in manifests.iter() {
+            caching.fetch_manifests(id).await?;
+        }
+        for id in snapshots.iter() {
+            caching.fetch_snapshot(id).await?;
+        }
+        assert_eq!(logging.fetch_operations().len(), 5);
+        assert_eq!(
+            caching.warm_manifests(&manifests).await?,
+            WarmSummary { requested: 3, fetched: 0 }
+        );
+
+        // a missing object fails the warm up
+        assert!(caching.warm_snapshots(&[SnapshotId::random()]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_caches_refs() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let caching = MemCachingStorage::new(Arc::clone(&backend), 0, 0, 0, 0)
+            .with_ref_cache(10, Duration::from_secs(60));
+        let s1 = SnapshotId::random();
+        let s2 = SnapshotId::random();
+        let s3 = SnapshotId::random();
+
+        update_branch(backend.as_ref(), "main", s1.clone(), None, false).await?;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s1);
+
+        // another writer moves the branch, but we resolve it from cache
+        update_branch(backend.as_ref(), "main", s2.clone(), Some(&s1), false).await?;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s1);
+
+        // writing through the cache invalidates, even if the first attempt fails because it
+        // was working with stale data
+        let res = update_branch(&caching, "main", s3.clone(), Some(&s1), false).await;
+        assert!(matches!(res, Err(RefError::Conflict { .. })));
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s2);
+        update_branch(&caching, "main", s3.clone(), Some(&s2), false).await?;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_ref_ttl() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let caching = MemCachingStorage::new(Arc::clone(&backend), 0, 0, 0, 0)
+            .with_ref_cache(10, Duration::from_millis(50));
+        let s1 = SnapshotId::random();
+        let s2 = SnapshotId::random();
+
+        update_branch(backend.as_ref(), "main", s1.clone(), None, false).await?;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s1);
+        update_branch(backend.as_ref(), "main", s2.clone(), Some(&s1), false).await?;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s2);
+
+        // without a ttl refs are not cached
+        let caching = MemCachingStorage::new(Arc::clone(&backend), 0, 0, 0, 0);
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s2);
+        update_branch(backend.as_ref(), "main", s1.clone(), Some(&s2), false).await?;
+        assert_eq!(fetch_branch_tip(&caching, "main").await?.snapshot, s1);
+
+        // within the ttl the backend is not called, after it the ref is fetched again
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let caching = MemCachingStorage::new(logging.clone(), 0, 0, 0, 0)
+            .with_ref_cache(10, Duration::from_millis(50));
+        let key = "tag.v1/ref.json";
+        backend.write_ref(key, true, Bytes::from_static(b"v1")).await?;
+        let backend_reads = || {
+            logging
+                .ref_operations()
+                .iter()
+                .filter(|(op, _)| op.starts_with("get_"))
+                .count()
+        };
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"v1"));
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"v1"));
+        assert_eq!(backend_reads(), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"v1"));
+        assert_eq!(backend_reads(), 2);
+
+        // writing through the cache invalidates the entry
+        caching.write_ref(key, true, Bytes::from_static(b"v2")).await?;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"v2"));
+        assert_eq!(backend_reads(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_storage_ref_revalidation(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let caching = MemCachingStorage::new(Arc::clone(&backend), 0, 0, 0, 0)
+            .with_ref_cache(10, Duration::from_secs(600))
+            .with_ref_revalidation(Duration::from_millis(50));
+        let key = "tag.v1/ref.json";
+
+        backend.write_ref(key, true, Bytes::from_static(b"first")).await?;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"first"));
+
+        // another writer changes the ref, within the grace period we get the cached value
+        backend.write_ref(key, true, Bytes::from_static(b"second")).await?;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"first"));
+
+        // after the grace period the change is detected, even if the ttl didn't expire
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"second"));
+
+        // unchanged refs keep being served after revalidation
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"second"));
+        backend.write_ref(key, true, Bytes::from_static(b"third")).await?;
+        assert_eq!(caching.get_ref(key).await?, Bytes::from_static(b"second"));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cold_start_burst_fetches_once() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let manifest_id = ManifestId::random();
+        backend
+            .write_manifests(manifest_id.clone(), Arc::new(Manifest::default()))
+            .await?;
+        backend.write_ref("tag.v1/ref.json", false, Bytes::from_static(b"tag")).await?;
+
+        // backend calls are serialized, so every fetch that isn't deduplicated queues up
+        let serializing = Arc::new(SerializingStorage::new(backend));
+        let serializing_c: Arc<dyn Storage + Send + Sync> = serializing.clone();
+        let caching = Arc::new(
+            MemCachingStorage::new(serializing_c, 2, 2, 0, 0)
+                .with_ref_cache(10, Duration::from_secs(600)),
+        );
+
+        let manifests = (0..100).map(|_| {
+            let caching = Arc::clone(&caching);
+            let id = manifest_id.clone();
+            tokio::spawn(async move { caching.fetch_manifests(&id).await.map(|_| ()) })
+        });
+        let refs = (0..100).map(|_| {
+            let caching = Arc::clone(&caching);
+            tokio::spawn(
+                async move { caching.get_ref("tag.v1/ref.json").await.map(|_| ()) },
+            )
+        });
+        let tasks: Vec<_> = manifests.chain(refs).collect();
+        for task in tasks {
+            task.await??;
+        }
+
+        let operations = serializing.operations();
+        let count = |name: &str| operations.iter().filter(|(op, _)| op == name).count();
+        assert_eq!(count("fetch_manifests"), 1);
+        assert_eq!(count("get_ref") + count("get_ref_if_changed"), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_manifest_cache_only_keeps_touched_nodes(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let manifest: Manifest = (0..10)
+            .flat_map(|node| {
+                (0..5).map(move |i| ChunkInfo {
+                    node,
+                    coord: ChunkIndices(vec![i]),
+                    payload: ChunkPayload::Inline(Bytes::from(vec![node as u8, i as u8])),
+                    uncompressed_size: None,
+                })
+            })
+            .collect();
+        let id = ManifestId::random();
+        backend.write_manifests(id.clone(), Arc::new(manifest)).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(logging_c, 2, 2, 2, 0);
+
+        let node_chunks = caching.fetch_node_chunks(&id, 3).await?;
+        assert_eq!(node_chunks.len(), 5);
+        assert!(node_chunks.chunks().keys().all(|(node, _)| *node == 3));
+
+        // only the node is cached, the full manifest isn't kept
+        assert_eq!(caching.node_manifest_cache.len(), 1);
+        assert_eq!(caching.manifest_cache.len(), 0);
+
+        assert_eq!(caching.fetch_node_chunks(&id, 3).await?, node_chunks);
+        assert_eq!(
+            caching.fetch_chunk_info(&id, 3, &ChunkIndices(vec![4])).await?,
+            Some(ChunkInfo {
+                node: 3,
+                coord: ChunkIndices(vec![4]),
+                payload: ChunkPayload::Inline(Bytes::from(vec![3, 4])),
+                uncompressed_size: None,
+            })
+        );
+        assert_eq!(
+            logging.fetch_operations(),
+            vec![("fetch_node_chunks".to_string(), id.0.to_vec())]
+        );
+        Ok(())
+    }
+
+    /// A backend that can't be reached, every operation fails
+    fn unreachable_storage() -> FaultyStorage {
+        FaultyStorage::in_memory().on_all(|_| {
+            Fault::Error(StorageError::Other("connection refused".to_string()))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_ping_is_forwarded() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        backend.ping().await?;
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        MemCachingStorage::new(logging.clone(), 2, 2, 2, 2).ping().await?;
+        assert_eq!(logging.fetch_operations(), vec![]);
 
+        // the backend error is returned, not hidden by the wrappers
+        let logging = Arc::new(LoggingStorage::new(Arc::new(unreachable_storage())));
+        let caching = MemCachingStorage::new(logging, 2, 2, 2, 2)
+            .with_ref_cache(2, Duration::from_secs(60));
+        assert!(matches!(
+            caching.ping().await,
+            Err(StorageError::Other(msg)) if msg == "connection refused"
+        ));
         Ok(())
     }
 }
@@ -0,0 +1,475 @@
+//! A [`Storage`] decorator that stops calling a backend that keeps failing
+//!
+//! The breaker starts closed, forwarding every call. Once `failure_threshold` transient failures
+//! happen within `window` it opens, and every call fails immediately with
+//! [`StorageError::CircuitOpen`]. After `cooldown` a single trial call is let through, the
+//! breaker is half-open while it runs: if it succeeds the breaker closes, otherwise it opens
+//! again for another cooldown.
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use super::{
+    retrying::is_transient, AnyObjectId, ObjectKind, RefFetch, Storage, StorageError,
+    StorageResult,
+};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Transient failures within `window` that open the breaker
+    pub failure_threshold: u32,
+    pub window: Duration,
+    /// How long the breaker stays open before it lets a trial call through
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 10,
+            window: Duration::from_secs(30),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// A trial call is deciding if the breaker closes
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    /// The times of the recent failures, oldest first
+    Closed { failures: VecDeque<Instant> },
+    /// Calls fail fast until `until`, `trial` is set while a trial call runs
+    Open { until: Instant, trial: bool },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreakerStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreakerStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        config: CircuitBreakerConfig,
+    ) -> Self {
+        Self {
+            backend,
+            config,
+            state: Mutex::new(State::Closed { failures: VecDeque::new() }),
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    pub fn state(&self) -> CircuitState {
+        match &*self.state.lock().expect("poison lock") {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { trial: true, .. } => CircuitState::HalfOpen,
+            State::Open { trial: false, .. } => CircuitState::Open,
+        }
+    }
+
+    async fn guarded<R>(
+        &self,
+        fut: impl Future<Output = StorageResult<R>>,
+    ) -> StorageResult<R> {
+        let trial = self.admit()?;
+        let res = fut.await;
+        self.record(trial, res.as_ref().err().is_some_and(is_transient));
+        res
+    }
+
+    /// Fails if the call must not be made, otherwise returns if it's the trial call
+    #[allow(clippy::expect_used)]
+    fn admit(&self) -> StorageResult<bool> {
+        let mut state = self.state.lock().expect("poison lock");
+        match &mut *state {
+            State::Closed { .. } => Ok(false),
+            State::Open { until, trial } => {
+                let now = Instant::now();
+                // a trial that was dropped before finishing is replaced after a cooldown
+                if now < *until {
+                    return Err(StorageError::CircuitOpen);
+                }
+                *until = now + self.config.cooldown;
+                *trial = true;
+                Ok(true)
+            }
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    fn record(&self, trial: bool, failed: bool) {
+        let mut state = self.state.lock().expect("poison lock");
+        let now = Instant::now();
+        match &mut *state {
+            State::Open { .. } if trial => {
+                *state = if failed {
+                    State::Open { until: now + self.config.cooldown, trial: false }
+                } else {
+                    State::Closed { failures: VecDeque::new() }
+                };
+            }
+            // calls started before the breaker opened don't change its state
+            State::Open { .. } => {}
+            State::Closed { failures } => {
+                if !failed {
+                    return;
+                }
+                failures.push_back(now);
+                while failures
+                    .front()
+                    .is_some_and(|at| now.duration_since(*at) > self.config.window)
+                {
+                    failures.pop_front();
+                }
+                if failures.len() >= self.config.failure_threshold as usize {
+                    *state =
+                        State::Open { until: now + self.config.cooldown, trial: false };
+                }
+            }
+        }
+    }
+}
+
+impl private::Sealed for CircuitBreakerStorage {}
+
+#[async_trait]
+impl Storage for CircuitBreakerStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.guarded(self.backend.fetch_snapshot(id)).await
+    }
+
+    async fn fetch_snapshot_subtree(
+        &self,
+        id: &SnapshotId,
+        root_path: &Path,
+    ) -> StorageResult<Snapshot> {
+        self.guarded(self.backend.fetch_snapshot_subtree(id, root_path)).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.guarded(self.backend.fetch_attributes(id)).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.guarded(self.backend.fetch_manifests(id)).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.guarded(self.backend.fetch_chunk(id, range)).await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.guarded(self.backend.fetch_chunk_info(manifest_id, node, coord)).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.guarded(self.backend.fetch_node_chunks(manifest_id, node)).await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.guarded(self.backend.exists(id)).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.guarded(self.backend.write_snapshot(id, table)).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.guarded(self.backend.write_attributes(id, table)).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.guarded(self.backend.write_manifests(id, table)).await
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        self.guarded(self.backend.write_manifests_if_not_exists(id, manifest)).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.guarded(self.backend.write_chunk(id, bytes)).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        self.guarded(self.backend.write_chunk_if_absent(id, bytes)).await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        self.guarded(self.backend.write_new_chunk(bytes)).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.guarded(self.backend.get_ref(ref_key)).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.guarded(self.backend.get_ref_if_changed(ref_key, etag)).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.guarded(self.backend.get_refs(keys)).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.guarded(self.backend.ref_names()).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.guarded(self.backend.list_modified(kind, from, to)).await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.guarded(self.backend.list_objects(kind)).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.guarded(self.backend.ref_versions(ref_name)).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.guarded(self.backend.delete_chunk(id)).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.guarded(self.backend.delete_manifest(id)).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.guarded(self.backend.delete_snapshot(id)).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.guarded(self.backend.delete_attributes(id)).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.guarded(self.backend.delete_ref_version(ref_name, version_id)).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.guarded(self.backend.write_ref(ref_key, overwrite_refs, bytes)).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.guarded(self.backend.compare_and_swap_ref(ref_key, expected, new)).await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.guarded(self.backend.backend_time()).await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.guarded(self.backend.ping()).await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        self.guarded(self.backend.record_ref_version(ref_name, bytes)).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::storage::faulty::{Fault, FaultyStorage};
+
+    /// The chunk fetches that reached `storage` since the last call
+    fn take_fetches(storage: &FaultyStorage) -> usize {
+        storage.take_calls().iter().filter(|call| call.method == "fetch_chunk").count()
+    }
+
+    /// A storage that fails every chunk fetch while the flag is set
+    async fn setup(
+    ) -> (Arc<FaultyStorage>, Arc<AtomicBool>, CircuitBreakerStorage, ChunkId) {
+        let down = Arc::new(AtomicBool::new(false));
+        let is_down = Arc::clone(&down);
+        let backend = Arc::new(FaultyStorage::in_memory().on("fetch_chunk", move |_| {
+            if is_down.load(Ordering::SeqCst) {
+                Fault::Error(StorageError::ObjectStore(::object_store::Error::Generic {
+                    store: "test",
+                    source: "backend unavailable".into(),
+                }))
+            } else {
+                Fault::Pass
+            }
+        }));
+        let id = ChunkId::random();
+        backend.write_chunk(id.clone(), Bytes::from_static(b"hello")).await.unwrap();
+        let breaker = CircuitBreakerStorage::new(
+            backend.clone(),
+            CircuitBreakerConfig {
+                failure_threshold: 3,
+                window: Duration::from_secs(60),
+                cooldown: Duration::from_millis(100),
+            },
+        );
+        (backend, down, breaker, id)
+    }
+
+    #[tokio::test]
+    async fn test_breaker_trips_and_fails_fast() {
+        let (backend, down, breaker, id) = setup().await;
+        down.store(true, Ordering::SeqCst);
+
+        for _ in 0..3 {
+            assert!(matches!(
+                breaker.fetch_chunk(&id, &ByteRange::ALL).await,
+                Err(StorageError::ObjectStore(_))
+            ));
+            assert_eq!(take_fetches(&backend), 1);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // while open, calls don't reach the backend, even after it recovers
+        down.store(false, Ordering::SeqCst);
+        for _ in 0..10 {
+            assert!(matches!(
+                breaker.fetch_chunk(&id, &ByteRange::ALL).await,
+                Err(StorageError::CircuitOpen)
+            ));
+        }
+        assert_eq!(take_fetches(&backend), 0);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_dont_trip_the_breaker() {
+        let (backend, _, breaker, _) = setup().await;
+        let missing = ChunkId::random();
+        for _ in 0..5 {
+            assert!(breaker.fetch_chunk(&missing, &ByteRange::ALL).await.is_err());
+        }
+        assert_eq!(take_fetches(&backend), 5);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_half_open_trial_decides_recovery() {
+        let (backend, down, breaker, id) = setup().await;
+        down.store(true, Ordering::SeqCst);
+        for _ in 0..3 {
+            let _ = breaker.fetch_chunk(&id, &ByteRange::ALL).await;
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        take_fetches(&backend);
+
+        // a failed trial opens the breaker for another cooldown
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(matches!(
+            breaker.fetch_chunk(&id, &ByteRange::ALL).await,
+            Err(StorageError::ObjectStore(_))
+        ));
+        assert_eq!(take_fetches(&backend), 1);
+        assert!(matches!(
+            breaker.fetch_chunk(&id, &ByteRange::ALL).await,
+            Err(StorageError::CircuitOpen)
+        ));
+        assert_eq!(take_fetches(&backend), 0);
+
+        // a successful trial closes it
+        down.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(breaker.fetch_chunk(&id, &ByteRange::ALL).await.unwrap(), "hello");
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        for _ in 0..5 {
+            assert!(breaker.fetch_chunk(&id, &ByteRange::ALL).await.is_ok());
+        }
+        assert_eq!(take_fetches(&backend), 6);
+    }
+}
@@ -0,0 +1,788 @@
+use std::{collections::HashMap, fmt, future::Future, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    encrypting::{blob_id, blob_object, object_name, resolve_blobs, OBJECT_NAME_LEN},
+    fetch_chunk_header, AnyObjectId, ObjectKind, RefFetch, Storage, StorageError,
+    StorageResult,
+};
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+    },
+    private,
+};
+
+/// The codec id of [`NoCompression`] in the default [`CodecRegistry`]
+pub const NO_COMPRESSION_CODEC_ID: u8 = 0;
+/// The codec id of [`ZstdCodec`] in the default [`CodecRegistry`]
+pub const ZSTD_CODEC_ID: u8 = 1;
+/// The first bytes of every object written by [`CodecRegistry::encode`]
+///
+/// Objects without it were not compressed, they are read as they are.
+pub const COMPRESSED_MAGIC: &[u8; 4] = b"ICZ\x01";
+/// The first bytes of the chunks that store snapshots, manifests and attribute files
+///
+/// It's followed by the kind and id of the object, and the bytes written by
+/// [`CodecRegistry::encode`].
+pub const OBJECT_MAGIC: &[u8; 4] = b"ICO\x01";
+
+/// A compression algorithm for [`CompressingStorage`]
+pub trait Codec: fmt::Debug + Send + Sync {
+    fn compress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>>;
+    fn decompress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>>;
+}
+
+/// Stores the bytes as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl Codec for NoCompression {
+    fn compress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCodec {
+    level: i32,
+}
+
+impl ZstdCodec {
+    pub fn new(level: i32) -> Self {
+        Self { level }
+    }
+}
+
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl Codec for ZstdCodec {
+    fn compress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>> {
+        zstd::encode_all(bytes, self.level)
+            .map_err(|err| StorageError::Compression(err.to_string()))
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>> {
+        zstd::decode_all(bytes).map_err(|err| StorageError::Compression(err.to_string()))
+    }
+}
+
+/// The codecs [`CompressingStorage`] can use, by id
+///
+/// The id is stored in the header of every object, after [`COMPRESSED_MAGIC`], so readers
+/// need a registry with the codecs used by the writers, under the same ids. The default registry has
+/// [`NoCompression`] and [`ZstdCodec`].
+#[derive(Debug, Clone)]
+pub struct CodecRegistry {
+    codecs: HashMap<u8, Arc<dyn Codec>>,
+}
+
+impl Default for CodecRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(NO_COMPRESSION_CODEC_ID, NoCompression);
+        registry.register(ZSTD_CODEC_ID, ZstdCodec::default());
+        registry
+    }
+}
+
+impl CodecRegistry {
+    pub fn empty() -> Self {
+        Self { codecs: HashMap::new() }
+    }
+
+    /// Register `codec` under `id`, returns the codec it replaces, if any
+    pub fn register(
+        &mut self,
+        id: u8,
+        codec: impl Codec + 'static,
+    ) -> Option<Arc<dyn Codec>> {
+        self.codecs.insert(id, Arc::new(codec))
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Arc<dyn Codec>> {
+        self.codecs.get(&id)
+    }
+
+    /// Compress `bytes` with the codec `id`, and prefix them with the magic and the id
+    pub fn encode(&self, id: u8, bytes: &[u8]) -> StorageResult<Bytes> {
+        let compressed = self.codec(id)?.compress(bytes)?;
+        let mut encoded =
+            Vec::with_capacity(compressed.len() + COMPRESSED_MAGIC.len() + 1);
+        encoded.extend_from_slice(COMPRESSED_MAGIC);
+        encoded.push(id);
+        encoded.extend(compressed);
+        Ok(encoded.into())
+    }
+
+    /// Decompress bytes written by [`CodecRegistry::encode`], with the codec in their header
+    ///
+    /// Bytes without the header are returned as they are.
+    pub fn decode(&self, bytes: Bytes) -> StorageResult<Bytes> {
+        let Some(header) = bytes.strip_prefix(COMPRESSED_MAGIC.as_slice()) else {
+            return Ok(bytes);
+        };
+        let Some((id, compressed)) = header.split_first() else {
+            return Err(StorageError::Compression("object has no codec id".to_string()));
+        };
+        Ok(self.codec(*id)?.decompress(compressed)?.into())
+    }
+
+    fn codec(&self, id: u8) -> StorageResult<&Arc<dyn Codec>> {
+        self.get(id)
+            .ok_or_else(|| StorageError::Compression(format!("unknown codec id {id}")))
+    }
+}
+
+/// A [`Storage`] decorator that compresses objects before they reach the backend
+///
+/// Objects are written with one codec from the registry, and read with whatever codec their
+/// header names, so the codec can be changed without rewriting the repository. Like in
+/// [`super::encrypting::EncryptingStorage`], snapshots, manifests and attribute files are
+/// serialized and stored as chunks, after an [`OBJECT_MAGIC`] header that names them so they
+/// can be listed. Listing any kind of object reads the header of every chunk in the backend.
+/// Refs are small and stored as they are.
+///
+/// Chunks are usually compressed already by their Zarr codecs, so they are stored as they are
+/// unless [`CompressingStorage::with_compressed_chunks`] is set, or they start with
+/// [`COMPRESSED_MAGIC`] and would be mistaken for a compressed chunk. Chunks are always read
+/// by their header. Ranged reads of compressed chunks fetch and decompress the whole chunk,
+/// without compressed chunks they fetch the header first.
+///
+/// Objects written without this wrapper can still be read: snapshots, manifests and attribute
+/// files are fetched from their usual location if there is no compressed copy, and chunks
+/// without the [`COMPRESSED_MAGIC`] header are returned as they are.
+#[derive(Debug)]
+pub struct CompressingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    registry: Arc<CodecRegistry>,
+    codec_id: u8,
+    compress_chunks: bool,
+}
+
+impl CompressingStorage {
+    /// Fails if `codec_id` is not in the registry
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        registry: Arc<CodecRegistry>,
+        codec_id: u8,
+    ) -> StorageResult<Self> {
+        registry.codec(codec_id)?;
+        Ok(Self { backend, registry, codec_id, compress_chunks: false })
+    }
+
+    /// Compress with zstd at `level`, using the default registry otherwise
+    pub fn zstd(backend: Arc<dyn Storage + Send + Sync>, level: i32) -> Self {
+        let mut registry = CodecRegistry::default();
+        registry.register(ZSTD_CODEC_ID, ZstdCodec::new(level));
+        Self {
+            backend,
+            registry: Arc::new(registry),
+            codec_id: ZSTD_CODEC_ID,
+            compress_chunks: false,
+        }
+    }
+
+    /// Compress chunks too, not only snapshots, manifests and attribute files
+    pub fn with_compressed_chunks(mut self, compress_chunks: bool) -> Self {
+        self.compress_chunks = compress_chunks;
+        self
+    }
+
+    pub fn codec_id(&self) -> u8 {
+        self.codec_id
+    }
+
+    /// Fetch the compressed copy of an object, or the object written by the backend if
+    /// there is none
+    async fn fetch_object<T, F>(
+        &self,
+        id: AnyObjectId,
+        uncompressed: impl FnOnce() -> F,
+    ) -> StorageResult<Arc<T>>
+    where
+        T: DeserializeOwned,
+        F: Future<Output = StorageResult<Arc<T>>>,
+    {
+        match self.backend.fetch_chunk(&blob_id(&id), &ByteRange::ALL).await {
+            Ok(blob) => {
+                let encoded = if blob.starts_with(OBJECT_MAGIC) {
+                    blob.slice((OBJECT_MAGIC.len() + OBJECT_NAME_LEN).min(blob.len())..)
+                } else {
+                    blob
+                };
+                Ok(Arc::new(rmp_serde::from_slice(&self.registry.decode(encoded)?)?))
+            }
+            Err(err) if err.is_not_found() => uncompressed().await,
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn write_object<T: Serialize>(
+        &self,
+        id: AnyObjectId,
+        object: &T,
+    ) -> StorageResult<()> {
+        let encoded = self.registry.encode(self.codec_id, &rmp_serde::to_vec(object)?)?;
+        let blob = [OBJECT_MAGIC.as_slice(), &object_name(&id), &encoded].concat();
+        self.backend.write_chunk(blob_id(&id), blob.into()).await
+    }
+
+    /// The object stored in the backend chunk `blob`, `None` if the chunk is gone
+    async fn resolve_blob(&self, blob: ChunkId) -> StorageResult<Option<AnyObjectId>> {
+        let header_len = OBJECT_MAGIC.len() + OBJECT_NAME_LEN;
+        let header = fetch_chunk_header(self.backend.as_ref(), &blob, header_len).await?;
+        Ok(header.map(|header| {
+            header
+                .strip_prefix(OBJECT_MAGIC.as_slice())
+                .and_then(|name| blob_object(&blob, name))
+                .unwrap_or(AnyObjectId::Chunk(blob))
+        }))
+    }
+
+    fn encode_chunk(&self, bytes: Bytes) -> StorageResult<Bytes> {
+        // chunks that look compressed are encoded too, so they are never ambiguous
+        if self.compress_chunks || bytes.starts_with(COMPRESSED_MAGIC) {
+            self.registry.encode(self.codec_id, &bytes)
+        } else {
+            Ok(bytes)
+        }
+    }
+
+    /// Delete the compressed copy of an object, and the object written by the backend, if any
+    async fn delete_object(
+        &self,
+        id: AnyObjectId,
+        uncompressed: impl Future<Output = StorageResult<()>>,
+    ) -> StorageResult<()> {
+        self.backend.delete_chunk(&blob_id(&id)).await?;
+        uncompressed.await
+    }
+}
+
+impl private::Sealed for CompressingStorage {}
+
+#[async_trait]
+impl Storage for CompressingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.fetch_object(AnyObjectId::Snapshot(id.clone()), || {
+            self.backend.fetch_snapshot(id)
+        })
+        .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.fetch_object(AnyObjectId::Attributes(id.clone()), || {
+            self.backend.fetch_attributes(id)
+        })
+        .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.fetch_object(AnyObjectId::Manifest(id.clone()), || {
+            self.backend.fetch_manifests(id)
+        })
+        .await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        // without compressed chunks most chunks are stored as they are, and can be read in
+        // range once the header says so
+        if !self.compress_chunks && range != &ByteRange::ALL {
+            let header =
+                fetch_chunk_header(self.backend.as_ref(), id, COMPRESSED_MAGIC.len())
+                    .await?;
+            if header.is_none_or(|header| header.as_ref() != COMPRESSED_MAGIC) {
+                return self.backend.fetch_chunk(id, range).await;
+            }
+        }
+        let encoded = self.backend.fetch_chunk(id, &ByteRange::ALL).await?;
+        let bytes = self.registry.decode(encoded)?;
+        Ok(if range == &ByteRange::ALL { bytes } else { range.slice(bytes) })
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        if matches!(id, AnyObjectId::Chunk(_)) {
+            return self.backend.exists(id).await;
+        }
+        Ok(self.backend.exists(&AnyObjectId::Chunk(blob_id(id))).await?
+            || self.backend.exists(id).await?)
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.write_object(AnyObjectId::Snapshot(id), snapshot.as_ref()).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.write_object(AnyObjectId::Attributes(id), table.as_ref()).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.write_object(AnyObjectId::Manifest(id), table.as_ref()).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, self.encode_chunk(bytes)?).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.write_chunk_if_absent(id, self.encode_chunk(bytes)?).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.backend.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.backend.get_refs(keys).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.backend.delete_chunk(id).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.delete_object(
+            AnyObjectId::Manifest(id.clone()),
+            self.backend.delete_manifest(id),
+        )
+        .await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.delete_object(
+            AnyObjectId::Snapshot(id.clone()),
+            self.backend.delete_snapshot(id),
+        )
+        .await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.delete_object(
+            AnyObjectId::Attributes(id.clone()),
+            self.backend.delete_attributes(id),
+        )
+        .await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.delete_ref_version(ref_name, version_id).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.compare_and_swap_ref(ref_key, expected, new).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        let blobs = self.backend.list_modified(ObjectKind::Chunk, from, to).await?;
+        let compressed = resolve_blobs(blobs, kind, move |blob| self.resolve_blob(blob));
+        if kind == ObjectKind::Chunk {
+            return Ok(compressed);
+        }
+        let uncompressed = self.backend.list_modified(kind, from, to).await?;
+        Ok(compressed.chain(uncompressed).boxed())
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        let blobs = self.backend.list_objects(ObjectKind::Chunk).await?;
+        let compressed = resolve_blobs(blobs, kind, move |blob| self.resolve_blob(blob));
+        if kind == ObjectKind::Chunk {
+            return Ok(compressed);
+        }
+        // objects written without this wrapper
+        let uncompressed = self.backend.list_objects(kind).await?;
+        Ok(compressed.chain(uncompressed).boxed())
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.backend.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.backend.ping().await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        self.backend.record_ref_version(ref_name, bytes).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::collections::HashSet;
+
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::{
+        format::{
+            manifest::{ChunkInfo, ChunkPayload, ChunkRef},
+            ChunkIndices, ObjectId,
+        },
+        ObjectStorage,
+    };
+
+    /// Reverses the bytes, easy to recognize in the backend
+    #[derive(Debug)]
+    struct Reverse;
+
+    impl Codec for Reverse {
+        fn compress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>> {
+            Ok(bytes.iter().rev().copied().collect())
+        }
+
+        fn decompress(&self, bytes: &[u8]) -> StorageResult<Vec<u8>> {
+            self.compress(bytes)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_codec_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut registry = CodecRegistry::default();
+        assert!(registry.register(42, Reverse).is_none());
+        let registry = Arc::new(registry);
+        let storage = CompressingStorage::new(Arc::clone(&backend), registry, 42)?
+            .with_compressed_chunks(true);
+
+        let snapshot_id = SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        storage.write_snapshot(snapshot_id.clone(), Arc::clone(&snapshot)).await?;
+        assert_eq!(storage.fetch_snapshot(&snapshot_id).await?, snapshot);
+
+        let chunk_id = ChunkId::random();
+        storage.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello world")).await?;
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"hello world")
+        );
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::bounded(6, 11)).await?,
+            Bytes::from_static(b"world")
+        );
+        assert_eq!(
+            backend.fetch_chunk(&chunk_id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"ICZ\x01*dlrow olleh")
+        );
+
+        // any storage with the codec registered reads the objects, whatever codec it writes
+        let mut registry = CodecRegistry::default();
+        registry.register(42, Reverse);
+        let zstd = CompressingStorage::new(
+            Arc::clone(&backend),
+            Arc::new(registry),
+            ZSTD_CODEC_ID,
+        )?
+        .with_compressed_chunks(true);
+        assert_eq!(zstd.fetch_snapshot(&snapshot_id).await?, snapshot);
+        let zstd_chunk = ChunkId::random();
+        let large = Bytes::from(vec![7; 10_000]);
+        zstd.write_chunk(zstd_chunk.clone(), large.clone()).await?;
+        assert_eq!(storage.fetch_chunk(&zstd_chunk, &ByteRange::ALL).await?, large);
+        assert!(backend.fetch_chunk(&zstd_chunk, &ByteRange::ALL).await?.len() < 100);
+
+        // without the codec objects can't be read
+        let default = CompressingStorage::new(
+            Arc::clone(&backend),
+            Arc::new(CodecRegistry::default()),
+            NO_COMPRESSION_CODEC_ID,
+        )?
+        .with_compressed_chunks(true);
+        assert!(matches!(
+            default.fetch_chunk(&chunk_id, &ByteRange::ALL).await,
+            Err(StorageError::Compression(_))
+        ));
+        assert!(matches!(
+            CompressingStorage::new(backend, Arc::new(CodecRegistry::empty()), 42),
+            Err(StorageError::Compression(_))
+        ));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_chunks_are_read_by_their_header(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let compressing = CompressingStorage::zstd(Arc::clone(&backend), 3)
+            .with_compressed_chunks(true);
+        let plain = CompressingStorage::zstd(Arc::clone(&backend), 3);
+
+        // compressed chunks are read without compress_chunks set
+        let compressed = ChunkId::random();
+        let large = Bytes::from(vec![7; 10_000]);
+        compressing.write_chunk(compressed.clone(), large.clone()).await?;
+        assert_eq!(plain.fetch_chunk(&compressed, &ByteRange::ALL).await?, large);
+        assert_eq!(
+            plain.fetch_chunk(&compressed, &ByteRange::bounded(10, 13)).await?,
+            Bytes::from_static(&[7, 7, 7])
+        );
+
+        // chunks that look compressed are escaped, and read back as they were written
+        let look_alike = Bytes::from_static(b"ICZ\x01\x01not zstd");
+        let escaped = ChunkId::random();
+        plain.write_chunk(escaped.clone(), look_alike.clone()).await?;
+        assert_ne!(backend.fetch_chunk(&escaped, &ByteRange::ALL).await?, look_alike);
+        for storage in [&plain, &compressing] {
+            assert_eq!(storage.fetch_chunk(&escaped, &ByteRange::ALL).await?, look_alike);
+            assert_eq!(
+                storage.fetch_chunk(&escaped, &ByteRange::bounded(5, 8)).await?,
+                Bytes::from_static(b"not")
+            );
+        }
+
+        // other chunks are stored as they are
+        let raw = ChunkId::random();
+        plain.write_chunk(raw.clone(), Bytes::from_static(b"hello world")).await?;
+        assert_eq!(backend.fetch_chunk(&raw, &ByteRange::ALL).await?, "hello world");
+        assert_eq!(plain.fetch_chunk(&raw, &ByteRange::bounded(6, 11)).await?, "world");
+        assert!(plain
+            .fetch_chunk(&ChunkId::random(), &ByteRange::bounded(0, 1))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zstd_compresses_manifests() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = CompressingStorage::zstd(Arc::clone(&backend), 3);
+
+        let chunk_id = ObjectId::random();
+        let manifest: Arc<Manifest> = Arc::new(
+            (0..10_000)
+                .map(|i| ChunkInfo {
+                    node: 1,
+                    coord: ChunkIndices(vec![i]),
+                    payload: ChunkPayload::Ref(ChunkRef {
+                        id: chunk_id.clone(),
+                        offset: u64::from(i) * 100,
+                        length: 100,
+                    }),
+                    uncompressed_size: None,
+                })
+                .collect(),
+        );
+        let manifest_id = ManifestId::random();
+        storage.write_manifests(manifest_id.clone(), Arc::clone(&manifest)).await?;
+        assert_eq!(storage.fetch_manifests(&manifest_id).await?, manifest);
+        assert!(storage.exists(&AnyObjectId::Manifest(manifest_id.clone())).await?);
+
+        let stored = backend
+            .fetch_chunk(
+                &blob_id(&AnyObjectId::Manifest(manifest_id.clone())),
+                &ByteRange::ALL,
+            )
+            .await?;
+        assert!(stored.starts_with(OBJECT_MAGIC));
+        assert!(
+            stored[OBJECT_MAGIC.len() + OBJECT_NAME_LEN..].starts_with(COMPRESSED_MAGIC)
+        );
+        assert!(stored.len() * 4 < rmp_serde::to_vec(manifest.as_ref())?.len());
+
+        // chunks are stored as they are by default
+        let chunk_id = ChunkId::random();
+        let chunk = Bytes::from(vec![7; 10_000]);
+        storage.write_chunk(chunk_id.clone(), chunk.clone()).await?;
+        assert_eq!(backend.fetch_chunk(&chunk_id, &ByteRange::ALL).await?, chunk);
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::bounded(1, 3)).await?,
+            Bytes::from_static(&[7, 7])
+        );
+
+        storage.delete_manifest(&manifest_id).await?;
+        assert!(!storage.exists(&AnyObjectId::Manifest(manifest_id)).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reads_uncompressed_objects() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+
+        // objects written before the repository was compressed
+        let snapshot_id = SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        backend.write_snapshot(snapshot_id.clone(), Arc::clone(&snapshot)).await?;
+        let manifest_id = ManifestId::random();
+        let manifest = Arc::new(Manifest::default());
+        backend.write_manifests(manifest_id.clone(), Arc::clone(&manifest)).await?;
+        let chunk_id = ChunkId::random();
+        backend.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello")).await?;
+
+        let storage = CompressingStorage::zstd(Arc::clone(&backend), 3)
+            .with_compressed_chunks(true);
+        assert_eq!(storage.fetch_snapshot(&snapshot_id).await?, snapshot);
+        assert_eq!(storage.fetch_manifests(&manifest_id).await?, manifest);
+        assert!(storage.exists(&AnyObjectId::Snapshot(snapshot_id.clone())).await?);
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"hello")
+        );
+
+        // new objects are compressed
+        let compressed_chunk = ChunkId::random();
+        storage
+            .write_chunk(compressed_chunk.clone(), Bytes::from_static(b"world"))
+            .await?;
+        assert!(backend
+            .fetch_chunk(&compressed_chunk, &ByteRange::ALL)
+            .await?
+            .starts_with(COMPRESSED_MAGIC));
+        assert_eq!(
+            storage.fetch_chunk(&compressed_chunk, &ByteRange::ALL).await?,
+            Bytes::from_static(b"world")
+        );
+
+        storage.delete_snapshot(&snapshot_id).await?;
+        assert!(!backend.exists(&AnyObjectId::Snapshot(snapshot_id.clone())).await?);
+        assert!(!storage.exists(&AnyObjectId::Snapshot(snapshot_id)).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listings_name_the_objects() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = CompressingStorage::zstd(Arc::clone(&backend), 3);
+
+        let (compressed, uncompressed) = (SnapshotId::random(), SnapshotId::random());
+        storage.write_snapshot(compressed.clone(), Arc::new(Snapshot::empty())).await?;
+        backend.write_snapshot(uncompressed.clone(), Arc::new(Snapshot::empty())).await?;
+        let manifest_id = ManifestId::random();
+        storage
+            .write_manifests(manifest_id.clone(), Arc::new(Manifest::default()))
+            .await?;
+        // a chunk can look like an object header, it's not stored under the object's blob id
+        let lookalike = ChunkId::random();
+        let header = [
+            OBJECT_MAGIC.as_slice(),
+            &object_name(&AnyObjectId::Snapshot(SnapshotId::random())),
+        ]
+        .concat();
+        storage.write_chunk(lookalike.clone(), header.into()).await?;
+        let empty = ChunkId::random();
+        storage.write_chunk(empty.clone(), Bytes::new()).await?;
+
+        async fn listed(
+            storage: &CompressingStorage,
+            kind: ObjectKind,
+        ) -> StorageResult<HashSet<AnyObjectId>> {
+            storage.list_objects(kind).await?.try_collect().await
+        }
+        assert_eq!(
+            listed(&storage, ObjectKind::Chunk).await?,
+            HashSet::from([AnyObjectId::Chunk(lookalike), AnyObjectId::Chunk(empty)])
+        );
+        assert_eq!(
+            listed(&storage, ObjectKind::Snapshot).await?,
+            HashSet::from([
+                AnyObjectId::Snapshot(compressed),
+                AnyObjectId::Snapshot(uncompressed.clone())
+            ])
+        );
+        assert_eq!(
+            listed(&storage, ObjectKind::Manifest).await?,
+            HashSet::from([AnyObjectId::Manifest(manifest_id.clone())])
+        );
+
+        let modified: Vec<_> = storage
+            .list_modified(
+                ObjectKind::Manifest,
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now() + std::time::Duration::from_secs(60),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(modified.len(), 1);
+        assert_eq!(modified[0].0, AnyObjectId::Manifest(manifest_id));
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+//! A [`Storage`] decorator that stores identical chunks once
+//!
+//! Chunks written with [`Storage::write_new_chunk`] get an id derived from a hash of their
+//! bytes. Writing the same bytes again finds the existing object and returns its id, so the
+//! manifests of every array that wrote them reference a single object. Chunks written with an
+//! explicit id are forwarded unchanged.
+//!
+//! Stack it above decorators that change the bytes, like
+//! [`super::compressing::CompressingStorage`] or [`super::encrypting::EncryptingStorage`], they
+//! write new chunks under random ids. A shared chunk can only be deleted once no manifest
+//! references it.
+use std::{sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use sha2::{Digest, Sha256};
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
+    },
+    private,
+};
+
+#[derive(Debug)]
+pub struct DedupingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    dedup_chunks: bool,
+}
+
+impl DedupingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { backend, dedup_chunks: true }
+    }
+
+    /// Disable deduplication to get random chunk ids, the default is to deduplicate
+    pub fn with_dedup_chunks(mut self, dedup_chunks: bool) -> Self {
+        self.dedup_chunks = dedup_chunks;
+        self
+    }
+}
+
+/// The id of a chunk with these bytes
+fn content_id(bytes: &[u8]) -> ChunkId {
+    let mut hasher = Sha256::new();
+    hasher.update(b"chunk");
+    hasher.update(bytes);
+    let mut id = [0; 12];
+    id.copy_from_slice(&hasher.finalize()[..12]);
+    ChunkId::new(id)
+}
+
+impl private::Sealed for DedupingStorage {}
+
+#[async_trait]
+impl Storage for DedupingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.backend.fetch_chunk_info(manifest_id, node, coord).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_node_chunks(manifest_id, node).await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.backend.exists(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, snapshot).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, manifest).await
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        self.backend.write_manifests_if_not_exists(id, manifest).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.write_chunk_if_absent(id, bytes).await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        if !self.dedup_chunks {
+            return self.backend.write_new_chunk(bytes).await;
+        }
+        let id = content_id(&bytes);
+        // checking first is cheaper than uploading the bytes again
+        if !self.backend.exists(&AnyObjectId::Chunk(id.clone())).await? {
+            // concurrent writers of the same bytes use the same id, only one creates the object
+            self.backend.write_chunk_if_absent(id.clone(), bytes).await?;
+        }
+        Ok(id)
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.backend.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.backend.get_refs(keys).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.backend.list_modified(kind, from, to).await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.backend.list_objects(kind).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.backend.delete_chunk(id).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.backend.delete_manifest(id).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.backend.delete_snapshot(id).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.backend.delete_attributes(id).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.delete_ref_version(ref_name, version_id).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.compare_and_swap_ref(ref_key, expected, new).await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.backend.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.backend.ping().await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        self.backend.record_ref_version(ref_name, bytes).await
+    }
+}
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkPayload, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        storage::{logging::LoggingStorage, ObjectStorage},
+        Repository,
+    };
+
+    async fn write_twice(
+        dedup_chunks: bool,
+    ) -> Result<(Arc<LoggingStorage>, Vec<ChunkPayload>), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(backend));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(DedupingStorage::new(logging_c).with_dedup_chunks(dedup_chunks));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let metadata = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        repo.add_group(Path::root()).await?;
+        let paths: Vec<Path> = vec!["/a".try_into()?, "/b".try_into()?];
+        for path in paths.iter() {
+            repo.add_array(path.clone(), metadata.clone()).await?;
+            let payload =
+                repo.get_chunk_writer()(Bytes::from_static(b"fill fill fill")).await?;
+            repo.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload))
+                .await?;
+        }
+        repo.commit("main", "two chunks", None).await?;
+
+        let repo = Repository::update(storage, repo.snapshot_id().clone()).build();
+        let mut payloads = Vec::new();
+        for path in paths.iter() {
+            let coord = ChunkIndices(vec![0]);
+            let chunk = repo.get_chunk_reader(path, &coord, &ByteRange::ALL).await?;
+            let bytes = chunk.unwrap().await?;
+            assert_eq!(bytes, Bytes::from_static(b"fill fill fill"));
+            payloads.push(repo.get_chunk_ref(path, &coord).await?.unwrap());
+        }
+        Ok((logging, payloads))
+    }
+
+    #[tokio::test]
+    async fn test_identical_chunks_are_stored_once(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (logging, payloads) = write_twice(true).await?;
+        let chunks: Vec<_> =
+            logging.list_objects(ObjectKind::Chunk).await?.try_collect().await?;
+        assert_eq!(chunks.len(), 1);
+        let chunk_writes: Vec<_> = logging
+            .write_operations()
+            .into_iter()
+            .filter(|(_, id)| id.kind() == ObjectKind::Chunk)
+            .collect();
+        assert_eq!(
+            chunk_writes,
+            vec![("write_chunk_if_absent".to_string(), chunks[0].clone())]
+        );
+        assert_eq!(payloads[0], payloads[1]);
+        assert!(matches!(
+            &payloads[0],
+            ChunkPayload::Ref(chunk_ref) if AnyObjectId::Chunk(chunk_ref.id.clone()) == chunks[0]
+        ));
+
+        // without deduplication every write is a new object
+        let (logging, payloads) = write_twice(false).await?;
+        let chunks: Vec<_> =
+            logging.list_objects(ObjectKind::Chunk).await?.try_collect().await?;
+        assert_eq!(chunks.len(), 2);
+        assert_ne!(payloads[0], payloads[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writers_share_the_chunk(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = DedupingStorage::new(Arc::clone(&backend));
+        let bytes = Bytes::from_static(b"same bytes");
+        let (first, second) = futures::try_join!(
+            storage.write_new_chunk(bytes.clone()),
+            storage.write_new_chunk(bytes.clone())
+        )?;
+        assert_eq!(first, second);
+        let chunks: Vec<_> =
+            backend.list_objects(ObjectKind::Chunk).await?.try_collect().await?;
+        assert_eq!(chunks, vec![AnyObjectId::Chunk(first.clone())]);
+        assert_eq!(backend.fetch_chunk(&first, &ByteRange::ALL).await?, bytes);
+        assert_ne!(storage.write_new_chunk(Bytes::from_static(b"other")).await?, first);
+        Ok(())
+    }
+}
@@ -0,0 +1,644 @@
+use std::{fmt, future::Future, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{future::ready, stream::BoxStream, StreamExt, TryStreamExt};
+use rand::{thread_rng, Rng};
+use ring::aead::{
+    Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, MAX_TAG_LEN, NONCE_LEN,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    fetch_chunk_header, AnyObjectId, ObjectKind, RefFetch, Storage, StorageError,
+    StorageResult,
+};
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+    },
+    private,
+};
+
+/// A 256 bit key for [`EncryptingStorage`]
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn random() -> Self {
+        Self(thread_rng().gen())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// A [`Storage`] decorator that encrypts every object before it reaches the backend
+///
+/// Objects and refs are encrypted with ChaCha20-Poly1305, the stored bytes are a random nonce
+/// followed by the ciphertext. The object id, or the ref key, is authenticated, so an object
+/// can't be passed for a different one.
+///
+/// The backend only sees opaque blobs: snapshots, manifests and attribute files are serialized
+/// and stored as chunks, under ids derived from their own. Only the names of refs are stored in
+/// plain text. The backend must only be used through an `EncryptingStorage` with the same key.
+///
+/// Those blobs start with an encrypted header naming the object, so they can be listed.
+/// Listing any kind of object reads the header of every chunk in the backend.
+///
+/// Object ids are assigned by the repository before objects reach this layer, they don't
+/// depend on the stored bytes. Encryption changes the byte offsets of chunks, so ranged chunk
+/// reads are not supported.
+#[derive(Debug)]
+pub struct EncryptingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    key: LessSafeKey,
+}
+
+impl EncryptingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>, key: EncryptionKey) -> Self {
+        #[allow(clippy::expect_used)] // the key has the length required by the algorithm
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key.0).expect("valid key length");
+        Self { backend, key: LessSafeKey::new(key) }
+    }
+
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> StorageResult<Bytes> {
+        let nonce: [u8; NONCE_LEN] = thread_rng().gen();
+        let mut sealed = Vec::with_capacity(
+            NONCE_LEN + plaintext.len() + self.key.algorithm().tag_len(),
+        );
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(plaintext);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut sealed[NONCE_LEN..],
+            )
+            .map_err(|_| StorageError::Encryption("encryption failed".to_string()))?;
+        sealed.extend_from_slice(tag.as_ref());
+        Ok(sealed.into())
+    }
+
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> StorageResult<Bytes> {
+        let failed = || {
+            StorageError::Encryption(
+                "decryption failed, the object is corrupted or the key is wrong"
+                    .to_string(),
+            )
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| failed())?;
+        let mut buffer = ciphertext.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::from(aad), &mut buffer)
+            .map_err(|_| failed())?
+            .len();
+        buffer.truncate(len);
+        Ok(buffer.into())
+    }
+
+    async fn fetch_object<T: DeserializeOwned>(
+        &self,
+        id: AnyObjectId,
+    ) -> StorageResult<T> {
+        let sealed = self.backend.fetch_chunk(&blob_id(&id), &ByteRange::ALL).await?;
+        let bytes =
+            self.open(&object_name(&id), sealed.get(HEADER_LEN..).unwrap_or_default())?;
+        Ok(rmp_serde::from_slice(&bytes)?)
+    }
+
+    async fn write_object<T: Serialize>(
+        &self,
+        id: AnyObjectId,
+        object: &T,
+    ) -> StorageResult<()> {
+        let blob = blob_id(&id);
+        let header = self.seal(&header_aad(&blob), &object_name(&id))?;
+        let sealed = self.seal(&object_name(&id), &rmp_serde::to_vec(object)?)?;
+        self.backend.write_chunk(blob, [header, sealed].concat().into()).await
+    }
+
+    /// The object stored in the backend chunk `blob`, `None` if the chunk is gone
+    async fn resolve_blob(&self, blob: ChunkId) -> StorageResult<Option<AnyObjectId>> {
+        let header = fetch_chunk_header(self.backend.as_ref(), &blob, HEADER_LEN).await?;
+        Ok(header.map(|header| {
+            // chunks have no header, so it doesn't open
+            self.open(&header_aad(&blob), &header)
+                .ok()
+                .and_then(|name| blob_object(&blob, &name))
+                .unwrap_or(AnyObjectId::Chunk(blob))
+        }))
+    }
+
+    fn open_ref(&self, ref_key: &str, sealed: &[u8]) -> StorageResult<Bytes> {
+        self.open(&ref_aad(ref_key), sealed)
+    }
+
+    fn seal_ref(&self, ref_key: &str, bytes: &[u8]) -> StorageResult<Bytes> {
+        self.seal(&ref_aad(ref_key), bytes)
+    }
+}
+
+/// The id of the backend chunk that stores an object
+///
+/// Different kinds of objects can have the same id, so they are hashed together with the kind.
+pub(super) fn blob_id(id: &AnyObjectId) -> ChunkId {
+    match id {
+        AnyObjectId::Chunk(id) => id.clone(),
+        other => {
+            let mut hasher = Sha256::new();
+            hasher.update([other.kind() as u8]);
+            hasher.update(other.as_bytes());
+            let mut bytes = [0; 12];
+            bytes.copy_from_slice(&hasher.finalize()[..12]);
+            ChunkId::new(bytes)
+        }
+    }
+}
+
+/// The kind and id of an object, as bytes
+///
+/// It authenticates the encrypted objects, and names them in the header of their blob.
+pub(super) fn object_name(id: &AnyObjectId) -> Vec<u8> {
+    let mut name = vec![id.kind() as u8];
+    name.extend_from_slice(id.as_bytes());
+    name
+}
+
+/// The length of [`object_name`]
+pub(super) const OBJECT_NAME_LEN: usize = 13;
+
+/// The length of the encrypted header of the blobs that store objects, ChaCha20-Poly1305
+/// tags have the maximum length
+const HEADER_LEN: usize = NONCE_LEN + OBJECT_NAME_LEN + MAX_TAG_LEN;
+
+/// How many backend chunks have their header read at the same time when listing objects
+const LIST_HEADERS_CONCURRENCY: usize = 16;
+
+/// The object named by the header of the blob `blob`
+///
+/// A chunk can start with bytes that look like a name, so the name must be the one of an
+/// object stored in this blob.
+pub(super) fn blob_object(blob: &ChunkId, name: &[u8]) -> Option<AnyObjectId> {
+    let (kind, id) = name.split_first()?;
+    let kind = [ObjectKind::Snapshot, ObjectKind::Manifest, ObjectKind::Attributes]
+        .into_iter()
+        .find(|candidate| *candidate as u8 == *kind)?;
+    let id = AnyObjectId::from_bytes(kind, id)?;
+    (&blob_id(&id) == blob).then_some(id)
+}
+
+fn header_aad(blob: &ChunkId) -> Vec<u8> {
+    let mut aad = b"blob:".to_vec();
+    aad.extend_from_slice(&blob.0);
+    aad
+}
+
+/// An entry of a listing of objects
+pub(super) trait Listed: Send {
+    fn id_mut(&mut self) -> &mut AnyObjectId;
+}
+
+impl Listed for AnyObjectId {
+    fn id_mut(&mut self) -> &mut AnyObjectId {
+        self
+    }
+}
+
+impl Listed for (AnyObjectId, SystemTime) {
+    fn id_mut(&mut self) -> &mut AnyObjectId {
+        &mut self.0
+    }
+}
+
+/// The objects of `kind` in a listing of backend chunks
+///
+/// `resolve` finds the object stored in a backend chunk, `None` if the chunk is gone.
+pub(super) fn resolve_blobs<'a, T, F, Fut>(
+    blobs: BoxStream<'a, StorageResult<T>>,
+    kind: ObjectKind,
+    resolve: F,
+) -> BoxStream<'a, StorageResult<T>>
+where
+    T: Listed + 'a,
+    F: Fn(ChunkId) -> Fut + Send + 'a,
+    Fut: Future<Output = StorageResult<Option<AnyObjectId>>> + Send + 'a,
+{
+    blobs
+        .map_ok(move |mut entry| {
+            let resolved = match entry.id_mut() {
+                AnyObjectId::Chunk(blob) => Some(resolve(blob.clone())),
+                _ => None,
+            };
+            async move {
+                if let Some(resolved) = resolved {
+                    match resolved.await? {
+                        Some(id) => *entry.id_mut() = id,
+                        None => return Ok(None),
+                    }
+                }
+                Ok((entry.id_mut().kind() == kind).then_some(entry))
+            }
+        })
+        .try_buffered(LIST_HEADERS_CONCURRENCY)
+        .try_filter_map(|entry| ready(Ok(entry)))
+        .boxed()
+}
+
+fn ref_aad(ref_key: &str) -> Vec<u8> {
+    format!("ref:{ref_key}").into_bytes()
+}
+
+impl private::Sealed for EncryptingStorage {}
+
+#[async_trait]
+impl Storage for EncryptingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.fetch_object(AnyObjectId::Snapshot(id.clone())).await.map(Arc::new)
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.fetch_object(AnyObjectId::Attributes(id.clone())).await.map(Arc::new)
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.fetch_object(AnyObjectId::Manifest(id.clone())).await.map(Arc::new)
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        if range != &ByteRange::ALL {
+            return Err(StorageError::Unsupported(
+                "ranged reads of encrypted chunks, fetch the whole chunk instead"
+                    .to_string(),
+            ));
+        }
+        let id = AnyObjectId::Chunk(id.clone());
+        let sealed = self.backend.fetch_chunk(&blob_id(&id), &ByteRange::ALL).await?;
+        self.open(&object_name(&id), &sealed)
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.backend.exists(&AnyObjectId::Chunk(blob_id(id))).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.write_object(AnyObjectId::Snapshot(id), snapshot.as_ref()).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.write_object(AnyObjectId::Attributes(id), table.as_ref()).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.write_object(AnyObjectId::Manifest(id), table.as_ref()).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let sealed = self.seal(&object_name(&AnyObjectId::Chunk(id.clone())), &bytes)?;
+        self.backend.write_chunk(id, sealed).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        let sealed = self.seal(&object_name(&AnyObjectId::Chunk(id.clone())), &bytes)?;
+        self.backend.write_chunk_if_absent(id, sealed).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        let sealed = self.backend.get_ref(ref_key).await?;
+        self.open_ref(ref_key, &sealed)
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        match self.backend.get_ref_if_changed(ref_key, etag).await? {
+            RefFetch::Unchanged => Ok(RefFetch::Unchanged),
+            RefFetch::Changed { bytes, etag } => {
+                Ok(RefFetch::Changed { bytes: self.open_ref(ref_key, &bytes)?, etag })
+            }
+        }
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.backend.delete_chunk(id).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.backend.delete_chunk(&blob_id(&AnyObjectId::Manifest(id.clone()))).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.backend.delete_chunk(&blob_id(&AnyObjectId::Snapshot(id.clone()))).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.backend.delete_chunk(&blob_id(&AnyObjectId::Attributes(id.clone()))).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.delete_ref_version(ref_name, version_id).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let sealed = self.seal_ref(ref_key, &bytes)?;
+        self.backend.write_ref(ref_key, overwrite_refs, sealed).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        let sealed = self.seal_ref(ref_key, &new)?;
+        let Some(expected) = expected else {
+            return self.backend.compare_and_swap_ref(ref_key, None, sealed).await;
+        };
+        // every encryption uses a new nonce, so we compare plain texts and then swap the exact
+        // bytes we read
+        let current = match self.backend.get_ref(ref_key).await {
+            Ok(current) => current,
+            Err(StorageError::RefNotFound(_)) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if self.open_ref(ref_key, &current)? != expected {
+            return Ok(false);
+        }
+        self.backend.compare_and_swap_ref(ref_key, Some(current), sealed).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        let blobs = self.backend.list_modified(ObjectKind::Chunk, from, to).await?;
+        Ok(resolve_blobs(blobs, kind, move |blob| self.resolve_blob(blob)))
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        let blobs = self.backend.list_objects(ObjectKind::Chunk).await?;
+        Ok(resolve_blobs(blobs, kind, move |blob| self.resolve_blob(blob)))
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.backend.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.backend.ping().await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{format::manifest::ChunkPayload, ObjectStorage};
+
+    fn storages() -> (Arc<dyn Storage + Send + Sync>, EncryptingStorage) {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let encrypting =
+            EncryptingStorage::new(Arc::clone(&backend), EncryptionKey::random());
+        (backend, encrypting)
+    }
+
+    #[tokio::test]
+    async fn test_encryption_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let (backend, storage) = storages();
+
+        let snapshot_id = SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        storage.write_snapshot(snapshot_id.clone(), Arc::clone(&snapshot)).await?;
+        assert_eq!(storage.fetch_snapshot(&snapshot_id).await?, snapshot);
+
+        let manifest_id = ManifestId::random();
+        let manifest: Manifest = Manifest::new(
+            [(
+                (0, crate::format::ChunkIndices(vec![0])),
+                ChunkPayload::Inline("a".into()),
+            )]
+            .into(),
+        );
+        let manifest = Arc::new(manifest);
+        storage.write_manifests(manifest_id.clone(), Arc::clone(&manifest)).await?;
+        assert_eq!(storage.fetch_manifests(&manifest_id).await?, manifest);
+
+        let attributes_id = AttributesId::random();
+        let attributes = Arc::new(AttributesTable {});
+        storage.write_attributes(attributes_id.clone(), Arc::clone(&attributes)).await?;
+        assert_eq!(storage.fetch_attributes(&attributes_id).await?, attributes);
+
+        let chunk_id = ChunkId::random();
+        let secret = Bytes::from_static(b"a secret chunk");
+        storage.write_chunk(chunk_id.clone(), secret.clone()).await?;
+        assert_eq!(storage.fetch_chunk(&chunk_id, &ByteRange::ALL).await?, secret);
+        assert!(storage.exists(&AnyObjectId::Chunk(chunk_id.clone())).await?);
+        assert!(storage.exists(&AnyObjectId::Snapshot(snapshot_id.clone())).await?);
+
+        let ref_bytes = Bytes::from_static(b"a secret ref");
+        storage.write_ref("tag.v1/ref.json", false, ref_bytes.clone()).await?;
+        assert_eq!(storage.get_ref("tag.v1/ref.json").await?, ref_bytes);
+        let new_ref = Bytes::from_static(b"another secret");
+        assert!(
+            storage
+                .compare_and_swap_ref(
+                    "tag.v1/ref.json",
+                    Some(ref_bytes.clone()),
+                    new_ref.clone()
+                )
+                .await?
+        );
+        assert!(
+            !storage
+                .compare_and_swap_ref("tag.v1/ref.json", Some(ref_bytes), Bytes::new())
+                .await?
+        );
+        assert_eq!(storage.get_ref("tag.v1/ref.json").await?, new_ref);
+
+        // the backend never sees plain text, or the metadata objects
+        let stored = backend.fetch_chunk(&chunk_id, &ByteRange::ALL).await?;
+        assert!(!stored.windows(secret.len()).any(|w| w == secret));
+        let stored_ref = backend.get_ref("tag.v1/ref.json").await?;
+        assert!(!stored_ref.windows(new_ref.len()).any(|w| w == new_ref));
+        assert!(!backend.exists(&AnyObjectId::Snapshot(snapshot_id.clone())).await?);
+
+        // a different key can't read the objects
+        let other = EncryptingStorage::new(Arc::clone(&backend), EncryptionKey::random());
+        assert!(matches!(
+            other.fetch_snapshot(&snapshot_id).await,
+            Err(StorageError::Encryption(_))
+        ));
+        assert!(matches!(
+            other.fetch_chunk(&chunk_id, &ByteRange::ALL).await,
+            Err(StorageError::Encryption(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_identical_plaintext_is_stored_differently(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (backend, storage) = storages();
+        let plaintext = Bytes::from_static(b"the same bytes");
+        let (id1, id2) = (ChunkId::random(), ChunkId::random());
+        storage.write_chunk(id1.clone(), plaintext.clone()).await?;
+        storage.write_chunk(id2.clone(), plaintext.clone()).await?;
+        let first = backend.fetch_chunk(&id1, &ByteRange::ALL).await?;
+        assert_ne!(first, backend.fetch_chunk(&id2, &ByteRange::ALL).await?);
+
+        // writing the same object again uses a new nonce too
+        storage.write_chunk(id1.clone(), plaintext.clone()).await?;
+        let second = backend.fetch_chunk(&id1, &ByteRange::ALL).await?;
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+        assert_ne!(first, second);
+        assert_eq!(storage.fetch_chunk(&id1, &ByteRange::ALL).await?, plaintext);
+        assert_eq!(storage.fetch_chunk(&id2, &ByteRange::ALL).await?, plaintext);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ranged_reads_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, storage) = storages();
+        let chunk_id = ChunkId::random();
+        storage.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello world")).await?;
+
+        let err = storage.fetch_chunk(&chunk_id, &ByteRange::from_offset(6)).await;
+        match err {
+            Err(StorageError::Unsupported(msg)) => assert!(msg.contains("ranged reads")),
+            other => panic!("expected an unsupported error, got {other:?}"),
+        }
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"hello world")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listings_name_the_objects() -> Result<(), Box<dyn std::error::Error>> {
+        let (backend, storage) = storages();
+        let snapshot_id = SnapshotId::random();
+        storage.write_snapshot(snapshot_id.clone(), Arc::new(Snapshot::empty())).await?;
+        let manifest_id = ManifestId::random();
+        storage
+            .write_manifests(manifest_id.clone(), Arc::new(Manifest::default()))
+            .await?;
+        let attributes_id = AttributesId::random();
+        storage
+            .write_attributes(attributes_id.clone(), Arc::new(AttributesTable {}))
+            .await?;
+        // shorter than the header of an object
+        let (small, large) = (ChunkId::random(), ChunkId::random());
+        storage.write_chunk(small.clone(), Bytes::from_static(b"a")).await?;
+        storage.write_chunk(large.clone(), Bytes::from(vec![1; 100])).await?;
+
+        async fn listed(
+            storage: &EncryptingStorage,
+            kind: ObjectKind,
+        ) -> StorageResult<HashSet<AnyObjectId>> {
+            storage.list_objects(kind).await?.try_collect().await
+        }
+        assert_eq!(backend.list_objects(ObjectKind::Chunk).await?.count().await, 5);
+        assert_eq!(
+            listed(&storage, ObjectKind::Chunk).await?,
+            HashSet::from([AnyObjectId::Chunk(small), AnyObjectId::Chunk(large)])
+        );
+        assert_eq!(
+            listed(&storage, ObjectKind::Snapshot).await?,
+            HashSet::from([AnyObjectId::Snapshot(snapshot_id.clone())])
+        );
+        assert_eq!(
+            listed(&storage, ObjectKind::Manifest).await?,
+            HashSet::from([AnyObjectId::Manifest(manifest_id)])
+        );
+        assert_eq!(
+            listed(&storage, ObjectKind::Attributes).await?,
+            HashSet::from([AnyObjectId::Attributes(attributes_id)])
+        );
+
+        let modified: Vec<_> = storage
+            .list_modified(
+                ObjectKind::Snapshot,
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now() + std::time::Duration::from_secs(60),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(modified.len(), 1);
+        assert_eq!(modified[0].0, AnyObjectId::Snapshot(snapshot_id.clone()));
+
+        // a different key sees every blob as a chunk
+        let other = EncryptingStorage::new(Arc::clone(&backend), EncryptionKey::random());
+        assert_eq!(listed(&other, ObjectKind::Chunk).await?.len(), 5);
+        assert!(listed(&other, ObjectKind::Snapshot).await?.is_empty());
+
+        storage.delete_snapshot(&snapshot_id).await?;
+        assert!(listed(&storage, ObjectKind::Snapshot).await?.is_empty());
+        Ok(())
+    }
+}
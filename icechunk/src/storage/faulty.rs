@@ -0,0 +1,463 @@
+//! A [`Storage`] decorator to inject failures and delays in tests
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+
+use super::{AnyObjectId, ObjectKind, Storage, StorageError, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+    },
+    private, ObjectStorage,
+};
+
+/// A call to [`FaultyStorage`], as seen by its hooks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    /// The name of the [`Storage`] method
+    pub method: &'static str,
+    /// The object the call reads, writes or deletes, if any
+    pub object: Option<AnyObjectId>,
+    /// The ref key, or ref name, the call uses, if any
+    pub ref_key: Option<String>,
+}
+
+impl Call {
+    fn new(method: &'static str) -> Self {
+        Self { method, object: None, ref_key: None }
+    }
+
+    fn object(method: &'static str, id: AnyObjectId) -> Self {
+        Self { object: Some(id), ..Self::new(method) }
+    }
+
+    fn reference(method: &'static str, ref_key: &str) -> Self {
+        Self { ref_key: Some(ref_key.to_string()), ..Self::new(method) }
+    }
+}
+
+/// What a hook of [`FaultyStorage`] does with a call
+#[derive(Debug)]
+pub enum Fault {
+    /// Forward the call to the backend
+    Pass,
+    /// Forward the call to the backend after sleeping
+    Delay(Duration),
+    /// Fail the call, without reaching the backend
+    Error(StorageError),
+    /// Answer without reaching the backend, as if the target of the call didn't exist
+    ///
+    /// Reads don't find it, listings are empty, conditional writes don't apply, and other
+    /// writes and deletes do nothing.
+    Absent,
+}
+
+type Hook = Box<dyn Fn(&Call) -> Fault + Send + Sync>;
+
+/// Forwards to a backend, letting hooks fail, delay or drop each call
+///
+/// Hooks are registered per method with [`FaultyStorage::on`], or for every method with
+/// [`FaultyStorage::on_all`]. The first hook of a call that doesn't return [`Fault::Pass`]
+/// decides what happens to it. Every call is recorded, see [`FaultyStorage::take_calls`].
+///
+/// Methods the trait implements on top of others, like [`Storage::get_refs`] or
+/// [`Storage::ping`], keep their default implementation, so the hooks of the methods they
+/// call apply. Only available in tests, or with the `test-util` feature.
+pub struct FaultyStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    hooks: Vec<(Option<&'static str>, Hook)>,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl fmt::Debug for FaultyStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultyStorage")
+            .field("backend", &self.backend)
+            .field("hooks", &self.hooks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FaultyStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { backend, hooks: Vec::new(), calls: Mutex::new(Vec::new()) }
+    }
+
+    /// Forward to a new in memory storage
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(ObjectStorage::new_in_memory_store(None)))
+    }
+
+    /// Call `hook` for every call to `method`
+    pub fn on(
+        mut self,
+        method: &'static str,
+        hook: impl Fn(&Call) -> Fault + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.push((Some(method), Box::new(hook)));
+        self
+    }
+
+    /// Call `hook` for every call, to any method
+    pub fn on_all(
+        mut self,
+        hook: impl Fn(&Call) -> Fault + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.push((None, Box::new(hook)));
+        self
+    }
+
+    /// The calls received since the last time they were taken, in order, faulty or not
+    #[allow(clippy::expect_used)]
+    pub fn take_calls(&self) -> Vec<Call> {
+        std::mem::take(&mut *self.calls.lock().expect("poison lock"))
+    }
+
+    /// Run the hooks of `call`, then `forward` it or answer it with `absent`
+    #[allow(clippy::expect_used)]
+    async fn inject<R>(
+        &self,
+        call: Call,
+        absent: impl FnOnce() -> StorageResult<R>,
+        forward: impl Future<Output = StorageResult<R>>,
+    ) -> StorageResult<R> {
+        let fault = self
+            .hooks
+            .iter()
+            .filter(|(method, _)| method.is_none_or(|method| method == call.method))
+            .map(|(_, hook)| hook(&call))
+            .find(|fault| !matches!(fault, Fault::Pass));
+        self.calls.lock().expect("poison lock").push(call);
+        match fault {
+            None | Some(Fault::Pass) => forward.await,
+            Some(Fault::Delay(delay)) => {
+                tokio::time::sleep(delay).await;
+                forward.await
+            }
+            Some(Fault::Error(err)) => Err(err),
+            Some(Fault::Absent) => absent(),
+        }
+    }
+}
+
+fn not_found<R>(id: AnyObjectId) -> impl FnOnce() -> StorageResult<R> {
+    move || Err(StorageError::ObjectNotFound(id))
+}
+
+impl private::Sealed for FaultyStorage {}
+
+#[async_trait]
+impl Storage for FaultyStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let object = AnyObjectId::Snapshot(id.clone());
+        self.inject(
+            Call::object("fetch_snapshot", object.clone()),
+            not_found(object),
+            self.backend.fetch_snapshot(id),
+        )
+        .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let object = AnyObjectId::Attributes(id.clone());
+        self.inject(
+            Call::object("fetch_attributes", object.clone()),
+            not_found(object),
+            self.backend.fetch_attributes(id),
+        )
+        .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let object = AnyObjectId::Manifest(id.clone());
+        self.inject(
+            Call::object("fetch_manifests", object.clone()),
+            not_found(object),
+            self.backend.fetch_manifests(id),
+        )
+        .await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let object = AnyObjectId::Chunk(id.clone());
+        self.inject(
+            Call::object("fetch_chunk", object.clone()),
+            not_found(object),
+            self.backend.fetch_chunk(id, range),
+        )
+        .await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.inject(
+            Call::object("exists", id.clone()),
+            || Ok(false),
+            self.backend.exists(id),
+        )
+        .await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.inject(
+            Call::object("write_snapshot", AnyObjectId::Snapshot(id.clone())),
+            || Ok(()),
+            self.backend.write_snapshot(id, snapshot),
+        )
+        .await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.inject(
+            Call::object("write_attributes", AnyObjectId::Attributes(id.clone())),
+            || Ok(()),
+            self.backend.write_attributes(id, table),
+        )
+        .await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.inject(
+            Call::object("write_manifests", AnyObjectId::Manifest(id.clone())),
+            || Ok(()),
+            self.backend.write_manifests(id, table),
+        )
+        .await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.inject(
+            Call::object("write_chunk", AnyObjectId::Chunk(id.clone())),
+            || Ok(()),
+            self.backend.write_chunk(id, bytes),
+        )
+        .await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.inject(
+            Call::reference("get_ref", ref_key),
+            || Err(StorageError::RefNotFound(ref_key.to_string())),
+            self.backend.get_ref(ref_key),
+        )
+        .await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.inject(Call::new("ref_names"), || Ok(Vec::new()), self.backend.ref_names())
+            .await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.inject(
+            Call::new("list_modified"),
+            || Ok(futures::stream::empty().boxed()),
+            self.backend.list_modified(kind, from, to),
+        )
+        .await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.inject(
+            Call::new("list_objects"),
+            || Ok(futures::stream::empty().boxed()),
+            self.backend.list_objects(kind),
+        )
+        .await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.inject(
+            Call::reference("ref_versions", ref_name),
+            || Ok(futures::stream::empty().boxed()),
+            self.backend.ref_versions(ref_name),
+        )
+        .await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.inject(
+            Call::object("delete_chunk", AnyObjectId::Chunk(id.clone())),
+            || Ok(()),
+            self.backend.delete_chunk(id),
+        )
+        .await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.inject(
+            Call::object("delete_manifest", AnyObjectId::Manifest(id.clone())),
+            || Ok(()),
+            self.backend.delete_manifest(id),
+        )
+        .await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.inject(
+            Call::object("delete_snapshot", AnyObjectId::Snapshot(id.clone())),
+            || Ok(()),
+            self.backend.delete_snapshot(id),
+        )
+        .await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.inject(
+            Call::object("delete_attributes", AnyObjectId::Attributes(id.clone())),
+            || Ok(()),
+            self.backend.delete_attributes(id),
+        )
+        .await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.inject(
+            Call::reference("delete_ref_version", ref_name),
+            || Ok(()),
+            self.backend.delete_ref_version(ref_name, version_id),
+        )
+        .await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.inject(
+            Call::reference("write_ref", ref_key),
+            || Ok(()),
+            self.backend.write_ref(ref_key, overwrite_refs, bytes),
+        )
+        .await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.inject(
+            Call::reference("compare_and_swap_ref", ref_key),
+            || Ok(false),
+            self.backend.compare_and_swap_ref(ref_key, expected, new),
+        )
+        .await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.inject(
+            Call::new("backend_time"),
+            || Err(StorageError::Unsupported("backend_time".to_string())),
+            self.backend.backend_time(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hooks_decide_the_fault() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = FaultyStorage::in_memory()
+            .on("get_ref", |call| {
+                if call.ref_key.as_deref() == Some("branch.gone/ref.json") {
+                    Fault::Absent
+                } else {
+                    Fault::Pass
+                }
+            })
+            .on("compare_and_swap_ref", |_| Fault::Absent)
+            .on_all(|call| match call.method {
+                "write_chunk" => Fault::Error(StorageError::Other("full".to_string())),
+                _ => Fault::Pass,
+            });
+
+        storage.write_ref("branch.gone/ref.json", false, Bytes::from("a")).await?;
+        storage.write_ref("branch.main/ref.json", false, Bytes::from("b")).await?;
+        assert!(matches!(
+            storage.get_ref("branch.gone/ref.json").await,
+            Err(StorageError::RefNotFound(_))
+        ));
+        assert_eq!(storage.get_ref("branch.main/ref.json").await?, "b");
+        assert!(
+            !storage
+                .compare_and_swap_ref(
+                    "branch.main/ref.json",
+                    Some(Bytes::from("b")),
+                    Bytes::new()
+                )
+                .await?
+        );
+        assert_eq!(storage.get_ref("branch.main/ref.json").await?, "b");
+
+        let id = ChunkId::random();
+        assert!(matches!(
+            storage.write_chunk(id.clone(), Bytes::new()).await,
+            Err(StorageError::Other(msg)) if msg == "full"
+        ));
+        assert!(!storage.exists(&AnyObjectId::Chunk(id.clone())).await?);
+
+        let calls = storage.take_calls();
+        let methods: Vec<_> = calls.iter().map(|call| call.method).collect();
+        assert_eq!(
+            methods,
+            vec![
+                "write_ref",
+                "write_ref",
+                "get_ref",
+                "get_ref",
+                "compare_and_swap_ref",
+                "get_ref",
+                "write_chunk",
+                "exists"
+            ]
+        );
+        assert_eq!(calls[6].object, Some(AnyObjectId::Chunk(id)));
+        assert!(storage.take_calls().is_empty());
+        Ok(())
+    }
+}
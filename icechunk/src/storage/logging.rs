@@ -1,72 +1,259 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::stream::BoxStream;
 
-use super::{Storage, StorageError, StorageResult};
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageError, StorageResult};
 use crate::{
     format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
     },
     private,
 };
 
+/// A storage operation that took longer than the configured threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowOp {
+    pub operation: String,
+    /// The kind of object operated on, `None` for ref operations
+    pub kind: Option<ObjectKind>,
+    /// The object id, or the ref key for ref operations
+    pub id: Vec<u8>,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowOpConfig {
+    /// Operations taking longer than this are recorded
+    pub threshold: Duration,
+    /// How many slow operations to keep, only the slowest ones are retained
+    pub capacity: usize,
+}
+
 #[derive(Debug)]
 pub struct LoggingStorage {
     backend: Arc<dyn Storage + Send + Sync>,
+    /// Record every operation, the logs below grow without bound
+    log_operations: bool,
     fetch_log: Mutex<Vec<(String, Vec<u8>)>>,
+    write_log: Mutex<Vec<(String, AnyObjectId)>>,
+    ref_log: Mutex<Vec<(String, String)>>,
+    slow_op_config: Option<SlowOpConfig>,
+    slow_ops: Mutex<Vec<SlowOp>>,
 }
 
-#[cfg(test)]
 impl LoggingStorage {
+    fn build(
+        backend: Arc<dyn Storage + Send + Sync>,
+        log_operations: bool,
+        slow_op_config: Option<SlowOpConfig>,
+    ) -> Self {
+        Self {
+            backend,
+            log_operations,
+            fetch_log: Mutex::new(Vec::new()),
+            write_log: Mutex::new(Vec::new()),
+            ref_log: Mutex::new(Vec::new()),
+            slow_op_config,
+            slow_ops: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Create a [`LoggingStorage`] that records every operation, in order
+    #[cfg(any(test, feature = "test-util"))]
     pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
-        Self { backend, fetch_log: Mutex::new(Vec::new()) }
+        Self::build(backend, true, None)
+    }
+
+    /// Create a [`LoggingStorage`] that only keeps track of the slowest operations
+    ///
+    /// Only the `config.capacity` slowest operations exceeding `config.threshold` are kept.
+    pub fn with_slow_op_tracking(
+        backend: Arc<dyn Storage + Send + Sync>,
+        config: SlowOpConfig,
+    ) -> Self {
+        Self::build(backend, false, Some(config))
     }
 
-    #[allow(clippy::expect_used)] // this implementation is intended for tests only
+    #[cfg(any(test, feature = "test-util"))]
+    #[allow(clippy::expect_used)]
     pub fn fetch_operations(&self) -> Vec<(String, Vec<u8>)> {
         self.fetch_log.lock().expect("poison lock").clone()
     }
+
+    /// The object writes done so far, in order, with the id of the object written
+    #[cfg(any(test, feature = "test-util"))]
+    #[allow(clippy::expect_used)]
+    pub fn write_operations(&self) -> Vec<(String, AnyObjectId)> {
+        self.write_log.lock().expect("poison lock").clone()
+    }
+
+    /// The ref reads and writes done so far, in order, with the key of the ref
+    #[cfg(any(test, feature = "test-util"))]
+    #[allow(clippy::expect_used)]
+    pub fn ref_operations(&self) -> Vec<(String, String)> {
+        self.ref_log.lock().expect("poison lock").clone()
+    }
+
+    #[allow(clippy::expect_used)]
+    fn log_fetch(&self, operation: &str, id: &[u8]) {
+        if self.log_operations {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push((operation.to_string(), id.to_vec()));
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    fn log_write(&self, operation: &str, id: AnyObjectId) {
+        if self.log_operations {
+            self.write_log.lock().expect("poison lock").push((operation.to_string(), id));
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    fn log_ref(&self, operation: &str, ref_key: &str) {
+        if self.log_operations {
+            self.ref_log
+                .lock()
+                .expect("poison lock")
+                .push((operation.to_string(), ref_key.to_string()));
+        }
+    }
+
+    /// The slowest operations observed so far, slowest first
+    #[allow(clippy::expect_used)]
+    pub fn slowest_operations(&self) -> Vec<SlowOp> {
+        self.slow_ops.lock().expect("poison lock").clone()
+    }
+
+    async fn timed<R>(
+        &self,
+        operation: &str,
+        kind: Option<ObjectKind>,
+        id: &[u8],
+        fut: impl Future<Output = R>,
+    ) -> R {
+        let start = Instant::now();
+        let res = fut.await;
+        self.record_duration(operation, kind, id, start.elapsed());
+        res
+    }
+
+    #[allow(clippy::expect_used)]
+    fn record_duration(
+        &self,
+        operation: &str,
+        kind: Option<ObjectKind>,
+        id: &[u8],
+        duration: Duration,
+    ) {
+        let Some(config) = &self.slow_op_config else { return };
+        if duration <= config.threshold {
+            return;
+        }
+        let mut ops = self.slow_ops.lock().expect("poison lock");
+        // the buffer is kept sorted, slowest first
+        let ix = ops.partition_point(|op| op.duration >= duration);
+        if ix < config.capacity {
+            ops.insert(
+                ix,
+                SlowOp {
+                    operation: operation.to_string(),
+                    kind,
+                    id: id.to_vec(),
+                    duration,
+                },
+            );
+            ops.truncate(config.capacity);
+        }
+    }
 }
 
 impl private::Sealed for LoggingStorage {}
 
 #[async_trait]
-#[allow(clippy::expect_used)] // this implementation is intended for tests only
 impl Storage for LoggingStorage {
     async fn fetch_snapshot(
         &self,
         id: &SnapshotId,
     ) -> Result<Arc<Snapshot>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_snapshot".to_string(), id.0.to_vec()));
-        self.backend.fetch_snapshot(id).await
+        self.log_fetch("fetch_snapshot", &id.0);
+        self.timed(
+            "fetch_snapshot",
+            Some(ObjectKind::Snapshot),
+            &id.0,
+            self.backend.fetch_snapshot(id),
+        )
+        .await
     }
 
     async fn fetch_attributes(
         &self,
         id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_attributes".to_string(), id.0.to_vec()));
-        self.backend.fetch_attributes(id).await
+        self.log_fetch("fetch_attributes", &id.0);
+        self.timed(
+            "fetch_attributes",
+            Some(ObjectKind::Attributes),
+            &id.0,
+            self.backend.fetch_attributes(id),
+        )
+        .await
     }
 
     async fn fetch_manifests(
         &self,
         id: &ManifestId,
     ) -> Result<Arc<Manifest>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_manifests".to_string(), id.0.to_vec()));
-        self.backend.fetch_manifests(id).await
+        self.log_fetch("fetch_manifests", &id.0);
+        self.timed(
+            "fetch_manifests",
+            Some(ObjectKind::Manifest),
+            &id.0,
+            self.backend.fetch_manifests(id),
+        )
+        .await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.log_fetch("fetch_node_chunks", &manifest_id.0);
+        self.timed(
+            "fetch_node_chunks",
+            Some(ObjectKind::Manifest),
+            &manifest_id.0,
+            self.backend.fetch_node_chunks(manifest_id, node),
+        )
+        .await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.log_fetch("fetch_chunk_info", &manifest_id.0);
+        self.timed(
+            "fetch_chunk_info",
+            Some(ObjectKind::Manifest),
+            &manifest_id.0,
+            self.backend.fetch_chunk_info(manifest_id, node, coord),
+        )
+        .await
     }
 
     async fn fetch_chunk(
@@ -74,11 +261,19 @@ impl Storage for LoggingStorage {
         id: &ChunkId,
         range: &ByteRange,
     ) -> Result<Bytes, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_chunk".to_string(), id.0.to_vec()));
-        self.backend.fetch_chunk(id, range).await
+        self.log_fetch("fetch_chunk", &id.0);
+        self.timed(
+            "fetch_chunk",
+            Some(ObjectKind::Chunk),
+            &id.0,
+            self.backend.fetch_chunk(id, range),
+        )
+        .await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.timed("exists", Some(id.kind()), id.as_bytes(), self.backend.exists(id))
+            .await
     }
 
     async fn write_snapshot(
@@ -86,7 +281,15 @@ impl Storage for LoggingStorage {
         id: SnapshotId,
         table: Arc<Snapshot>,
     ) -> Result<(), StorageError> {
-        self.backend.write_snapshot(id, table).await
+        self.log_write("write_snapshot", AnyObjectId::Snapshot(id.clone()));
+        let oid = id.0;
+        self.timed(
+            "write_snapshot",
+            Some(ObjectKind::Snapshot),
+            &oid,
+            self.backend.write_snapshot(id, table),
+        )
+        .await
     }
 
     async fn write_attributes(
@@ -94,7 +297,15 @@ impl Storage for LoggingStorage {
         id: AttributesId,
         table: Arc<AttributesTable>,
     ) -> Result<(), StorageError> {
-        self.backend.write_attributes(id, table).await
+        self.log_write("write_attributes", AnyObjectId::Attributes(id.clone()));
+        let oid = id.0;
+        self.timed(
+            "write_attributes",
+            Some(ObjectKind::Attributes),
+            &oid,
+            self.backend.write_attributes(id, table),
+        )
+        .await
     }
 
     async fn write_manifests(
@@ -102,19 +313,184 @@ impl Storage for LoggingStorage {
         id: ManifestId,
         table: Arc<Manifest>,
     ) -> Result<(), StorageError> {
-        self.backend.write_manifests(id, table).await
+        self.log_write("write_manifests", AnyObjectId::Manifest(id.clone()));
+        let oid = id.0;
+        self.timed(
+            "write_manifests",
+            Some(ObjectKind::Manifest),
+            &oid,
+            self.backend.write_manifests(id, table),
+        )
+        .await
     }
 
     async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
-        self.backend.write_chunk(id, bytes).await
+        self.log_write("write_chunk", AnyObjectId::Chunk(id.clone()));
+        let oid = id.0;
+        self.timed(
+            "write_chunk",
+            Some(ObjectKind::Chunk),
+            &oid,
+            self.backend.write_chunk(id, bytes),
+        )
+        .await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        let oid = id.clone();
+        let written = self
+            .timed(
+                "write_chunk_if_absent",
+                Some(ObjectKind::Chunk),
+                &oid.0,
+                self.backend.write_chunk_if_absent(id, bytes),
+            )
+            .await?;
+        // a chunk that was already there wasn't written
+        if written {
+            self.log_write("write_chunk_if_absent", AnyObjectId::Chunk(oid));
+        }
+        Ok(written)
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        // the id is only known once the chunk is written
+        let id = self
+            .timed(
+                "write_new_chunk",
+                Some(ObjectKind::Chunk),
+                &[],
+                self.backend.write_new_chunk(bytes),
+            )
+            .await?;
+        // recorded as a chunk write, whatever way the backend picked the id
+        self.log_write("write_chunk", AnyObjectId::Chunk(id.clone()));
+        Ok(id)
     }
 
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
-        self.backend.get_ref(ref_key).await
+        self.log_ref("get_ref", ref_key);
+        self.timed("get_ref", None, ref_key.as_bytes(), self.backend.get_ref(ref_key))
+            .await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        for key in keys {
+            self.log_ref("get_refs", key);
+        }
+        self.timed(
+            "get_refs",
+            None,
+            keys.join("\n").as_bytes(),
+            self.backend.get_refs(keys),
+        )
+        .await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.log_ref("get_ref_if_changed", ref_key);
+        self.timed(
+            "get_ref_if_changed",
+            None,
+            ref_key.as_bytes(),
+            self.backend.get_ref_if_changed(ref_key, etag),
+        )
+        .await
     }
 
     async fn ref_names(&self) -> StorageResult<Vec<String>> {
-        self.backend.ref_names().await
+        self.timed("ref_names", None, &[], self.backend.ref_names()).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.timed(
+            "list_modified",
+            Some(kind),
+            &[],
+            self.backend.list_modified(kind, from, to),
+        )
+        .await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.timed("list_objects", Some(kind), &[], self.backend.list_objects(kind)).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        let oid = id.0;
+        self.timed(
+            "delete_chunk",
+            Some(ObjectKind::Chunk),
+            &oid,
+            self.backend.delete_chunk(id),
+        )
+        .await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        let oid = id.0;
+        self.timed(
+            "delete_manifest",
+            Some(ObjectKind::Manifest),
+            &oid,
+            self.backend.delete_manifest(id),
+        )
+        .await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        let oid = id.0;
+        self.timed(
+            "delete_snapshot",
+            Some(ObjectKind::Snapshot),
+            &oid,
+            self.backend.delete_snapshot(id),
+        )
+        .await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        let oid = id.0;
+        self.timed(
+            "delete_attributes",
+            Some(ObjectKind::Attributes),
+            &oid,
+            self.backend.delete_attributes(id),
+        )
+        .await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.timed(
+            "delete_ref_version",
+            None,
+            ref_name.as_bytes(),
+            self.backend.delete_ref_version(ref_name, version_id),
+        )
+        .await
     }
 
     async fn write_ref(
@@ -123,13 +499,206 @@ impl Storage for LoggingStorage {
         overwrite_refs: bool,
         bytes: Bytes,
     ) -> StorageResult<()> {
-        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+        self.log_ref("write_ref", ref_key);
+        self.timed(
+            "write_ref",
+            None,
+            ref_key.as_bytes(),
+            self.backend.write_ref(ref_key, overwrite_refs, bytes),
+        )
+        .await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.log_ref("compare_and_swap_ref", ref_key);
+        self.timed(
+            "compare_and_swap_ref",
+            None,
+            ref_key.as_bytes(),
+            self.backend.compare_and_swap_ref(ref_key, expected, new),
+        )
+        .await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.timed("backend_time", None, &[], self.backend.backend_time()).await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.timed("ping", None, &[], self.backend.ping()).await
     }
 
     async fn ref_versions(
         &self,
         ref_name: &str,
     ) -> StorageResult<BoxStream<StorageResult<String>>> {
-        self.backend.ref_versions(ref_name).await
+        self.timed(
+            "ref_versions",
+            None,
+            ref_name.as_bytes(),
+            self.backend.ref_versions(ref_name),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, num::NonZeroU64};
+
+    use super::*;
+
+    use crate::{
+        format::{
+            manifest::{ChunkPayload, ChunkRef},
+            Path,
+        },
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        storage::{
+            faulty::{Fault, FaultyStorage},
+            ObjectStorage,
+        },
+        Repository,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_slowest_operations_are_recorded(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let fast = ChunkId::random();
+        let slow = ChunkId::random();
+        let slower = ChunkId::random();
+        let slowest = ChunkId::random();
+        let delays = HashMap::from([
+            (slow.clone(), Duration::from_millis(60)),
+            (slower.clone(), Duration::from_millis(120)),
+            (slowest.clone(), Duration::from_millis(200)),
+        ]);
+        // delays chunk fetches by a fixed amount per chunk id
+        let backend =
+            FaultyStorage::in_memory().on("fetch_chunk", move |call| {
+                match &call.object {
+                    Some(AnyObjectId::Chunk(id)) => {
+                        delays.get(id).map_or(Fault::Pass, |delay| Fault::Delay(*delay))
+                    }
+                    _ => Fault::Pass,
+                }
+            });
+        for id in [&fast, &slow, &slower, &slowest] {
+            backend.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        }
+
+        let logging = LoggingStorage::with_slow_op_tracking(
+            Arc::new(backend),
+            SlowOpConfig { threshold: Duration::from_millis(40), capacity: 2 },
+        );
+        for id in [&slower, &fast, &slowest, &slow] {
+            logging.fetch_chunk(id, &ByteRange::ALL).await?;
+        }
+
+        let slow_ops = logging.slowest_operations();
+        assert_eq!(
+            slow_ops
+                .iter()
+                .map(|op| (op.operation.as_str(), op.kind, op.id.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("fetch_chunk", Some(ObjectKind::Chunk), slowest.0.to_vec()),
+                ("fetch_chunk", Some(ObjectKind::Chunk), slower.0.to_vec()),
+            ]
+        );
+        assert!(slow_ops[0].duration >= slow_ops[1].duration);
+        assert!(slow_ops[1].duration >= Duration::from_millis(120));
+
+        // slow op tracking alone doesn't log every operation
+        assert!(logging.fetch_operations().is_empty());
+
+        // without tracking nothing is recorded
+        let logging = LoggingStorage::new(Arc::clone(&logging.backend));
+        logging.fetch_chunk(&slowest, &ByteRange::ALL).await?;
+        assert!(logging.slowest_operations().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_and_ref_operations_are_recorded(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(backend));
+        let storage: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        repo.add_group(Path::root()).await?;
+        let init = repo.snapshot_id().clone();
+        let first = repo.commit("main", "first", None).await?;
+        assert_eq!(
+            logging.write_operations(),
+            vec![
+                ("write_snapshot".to_string(), AnyObjectId::Snapshot(init)),
+                ("write_snapshot".to_string(), AnyObjectId::Snapshot(first)),
+            ]
+        );
+        // the commit moved the branch to a new version
+        let refs = logging.ref_operations();
+        assert_eq!(refs.iter().filter(|(op, _)| op == "write_ref").count(), 2);
+        assert!(refs.iter().all(|(_, key)| key.starts_with("branch.main/")));
+
+        // batched reads record every key
+        storage.get_refs(&["branch.main/ZZZZZZZZ.json", "tag.missing/ref.json"]).await?;
+        let batched: Vec<_> = logging.ref_operations().split_off(refs.len());
+        assert_eq!(
+            batched,
+            vec![
+                ("get_refs".to_string(), "branch.main/ZZZZZZZZ.json".to_string()),
+                ("get_refs".to_string(), "tag.missing/ref.json".to_string()),
+            ]
+        );
+
+        let path: Path = "/array".try_into()?;
+        repo.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![1],
+                data_type: DataType::Int32,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int32(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+            },
+        )
+        .await?;
+        let payload = repo.get_chunk_writer()(Bytes::from(vec![0; 1_000])).await?;
+        let ChunkPayload::Ref(ChunkRef { id: chunk_id, .. }) = &payload else {
+            panic!("chunk should not be inlined");
+        };
+        let chunk_id = chunk_id.clone();
+        repo.set_chunk_ref(path, ChunkIndices(vec![0]), Some(payload)).await?;
+        let second = repo.commit("main", "second", None).await?;
+
+        let writes = logging.write_operations();
+        let ops: Vec<_> = writes[2..].iter().map(|(op, _)| op.as_str()).collect();
+        assert_eq!(ops, vec!["write_chunk", "write_manifests", "write_snapshot"]);
+        assert_eq!(writes[2].1, AnyObjectId::Chunk(chunk_id));
+        assert_eq!(writes[4].1, AnyObjectId::Snapshot(second));
+
+        // conditional chunk writes are only recorded when they happen
+        let id = ChunkId::random();
+        for _ in 0..2 {
+            storage.write_chunk_if_absent(id.clone(), Bytes::from_static(b"x")).await?;
+        }
+        assert_eq!(
+            logging.write_operations()[5..],
+            [("write_chunk_if_absent".to_string(), AnyObjectId::Chunk(id))]
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,565 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future::ready,
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
+    },
+    private,
+};
+
+/// An object that was read from the old layout, but couldn't be copied to the new one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationFailure {
+    pub id: AnyObjectId,
+    pub error: String,
+}
+
+/// A [`Storage`] decorator to read a repository while it moves between key layouts
+///
+/// The two layouts are two backends, usually on the same bucket, for example
+/// [`super::ObjectStorage`] instances with different prefixes or
+/// [`super::object_store::KeyEncoding`]. Objects are fetched from the new layout first, and
+/// from the old one if they are not found. Writes always go to the new layout.
+///
+/// With lazy migration enabled, objects found in the old layout are also written to the new
+/// one, so they are migrated as they are used. This is done on a best effort basis, failures
+/// don't fail the read, they are recorded and can be inspected with
+/// [`MigratingStorage::migration_failures`].
+///
+/// The keys of refs don't depend on the layout, so refs are only read and written in the new
+/// layout. Listings merge both layouts, objects in both are listed once, from the new one.
+#[derive(Debug)]
+pub struct MigratingStorage {
+    new: Arc<dyn Storage + Send + Sync>,
+    old: Arc<dyn Storage + Send + Sync>,
+    lazy_migration: bool,
+    migrated: AtomicUsize,
+    failures: Mutex<Vec<MigrationFailure>>,
+}
+
+impl MigratingStorage {
+    pub fn new(
+        new: Arc<dyn Storage + Send + Sync>,
+        old: Arc<dyn Storage + Send + Sync>,
+    ) -> Self {
+        Self {
+            new,
+            old,
+            lazy_migration: false,
+            migrated: AtomicUsize::new(0),
+            failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Write the objects read from the old layout to the new one
+    pub fn with_lazy_migration(self, lazy_migration: bool) -> Self {
+        Self { lazy_migration, ..self }
+    }
+
+    /// How many objects have been lazily migrated to the new layout
+    pub fn migrated_objects(&self) -> usize {
+        self.migrated.load(Ordering::Relaxed)
+    }
+
+    #[allow(clippy::expect_used)]
+    pub fn migration_failures(&self) -> Vec<MigrationFailure> {
+        self.failures.lock().expect("poison lock").clone()
+    }
+
+    #[allow(clippy::expect_used)]
+    fn record_migration(&self, id: AnyObjectId, res: StorageResult<()>) {
+        match res {
+            Ok(()) => {
+                self.migrated.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => self
+                .failures
+                .lock()
+                .expect("poison lock")
+                .push(MigrationFailure { id, error: err.to_string() }),
+        }
+    }
+
+    /// The objects of `kind` in the new layout
+    async fn migrated(&self, kind: ObjectKind) -> StorageResult<HashSet<AnyObjectId>> {
+        self.new.list_objects(kind).await?.try_collect().await
+    }
+}
+
+impl private::Sealed for MigratingStorage {}
+
+#[async_trait]
+impl Storage for MigratingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        match self.new.fetch_snapshot(id).await {
+            Err(err) if err.is_not_found() => {
+                let snapshot = self.old.fetch_snapshot(id).await?;
+                if self.lazy_migration {
+                    let res =
+                        self.new.write_snapshot(id.clone(), Arc::clone(&snapshot)).await;
+                    self.record_migration(AnyObjectId::Snapshot(id.clone()), res);
+                }
+                Ok(snapshot)
+            }
+            res => res,
+        }
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        match self.new.fetch_attributes(id).await {
+            Err(err) if err.is_not_found() => {
+                let table = self.old.fetch_attributes(id).await?;
+                if self.lazy_migration {
+                    let res =
+                        self.new.write_attributes(id.clone(), Arc::clone(&table)).await;
+                    self.record_migration(AnyObjectId::Attributes(id.clone()), res);
+                }
+                Ok(table)
+            }
+            res => res,
+        }
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        match self.new.fetch_manifests(id).await {
+            Err(err) if err.is_not_found() => {
+                let manifest = self.old.fetch_manifests(id).await?;
+                if self.lazy_migration {
+                    let res =
+                        self.new.write_manifests(id.clone(), Arc::clone(&manifest)).await;
+                    self.record_migration(AnyObjectId::Manifest(id.clone()), res);
+                }
+                Ok(manifest)
+            }
+            res => res,
+        }
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        match self.new.fetch_chunk_info(manifest_id, node, coord).await {
+            Err(err) if err.is_not_found() => {
+                if self.lazy_migration {
+                    // the whole manifest is needed to migrate it
+                    let manifest = self.fetch_manifests(manifest_id).await?;
+                    Ok(manifest.get_chunk_info(node, coord))
+                } else {
+                    self.old.fetch_chunk_info(manifest_id, node, coord).await
+                }
+            }
+            res => res,
+        }
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        match self.new.fetch_node_chunks(manifest_id, node).await {
+            Err(err) if err.is_not_found() => {
+                if self.lazy_migration {
+                    let manifest = self.fetch_manifests(manifest_id).await?;
+                    Ok(Arc::new(manifest.node_manifest(node)))
+                } else {
+                    self.old.fetch_node_chunks(manifest_id, node).await
+                }
+            }
+            res => res,
+        }
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        match self.new.fetch_chunk(id, range).await {
+            Err(err) if err.is_not_found() => {
+                if self.lazy_migration {
+                    let bytes = self.old.fetch_chunk(id, &ByteRange::ALL).await?;
+                    let res = self.new.write_chunk(id.clone(), bytes.clone()).await;
+                    self.record_migration(AnyObjectId::Chunk(id.clone()), res);
+                    Ok(range.slice(bytes))
+                } else {
+                    self.old.fetch_chunk(id, range).await
+                }
+            }
+            res => res,
+        }
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        Ok(self.new.exists(id).await? || self.old.exists(id).await?)
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.new.write_snapshot(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.new.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.new.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        self.new.write_manifests_if_not_exists(id, manifest).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.new.write_chunk(id, bytes).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        self.new.write_chunk_if_absent(id, bytes).await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        self.new.write_new_chunk(bytes).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.new.get_ref(ref_key).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.new.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.new.get_refs(keys).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.new.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.new.ref_versions(ref_name).await
+    }
+
+    // the object is deleted from both layouts, so reads can't fall back to the old one
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.new.delete_chunk(id).await?;
+        self.old.delete_chunk(id).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.new.delete_manifest(id).await?;
+        self.old.delete_manifest(id).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.new.delete_snapshot(id).await?;
+        self.old.delete_snapshot(id).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.new.delete_attributes(id).await?;
+        self.old.delete_attributes(id).await
+    }
+
+    // refs are only read from the new layout
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.new.delete_ref_version(ref_name, version_id).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.new.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.new.compare_and_swap_ref(ref_key, expected, new).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        // objects copied to the new layout have its modification time, even outside the range
+        let migrated = self.migrated(kind).await?;
+        let old = self
+            .old
+            .list_modified(kind, from, to)
+            .await?
+            .try_filter(move |(id, _)| ready(!migrated.contains(id)));
+        Ok(self.new.list_modified(kind, from, to).await?.chain(old).boxed())
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        let migrated = self.migrated(kind).await?;
+        let new: Vec<_> = migrated.iter().cloned().map(Ok).collect();
+        let old = self
+            .old
+            .list_objects(kind)
+            .await?
+            .try_filter(move |id| ready(!migrated.contains(id)));
+        Ok(stream::iter(new).chain(old).boxed())
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.new.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        // reads can fall back to the old layout, so it must be reachable too
+        self.new.ping().await?;
+        self.old.ping().await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        self.new.record_ref_version(ref_name, bytes).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+    use crate::{
+        format::manifest::{ChunkPayload, ChunkRef},
+        storage::{
+            faulty::{Fault, FaultyStorage},
+            object_store::KeyEncoding,
+            ObjectStorage,
+        },
+    };
+
+    /// Old and new layouts of the same bucket
+    fn layouts() -> (Arc<ObjectStorage>, Arc<ObjectStorage>) {
+        let store = Arc::new(InMemory::new());
+        let old = ObjectStorage::from_object_store(store.clone(), "repo".to_string());
+        let new = ObjectStorage::from_object_store(store, "repo".to_string())
+            .with_key_encoding(KeyEncoding::HexLower);
+        (Arc::new(old), Arc::new(new))
+    }
+
+    fn manifest(chunk: &ChunkId) -> Manifest {
+        [ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![0]),
+            payload: ChunkPayload::Ref(ChunkRef {
+                id: chunk.clone(),
+                offset: 0,
+                length: 5,
+            }),
+            uncompressed_size: None,
+        }]
+        .into_iter()
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reads_fall_back_to_the_old_layout(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (old, new) = layouts();
+        let old_chunk = ChunkId::random();
+        let new_chunk = ChunkId::random();
+        let manifest_id = ManifestId::random();
+        old.write_chunk(old_chunk.clone(), Bytes::from_static(b"older")).await?;
+        old.write_manifests(manifest_id.clone(), Arc::new(manifest(&old_chunk))).await?;
+        new.write_chunk(new_chunk.clone(), Bytes::from_static(b"newer")).await?;
+
+        let storage = MigratingStorage::new(new.clone(), old.clone());
+        assert_eq!(storage.fetch_chunk(&old_chunk, &ByteRange::ALL).await?, "older");
+        assert_eq!(storage.fetch_chunk(&new_chunk, &ByteRange::ALL).await?, "newer");
+        assert_eq!(
+            storage.fetch_chunk(&old_chunk, &ByteRange::bounded(1, 3)).await?,
+            "ld"
+        );
+        assert_eq!(*storage.fetch_manifests(&manifest_id).await?, manifest(&old_chunk));
+        assert!(storage
+            .fetch_chunk_info(&manifest_id, 1, &ChunkIndices(vec![0]))
+            .await?
+            .is_some());
+        assert!(storage.exists(&AnyObjectId::Chunk(old_chunk.clone())).await?);
+        // missing objects still fail
+        assert!(storage.fetch_chunk(&ChunkId::random(), &ByteRange::ALL).await.is_err());
+
+        // without lazy migration nothing moves
+        assert_eq!(storage.migrated_objects(), 0);
+        assert!(!new.exists(&AnyObjectId::Chunk(old_chunk)).await?);
+        assert!(!new.exists(&AnyObjectId::Manifest(manifest_id)).await?);
+
+        // writes go to the new layout
+        let written = ChunkId::random();
+        storage.write_chunk(written.clone(), Bytes::from_static(b"hello")).await?;
+        assert!(new.exists(&AnyObjectId::Chunk(written.clone())).await?);
+        assert!(!old.exists(&AnyObjectId::Chunk(written)).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_object_not_found_falls_back_to_the_old_layout(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (old, new) = layouts();
+        let chunk = ChunkId::random();
+        old.write_chunk(chunk.clone(), Bytes::from_static(b"older")).await?;
+        // wrappers like the splitting storage report missing objects by id
+        let new = FaultyStorage::new(new).on("fetch_chunk", |_| Fault::Absent);
+        let storage = MigratingStorage::new(Arc::new(new), old);
+        assert_eq!(storage.fetch_chunk(&chunk, &ByteRange::ALL).await?, "older");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lazy_migration() -> Result<(), Box<dyn std::error::Error>> {
+        let (old, new) = layouts();
+        let chunks: Vec<_> = (0..4).map(|_| ChunkId::random()).collect();
+        for (i, id) in chunks.iter().enumerate() {
+            // half the chunks were already migrated
+            let layout = if i % 2 == 0 { &old } else { &new };
+            layout.write_chunk(id.clone(), Bytes::from(vec![i as u8; 4])).await?;
+        }
+        let manifest_id = ManifestId::random();
+        old.write_manifests(manifest_id.clone(), Arc::new(manifest(&chunks[0]))).await?;
+
+        let storage =
+            MigratingStorage::new(new.clone(), old.clone()).with_lazy_migration(true);
+        for (i, id) in chunks.iter().enumerate() {
+            assert_eq!(
+                storage.fetch_chunk(id, &ByteRange::bounded(0, 2)).await?,
+                Bytes::from(vec![i as u8; 2])
+            );
+        }
+        assert!(storage
+            .fetch_chunk_info(&manifest_id, 1, &ChunkIndices(vec![0]))
+            .await?
+            .is_some());
+        assert_eq!(storage.migrated_objects(), 3);
+        assert!(storage.migration_failures().is_empty());
+
+        // all objects can now be read from the new layout alone, with their full contents
+        for (i, id) in chunks.iter().enumerate() {
+            assert_eq!(
+                new.fetch_chunk(id, &ByteRange::ALL).await?,
+                Bytes::from(vec![i as u8; 4])
+            );
+        }
+        assert_eq!(*new.fetch_manifests(&manifest_id).await?, manifest(&chunks[0]));
+
+        // migrated objects are then served by the new layout
+        storage.fetch_chunk(&chunks[0], &ByteRange::ALL).await?;
+        assert_eq!(storage.migrated_objects(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listings_merge_the_layouts() -> Result<(), Box<dyn std::error::Error>> {
+        let (old, new) = layouts();
+        let (only_old, both, only_new) =
+            (ChunkId::random(), ChunkId::random(), ChunkId::random());
+        old.write_chunk(only_old.clone(), Bytes::from_static(b"old")).await?;
+        old.write_chunk(both.clone(), Bytes::from_static(b"both")).await?;
+        new.write_chunk(both.clone(), Bytes::from_static(b"both")).await?;
+        new.write_chunk(only_new.clone(), Bytes::from_static(b"new")).await?;
+        let manifest_id = ManifestId::random();
+        old.write_manifests(manifest_id.clone(), Arc::new(manifest(&only_old))).await?;
+
+        let storage = MigratingStorage::new(new.clone(), old.clone());
+        let mut chunks: Vec<_> =
+            storage.list_objects(ObjectKind::Chunk).await?.try_collect().await?;
+        chunks.sort();
+        let mut expected: Vec<_> =
+            [only_old, both, only_new].into_iter().map(AnyObjectId::Chunk).collect();
+        expected.sort();
+        assert_eq!(chunks, expected);
+        let manifests: Vec<_> =
+            storage.list_objects(ObjectKind::Manifest).await?.try_collect().await?;
+        assert_eq!(manifests, vec![AnyObjectId::Manifest(manifest_id)]);
+
+        let mut modified: Vec<_> = storage
+            .list_modified(
+                ObjectKind::Chunk,
+                SystemTime::UNIX_EPOCH,
+                SystemTime::now() + std::time::Duration::from_secs(60),
+            )
+            .await?
+            .map_ok(|(id, _)| id)
+            .try_collect()
+            .await?;
+        modified.sort();
+        assert_eq!(modified, expected);
+        Ok(())
+    }
+}
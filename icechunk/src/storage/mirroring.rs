@@ -0,0 +1,478 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use tokio::sync::{mpsc, oneshot};
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageError, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
+    },
+    private,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorConfig {
+    /// How many writes can be waiting to be mirrored, writes block when the queue is full
+    pub queue_depth: usize,
+    /// How many mirroring failures to keep, the oldest ones are dropped first
+    pub failure_capacity: usize,
+}
+
+/// A write that couldn't be mirrored to the secondary backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorFailure {
+    /// The kind of object written, `None` for ref writes
+    pub kind: Option<ObjectKind>,
+    /// The object id, or the ref key for ref writes
+    pub id: Vec<u8>,
+    pub error: String,
+}
+
+#[derive(Debug)]
+enum MirrorOp {
+    Snapshot(SnapshotId, Arc<Snapshot>),
+    Attributes(AttributesId, Arc<AttributesTable>),
+    Manifest(ManifestId, Arc<Manifest>),
+    Chunk(ChunkId, Bytes),
+    Ref(String, Bytes),
+    /// Notifies when all the previous operations have been mirrored
+    Drain(oneshot::Sender<()>),
+}
+
+/// A [`Storage`] decorator that copies every write to a secondary backend in the background
+///
+/// Writes return as soon as they succeed in the primary backend, they are then mirrored in
+/// order by a background task. Reads are always served by the primary. Mirroring failures are
+/// not retried, they are logged and the most recent ones can be inspected with
+/// [`MirroringStorage::mirror_failures`].
+///
+/// Must be created from within a Tokio runtime.
+#[derive(Debug)]
+pub struct MirroringStorage {
+    primary: Arc<dyn Storage + Send + Sync>,
+    queue: mpsc::Sender<MirrorOp>,
+    failures: Arc<Mutex<VecDeque<MirrorFailure>>>,
+}
+
+impl MirroringStorage {
+    pub fn new(
+        primary: Arc<dyn Storage + Send + Sync>,
+        secondary: Arc<dyn Storage + Send + Sync>,
+        config: MirrorConfig,
+    ) -> Self {
+        let (queue, receiver) = mpsc::channel(config.queue_depth.max(1));
+        let failures = Arc::new(Mutex::new(VecDeque::new()));
+        tokio::spawn(mirror_writes(
+            secondary,
+            receiver,
+            Arc::clone(&failures),
+            config.failure_capacity,
+        ));
+        Self { primary, queue, failures }
+    }
+
+    /// Wait until all the writes done so far have been mirrored, or failed to mirror
+    pub async fn drain_mirror(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.queue.send(MirrorOp::Drain(done)).await.is_ok() {
+            // an error means the mirroring task is gone, nothing left to wait for
+            let _ = wait.await;
+        }
+    }
+
+    /// The most recent writes that couldn't be mirrored, in the order they were attempted
+    #[allow(clippy::expect_used)]
+    pub fn mirror_failures(&self) -> Vec<MirrorFailure> {
+        self.failures.lock().expect("poison lock").iter().cloned().collect()
+    }
+
+    async fn enqueue(&self, op: MirrorOp) -> StorageResult<()> {
+        self.queue
+            .send(op)
+            .await
+            .map_err(|_| StorageError::Other("mirroring task has stopped".to_string()))
+    }
+}
+
+#[allow(clippy::expect_used)]
+async fn mirror_writes(
+    secondary: Arc<dyn Storage + Send + Sync>,
+    mut receiver: mpsc::Receiver<MirrorOp>,
+    failures: Arc<Mutex<VecDeque<MirrorFailure>>>,
+    failure_capacity: usize,
+) {
+    while let Some(op) = receiver.recv().await {
+        let (kind, id, res) = match op {
+            MirrorOp::Snapshot(id, table) => (
+                Some(ObjectKind::Snapshot),
+                id.0.to_vec(),
+                secondary.write_snapshot(id, table).await,
+            ),
+            MirrorOp::Attributes(id, table) => (
+                Some(ObjectKind::Attributes),
+                id.0.to_vec(),
+                secondary.write_attributes(id, table).await,
+            ),
+            MirrorOp::Manifest(id, table) => (
+                Some(ObjectKind::Manifest),
+                id.0.to_vec(),
+                secondary.write_manifests(id, table).await,
+            ),
+            MirrorOp::Chunk(id, bytes) => (
+                Some(ObjectKind::Chunk),
+                id.0.to_vec(),
+                secondary.write_chunk(id, bytes).await,
+            ),
+            // the secondary follows whatever the primary has
+            MirrorOp::Ref(key, bytes) => {
+                let res = secondary.write_ref(&key, true, bytes).await;
+                (None, key.into_bytes(), res)
+            }
+            MirrorOp::Drain(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if let Err(err) = res {
+            tracing::warn!(?kind, error = %err, "failed to mirror write");
+            if failure_capacity == 0 {
+                continue;
+            }
+            let mut failures = failures.lock().expect("poison lock");
+            if failures.len() == failure_capacity {
+                failures.pop_front();
+            }
+            failures.push_back(MirrorFailure { kind, id, error: err.to_string() });
+        }
+    }
+}
+
+impl private::Sealed for MirroringStorage {}
+
+#[async_trait]
+impl Storage for MirroringStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.primary.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.primary.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.primary.fetch_manifests(id).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.primary.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.primary.fetch_node_chunks(manifest_id, node).await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.primary.fetch_chunk_info(manifest_id, node, coord).await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.primary.exists(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot(id.clone(), Arc::clone(&table)).await?;
+        self.enqueue(MirrorOp::Snapshot(id, table)).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.primary.write_attributes(id.clone(), Arc::clone(&table)).await?;
+        self.enqueue(MirrorOp::Attributes(id, table)).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.primary.write_manifests(id.clone(), Arc::clone(&table)).await?;
+        self.enqueue(MirrorOp::Manifest(id, table)).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.primary.write_chunk(id.clone(), bytes.clone()).await?;
+        self.enqueue(MirrorOp::Chunk(id, bytes)).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        if !self.primary.write_chunk_if_absent(id.clone(), bytes.clone()).await? {
+            return Ok(false);
+        }
+        self.enqueue(MirrorOp::Chunk(id, bytes)).await?;
+        Ok(true)
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        let id = self.primary.write_new_chunk(bytes.clone()).await?;
+        self.enqueue(MirrorOp::Chunk(id.clone(), bytes)).await?;
+        Ok(id)
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.primary.get_ref(ref_key).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.primary.get_refs(keys).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.primary.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.primary.ref_names().await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.primary.list_modified(kind, from, to).await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.primary.list_objects(kind).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.primary.ref_versions(ref_name).await
+    }
+
+    // deletes are not mirrored, the mirror keeps unreachable objects until it's collected
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.primary.delete_chunk(id).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.primary.delete_manifest(id).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.primary.delete_snapshot(id).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.primary.delete_attributes(id).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.primary.delete_ref_version(ref_name, version_id).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.primary.write_ref(ref_key, overwrite_refs, bytes.clone()).await?;
+        self.enqueue(MirrorOp::Ref(ref_key.to_string(), bytes)).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        let swapped =
+            self.primary.compare_and_swap_ref(ref_key, expected, new.clone()).await?;
+        if swapped {
+            self.enqueue(MirrorOp::Ref(ref_key.to_string(), new)).await?;
+        }
+        Ok(swapped)
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.primary.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.primary.ping().await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        refs::{fetch_branch_tip, update_branch},
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_secondary_receives_all_writes_after_drain() {
+        let primary = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let secondary = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = MirroringStorage::new(
+            Arc::clone(&primary) as Arc<dyn Storage + Send + Sync>,
+            Arc::clone(&secondary) as Arc<dyn Storage + Send + Sync>,
+            MirrorConfig { queue_depth: 2, failure_capacity: 10 },
+        );
+
+        let mut chunk_ids = Vec::new();
+        for i in 0..20u8 {
+            let id = ChunkId::random();
+            storage.write_chunk(id.clone(), Bytes::from(vec![i; 4])).await.unwrap();
+            chunk_ids.push(id);
+        }
+        let snapshot = Arc::new(Snapshot::empty());
+        let snapshot_id = snapshot.metadata.id.clone();
+        storage.write_snapshot(snapshot_id.clone(), snapshot).await.unwrap();
+        update_branch(&storage, "main", snapshot_id.clone(), None, false).await.unwrap();
+
+        storage.drain_mirror().await;
+
+        let mut primary_keys = primary.all_keys().await.unwrap();
+        let mut secondary_keys = secondary.all_keys().await.unwrap();
+        primary_keys.sort();
+        secondary_keys.sort();
+        assert_eq!(primary_keys, secondary_keys);
+        assert_eq!(primary_keys.len(), 22);
+
+        for (i, id) in chunk_ids.iter().enumerate() {
+            assert_eq!(
+                secondary.fetch_chunk(id, &ByteRange::ALL).await.unwrap(),
+                Bytes::from(vec![i as u8; 4])
+            );
+        }
+        assert_eq!(
+            fetch_branch_tip(secondary.as_ref(), "main").await.unwrap().snapshot,
+            snapshot_id
+        );
+        assert_eq!(storage.mirror_failures(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_refs_are_overwritten_in_the_secondary() {
+        let primary = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let secondary = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = MirroringStorage::new(
+            Arc::clone(&primary) as Arc<dyn Storage + Send + Sync>,
+            Arc::clone(&secondary) as Arc<dyn Storage + Send + Sync>,
+            MirrorConfig { queue_depth: 10, failure_capacity: 10 },
+        );
+
+        // the secondary already holds a conflicting version of the tag
+        secondary
+            .write_ref("tag.v1/ref.json", false, Bytes::from_static(b"{}"))
+            .await
+            .unwrap();
+        storage
+            .write_ref("tag.v1/ref.json", false, Bytes::from_static(b"[]"))
+            .await
+            .unwrap();
+        storage.drain_mirror().await;
+
+        // refs are overwritten in the secondary to follow the primary
+        assert_eq!(
+            secondary.get_ref("tag.v1/ref.json").await.unwrap(),
+            Bytes::from_static(b"[]")
+        );
+        assert_eq!(storage.mirror_failures(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_failures_are_recorded() {
+        let primary = Arc::new(ObjectStorage::new_in_memory_store(None));
+        // a file where the secondary expects its root directory makes every write fail
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let secondary = Arc::new(ObjectStorage::new_local_store(&root).unwrap());
+        std::fs::remove_dir(&root).unwrap();
+        std::fs::write(&root, b"not a directory").unwrap();
+        let storage = MirroringStorage::new(
+            Arc::clone(&primary) as Arc<dyn Storage + Send + Sync>,
+            secondary,
+            MirrorConfig { queue_depth: 10, failure_capacity: 2 },
+        );
+
+        let ids: Vec<_> = (0..3).map(|_| ChunkId::random()).collect();
+        for id in ids.iter() {
+            storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await.unwrap();
+        }
+        storage.drain_mirror().await;
+
+        for id in ids.iter() {
+            assert_eq!(
+                primary.fetch_chunk(id, &ByteRange::ALL).await.unwrap(),
+                Bytes::from_static(b"hello")
+            );
+        }
+        // only the most recent failures are kept
+        let failures = storage.mirror_failures();
+        assert_eq!(
+            failures.iter().map(|f| (f.kind, f.id.clone())).collect::<Vec<_>>(),
+            vec![
+                (Some(ObjectKind::Chunk), ids[1].0.to_vec()),
+                (Some(ObjectKind::Chunk), ids[2].0.to_vec()),
+            ]
+        );
+    }
+}
@@ -2,26 +2,42 @@ use aws_sdk_s3::{
     config::http::HttpResponse,
     error::SdkError,
     operation::{
-        get_object::GetObjectError, list_objects_v2::ListObjectsV2Error,
+        delete_object::DeleteObjectError, get_object::GetObjectError,
+        head_object::HeadObjectError, list_objects_v2::ListObjectsV2Error,
         put_object::PutObjectError,
     },
     primitives::ByteStreamError,
 };
 use core::fmt;
-use futures::stream::BoxStream;
-use std::{ffi::OsString, sync::Arc};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use std::{ffi::OsString, sync::Arc, time::SystemTime};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 pub mod caching;
-
-#[cfg(test)]
+pub mod circuit_breaker;
+pub mod compressing;
+pub mod deduping;
+pub mod encrypting;
+#[cfg(any(test, feature = "test-util"))]
+pub mod faulty;
 pub mod logging;
+pub mod migrating;
+pub mod mirroring;
 
 pub mod object_store;
+pub mod retrying;
 pub mod s3;
+#[cfg(any(test, feature = "test-util"))]
+pub mod serializing;
+pub mod splitting;
+pub mod tracing;
+pub mod verifying;
 pub mod virtual_ref;
 
 pub use caching::MemCachingStorage;
@@ -29,8 +45,11 @@ pub use object_store::ObjectStorage;
 
 use crate::{
     format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ChunkLength, ChunkOffset,
+        ManifestId, NodeId, Path, SnapshotId,
     },
     private,
 };
@@ -42,11 +61,15 @@ pub enum StorageError {
     #[error("bad object store prefix {0:?}")]
     BadPrefix(OsString),
     #[error("error getting object from object store {0}")]
-    S3GetObjectError(#[from] SdkError<GetObjectError, HttpResponse>),
+    S3GetObjectError(Box<SdkError<GetObjectError, HttpResponse>>),
     #[error("error writing object to object store {0}")]
-    S3PutObjectError(#[from] SdkError<PutObjectError, HttpResponse>),
+    S3PutObjectError(Box<SdkError<PutObjectError, HttpResponse>>),
+    #[error("error getting object metadata from object store {0}")]
+    S3HeadObjectError(Box<SdkError<HeadObjectError, HttpResponse>>),
     #[error("error listing objects in object store {0}")]
-    S3ListObjectError(#[from] SdkError<ListObjectsV2Error, HttpResponse>),
+    S3ListObjectError(Box<SdkError<ListObjectsV2Error, HttpResponse>>),
+    #[error("error deleting object from object store {0}")]
+    S3DeleteObjectError(Box<SdkError<DeleteObjectError, HttpResponse>>),
     #[error("error streaming bytes from object store {0}")]
     S3StreamError(#[from] ByteStreamError),
     #[error("messagepack decode error: {0}")]
@@ -57,12 +80,215 @@ pub enum StorageError {
     RefAlreadyExists(String),
     #[error("ref not found: {0}")]
     RefNotFound(String),
+    #[error("operation not supported by this storage: {0}")]
+    Unsupported(String),
+    #[error("no chunk at path: {0}")]
+    ChunkPathNotFound(String),
+    #[error("circuit breaker is open, the backend is failing")]
+    CircuitOpen,
+    #[error("cannot encrypt or decrypt object: {0}")]
+    Encryption(String),
+    #[error("cannot compress or decompress object: {0}")]
+    Compression(String),
+    #[error("fetched object failed verification, expected {expected}, got {actual}")]
+    IntegrityError { expected: String, actual: String },
+    #[error("object not found: {0:?}")]
+    ObjectNotFound(AnyObjectId),
+    #[error("snapshot {snapshot} has parent {parent}, which is not in storage")]
+    MissingParent { snapshot: SnapshotId, parent: SnapshotId },
     #[error("unknown storage error: {0}")]
     Other(String),
 }
 
+// the SDK errors are large, they are boxed so `StorageResult` stays small
+macro_rules! from_sdk_error {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(impl From<SdkError<$error, HttpResponse>> for StorageError {
+            fn from(err: SdkError<$error, HttpResponse>) -> Self {
+                StorageError::$variant(Box::new(err))
+            }
+        })*
+    };
+}
+
+from_sdk_error!(
+    S3GetObjectError(GetObjectError),
+    S3PutObjectError(PutObjectError),
+    S3HeadObjectError(HeadObjectError),
+    S3ListObjectError(ListObjectsV2Error),
+    S3DeleteObjectError(DeleteObjectError),
+);
+
+impl StorageError {
+    /// The object or ref doesn't exist, whatever the backend
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            StorageError::ObjectStore(::object_store::Error::NotFound { .. })
+            | StorageError::RefNotFound(_)
+            | StorageError::ObjectNotFound(_) => true,
+            StorageError::S3GetObjectError(err) => {
+                err.as_service_error().is_some_and(|err| err.is_no_such_key())
+            }
+            StorageError::S3HeadObjectError(err) => {
+                err.as_service_error().is_some_and(|err| err.is_not_found())
+            }
+            _ => false,
+        }
+    }
+}
+
 pub type StorageResult<A> = Result<A, StorageError>;
 
+/// The different types of immutable objects stored in a repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ObjectKind {
+    Snapshot,
+    Manifest,
+    Attributes,
+    Chunk,
+}
+
+/// The id of any of the immutable objects stored in a repository
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum AnyObjectId {
+    Snapshot(SnapshotId),
+    Manifest(ManifestId),
+    Attributes(AttributesId),
+    Chunk(ChunkId),
+}
+
+impl AnyObjectId {
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            AnyObjectId::Snapshot(_) => ObjectKind::Snapshot,
+            AnyObjectId::Manifest(_) => ObjectKind::Manifest,
+            AnyObjectId::Attributes(_) => ObjectKind::Attributes,
+            AnyObjectId::Chunk(_) => ObjectKind::Chunk,
+        }
+    }
+
+    /// The id of an object of `kind`, `None` if `bytes` don't have the right length
+    pub fn from_bytes(kind: ObjectKind, bytes: &[u8]) -> Option<Self> {
+        match kind {
+            ObjectKind::Snapshot => bytes.try_into().ok().map(AnyObjectId::Snapshot),
+            ObjectKind::Manifest => bytes.try_into().ok().map(AnyObjectId::Manifest),
+            ObjectKind::Attributes => bytes.try_into().ok().map(AnyObjectId::Attributes),
+            ObjectKind::Chunk => bytes.try_into().ok().map(AnyObjectId::Chunk),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            AnyObjectId::Snapshot(id) => &id.0,
+            AnyObjectId::Manifest(id) => &id.0,
+            AnyObjectId::Attributes(id) => &id.0,
+            AnyObjectId::Chunk(id) => &id.0,
+        }
+    }
+}
+
+/// The result of [`Storage::get_ref_if_changed`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefFetch {
+    /// The ref still has the version identified by the etag
+    Unchanged,
+    /// The current contents of the ref, with their etag if the backend supports them
+    Changed { bytes: Bytes, etag: Option<String> },
+}
+
+/// Prefix of the version ids created by [`Storage::record_ref_version`]
+///
+/// It sorts after all the characters used to encode regular branch versions, so recorded
+/// versions are listed after them.
+pub const RECORDED_REF_VERSION_PREFIX: &str = "recorded.";
+
+/// How many refs [`Storage::get_refs`] fetches at the same time
+pub const REF_FETCH_CONCURRENCY: usize = 16;
+
+/// How many chunks [`Storage::fetch_chunks`] fetches at the same time
+pub const CHUNK_FETCH_CONCURRENCY: usize = 32;
+
+/// Ranges fetched together by [`Storage::fetch_chunk_ranges`]
+#[derive(Debug, PartialEq, Eq)]
+struct RangeGroup {
+    range: ByteRange,
+    /// Index of every request in the group, with its offsets relative to the group start, an
+    /// open end reads to the end of the fetched bytes
+    members: Vec<(usize, ChunkOffset, Option<ChunkOffset>)>,
+}
+
+fn coalesce_ranges(ranges: &[ByteRange], max_gap: ChunkLength) -> Vec<RangeGroup> {
+    let mut groups = Vec::new();
+    let mut sorted = Vec::new();
+    for (ix, range) in ranges.iter().enumerate() {
+        match range {
+            ByteRange::Bounded(range) => sorted.push((ix, range.start, Some(range.end))),
+            ByteRange::From(start) => sorted.push((ix, *start, None)),
+            ByteRange::Last(_) => groups
+                .push(RangeGroup { range: range.clone(), members: vec![(ix, 0, None)] }),
+        }
+    }
+    sorted.sort_by_key(|(_, start, _)| *start);
+
+    // the start and end of the group being built, and its members with absolute offsets
+    let mut current: Option<(ChunkOffset, Option<ChunkOffset>, Vec<_>)> = None;
+    let close = |(start, end, members): (ChunkOffset, Option<ChunkOffset>, Vec<_>)| {
+        let members = members
+            .into_iter()
+            .map(|(ix, s, e): (usize, ChunkOffset, Option<ChunkOffset>)| {
+                (ix, s - start, e.map(|e| e.max(s) - start))
+            })
+            .collect();
+        RangeGroup { range: (Some(start), end).into(), members }
+    };
+    for (ix, start, end) in sorted {
+        current = match current {
+            Some((group_start, group_end, mut members))
+                if group_end.is_none_or(|group_end| start <= group_end + max_gap) =>
+            {
+                members.push((ix, start, end));
+                let group_end = group_end.zip(end).map(|(a, b)| a.max(b));
+                Some((group_start, group_end, members))
+            }
+            previous => {
+                groups.extend(previous.map(close));
+                Some((start, end, vec![(ix, start, end)]))
+            }
+        };
+    }
+    groups.extend(current.map(close));
+    groups
+}
+
+/// Turn a missing ref into `None`
+pub(crate) fn ref_if_found(res: StorageResult<Bytes>) -> StorageResult<Option<Bytes>> {
+    match res {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(StorageError::RefNotFound(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// The first `len` bytes of a chunk, or all of it if it's shorter, `None` if it doesn't exist
+///
+/// For decorators that recognize their own objects by a header. Backends can reject ranged
+/// reads past the end of short objects, those are read in full.
+pub(crate) async fn fetch_chunk_header(
+    backend: &(dyn Storage + Send + Sync),
+    id: &ChunkId,
+    len: usize,
+) -> StorageResult<Option<Bytes>> {
+    match backend.fetch_chunk(id, &ByteRange::to_offset(len as ChunkOffset)).await {
+        Ok(header) => Ok(Some(header)),
+        Err(err) if err.is_not_found() => Ok(None),
+        Err(_) => match backend.fetch_chunk(id, &ByteRange::ALL).await {
+            Ok(bytes) => Ok(Some(bytes.slice(..len.min(bytes.len())))),
+            Err(err) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err),
+        },
+    }
+}
+
 /// Fetch and write the parquet files that represent the repository in object store
 ///
 /// Different implementation can cache the files differently, or not at all.
@@ -70,6 +296,55 @@ pub type StorageResult<A> = Result<A, StorageError>;
 #[async_trait]
 pub trait Storage: fmt::Debug + private::Sealed {
     async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>>;
+
+    /// Fetch a snapshot keeping only the nodes under `root_path`, and their ancestor groups
+    ///
+    /// Useful to work with a single group of a large hierarchy, without keeping all its nodes
+    /// in memory. See [`Snapshot::subtree`].
+    async fn fetch_snapshot_subtree(
+        &self,
+        id: &SnapshotId,
+        root_path: &Path,
+    ) -> StorageResult<Snapshot> {
+        Ok(self.fetch_snapshot(id).await?.subtree(root_path))
+    }
+
+    /// Fetch `snapshot_id` and each of its parents in turn, newest first
+    ///
+    /// The stream ends with the root snapshot, the one without a parent. A parent missing from
+    /// storage fails the stream with [`StorageError::MissingParent`].
+    fn ancestry<'a>(
+        &'a self,
+        snapshot_id: &SnapshotId,
+    ) -> BoxStream<'a, StorageResult<Arc<Snapshot>>>
+    where
+        Self: Sync,
+    {
+        // the state is the next snapshot to fetch, and the child pointing to it
+        let start = Some((snapshot_id.clone(), None));
+        futures::stream::try_unfold(
+            start,
+            move |next: Option<(SnapshotId, Option<SnapshotId>)>| async move {
+                let Some((id, child)) = next else {
+                    return Ok(None);
+                };
+                let snapshot = match (self.fetch_snapshot(&id).await, child) {
+                    (Ok(snapshot), _) => snapshot,
+                    (Err(err), Some(child)) if err.is_not_found() => {
+                        return Err(StorageError::MissingParent {
+                            snapshot: child,
+                            parent: id,
+                        })
+                    }
+                    (Err(err), _) => return Err(err),
+                };
+                let next = snapshot.parent_id().map(|parent| (parent.clone(), Some(id)));
+                Ok(Some((snapshot, next)))
+            },
+        )
+        .boxed()
+    }
+
     async fn fetch_attributes(
         &self,
         id: &AttributesId,
@@ -77,6 +352,103 @@ pub trait Storage: fmt::Debug + private::Sealed {
     async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>>; // FIXME: format flags
     async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes>; // FIXME: format flags
 
+    /// Fetch several ranges of a chunk, merging nearby ranges into a single fetch
+    ///
+    /// Ranges separated by at most `max_gap` bytes, or overlapping, are fetched together and
+    /// sliced back out of the merged bytes without copying. `Last` ranges are fetched on their
+    /// own, without knowing the chunk size they can't be merged. Returns the bytes of every
+    /// range, in the same order as `ranges`.
+    async fn fetch_chunk_ranges(
+        &self,
+        id: &ChunkId,
+        ranges: &[ByteRange],
+        max_gap: ChunkLength,
+    ) -> StorageResult<Vec<Bytes>> {
+        let groups = coalesce_ranges(ranges, max_gap);
+        let fetched = futures::future::try_join_all(
+            groups.iter().map(|group| self.fetch_chunk(id, &group.range)),
+        )
+        .await?;
+        let mut res = vec![Bytes::new(); ranges.len()];
+        for (group, bytes) in groups.into_iter().zip(fetched) {
+            for (ix, start, end) in group.members {
+                let len = bytes.len() as ChunkOffset;
+                let end = end.unwrap_or(len).min(len);
+                let start = start.min(end);
+                res[ix] = bytes.slice(start as usize..end as usize);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Fetch several chunks concurrently
+    ///
+    /// The stream yields the bytes of every request, in the same order as `requests`. At most
+    /// [`CHUNK_FETCH_CONCURRENCY`] chunks are fetched at the same time.
+    fn fetch_chunks<'a>(
+        &'a self,
+        requests: &'a [(ChunkId, ByteRange)],
+    ) -> BoxStream<'a, StorageResult<Bytes>>
+    where
+        Self: Sync,
+    {
+        futures::stream::iter(requests)
+            .map(|(id, range)| self.fetch_chunk(id, range))
+            .buffered(CHUNK_FETCH_CONCURRENCY)
+            .boxed()
+    }
+
+    /// Find a single chunk in a manifest
+    ///
+    /// Returns `None` if the manifest doesn't contain the chunk. Implementations can avoid
+    /// fetching the full manifest, the default one doesn't.
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        let manifest = self.fetch_manifests(manifest_id).await?;
+        Ok(manifest.get_chunk_info(node, coord))
+    }
+
+    /// Fetch the entries of a single node in a manifest
+    ///
+    /// Returns a manifest with only the chunks of `node`. Implementations can avoid keeping
+    /// the full manifest around, the default one doesn't.
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        let manifest = self.fetch_manifests(manifest_id).await?;
+        Ok(Arc::new(manifest.node_manifest(node)))
+    }
+
+    /// Stream the entries of a single node in a manifest, sorted by coordinates
+    ///
+    /// Implementations can avoid holding the full manifest in memory, to bound memory use for
+    /// huge manifests. The default one streams the result of [`Storage::fetch_node_chunks`].
+    fn fetch_manifest_chunks<'a>(
+        &'a self,
+        manifest_id: &'a ManifestId,
+        node: NodeId,
+    ) -> BoxStream<'a, StorageResult<ChunkInfo>>
+    where
+        Self: Sync,
+    {
+        futures::stream::once(self.fetch_node_chunks(manifest_id, node))
+            .map_ok(move |manifest| {
+                let chunks: Vec<_> = manifest.node_chunk_infos(node).map(Ok).collect();
+                futures::stream::iter(chunks)
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Check if an object is present in storage, without fetching it
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool>;
+
     async fn write_snapshot(
         &self,
         id: SnapshotId,
@@ -94,16 +466,240 @@ pub trait Storage: fmt::Debug + private::Sealed {
     ) -> StorageResult<()>;
     async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()>;
 
+    /// Write a chunk, only if there is no chunk with the same id
+    ///
+    /// Returns `false`, without writing anything, if the chunk already existed. Concurrent
+    /// writers of content addressed chunks use it to upload each chunk only once. The default
+    /// implementation checks if the chunk exists before writing it, so it's not atomic.
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        if self.exists(&AnyObjectId::Chunk(id.clone())).await? {
+            return Ok(false);
+        }
+        self.write_chunk(id, bytes).await?;
+        Ok(true)
+    }
+
+    /// Write a chunk under an id chosen by the storage, returns the id
+    ///
+    /// The default implementation uses a random id, [`deduping::DedupingStorage`] derives it
+    /// from the bytes instead. Decorators that write the bytes unchanged forward it.
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        let id = ChunkId::random();
+        self.write_chunk(id.clone(), bytes).await?;
+        Ok(id)
+    }
+
+    /// Write a manifest, only if there is no manifest with the same id
+    ///
+    /// Returns `false`, without writing anything, if the manifest already existed. This lets
+    /// concurrent writers of manifests with deterministic ids share the same object. The default
+    /// implementation checks if the manifest exists before writing it, so it's not atomic.
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        if self.exists(&AnyObjectId::Manifest(id.clone())).await? {
+            return Ok(false);
+        }
+        self.write_manifests(id, manifest).await?;
+        Ok(true)
+    }
+
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes>;
+
+    /// Fetch a ref only if it no longer has the version identified by `etag`
+    ///
+    /// With `etag` set to `None` the ref is always fetched. This makes revalidating a cached
+    /// ref cheap. The default implementation doesn't support etags, it always fetches the ref.
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        let _ = etag;
+        Ok(RefFetch::Changed { bytes: self.get_ref(ref_key).await?, etag: None })
+    }
+
+    /// Fetch several refs concurrently
+    ///
+    /// Returns the value of every key, in the same order as `keys`, or `None` if the ref
+    /// doesn't exist. At most [`REF_FETCH_CONCURRENCY`] refs are fetched at the same time.
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        futures::stream::iter(keys)
+            .map(|key| async move {
+                let bytes = ref_if_found(self.get_ref(&key).await)?;
+                Ok((key, bytes))
+            })
+            .buffered(REF_FETCH_CONCURRENCY)
+            .try_collect()
+            .await
+    }
     async fn ref_names(&self) -> StorageResult<Vec<String>>;
+
+    /// List the objects of a kind last modified in the `[from, to)` time window
+    ///
+    /// Useful for incremental backups, objects are immutable so they are only listed in the
+    /// window they were written in. Objects are not listed in any particular order. Returns
+    /// [`StorageError::Unsupported`] if the backend doesn't track modification times.
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        let _ = (kind, from, to);
+        Err(StorageError::Unsupported("list_modified".to_string()))
+    }
+
+    /// List the ids of all the objects of a kind in the backend, reachable or not
+    ///
+    /// A garbage collector compares them with the objects reachable from the refs, to find the
+    /// ones it can delete. Objects are not listed in any particular order. Returns
+    /// [`StorageError::Unsupported`] if the backend can't list objects.
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        let _ = kind;
+        Err(StorageError::Unsupported("list_objects".to_string()))
+    }
+
     async fn ref_versions(
         &self,
         ref_name: &str,
     ) -> StorageResult<BoxStream<StorageResult<String>>>;
+
+    /// Delete a chunk, for garbage collection
+    ///
+    /// Deleting an object that doesn't exist is not an error. Objects must only be deleted once
+    /// they are unreachable, nothing checks it. Returns [`StorageError::Unsupported`] if the
+    /// backend can't delete objects.
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        let _ = id;
+        Err(StorageError::Unsupported("delete_chunk".to_string()))
+    }
+
+    /// Delete a manifest, see [`Storage::delete_chunk`]
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        let _ = id;
+        Err(StorageError::Unsupported("delete_manifest".to_string()))
+    }
+
+    /// Delete a snapshot, see [`Storage::delete_chunk`]
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        let _ = id;
+        Err(StorageError::Unsupported("delete_snapshot".to_string()))
+    }
+
+    /// Delete an attributes file, see [`Storage::delete_chunk`]
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        let _ = id;
+        Err(StorageError::Unsupported("delete_attributes".to_string()))
+    }
+
+    /// Delete a version of a ref, as listed by [`Storage::ref_versions`]
+    ///
+    /// Deleting a version that doesn't exist is not an error. Returns
+    /// [`StorageError::Unsupported`] if the backend can't delete refs.
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        let _ = (ref_name, version_id);
+        Err(StorageError::Unsupported("delete_ref_version".to_string()))
+    }
+
+    /// Delete all but the `keep_last` most recent versions of a ref, returns how many were
+    /// deleted
+    ///
+    /// Versions are ordered like in [`Storage::ref_versions`], so recorded versions expire
+    /// before every regular version. The newest version, the current value of the ref, is always kept,
+    /// even with `keep_last` set to zero. Versions are deleted oldest first, so a concurrent
+    /// reader always finds at least the current value.
+    async fn expire_ref_versions(
+        &self,
+        ref_name: &str,
+        keep_last: usize,
+    ) -> StorageResult<usize> {
+        let versions: Vec<String> =
+            self.ref_versions(ref_name).await?.try_collect().await?;
+        let expired = versions.len().saturating_sub(keep_last.max(1));
+        for version_id in versions.iter().rev().take(expired) {
+            self.delete_ref_version(ref_name, version_id).await?;
+        }
+        Ok(expired)
+    }
+
     async fn write_ref(
         &self,
         ref_key: &str,
         overwrite_refs: bool,
         bytes: Bytes,
     ) -> StorageResult<()>;
+
+    /// Atomically replace the contents of a ref, if it currently holds `expected`
+    ///
+    /// With `expected` set to `None` the ref is only written if it doesn't exist. Returns
+    /// `false` without writing anything if the ref didn't hold the expected value.
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool>;
+
+    /// The current time according to the storage backend
+    ///
+    /// Comparing it with the local clock detects clock skew, which matters for anything based on
+    /// timestamps, like lease expiration. Returns [`StorageError::Unsupported`] if the backend
+    /// doesn't expose its time.
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        Err(StorageError::Unsupported("backend_time".to_string()))
+    }
+
+    /// Check that the backend is reachable and accepts the credentials
+    ///
+    /// Meant for startup and readiness checks, it's cheap and doesn't modify anything. The
+    /// default implementation lists the refs.
+    async fn ping(&self) -> StorageResult<()> {
+        self.ref_names().await.map(|_| ())
+    }
+
+    /// Append a new version to the history of a ref, without making it the ref's current value
+    ///
+    /// Returns the id of the new version, as listed by [`Storage::ref_versions`]. Recorded
+    /// versions are listed after every regular version, newest first, and their content can be
+    /// read with `get_ref("{ref_name}/{version_id}")`.
+    ///
+    /// Recorded versions are ignored when finding the latest version of a branch, so
+    /// [`crate::refs::fetch_branch_tip`] doesn't change until the version is promoted with
+    /// [`crate::refs::promote_branch_version`].
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        // newest versions first, the random suffix makes concurrent records unique
+        let millis = u64::try_from(Utc::now().timestamp_millis()).unwrap_or_default();
+        let mut version = (u64::MAX - millis).to_be_bytes().to_vec();
+        version.extend(thread_rng().gen::<[u8; 5]>());
+        let version_id = format!(
+            "{}{}.json",
+            RECORDED_REF_VERSION_PREFIX,
+            base32::encode(base32::Alphabet::Crockford, &version)
+        );
+        self.write_ref(format!("{}/{}", ref_name, version_id).as_str(), false, bytes)
+            .await?;
+        Ok(version_id)
+    }
 }
@@ -1,24 +1,33 @@
 use crate::{
     format::{
-        attributes::AttributesTable, format_constants, manifest::Manifest,
-        snapshot::Snapshot, AttributesId, ByteRange, ChunkId, FileTypeTag, ManifestId,
+        attributes::AttributesTable,
+        format_constants,
+        manifest::{ChunkInfo, Manifest, ManifestIndex},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, FileTypeTag, ManifestId, NodeId,
         ObjectId, SnapshotId,
     },
     private,
 };
+use async_stream::try_stream;
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use itertools::Itertools;
 use object_store::{
     local::LocalFileSystem, memory::InMemory, path::Path as ObjectPath, Attribute,
-    AttributeValue, Attributes, GetOptions, GetRange, ObjectStore, PutMode, PutOptions,
-    PutPayload,
+    AttributeValue, Attributes, GetOptions, GetRange, ObjectStore, PutMode,
+    PutMultipartOpts, PutOptions, PutPayload, UpdateVersion,
 };
 use std::{
-    fs::create_dir_all, future::ready, ops::Range, path::Path as StdPath, sync::Arc,
+    fs::create_dir_all, future::ready, ops::Range, path::Path as StdPath, path::PathBuf,
+    sync::Arc, time::SystemTime,
 };
 
-use super::{Storage, StorageError, StorageResult};
+use super::{
+    ref_if_found, AnyObjectId, ObjectKind, RefFetch, Storage, StorageError,
+    StorageResult, REF_FETCH_CONCURRENCY,
+};
 
 // Get Range is object_store specific, keep it with this module
 impl From<&ByteRange> for Option<GetRange> {
@@ -36,20 +45,75 @@ impl From<&ByteRange> for Option<GetRange> {
 
 const SNAPSHOT_PREFIX: &str = "snapshots/";
 const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
+const MANIFEST_INDEX_PREFIX: &str = "manifest_indexes/";
+const ATTRIBUTES_PREFIX: &str = "attributes/";
 const CHUNK_PREFIX: &str = "chunks/";
 const REF_PREFIX: &str = "refs";
 
+const LOCAL_FILESYSTEM_SHARD_LEN: usize = 2;
+
+const DEFAULT_CHUNK_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// How object ids are encoded in object keys
+///
+/// Hex encodings are useful for stores with case insensitive keys, or a restricted character
+/// set. All readers and writers of a repository must use the same encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyEncoding {
+    /// Crockford's base 32, the default
+    #[default]
+    Base32,
+    HexLower,
+    HexUpper,
+}
+
+impl KeyEncoding {
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            KeyEncoding::Base32 => base32::encode(base32::Alphabet::Crockford, bytes),
+            KeyEncoding::HexLower => format!("{:02x}", bytes.iter().format("")),
+            KeyEncoding::HexUpper => format!("{:02X}", bytes.iter().format("")),
+        }
+    }
+
+    fn decode(&self, key: &str) -> Option<Vec<u8>> {
+        match self {
+            KeyEncoding::Base32 => base32::decode(base32::Alphabet::Crockford, key),
+            KeyEncoding::HexLower | KeyEncoding::HexUpper => (0..key.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(key.get(i..i + 2)?, 16).ok())
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ObjectStorage {
     store: Arc<dyn ObjectStore>,
     prefix: String,
-    // We need this because object_store's local file implementation doesn't sort refs. Since this
-    // implementation is used only for tests, it's OK to sort in memory.
+    // We need this because object_store's local file implementation doesn't sort refs, and
+    // caller provided stores may not either. Ref listings are small, so it's OK to sort in memory.
     artificially_sort_refs_in_mem: bool,
 
     supports_create_if_not_exists: bool,
     supports_metadata: bool,
+    // Stores without conditional updates serialize compare and swap operations in process
+    supports_conditional_update: bool,
+    compare_and_swap_lock: tokio::sync::Mutex<()>,
+
+    // When set, manifests are written together with a ManifestIndex sidecar object
+    manifest_index_block_size: Option<usize>,
+
+    chunk_content_type: String,
+    chunk_content_encoding: Option<String>,
+
+    key_encoding: KeyEncoding,
+    // Objects are stored in a directory named after the first characters of their key
+    key_shard_len: usize,
+
+    // The in memory and local filesystem stores run on this machine, so their time is the
+    // local clock. Caller provided stores don't tell us their time.
+    uses_local_clock: bool,
 }
 
 impl ObjectStorage {
@@ -66,12 +130,51 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: false,
             supports_create_if_not_exists: true,
             supports_metadata: true,
+            supports_conditional_update: true,
+            compare_and_swap_lock: tokio::sync::Mutex::new(()),
+            manifest_index_block_size: None,
+            chunk_content_type: DEFAULT_CHUNK_CONTENT_TYPE.to_string(),
+            chunk_content_encoding: None,
+            key_encoding: KeyEncoding::default(),
+            key_shard_len: 0,
+            uses_local_clock: true,
+        }
+    }
+
+    /// Create a Storage implementation on top of a store configured by the caller
+    ///
+    /// This is the escape hatch for stores, middleware or credentials the other constructors
+    /// don't support. All objects are written under `prefix`. The store must support
+    /// conditional puts. Object attributes are not used by default, so chunks are written
+    /// without a `Content-Type`, enable them with [`ObjectStorage::with_metadata`].
+    ///
+    /// The store's clock is unknown, so [`Storage::backend_time`] is not supported.
+    pub fn from_object_store(
+        store: Arc<dyn ObjectStore>,
+        prefix: String,
+    ) -> ObjectStorage {
+        ObjectStorage {
+            store,
+            prefix,
+            artificially_sort_refs_in_mem: true,
+            supports_create_if_not_exists: true,
+            supports_metadata: false,
+            supports_conditional_update: true,
+            compare_and_swap_lock: tokio::sync::Mutex::new(()),
+            manifest_index_block_size: None,
+            chunk_content_type: DEFAULT_CHUNK_CONTENT_TYPE.to_string(),
+            chunk_content_encoding: None,
+            key_encoding: KeyEncoding::default(),
+            key_shard_len: 0,
+            uses_local_clock: false,
         }
     }
 
     /// Create an local filesystem Storage implementation
     ///
-    /// This implementation should not be used in production code.
+    /// This implementation should not be used in production code. Like in
+    /// [`ObjectStorage::new_local_filesystem`], swaps of existing refs are only serialized
+    /// within this instance.
     pub fn new_local_store(prefix: &StdPath) -> Result<ObjectStorage, std::io::Error> {
         create_dir_all(prefix)?;
         let prefix = prefix.display().to_string();
@@ -82,9 +185,83 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: true,
             supports_create_if_not_exists: true,
             supports_metadata: false,
+            supports_conditional_update: false,
+            compare_and_swap_lock: tokio::sync::Mutex::new(()),
+            manifest_index_block_size: None,
+            chunk_content_type: DEFAULT_CHUNK_CONTENT_TYPE.to_string(),
+            chunk_content_encoding: None,
+            key_encoding: KeyEncoding::default(),
+            key_shard_len: 0,
+            uses_local_clock: true,
         })
     }
 
+    /// Create a Storage implementation persisting the repository to the directory `root`
+    ///
+    /// Objects are sharded in subdirectories by the first two characters of their key, so
+    /// directories don't grow too large. Refs are stored under `refs/`, new ref versions are
+    /// created atomically, failing if the version already exists.
+    ///
+    /// The filesystem has no conditional overwrites, so [`Storage::compare_and_swap_ref`] of
+    /// an existing ref is only serialized within this `ObjectStorage`. Two instances on the
+    /// same directory, in one process or two, can both see the expected bytes and both
+    /// succeed, the last write wins. Share one instance, or use an object store, when several
+    /// writers update the same refs.
+    pub fn new_local_filesystem(root: PathBuf) -> Result<ObjectStorage, std::io::Error> {
+        Ok(Self::new_local_store(&root)?.with_sharded_keys(LOCAL_FILESYSTEM_SHARD_LEN))
+    }
+
+    /// Write a [`ManifestIndex`] sidecar object next to every manifest
+    ///
+    /// The index allows [`Storage::fetch_chunk_info`] to fetch only the block of
+    /// `entries_per_block` manifest entries that can contain the requested chunk.
+    pub fn with_manifest_index(mut self, entries_per_block: usize) -> Self {
+        self.manifest_index_block_size = Some(entries_per_block);
+        self
+    }
+
+    /// Write object attributes, like the chunk `Content-Type` and `Content-Encoding`
+    ///
+    /// Enabled by default for the in memory store. Disabled by default for the local
+    /// filesystem, which doesn't support attributes, and for stores passed to
+    /// [`ObjectStorage::from_object_store`], since not every store does.
+    pub fn with_metadata(mut self, supports_metadata: bool) -> Self {
+        self.supports_metadata = supports_metadata;
+        self
+    }
+
+    /// Set the `Content-Type` of chunk objects, `application/octet-stream` by default
+    ///
+    /// Ignored for stores that don't support object metadata, like the local filesystem.
+    pub fn with_chunk_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.chunk_content_type = content_type.into();
+        self
+    }
+
+    /// Set the `Content-Encoding` of chunk objects, by default it's not set
+    ///
+    /// This should match the compression applied to the chunks, so HTTP clients can decode them.
+    /// Ignored for stores that don't support object metadata, like the local filesystem.
+    pub fn with_chunk_content_encoding(mut self, encoding: impl Into<String>) -> Self {
+        self.chunk_content_encoding = Some(encoding.into());
+        self
+    }
+
+    /// Set the encoding used for object ids in keys, [`KeyEncoding::Base32`] by default
+    pub fn with_key_encoding(mut self, encoding: KeyEncoding) -> Self {
+        self.key_encoding = encoding;
+        self
+    }
+
+    /// Store objects in a directory named after the first `chars` characters of their key
+    ///
+    /// By default objects are not sharded. All readers and writers of a repository must use the
+    /// same sharding.
+    pub fn with_sharded_keys(mut self, chars: usize) -> Self {
+        self.key_shard_len = chars;
+        self
+    }
+
     /// Return all keys in the store
     ///
     /// Intended for testing and debugging purposes only.
@@ -103,8 +280,11 @@ impl ObjectStorage {
         id: &ObjectId<SIZE, T>,
     ) -> ObjectPath {
         // TODO: be careful about allocation here
-        // we serialize the url using crockford
-        let path = format!("{}/{}/{}", self.prefix, file_prefix, id);
+        let key = self.key_encoding.encode(&id.0);
+        let path = match key.get(..self.key_shard_len).filter(|shard| !shard.is_empty()) {
+            Some(shard) => format!("{}/{}/{}/{}", self.prefix, file_prefix, shard, key),
+            None => format!("{}/{}/{}", self.prefix, file_prefix, key),
+        };
         ObjectPath::from(path)
     }
 
@@ -116,14 +296,100 @@ impl ObjectStorage {
         self.get_path(MANIFEST_PREFIX, id)
     }
 
+    fn get_manifest_index_path(&self, id: &ManifestId) -> ObjectPath {
+        self.get_path(MANIFEST_INDEX_PREFIX, id)
+    }
+
+    fn get_attributes_path(&self, id: &AttributesId) -> ObjectPath {
+        self.get_path(ATTRIBUTES_PREFIX, id)
+    }
+
     fn get_chunk_path(&self, id: &ChunkId) -> ObjectPath {
         self.get_path(CHUNK_PREFIX, id)
     }
 
+    fn chunk_attributes(&self) -> Attributes {
+        let mut attributes = Attributes::new();
+        if self.supports_metadata {
+            attributes.insert(
+                Attribute::ContentType,
+                AttributeValue::from(self.chunk_content_type.clone()),
+            );
+            if let Some(encoding) = &self.chunk_content_encoding {
+                attributes.insert(
+                    Attribute::ContentEncoding,
+                    AttributeValue::from(encoding.clone()),
+                );
+            }
+        }
+        attributes
+    }
+
+    fn get_kind_prefix(&self, kind: ObjectKind) -> ObjectPath {
+        let file_prefix = match kind {
+            ObjectKind::Snapshot => SNAPSHOT_PREFIX,
+            ObjectKind::Manifest => MANIFEST_PREFIX,
+            ObjectKind::Attributes => ATTRIBUTES_PREFIX,
+            ObjectKind::Chunk => CHUNK_PREFIX,
+        };
+        ObjectPath::from(format!("{}/{}", self.prefix, file_prefix))
+    }
+
+    /// All the objects of `kind`, with their modification time
+    fn list_kind(
+        &self,
+        kind: ObjectKind,
+    ) -> BoxStream<'_, StorageResult<(AnyObjectId, SystemTime)>> {
+        let prefix = self.get_kind_prefix(kind);
+        let key_encoding = self.key_encoding;
+        self.store
+            .list(Some(&prefix))
+            .map_err(StorageError::from)
+            .try_filter_map(move |meta| {
+                let modified = SystemTime::from(meta.last_modified);
+                // keys that don't decode to an id weren't written by us, they are skipped
+                let id = meta
+                    .location
+                    .filename()
+                    .and_then(|name| key_encoding.decode(name))
+                    .and_then(|bytes| AnyObjectId::from_bytes(kind, &bytes));
+                ready(Ok(id.map(|id| (id, modified))))
+            })
+            .boxed()
+    }
+
+    async fn delete_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        match self.store.delete(path).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn get_object_path(&self, id: &AnyObjectId) -> ObjectPath {
+        match id {
+            AnyObjectId::Snapshot(id) => self.get_snapshot_path(id),
+            AnyObjectId::Manifest(id) => self.get_manifest_path(id),
+            AnyObjectId::Attributes(id) => self.get_attributes_path(id),
+            AnyObjectId::Chunk(id) => self.get_chunk_path(id),
+        }
+    }
+
     fn drop_prefix(&self, prefix: &ObjectPath, path: &ObjectPath) -> Option<ObjectPath> {
         path.prefix_match(&ObjectPath::from(format!("{}", prefix))).map(|it| it.collect())
     }
 
+    /// The index of a manifest, `None` if the manifest was written without one
+    async fn fetch_manifest_index(
+        &self,
+        id: &ManifestId,
+    ) -> StorageResult<Option<ManifestIndex>> {
+        match self.store.get(&self.get_manifest_index_path(id)).await {
+            Ok(res) => Ok(Some(rmp_serde::from_slice(res.bytes().await?.as_ref())?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     fn ref_key(&self, ref_key: &str) -> ObjectPath {
         // ObjectPath knows how to deal with empty path parts: bar//foo
         ObjectPath::from(format!("{}/{}/{}", self.prefix.as_str(), REF_PREFIX, ref_key))
@@ -145,6 +411,58 @@ impl ObjectStorage {
             })
             .boxed()
     }
+
+    /// Write a manifest, and its index if enabled, returns `false` if it already existed
+    async fn put_manifest(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+        mode: PutMode,
+    ) -> StorageResult<bool> {
+        let path = self.get_manifest_path(&id);
+        let (bytes, index) = match self.manifest_index_block_size {
+            Some(block_size) => {
+                let (bytes, index) =
+                    ManifestIndex::serialize_with_index(manifest.as_ref(), block_size)?;
+                (bytes, Some(index))
+            }
+            None => (rmp_serde::to_vec(manifest.as_ref())?, None),
+        };
+        let attributes = if self.supports_metadata {
+            Attributes::from_iter(vec![
+                (
+                    Attribute::ContentType,
+                    AttributeValue::from(
+                        format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE,
+                    ),
+                ),
+                (
+                    Attribute::Metadata(std::borrow::Cow::Borrowed(
+                        format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
+                    )),
+                    AttributeValue::from(
+                        manifest.icechunk_manifest_format_version.to_string(),
+                    ),
+                ),
+            ])
+        } else {
+            Attributes::new()
+        };
+        let options = PutOptions { attributes, mode, ..PutOptions::default() };
+        // FIXME: use multipart
+        match self.store.put_opts(&path, bytes.into(), options).await {
+            Ok(_) => {}
+            Err(object_store::Error::AlreadyExists { .. }) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        if let Some(index) = index {
+            let index_bytes = rmp_serde::to_vec(&index)?;
+            self.store
+                .put(&self.get_manifest_index_path(&id), index_bytes.into())
+                .await?;
+        }
+        Ok(true)
+    }
 }
 
 impl private::Sealed for ObjectStorage {}
@@ -164,9 +482,12 @@ impl Storage for ObjectStorage {
 
     async fn fetch_attributes(
         &self,
-        _id: &AttributesId,
+        id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
-        todo!();
+        let path = self.get_attributes_path(id);
+        let bytes = self.store.get(&path).await?.bytes().await?;
+        let res = rmp_serde::from_slice(bytes.as_ref())?;
+        Ok(Arc::new(res))
     }
 
     async fn fetch_manifests(
@@ -215,10 +536,13 @@ impl Storage for ObjectStorage {
 
     async fn write_attributes(
         &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
     ) -> Result<(), StorageError> {
-        todo!()
+        let path = self.get_attributes_path(&id);
+        let bytes = rmp_serde::to_vec(table.as_ref())?;
+        self.store.put(&path, bytes.into()).await?;
+        Ok(())
     }
 
     async fn write_manifests(
@@ -226,32 +550,77 @@ impl Storage for ObjectStorage {
         id: ManifestId,
         manifest: Arc<Manifest>,
     ) -> Result<(), StorageError> {
-        let path = self.get_manifest_path(&id);
-        let bytes = rmp_serde::to_vec(manifest.as_ref())?;
-        let attributes = if self.supports_metadata {
-            Attributes::from_iter(vec![
-                (
-                    Attribute::ContentType,
-                    AttributeValue::from(
-                        format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE,
-                    ),
-                ),
-                (
-                    Attribute::Metadata(std::borrow::Cow::Borrowed(
-                        format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
-                    )),
-                    AttributeValue::from(
-                        manifest.icechunk_manifest_format_version.to_string(),
-                    ),
-                ),
-            ])
+        self.put_manifest(id, manifest, PutMode::Overwrite).await.map(|_| ())
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        if self.supports_create_if_not_exists {
+            self.put_manifest(id, manifest, PutMode::Create).await
         } else {
-            Attributes::new()
+            if self.exists(&AnyObjectId::Manifest(id.clone())).await? {
+                return Ok(false);
+            }
+            self.write_manifests(id, manifest).await?;
+            Ok(true)
+        }
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        let Some(index) = self.fetch_manifest_index(manifest_id).await? else {
+            // the manifest was written without an index
+            let manifest = self.fetch_manifests(manifest_id).await?;
+            return Ok(manifest.get_chunk_info(node, coord));
         };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+        match index.block_range(node, coord) {
+            Some(range) => {
+                let path = self.get_manifest_path(manifest_id);
+                let block = self
+                    .store
+                    .get_range(&path, range.start as usize..range.end as usize)
+                    .await?;
+                Ok(ManifestIndex::find_in_block(block.as_ref(), node, coord)?)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Only the blocks of an indexed manifest that can hold entries of the node are fetched,
+    /// one at a time. Manifests without an index are fetched in full.
+    fn fetch_manifest_chunks<'a>(
+        &'a self,
+        manifest_id: &'a ManifestId,
+        node: NodeId,
+    ) -> BoxStream<'a, StorageResult<ChunkInfo>> {
+        try_stream! {
+            match self.fetch_manifest_index(manifest_id).await? {
+                Some(index) => {
+                    let path = self.get_manifest_path(manifest_id);
+                    for range in index.node_block_ranges(node) {
+                        let range = range.start as usize..range.end as usize;
+                        let block = self.store.get_range(&path, range).await?;
+                        for chunk in ManifestIndex::node_chunks_in_block(&block, node)? {
+                            yield chunk;
+                        }
+                    }
+                }
+                None => {
+                    let manifest = self.fetch_node_chunks(manifest_id, node).await?;
+                    for chunk in manifest.node_chunk_infos(node) {
+                        yield chunk;
+                    }
+                }
+            }
+        }
+        .boxed()
     }
 
     async fn fetch_chunk(
@@ -268,13 +637,23 @@ impl Storage for ObjectStorage {
         Ok(chunk)
     }
 
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        match self.store.head(&self.get_object_path(id)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn write_chunk(
         &self,
         id: ChunkId,
         bytes: bytes::Bytes,
     ) -> Result<(), StorageError> {
         let path = self.get_chunk_path(&id);
-        let upload = self.store.put_multipart(&path).await?;
+        let attributes = self.chunk_attributes();
+        let options = PutMultipartOpts { attributes, ..PutMultipartOpts::default() };
+        let upload = self.store.put_multipart_opts(&path, options).await?;
         // TODO: new_with_chunk_size?
         let mut write = object_store::WriteMultipart::new(upload);
         write.write(&bytes);
@@ -282,6 +661,31 @@ impl Storage for ObjectStorage {
         Ok(())
     }
 
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        if !self.supports_create_if_not_exists {
+            if self.exists(&AnyObjectId::Chunk(id.clone())).await? {
+                return Ok(false);
+            }
+            self.write_chunk(id, bytes).await?;
+            return Ok(true);
+        }
+        // multipart uploads can't be conditional, so this is a single put
+        let path = self.get_chunk_path(&id);
+        let attributes = self.chunk_attributes();
+        let options =
+            PutOptions { mode: PutMode::Create, attributes, ..PutOptions::default() };
+        match self.store.put_opts(&path, PutPayload::from_bytes(bytes), options).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::AlreadyExists { .. })
+            | Err(object_store::Error::Precondition { .. }) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
         let key = self.ref_key(ref_key);
         match self.store.get(&key).await {
@@ -293,6 +697,46 @@ impl Storage for ObjectStorage {
         }
     }
 
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        let mut refs: Vec<_> = futures::stream::iter(keys.into_iter().enumerate())
+            .map(|(ix, key)| async move {
+                let bytes = ref_if_found(self.get_ref(&key).await)?;
+                Ok::<_, StorageError>((ix, key, bytes))
+            })
+            .buffer_unordered(REF_FETCH_CONCURRENCY)
+            .try_collect()
+            .await?;
+        refs.sort_by_key(|(ix, _, _)| *ix);
+        Ok(refs.into_iter().map(|(_, key, bytes)| (key, bytes)).collect())
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        let key = self.ref_key(ref_key);
+        let options = GetOptions {
+            if_none_match: etag.map(|etag| etag.to_string()),
+            ..Default::default()
+        };
+        match self.store.get_opts(&key, options).await {
+            Ok(res) => {
+                let etag = res.meta.e_tag.clone();
+                Ok(RefFetch::Changed { bytes: res.bytes().await?, etag })
+            }
+            Err(object_store::Error::NotModified { .. }) => Ok(RefFetch::Unchanged),
+            Err(object_store::Error::NotFound { .. }) => {
+                Err(StorageError::RefNotFound(key.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn ref_names(&self) -> StorageResult<Vec<String>> {
         // FIXME: i don't think object_store's implementation of list_with_delimiter is any good
         // we need to test if it even works beyond 1k refs
@@ -310,18 +754,34 @@ impl Storage for ObjectStorage {
             .collect())
     }
 
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        Ok(self
+            .list_kind(kind)
+            .try_filter(move |(_, modified)| ready(from <= *modified && *modified < to))
+            .boxed())
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        Ok(self.list_kind(kind).map_ok(|(id, _)| id).boxed())
+    }
+
     async fn ref_versions(
         &self,
         ref_name: &str,
     ) -> StorageResult<BoxStream<StorageResult<String>>> {
         let res = self.do_ref_versions(ref_name).await;
         if self.artificially_sort_refs_in_mem {
-            #[allow(clippy::expect_used)]
-            // This branch is used for local tests, not in production. We don't expect the size of
-            // these streams to be large, so we can collect in memory and fail early if there is an
-            // error
-            let mut all =
-                res.try_collect::<Vec<_>>().await.expect("Error fetching ref versions");
+            // We don't expect the size of these streams to be large, so we can collect in memory
+            // and fail early if there is an error
+            let mut all = res.try_collect::<Vec<_>>().await?;
             all.sort();
             Ok(futures::stream::iter(all.into_iter().map(Ok)).boxed())
         } else {
@@ -329,6 +789,32 @@ impl Storage for ObjectStorage {
         }
     }
 
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.delete_path(&self.get_chunk_path(id)).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.delete_path(&self.get_manifest_path(id)).await?;
+        // manifests can be written with an index
+        self.delete_path(&self.get_manifest_index_path(id)).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.delete_path(&self.get_snapshot_path(id)).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.delete_path(&self.get_attributes_path(id)).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.delete_path(&self.ref_key(format!("{ref_name}/{version_id}").as_str())).await
+    }
+
     async fn write_ref(
         &self,
         ref_key: &str,
@@ -354,4 +840,884 @@ impl Storage for ObjectStorage {
             })
             .map(|_| ())
     }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        let key = self.ref_key(ref_key);
+        let _guard = if self.supports_conditional_update {
+            None
+        } else {
+            Some(self.compare_and_swap_lock.lock().await)
+        };
+
+        let mode = match expected {
+            None => PutMode::Create,
+            Some(expected) => {
+                let current = match self.store.get(&key).await {
+                    Ok(current) => current,
+                    Err(object_store::Error::NotFound { .. }) => return Ok(false),
+                    Err(err) => return Err(err.into()),
+                };
+                let version = UpdateVersion {
+                    e_tag: current.meta.e_tag.clone(),
+                    version: current.meta.version.clone(),
+                };
+                if current.bytes().await? != expected {
+                    return Ok(false);
+                }
+                if self.supports_conditional_update {
+                    PutMode::Update(version)
+                } else {
+                    PutMode::Overwrite
+                }
+            }
+        };
+        let opts = PutOptions { mode, ..PutOptions::default() };
+        match self.store.put_opts(&key, PutPayload::from_bytes(new), opts).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::AlreadyExists { .. })
+            | Err(object_store::Error::Precondition { .. }) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        if self.uses_local_clock {
+            Ok(SystemTime::now())
+        } else {
+            Err(StorageError::Unsupported(
+                "backend_time, the time of caller provided object stores is unknown"
+                    .to_string(),
+            ))
+        }
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        // the first page of a listing is enough to check access
+        let prefix = ObjectPath::from(self.prefix.as_str());
+        match self.store.list(Some(&prefix)).next().await {
+            Some(Err(err)) => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use async_trait::async_trait;
+    use futures::stream::BoxStream;
+    use object_store::{GetResult, ListResult, MultipartUpload, ObjectMeta, PutResult};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::manifest::ChunkPayload,
+        refs::{fetch_branch_tip, update_branch},
+    };
+
+    /// Records every get request, and every successful put, made to the wrapped store
+    #[derive(Debug, Default)]
+    struct RecordingStore {
+        inner: InMemory,
+        gets: Mutex<Vec<(ObjectPath, Option<GetRange>)>>,
+        puts: Mutex<Vec<ObjectPath>>,
+    }
+
+    impl std::fmt::Display for RecordingStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "RecordingStore")
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for RecordingStore {
+        async fn put_opts(
+            &self,
+            location: &ObjectPath,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            let res = self.inner.put_opts(location, payload, opts).await?;
+            self.puts.lock().unwrap().push(location.clone());
+            Ok(res)
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &ObjectPath,
+            opts: PutMultipartOpts,
+        ) -> object_store::Result<Box<dyn MultipartUpload>> {
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &ObjectPath,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.gets.lock().unwrap().push((location.clone(), options.range.clone()));
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(
+            &self,
+            prefix: Option<&ObjectPath>,
+        ) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&ObjectPath>,
+        ) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(
+            &self,
+            from: &ObjectPath,
+            to: &ObjectPath,
+        ) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(
+            &self,
+            from: &ObjectPath,
+            to: &ObjectPath,
+        ) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    fn recording_storage() -> (Arc<RecordingStore>, ObjectStorage) {
+        let store = Arc::new(RecordingStore::default());
+        let storage = ObjectStorage {
+            store: Arc::clone(&store) as Arc<dyn ObjectStore>,
+            prefix: "prefix".to_string(),
+            artificially_sort_refs_in_mem: false,
+            supports_create_if_not_exists: true,
+            supports_metadata: true,
+            supports_conditional_update: true,
+            compare_and_swap_lock: tokio::sync::Mutex::new(()),
+            manifest_index_block_size: None,
+            chunk_content_type: DEFAULT_CHUNK_CONTENT_TYPE.to_string(),
+            chunk_content_encoding: None,
+            key_encoding: KeyEncoding::default(),
+            key_shard_len: 0,
+            uses_local_clock: false,
+        };
+        (store, storage)
+    }
+
+    fn big_manifest() -> Manifest {
+        (0..1000)
+            .map(|i| ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![i / 100, i % 100]),
+                payload: ChunkPayload::Inline(Bytes::from(format!("chunk {i}"))),
+                uncompressed_size: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fetch_chunk_info_reads_a_bounded_range(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (store, storage) = recording_storage();
+        let storage = storage.with_manifest_index(32);
+        let manifest = Arc::new(big_manifest());
+        let id = ManifestId::random();
+        storage.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+        let manifest_size = store.inner.head(&storage.get_manifest_path(&id)).await?.size;
+
+        let coord = ChunkIndices(vec![4, 2]);
+        let info = storage.fetch_chunk_info(&id, 1, &coord).await?;
+        assert_eq!(info, manifest.get_chunk_info(1, &coord));
+        assert!(info.is_some());
+
+        let gets = store.gets.lock().unwrap().clone();
+        assert_eq!(gets.len(), 2);
+        assert_eq!(gets[0], (storage.get_manifest_index_path(&id), None));
+        let (path, range) = &gets[1];
+        assert_eq!(path, &storage.get_manifest_path(&id));
+        match range {
+            Some(GetRange::Bounded(range)) => assert!(range.len() < manifest_size / 20),
+            other => panic!("expected a bounded range, got {other:?}"),
+        }
+
+        let missing = ChunkIndices(vec![42, 42]);
+        assert_eq!(storage.fetch_chunk_info(&id, 1, &missing).await?, None);
+        assert_eq!(storage.fetch_chunk_info(&id, 2, &coord).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_chunk_info_without_index(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (store, storage) = recording_storage();
+        let manifest = Arc::new(big_manifest());
+        let id = ManifestId::random();
+        storage.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+
+        let coord = ChunkIndices(vec![9, 99]);
+        let info = storage.fetch_chunk_info(&id, 1, &coord).await?;
+        assert_eq!(info, manifest.get_chunk_info(1, &coord));
+        assert_eq!(
+            store.gets.lock().unwrap().last(),
+            Some(&(storage.get_manifest_path(&id), None))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_manifest_chunks() -> Result<(), Box<dyn std::error::Error>> {
+        let chunk = |node, i: u32| ChunkInfo {
+            node,
+            coord: ChunkIndices(vec![i]),
+            payload: ChunkPayload::Inline(Bytes::from(format!("chunk {i}"))),
+            uncompressed_size: (i % 3 != 1).then_some(u64::from(i)),
+        };
+        let manifest: Arc<Manifest> = Arc::new(
+            (0..10)
+                .map(|i| chunk(0, i))
+                .chain((0..1000).map(|i| chunk(1, i)))
+                .chain((0..10).map(|i| chunk(2, i)))
+                .collect(),
+        );
+
+        let (store, storage) = recording_storage();
+        let indexed = storage.with_manifest_index(32);
+        let (_, unindexed) = recording_storage();
+        for storage in [&indexed, &unindexed] {
+            let id = ManifestId::random();
+            storage.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+            for node in [0, 1, 2, 3] {
+                let streamed: Vec<ChunkInfo> =
+                    storage.fetch_manifest_chunks(&id, node).try_collect().await?;
+                let expected: Vec<ChunkInfo> = manifest.node_chunk_infos(node).collect();
+                assert_eq!(streamed, expected);
+            }
+        }
+
+        // the indexed manifest is only read in blocks
+        let manifest_gets: Vec<_> = store
+            .gets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(path, _)| path.as_ref().contains("manifests/"))
+            .map(|(_, range)| range.clone())
+            .collect();
+        assert!(manifest_gets.len() > 32);
+        assert!(manifest_gets
+            .iter()
+            .all(|range| matches!(range, Some(GetRange::Bounded(_)))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_content_type() {
+        let (store, storage) = recording_storage();
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await.unwrap();
+        let attributes =
+            store.get(&storage.get_chunk_path(&id)).await.unwrap().attributes;
+        assert_eq!(
+            attributes.get(&Attribute::ContentType),
+            Some(&AttributeValue::from("application/octet-stream"))
+        );
+        assert_eq!(attributes.get(&Attribute::ContentEncoding), None);
+
+        let storage = storage
+            .with_chunk_content_type("application/x-zarr-chunk")
+            .with_chunk_content_encoding("gzip");
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await.unwrap();
+        let attributes =
+            store.get(&storage.get_chunk_path(&id)).await.unwrap().attributes;
+        assert_eq!(
+            attributes.get(&Attribute::ContentType),
+            Some(&AttributeValue::from("application/x-zarr-chunk"))
+        );
+        assert_eq!(
+            attributes.get(&Attribute::ContentEncoding),
+            Some(&AttributeValue::from("gzip"))
+        );
+
+        // injected stores write attributes only when enabled
+        let store = Arc::new(InMemory::new());
+        let storage = ObjectStorage::from_object_store(
+            Arc::clone(&store) as Arc<dyn ObjectStore>,
+            "injected".to_string(),
+        );
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await.unwrap();
+        let attributes =
+            store.get(&storage.get_chunk_path(&id)).await.unwrap().attributes;
+        assert_eq!(attributes.get(&Attribute::ContentType), None);
+
+        let storage = storage.with_metadata(true);
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await.unwrap();
+        let attributes =
+            store.get(&storage.get_chunk_path(&id)).await.unwrap().attributes;
+        assert_eq!(
+            attributes.get(&Attribute::ContentType),
+            Some(&AttributeValue::from("application/octet-stream"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_key_encodings() -> Result<(), Box<dyn std::error::Error>> {
+        let id = ChunkId::new([0xab, 0xcd, 0xef, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let cases = [
+            (KeyEncoding::Base32, id.to_string()),
+            (KeyEncoding::HexLower, "abcdef000102030405060708".to_string()),
+            (KeyEncoding::HexUpper, "ABCDEF000102030405060708".to_string()),
+        ];
+        for (encoding, encoded) in cases {
+            let storage = ObjectStorage::new_in_memory_store(Some("prefix".to_string()))
+                .with_key_encoding(encoding);
+            storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+            let manifest_id = ManifestId::new([0xff; 12]);
+            storage
+                .write_manifests(manifest_id.clone(), Arc::new(big_manifest()))
+                .await?;
+
+            let mut keys = storage.all_keys().await?;
+            keys.sort();
+            assert_eq!(
+                keys,
+                vec![
+                    format!("prefix/chunks/{encoded}"),
+                    format!("prefix/manifests/{}", encoding.encode(&manifest_id.0)),
+                ]
+            );
+            assert_eq!(
+                storage.fetch_chunk(&id, &ByteRange::ALL).await?,
+                Bytes::from_static(b"hello")
+            );
+            assert_eq!(*storage.fetch_manifests(&manifest_id).await?, big_manifest());
+            assert!(storage.exists(&AnyObjectId::Chunk(id.clone())).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_modified() -> Result<(), Box<dyn std::error::Error>> {
+        for encoding in
+            [KeyEncoding::Base32, KeyEncoding::HexLower, KeyEncoding::HexUpper]
+        {
+            let storage = ObjectStorage::new_in_memory_store(Some("prefix".into()))
+                .with_key_encoding(encoding);
+            let write = |n: usize| {
+                let storage = &storage;
+                async move {
+                    let mut ids = Vec::new();
+                    for _ in 0..n {
+                        let id = ChunkId::random();
+                        storage
+                            .write_chunk(id.clone(), Bytes::from_static(b"hi"))
+                            .await?;
+                        ids.push(AnyObjectId::Chunk(id));
+                    }
+                    StorageResult::Ok(ids)
+                }
+            };
+            let tick = || tokio::time::sleep(std::time::Duration::from_millis(5));
+
+            let before = write(3).await?;
+            tick().await;
+            let start = SystemTime::now();
+            tick().await;
+            let mut inside = write(4).await?;
+            // other kinds are not listed
+            storage
+                .write_manifests(ManifestId::random(), Arc::new(big_manifest()))
+                .await?;
+            tick().await;
+            let end = SystemTime::now();
+            tick().await;
+            let after = write(2).await?;
+
+            let list = |from, to| {
+                let storage = &storage;
+                async move {
+                    let mut listed: Vec<_> = storage
+                        .list_modified(ObjectKind::Chunk, from, to)
+                        .await?
+                        .map_ok(|(id, modified)| {
+                            assert!(from <= modified && modified < to);
+                            id
+                        })
+                        .try_collect()
+                        .await?;
+                    listed.sort();
+                    StorageResult::Ok(listed)
+                }
+            };
+            inside.sort();
+            assert_eq!(list(start, end).await?, inside);
+
+            let mut all: Vec<_> = before.into_iter().chain(inside).chain(after).collect();
+            all.sort();
+            assert_eq!(list(SystemTime::UNIX_EPOCH, SystemTime::now()).await?, all);
+            assert!(list(end, end).await?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_objects() -> Result<(), Box<dyn std::error::Error>> {
+        // manifest indexes and refs share the prefix, but are not listed
+        let storage = ObjectStorage::new_in_memory_store(Some("prefix".into()))
+            .with_manifest_index(2)
+            .with_sharded_keys(2);
+        let mut expected = BTreeMap::<ObjectKind, Vec<AnyObjectId>>::new();
+        for _ in 0..3 {
+            let id = SnapshotId::random();
+            storage.write_snapshot(id.clone(), Arc::new(Snapshot::empty())).await?;
+            expected
+                .entry(ObjectKind::Snapshot)
+                .or_default()
+                .push(AnyObjectId::Snapshot(id));
+
+            let id = ManifestId::random();
+            storage.write_manifests(id.clone(), Arc::new(big_manifest())).await?;
+            expected
+                .entry(ObjectKind::Manifest)
+                .or_default()
+                .push(AnyObjectId::Manifest(id));
+
+            let id = AttributesId::random();
+            storage.write_attributes(id.clone(), Arc::new(AttributesTable {})).await?;
+            expected
+                .entry(ObjectKind::Attributes)
+                .or_default()
+                .push(AnyObjectId::Attributes(id));
+        }
+        for _ in 0..5 {
+            let id = ChunkId::random();
+            storage.write_chunk(id.clone(), Bytes::from_static(b"hi")).await?;
+            expected.entry(ObjectKind::Chunk).or_default().push(AnyObjectId::Chunk(id));
+        }
+        storage.write_ref("branch.main/ZZZZZZZZ.json", false, Bytes::new()).await?;
+
+        for (kind, mut ids) in expected {
+            let mut listed: Vec<_> =
+                storage.list_objects(kind).await?.try_collect().await?;
+            listed.sort();
+            ids.sort();
+            assert_eq!(listed, ids);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_refs() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = ObjectStorage::new_in_memory_store(Some("prefix".into()));
+        let keys: Vec<String> = (0..40).map(|i| format!("tag.t{i}/ref.json")).collect();
+        for key in keys.iter().step_by(2) {
+            storage.write_ref(key, false, Bytes::from(key.clone())).await?;
+        }
+        let keys: Vec<&str> = keys.iter().rev().map(|key| key.as_str()).collect();
+
+        let refs = storage.get_refs(&keys).await?;
+        assert_eq!(refs.len(), keys.len());
+        for (ix, ((key, bytes), expected_key)) in refs.iter().zip(keys.iter()).enumerate()
+        {
+            assert_eq!(key, expected_key);
+            // reversed order, odd positions hold the even keys that were written
+            let expected = (ix % 2 == 1).then(|| Bytes::from(key.clone()));
+            assert_eq!(bytes, &expected);
+        }
+        assert_eq!(storage.get_refs(&[]).await?, vec![]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_object_store() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Arc::new(InMemory::new());
+        let storage = ObjectStorage::from_object_store(
+            Arc::clone(&store) as Arc<dyn ObjectStore>,
+            "injected".to_string(),
+        );
+
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"hello")
+        );
+        let manifest_id = ManifestId::random();
+        storage.write_manifests(manifest_id.clone(), Arc::new(big_manifest())).await?;
+        assert_eq!(*storage.fetch_manifests(&manifest_id).await?, big_manifest());
+        storage
+            .write_ref("branch.main/ZZZZZZZZ.json", false, Bytes::from_static(b"{}"))
+            .await?;
+        storage
+            .write_ref("branch.main/ZZZZZZZY.json", false, Bytes::from_static(b"{}"))
+            .await?;
+        let versions: Vec<_> =
+            storage.ref_versions("branch.main").await?.try_collect().await?;
+        assert_eq!(versions, vec!["ZZZZZZZY.json", "ZZZZZZZZ.json"]);
+
+        // the objects are in the injected store
+        let chunk_path = ObjectPath::from(format!("injected/chunks/{id}"));
+        assert_eq!(
+            store.get(&chunk_path).await?.bytes().await?,
+            Bytes::from_static(b"hello")
+        );
+
+        // the injected store could be remote, its clock is not assumed to be ours
+        assert!(matches!(
+            storage.backend_time().await,
+            Err(StorageError::Unsupported(_))
+        ));
+        let before = SystemTime::now();
+        let local = ObjectStorage::new_in_memory_store(None).backend_time().await?;
+        assert!(local >= before);
+        Ok(())
+    }
+
+    /// The conditional ref writes stores passed to `from_object_store` have to support
+    #[tokio::test]
+    async fn test_from_object_store_conditional_refs(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = ObjectStorage::from_object_store(
+            Arc::new(InMemory::new()),
+            "injected".to_string(),
+        );
+        storage.write_ref("branch.main/ZZZZZZZZ.json", false, Bytes::from("1")).await?;
+        assert!(matches!(
+            storage.write_ref("branch.main/ZZZZZZZZ.json", false, Bytes::from("2")).await,
+            Err(StorageError::RefAlreadyExists(_))
+        ));
+        assert_eq!(storage.get_ref("branch.main/ZZZZZZZZ.json").await?, Bytes::from("1"));
+
+        assert!(
+            storage.compare_and_swap_ref("branch.tip", None, Bytes::from("a")).await?
+        );
+        assert!(
+            !storage.compare_and_swap_ref("branch.tip", None, Bytes::from("b")).await?
+        );
+        assert!(
+            storage
+                .compare_and_swap_ref(
+                    "branch.tip",
+                    Some(Bytes::from("a")),
+                    Bytes::from("c")
+                )
+                .await?
+        );
+        assert!(
+            !storage
+                .compare_and_swap_ref(
+                    "branch.tip",
+                    Some(Bytes::from("a")),
+                    Bytes::from("d")
+                )
+                .await?
+        );
+        assert_eq!(storage.get_ref("branch.tip").await?, Bytes::from("c"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_chunk_if_absent() -> Result<(), Box<dyn std::error::Error>> {
+        let (store, storage) = recording_storage();
+        let id = ChunkId::random();
+        assert!(
+            storage.write_chunk_if_absent(id.clone(), Bytes::from_static(b"a")).await?
+        );
+        assert!(
+            !storage.write_chunk_if_absent(id.clone(), Bytes::from_static(b"b")).await?
+        );
+        assert_eq!(*store.puts.lock().unwrap(), vec![storage.get_chunk_path(&id)]);
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"a")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_open_ended_chunk_ranges() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let (store, storage) = recording_storage();
+        let id = ChunkId::random();
+        let bytes = Bytes::from_iter(0..100u8);
+        storage.write_chunk(id.clone(), bytes.clone()).await?;
+
+        let cases = [
+            (ByteRange::ALL, bytes.clone(), None),
+            (ByteRange::from_offset(90), bytes.slice(90..), Some(GetRange::Offset(90))),
+            (ByteRange::to_offset(10), bytes.slice(..10), Some(GetRange::Bounded(0..10))),
+            (ByteRange::last(5), bytes.slice(95..), Some(GetRange::Suffix(5))),
+            // suffixes longer than the chunk return all of it
+            (ByteRange::last(500), bytes.clone(), Some(GetRange::Suffix(500))),
+        ];
+        for (range, expected, get_range) in cases {
+            assert_eq!(storage.fetch_chunk(&id, &range).await?, expected);
+            assert_eq!(store.gets.lock().unwrap().pop().map(|(_, r)| r), Some(get_range));
+            // slicing the full chunk gives the same bytes
+            assert_eq!(range.slice(bytes.clone()), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_filesystem() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let snapshot_id = SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        let manifest_id = ManifestId::random();
+        let chunk_id = ChunkId::random();
+        let chunk = Bytes::from_static(b"hello");
+        {
+            let storage = ObjectStorage::new_local_filesystem(dir.path().to_path_buf())?;
+            storage.write_snapshot(snapshot_id.clone(), Arc::clone(&snapshot)).await?;
+            storage
+                .write_manifests(manifest_id.clone(), Arc::new(big_manifest()))
+                .await?;
+            storage.write_chunk(chunk_id.clone(), chunk.clone()).await?;
+            storage.write_ref("branch.main/ZZZZZZZZ", false, Bytes::from("1")).await?;
+            assert!(matches!(
+                storage.write_ref("branch.main/ZZZZZZZZ", false, Bytes::from("2")).await,
+                Err(StorageError::RefAlreadyExists(_))
+            ));
+            storage.write_ref("branch.main/ZZZZZZZY", false, Bytes::from("3")).await?;
+
+            let key = chunk_id.to_string();
+            assert!(dir.path().join("chunks").join(&key[..2]).join(&key).is_file());
+        }
+
+        let storage = ObjectStorage::new_local_filesystem(dir.path().to_path_buf())?;
+        assert_eq!(storage.fetch_snapshot(&snapshot_id).await?, snapshot);
+        assert_eq!(*storage.fetch_manifests(&manifest_id).await?, big_manifest());
+        assert_eq!(storage.fetch_chunk(&chunk_id, &ByteRange::ALL).await?, chunk);
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::Last(2)).await?,
+            Bytes::from_static(b"lo")
+        );
+        assert_eq!(storage.get_ref("branch.main/ZZZZZZZZ").await?, Bytes::from("1"));
+        assert_eq!(storage.ref_names().await?, vec!["branch.main".to_string()]);
+        let versions: Vec<_> =
+            storage.ref_versions("branch.main").await?.try_collect().await?;
+        assert_eq!(versions, vec!["ZZZZZZZY".to_string(), "ZZZZZZZZ".to_string()]);
+
+        let listed: Vec<_> = storage
+            .list_modified(ObjectKind::Chunk, SystemTime::UNIX_EPOCH, SystemTime::now())
+            .await?
+            .map_ok(|(id, _)| id)
+            .try_collect()
+            .await?;
+        assert_eq!(listed, vec![AnyObjectId::Chunk(chunk_id)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_chunk_ranges_coalesces() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let (store, storage) = recording_storage();
+        let id = ChunkId::random();
+        let bytes = Bytes::from_iter(0..100u8);
+        storage.write_chunk(id.clone(), bytes.clone()).await?;
+        let path = storage.get_chunk_path(&id);
+
+        // adjacent ranges, in any order, are a single fetch
+        let ranges = [
+            ByteRange::bounded(20, 30),
+            ByteRange::bounded(10, 20),
+            ByteRange::bounded(30, 35),
+        ];
+        let fetched = storage.fetch_chunk_ranges(&id, &ranges, 0).await?;
+        assert_eq!(
+            fetched,
+            vec![bytes.slice(20..30), bytes.slice(10..20), bytes.slice(30..35)]
+        );
+        assert_eq!(
+            store.gets.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![(path.clone(), Some(GetRange::Bounded(10..35)))]
+        );
+
+        // ranges further apart than the gap are fetched separately, open ranges absorb the rest
+        let ranges = [
+            ByteRange::bounded(0, 5),
+            ByteRange::bounded(8, 12),
+            ByteRange::bounded(50, 60),
+            ByteRange::from_offset(55),
+            ByteRange::bounded(90, 95),
+            ByteRange::Last(3),
+        ];
+        let fetched = storage.fetch_chunk_ranges(&id, &ranges, 5).await?;
+        assert_eq!(
+            fetched,
+            vec![
+                bytes.slice(0..5),
+                bytes.slice(8..12),
+                bytes.slice(50..60),
+                bytes.slice(55..),
+                bytes.slice(90..95),
+                bytes.slice(97..),
+            ]
+        );
+        let mut gets =
+            store.gets.lock().unwrap().drain(..).map(|(_, r)| r).collect::<Vec<_>>();
+        gets.sort_by_key(|r| format!("{r:?}"));
+        assert_eq!(
+            gets,
+            vec![
+                Some(GetRange::Bounded(0..12)),
+                Some(GetRange::Offset(50)),
+                Some(GetRange::Suffix(3)),
+            ]
+        );
+        Ok(())
+    }
+    /// Race `writers` compare and swaps of the same ref, returns the value that won
+    ///
+    /// Every writer writes a different value, not used by any other round.
+    async fn race_on_ref(
+        storage: &Arc<ObjectStorage>,
+        round: &'static str,
+        expected: Option<Bytes>,
+        writers: usize,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        let handles: Vec<_> = (0..writers)
+            .map(|i| {
+                let storage = Arc::clone(storage);
+                let expected = expected.clone();
+                tokio::spawn(async move {
+                    let new = Bytes::from(format!("{round} writer {i}"));
+                    let swapped = storage
+                        .compare_and_swap_ref(
+                            "branch.main/ref.json",
+                            expected,
+                            new.clone(),
+                        )
+                        .await?;
+                    StorageResult::Ok((swapped, new))
+                })
+            })
+            .collect();
+        let mut winners = Vec::new();
+        for handle in handles {
+            let (swapped, new) = handle.await??;
+            if swapped {
+                winners.push(new);
+            }
+        }
+        assert_eq!(winners.len(), 1);
+        assert_eq!(storage.get_ref("branch.main/ref.json").await?, winners[0]);
+        Ok(winners.remove(0))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compare_and_swap_ref_races() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        // the local store doesn't support conditional updates, it swaps under a lock
+        for storage in [
+            ObjectStorage::new_in_memory_store(None),
+            ObjectStorage::new_local_store(dir.path())?,
+        ] {
+            let storage = Arc::new(storage);
+            let created = race_on_ref(&storage, "create", None, 10).await?;
+            let updated =
+                race_on_ref(&storage, "update", Some(created.clone()), 10).await?;
+
+            // a writer that lost the race can't overwrite the winner
+            assert!(
+                !storage
+                    .compare_and_swap_ref(
+                        "branch.main/ref.json",
+                        Some(created),
+                        Bytes::from("late")
+                    )
+                    .await?
+            );
+            assert_eq!(storage.get_ref("branch.main/ref.json").await?, updated);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_ancestry() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let root = Arc::new(Snapshot::empty());
+        let child = Arc::new(Snapshot::from_iter(&root, None, vec![], vec![], []));
+        let grandchild = Arc::new(Snapshot::from_iter(&child, None, vec![], vec![], []));
+        for snapshot in [&root, &child, &grandchild] {
+            storage
+                .write_snapshot(snapshot.metadata.id.clone(), Arc::clone(snapshot))
+                .await?;
+        }
+
+        let ancestry: Vec<_> =
+            storage.ancestry(&grandchild.metadata.id).try_collect().await?;
+        assert_eq!(ancestry, vec![grandchild.clone(), child.clone(), root.clone()]);
+        let ancestry: Vec<_> = storage.ancestry(&root.metadata.id).try_collect().await?;
+        assert_eq!(ancestry, vec![root.clone()]);
+
+        // a missing parent fails the stream, after yielding its child
+        storage.delete_snapshot(&child.metadata.id).await?;
+        let mut ancestry = storage.ancestry(&grandchild.metadata.id);
+        assert_eq!(ancestry.try_next().await?, Some(grandchild.clone()));
+        assert!(matches!(
+            ancestry.try_next().await,
+            Err(StorageError::MissingParent { snapshot, parent })
+                if snapshot == grandchild.metadata.id && parent == child.metadata.id
+        ));
+        assert!(ancestry.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expire_ref_versions() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        for storage in [
+            ObjectStorage::new_in_memory_store(None),
+            ObjectStorage::new_local_store(dir.path())?,
+        ] {
+            let mut parent = None;
+            for _ in 0..5 {
+                let snapshot = SnapshotId::random();
+                update_branch(&storage, "main", snapshot.clone(), parent.as_ref(), false)
+                    .await?;
+                parent = Some(snapshot);
+            }
+            let versions: Vec<String> =
+                storage.ref_versions("branch.main").await?.try_collect().await?;
+            assert_eq!(versions.len(), 5);
+
+            assert_eq!(storage.expire_ref_versions("branch.main", 2).await?, 3);
+            let remaining: Vec<String> =
+                storage.ref_versions("branch.main").await?.try_collect().await?;
+            assert_eq!(remaining, versions[..2]);
+            assert_eq!(
+                fetch_branch_tip(&storage, "main").await?.snapshot,
+                parent.unwrap()
+            );
+            assert_eq!(storage.expire_ref_versions("branch.main", 2).await?, 0);
+
+            // the current value is never deleted
+            assert_eq!(storage.expire_ref_versions("branch.main", 0).await?, 1);
+            let remaining: Vec<String> =
+                storage.ref_versions("branch.main").await?.try_collect().await?;
+            assert_eq!(remaining, versions[..1]);
+            assert_eq!(storage.expire_ref_versions("branch.missing", 1).await?, 0);
+        }
+        Ok(())
+    }
 }
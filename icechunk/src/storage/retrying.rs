@@ -0,0 +1,576 @@
+//! A [`Storage`] decorator that retries failed operations
+//!
+//! Retries are limited per call by [`RetryConfig`], and optionally across all the calls of a
+//! workload by a shared [`RetryBudget`]. Once the budget is exhausted, failures are returned
+//! immediately, which keeps a struggling backend from being hammered by retries of thousands of
+//! concurrent calls.
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{config::http::HttpResponse, error::SdkError};
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageError, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// How many times a single call is retried, not counting the first attempt
+    pub max_retries: u32,
+    /// Wait before the first retry, it doubles with every retry of the same call
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryBudgetConfig {
+    /// Total retries allowed in a window, across all the calls sharing the budget
+    pub max_retries: u32,
+    /// Time for an exhausted budget to be fully replenished, it refills gradually
+    pub window: Duration,
+}
+
+/// A token bucket of retry attempts that can be shared by several [`RetryingStorage`]
+///
+/// Every retry takes a token. Tokens are replenished continuously, at a rate of
+/// `config.max_retries` per `config.window`.
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    state: Mutex<BudgetState>,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        let state = BudgetState {
+            tokens: config.max_retries as f64,
+            refilled_at: Instant::now(),
+        };
+        Self { config, state: Mutex::new(state) }
+    }
+
+    pub fn config(&self) -> &RetryBudgetConfig {
+        &self.config
+    }
+
+    /// Retries that can be done right now
+    #[allow(clippy::expect_used)]
+    pub fn remaining(&self) -> u32 {
+        let mut state = self.state.lock().expect("poison lock");
+        self.refill(&mut state);
+        state.tokens as u32
+    }
+
+    /// Take a token for a retry, returns `false` if the budget is exhausted
+    #[allow(clippy::expect_used)]
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().expect("poison lock");
+        self.refill(&mut state);
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, state: &mut BudgetState) {
+        let max = self.config.max_retries as f64;
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled_at);
+        state.tokens = if self.config.window.is_zero() {
+            max
+        } else {
+            let rate = max / self.config.window.as_secs_f64();
+            (state.tokens + elapsed.as_secs_f64() * rate).min(max)
+        };
+        state.refilled_at = now;
+    }
+}
+
+#[derive(Debug)]
+pub struct RetryingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    config: RetryConfig,
+    budget: Option<Arc<RetryBudget>>,
+}
+
+impl RetryingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>, config: RetryConfig) -> Self {
+        Self { backend, config, budget: None }
+    }
+
+    /// Limit the total retries of this storage, and anything else sharing `budget`
+    pub fn with_budget(self, budget: Arc<RetryBudget>) -> Self {
+        Self { budget: Some(budget), ..self }
+    }
+
+    pub fn budget(&self) -> Option<&Arc<RetryBudget>> {
+        self.budget.as_ref()
+    }
+
+    async fn retry<R, F, Fut>(&self, mut op: F) -> StorageResult<R>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = StorageResult<R>> + Send,
+    {
+        let mut backoff = self.config.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match op().await {
+                Err(err)
+                    if is_transient(&err)
+                        && retries < self.config.max_retries
+                        && self.budget.as_ref().is_none_or(|b| b.try_acquire()) =>
+                {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Errors that could go away by trying again
+///
+/// Only I/O errors, interrupted streams, and S3 server errors, timeouts and throttling are
+/// retried. Missing or conflicting objects, errors decoding them, requests rejected by S3 with
+/// a client error status, and any other error, are permanent.
+pub(crate) fn is_transient(err: &StorageError) -> bool {
+    match err {
+        StorageError::ObjectStore(err) => !matches!(
+            err,
+            ::object_store::Error::NotFound { .. }
+                | ::object_store::Error::AlreadyExists { .. }
+                | ::object_store::Error::Precondition { .. }
+                | ::object_store::Error::NotModified { .. }
+                | ::object_store::Error::NotSupported { .. }
+                | ::object_store::Error::NotImplemented
+        ),
+        StorageError::S3GetObjectError(err) => is_transient_sdk_error(err.as_ref()),
+        StorageError::S3PutObjectError(err) => is_transient_sdk_error(err.as_ref()),
+        StorageError::S3HeadObjectError(err) => is_transient_sdk_error(err.as_ref()),
+        StorageError::S3ListObjectError(err) => is_transient_sdk_error(err.as_ref()),
+        StorageError::S3DeleteObjectError(err) => is_transient_sdk_error(err.as_ref()),
+        StorageError::S3StreamError(_) => true,
+        _ => false,
+    }
+}
+
+/// Timeouts, dispatch failures and server errors are transient, throttling too
+fn is_transient_sdk_error<E>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::ConstructionFailure(_) => false,
+        SdkError::ServiceError(_) => err.raw_response().is_some_and(|response| {
+            let status = response.status();
+            status.is_server_error() || matches!(status.as_u16(), 408 | 429)
+        }),
+        _ => true,
+    }
+}
+
+impl private::Sealed for RetryingStorage {}
+
+#[async_trait]
+impl Storage for RetryingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.retry(|| self.backend.fetch_snapshot(id)).await
+    }
+
+    async fn fetch_snapshot_subtree(
+        &self,
+        id: &SnapshotId,
+        root_path: &Path,
+    ) -> StorageResult<Snapshot> {
+        self.retry(|| self.backend.fetch_snapshot_subtree(id, root_path)).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.retry(|| self.backend.fetch_attributes(id)).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.retry(|| self.backend.fetch_manifests(id)).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.retry(|| self.backend.fetch_chunk(id, range)).await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.retry(|| self.backend.fetch_chunk_info(manifest_id, node, coord)).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.retry(|| self.backend.fetch_node_chunks(manifest_id, node)).await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.retry(|| self.backend.exists(id)).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.write_snapshot(id.clone(), Arc::clone(&table))).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.write_attributes(id.clone(), Arc::clone(&table))).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.write_manifests(id.clone(), Arc::clone(&table))).await
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        self.retry(|| {
+            self.backend.write_manifests_if_not_exists(id.clone(), Arc::clone(&manifest))
+        })
+        .await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.retry(|| self.backend.write_chunk(id.clone(), bytes.clone())).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        // a retry after a lost response finds the chunk, writing it again wouldn't change it
+        self.retry(|| self.backend.write_chunk_if_absent(id.clone(), bytes.clone())).await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        // with random ids, a retry after a lost response leaves an unreachable copy behind
+        self.retry(|| self.backend.write_new_chunk(bytes.clone())).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.retry(|| self.backend.get_ref(ref_key)).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.retry(|| self.backend.get_ref_if_changed(ref_key, etag)).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.retry(|| self.backend.get_refs(keys)).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.retry(|| self.backend.ref_names()).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.retry(|| self.backend.list_modified(kind, from, to)).await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.retry(|| self.backend.list_objects(kind)).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.retry(|| self.backend.ref_versions(ref_name)).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.retry(|| self.backend.delete_chunk(id)).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.retry(|| self.backend.delete_manifest(id)).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.retry(|| self.backend.delete_snapshot(id)).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.retry(|| self.backend.delete_attributes(id)).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.delete_ref_version(ref_name, version_id)).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        if overwrite_refs {
+            self.retry(|| self.backend.write_ref(ref_key, true, bytes.clone())).await
+        } else {
+            // not retried, if the failed attempt created the ref a retry would report a conflict
+            self.backend.write_ref(ref_key, false, bytes).await
+        }
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        // not retried, if the failed attempt did the swap a retry would report a conflict
+        self.backend.compare_and_swap_ref(ref_key, expected, new).await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.retry(|| self.backend.backend_time()).await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.retry(|| self.backend.ping()).await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        // not retried, a retry would record a second version
+        self.backend.record_ref_version(ref_name, bytes).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::storage::faulty::{Call, Fault, FaultyStorage};
+
+    fn is_attempt(call: &Call) -> bool {
+        matches!(call.method, "fetch_chunk" | "write_ref")
+    }
+
+    /// Fails the first `failures` chunk fetches and ref writes
+    fn failing_storage(failures: usize) -> Arc<FaultyStorage> {
+        let failures = AtomicUsize::new(failures);
+        Arc::new(FaultyStorage::in_memory().on_all(move |call| {
+            let failed = is_attempt(call)
+                && failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        n.checked_sub(1)
+                    })
+                    .is_ok();
+            if failed {
+                Fault::Error(StorageError::ObjectStore(::object_store::Error::Generic {
+                    store: "test",
+                    source: "backend overloaded".into(),
+                }))
+            } else {
+                Fault::Pass
+            }
+        }))
+    }
+
+    /// The chunk fetches and ref writes that reached `storage` since the last call
+    fn take_attempts(storage: &FaultyStorage) -> usize {
+        storage.take_calls().iter().filter(|call| is_attempt(call)).count()
+    }
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_retries: 3,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend = failing_storage(3);
+        let id = ChunkId::random();
+        backend.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+
+        let storage = RetryingStorage::new(backend.clone(), config());
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+        assert_eq!(take_attempts(&backend), 4);
+
+        // permanent errors are not retried
+        let missing = ChunkId::random();
+        assert!(storage.fetch_chunk(&missing, &ByteRange::ALL).await.is_err());
+        assert_eq!(take_attempts(&backend), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_only_overwriting_ref_writes_are_retried(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend = failing_storage(2);
+        let storage = RetryingStorage::new(backend.clone(), config());
+        storage.write_ref("branch.main/0", true, Bytes::from("a")).await?;
+        assert_eq!(take_attempts(&backend), 3);
+
+        let backend = failing_storage(1);
+        let storage = RetryingStorage::new(backend.clone(), config());
+        assert!(storage
+            .write_ref("branch.main/0", false, Bytes::from("a"))
+            .await
+            .is_err());
+        assert_eq!(take_attempts(&backend), 1);
+        storage.write_ref("branch.main/0", false, Bytes::from("a")).await?;
+        assert!(matches!(
+            storage.write_ref("branch.main/0", false, Bytes::from("b")).await,
+            Err(StorageError::RefAlreadyExists(_))
+        ));
+        assert_eq!(take_attempts(&backend), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_s3_errors_are_transient_by_status() {
+        use aws_sdk_s3::{operation::get_object::GetObjectError, primitives::SdkBody};
+
+        let with_status = |status: u16| {
+            let response =
+                HttpResponse::new(status.try_into().unwrap(), SdkBody::empty());
+            let err = GetObjectError::unhandled("failed");
+            StorageError::S3GetObjectError(Box::new(SdkError::service_error(
+                err, response,
+            )))
+        };
+        for status in [500, 503, 429, 408] {
+            assert!(is_transient(&with_status(status)), "{status}");
+        }
+        for status in [400, 403, 404, 412] {
+            assert!(!is_transient(&with_status(status)), "{status}");
+        }
+        assert!(is_transient(&StorageError::S3GetObjectError(Box::new(
+            SdkError::timeout_error("timed out")
+        ))));
+        assert!(!is_transient(&StorageError::Other("unknown".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_budget_fails_fast() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend = failing_storage(usize::MAX);
+        let id = ChunkId::random();
+        let budget = Arc::new(RetryBudget::new(RetryBudgetConfig {
+            max_retries: 5,
+            window: Duration::from_secs(3600),
+        }));
+        // two storages in the same operation group share the budget
+        let first = RetryingStorage::new(backend.clone(), config())
+            .with_budget(Arc::clone(&budget));
+        let second = RetryingStorage::new(backend.clone(), config())
+            .with_budget(Arc::clone(&budget));
+
+        assert!(first.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        assert_eq!(take_attempts(&backend), 4);
+        assert_eq!(budget.remaining(), 2);
+
+        assert!(second.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        assert_eq!(take_attempts(&backend), 3);
+        assert_eq!(budget.remaining(), 0);
+
+        // once exhausted, every failure is returned without retrying
+        for storage in [&first, &second, &first] {
+            assert!(storage.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+            assert_eq!(take_attempts(&backend), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_budget_refills_over_its_window() {
+        let budget = RetryBudget::new(RetryBudgetConfig {
+            max_retries: 2,
+            window: Duration::from_millis(100),
+        });
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(budget.remaining(), 2);
+    }
+}
@@ -1,17 +1,26 @@
-use std::{ops::Range, path::PathBuf, sync::Arc};
+use std::{
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 use async_stream::try_stream;
 use async_trait::async_trait;
 use aws_config::{meta::region::RegionProviderChain, AppName, BehaviorVersion};
 use aws_credential_types::Credentials;
 use aws_sdk_s3::{
-    config::{Builder, Region},
+    config::{
+        interceptors::BeforeDeserializationInterceptorContextRef, Builder, ConfigBag,
+        Intercept, Region, RuntimeComponents,
+    },
     error::ProvideErrorMetadata,
     primitives::ByteStream,
     Client,
 };
 use bytes::Bytes;
-use futures::StreamExt;
+use chrono::DateTime;
+use futures::{future::ready, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -25,8 +34,13 @@ use crate::{
     Storage, StorageError,
 };
 
-use super::StorageResult;
+use super::{AnyObjectId, ObjectKind, RefFetch, StorageResult};
 
+/// [`Storage`] backed by an S3 compatible object store
+///
+/// Objects are stored under `prefix` in `snapshots/`, `manifests/`, `attributes/` and `chunks/`,
+/// keyed by their id, and refs under `refs/`. Refs are written with a conditional put unless
+/// overwriting is allowed, so concurrent commits to the same branch version can't both win.
 #[derive(Debug)]
 pub struct S3Storage {
     client: Arc<Client>,
@@ -106,12 +120,41 @@ pub async fn mk_client(config: Option<&S3Config>) -> Client {
     Client::from_conf(config)
 }
 
+/// Captures the `Date` header of the response
+#[derive(Debug, Default, Clone)]
+struct DateHeaderInterceptor {
+    date: Arc<Mutex<Option<String>>>,
+}
+
+impl Intercept for DateHeaderInterceptor {
+    fn name(&self) -> &'static str {
+        "DateHeaderInterceptor"
+    }
+
+    fn read_after_transmit(
+        &self,
+        context: &BeforeDeserializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(date) = context.response().headers().get("date") {
+            #[allow(clippy::expect_used)]
+            let mut captured = self.date.lock().expect("poison lock");
+            *captured = Some(date.to_string());
+        }
+        Ok(())
+    }
+}
+
 const SNAPSHOT_PREFIX: &str = "snapshots/";
 const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
+const ATTRIBUTES_PREFIX: &str = "attributes/";
 const CHUNK_PREFIX: &str = "chunks/";
 const REF_PREFIX: &str = "refs";
 
+type ListedObjects<'a> =
+    futures::stream::BoxStream<'a, StorageResult<(AnyObjectId, Option<SystemTime>)>>;
+
 impl S3Storage {
     pub async fn new_s3_store(
         bucket_name: impl Into<String>,
@@ -144,15 +187,75 @@ impl S3Storage {
         self.get_path(MANIFEST_PREFIX, id)
     }
 
+    fn get_attributes_path(&self, id: &AttributesId) -> StorageResult<String> {
+        self.get_path(ATTRIBUTES_PREFIX, id)
+    }
+
     fn get_chunk_path(&self, id: &ChunkId) -> StorageResult<String> {
         self.get_path(CHUNK_PREFIX, id)
     }
 
+    fn get_object_path(&self, id: &AnyObjectId) -> StorageResult<String> {
+        match id {
+            AnyObjectId::Snapshot(id) => self.get_snapshot_path(id),
+            AnyObjectId::Manifest(id) => self.get_manifest_path(id),
+            AnyObjectId::Attributes(id) => self.get_attributes_path(id),
+            AnyObjectId::Chunk(id) => self.get_chunk_path(id),
+        }
+    }
+
     fn ref_key(&self, ref_key: &str) -> StorageResult<String> {
         let path = PathBuf::from_iter([self.prefix.as_str(), REF_PREFIX, ref_key]);
         path.into_os_string().into_string().map_err(StorageError::BadPrefix)
     }
 
+    /// All the objects of `kind`, with their modification time if S3 returned it
+    fn list_kind(&self, kind: ObjectKind) -> StorageResult<ListedObjects<'_>> {
+        let file_prefix = match kind {
+            ObjectKind::Snapshot => SNAPSHOT_PREFIX,
+            ObjectKind::Manifest => MANIFEST_PREFIX,
+            ObjectKind::Attributes => ATTRIBUTES_PREFIX,
+            ObjectKind::Chunk => CHUNK_PREFIX,
+        };
+        let prefix = PathBuf::from_iter([self.prefix.as_str(), file_prefix])
+            .into_os_string()
+            .into_string()
+            .map_err(StorageError::BadPrefix)?;
+        let mut paginator = self
+            .client
+            .list_objects_v2()
+            .bucket(self.bucket.clone())
+            .prefix(prefix.clone())
+            .into_paginator()
+            .send();
+
+        let stream = try_stream! {
+            while let Some(page) = paginator.try_next().await? {
+                for object in page.contents() {
+                    let id = object
+                        .key
+                        .as_ref()
+                        .and_then(|key| key.strip_prefix(prefix.as_str()))
+                        .and_then(|key| base32::decode(base32::Alphabet::Crockford, key))
+                        .and_then(|bytes| AnyObjectId::from_bytes(kind, &bytes));
+                    let modified =
+                        object.last_modified.and_then(|at| SystemTime::try_from(at).ok());
+                    if let Some(id) = id {
+                        yield (id, modified)
+                    }
+                }
+            }
+        };
+        Ok(stream.boxed())
+    }
+
+    /// S3 deletes are idempotent, deleting a missing object succeeds
+    async fn delete_object(&self, id: &AnyObjectId) -> StorageResult<()> {
+        let key = self.get_object_path(id)?;
+        self.client.delete_object().bucket(self.bucket.clone()).key(key).send().await?;
+        Ok(())
+    }
+
     async fn get_object(&self, key: &str) -> StorageResult<Bytes> {
         Ok(self
             .client
@@ -212,7 +315,7 @@ pub fn range_to_header(range: &ByteRange) -> Option<String> {
         }
         ByteRange::From(offset) if *offset == 0 => None,
         ByteRange::From(offset) => Some(format!("bytes={}-", offset)),
-        ByteRange::Last(n) => Some(format!("bytes={}-", n)),
+        ByteRange::Last(n) => Some(format!("bytes=-{}", n)),
     }
 }
 
@@ -230,9 +333,12 @@ impl Storage for S3Storage {
 
     async fn fetch_attributes(
         &self,
-        _id: &AttributesId,
+        id: &AttributesId,
     ) -> StorageResult<Arc<AttributesTable>> {
-        todo!()
+        let key = self.get_attributes_path(id)?;
+        let bytes = self.get_object(key.as_str()).await?;
+        let res = rmp_serde::from_slice(bytes.as_ref())?;
+        Ok(Arc::new(res))
     }
 
     async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
@@ -249,6 +355,21 @@ impl Storage for S3Storage {
         Ok(bytes)
     }
 
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        let key = self.get_object_path(id)?;
+        let res =
+            self.client.head_object().bucket(self.bucket.clone()).key(key).send().await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(err)
+                if err.as_service_error().map(|e| e.is_not_found()).unwrap_or(false) =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn write_snapshot(
         &self,
         id: SnapshotId,
@@ -271,10 +392,13 @@ impl Storage for S3Storage {
 
     async fn write_attributes(
         &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
     ) -> StorageResult<()> {
-        todo!()
+        let key = self.get_attributes_path(&id)?;
+        let bytes = rmp_serde::to_vec(table.as_ref())?;
+        let metadata: [(String, String); 0] = [];
+        self.put_object(key.as_str(), None::<String>, metadata, bytes).await
     }
 
     async fn write_manifests(
@@ -308,6 +432,36 @@ impl Storage for S3Storage {
         self.put_object(key.as_str(), None::<String>, metadata, bytes).await
     }
 
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        let key = self.get_chunk_path(&id)?;
+        let res = self
+            .client
+            .put_object()
+            .bucket(self.bucket.clone())
+            .key(key)
+            .if_none_match("*")
+            .body(bytes.into())
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(err) => {
+                let code = err.as_service_error().and_then(|e| e.code()).unwrap_or("");
+                if code.contains("PreconditionFailed")
+                    || code.contains("ConditionalRequestConflict")
+                {
+                    Ok(false)
+                } else {
+                    Err(err.into())
+                }
+            }
+        }
+    }
+
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
         let key = self.ref_key(ref_key)?;
         let res = self
@@ -332,6 +486,46 @@ impl Storage for S3Storage {
         }
     }
 
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        let key = self.ref_key(ref_key)?;
+        let res = self
+            .client
+            .get_object()
+            .bucket(self.bucket.clone())
+            .key(key.clone())
+            .set_if_none_match(etag.map(|etag| etag.to_string()))
+            .send()
+            .await;
+
+        match res {
+            Ok(res) => {
+                let etag = res.e_tag.clone();
+                Ok(RefFetch::Changed {
+                    bytes: res.body.collect().await?.into_bytes(),
+                    etag,
+                })
+            }
+            Err(err)
+                if err.raw_response().map(|res| res.status().as_u16()) == Some(304) =>
+            {
+                Ok(RefFetch::Unchanged)
+            }
+            Err(err)
+                if err
+                    .as_service_error()
+                    .map(|e| e.is_no_such_key())
+                    .unwrap_or(false) =>
+            {
+                Err(StorageError::RefNotFound(key.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn ref_names(&self) -> StorageResult<Vec<String>> {
         let prefix = self.ref_key("")?;
         let mut paginator = self
@@ -361,6 +555,26 @@ impl Storage for S3Storage {
         Ok(res)
     }
 
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<futures::stream::BoxStream<StorageResult<(AnyObjectId, SystemTime)>>>
+    {
+        let stream = self.list_kind(kind)?.try_filter_map(move |(id, modified)| {
+            ready(Ok(modified.filter(|at| from <= *at && *at < to).map(|at| (id, at))))
+        });
+        Ok(stream.boxed())
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<futures::stream::BoxStream<StorageResult<AnyObjectId>>> {
+        Ok(self.list_kind(kind)?.map_ok(|(id, _)| id).boxed())
+    }
+
     async fn ref_versions(
         &self,
         ref_name: &str,
@@ -387,6 +601,32 @@ impl Storage for S3Storage {
         Ok(stream.boxed())
     }
 
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.delete_object(&AnyObjectId::Chunk(id.clone())).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.delete_object(&AnyObjectId::Manifest(id.clone())).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.delete_object(&AnyObjectId::Snapshot(id.clone())).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.delete_object(&AnyObjectId::Attributes(id.clone())).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        let key = self.ref_key(format!("{ref_name}/{version_id}").as_str())?;
+        self.client.delete_object().bucket(self.bucket.clone()).key(key).send().await?;
+        Ok(())
+    }
+
     async fn write_ref(
         &self,
         ref_key: &str,
@@ -417,4 +657,169 @@ impl Storage for S3Storage {
             }
         }
     }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        let key = self.ref_key(ref_key)?;
+        let builder =
+            self.client.put_object().bucket(self.bucket.clone()).key(key.clone());
+        let res = match expected {
+            None => builder.if_none_match("*").body(new.into()).send().await,
+            Some(expected) => {
+                let current = self
+                    .client
+                    .get_object()
+                    .bucket(self.bucket.clone())
+                    .key(key.clone())
+                    .send()
+                    .await;
+                let current = match current {
+                    Ok(current) => current,
+                    Err(err)
+                        if err
+                            .as_service_error()
+                            .map(|e| e.is_no_such_key())
+                            .unwrap_or(false) =>
+                    {
+                        return Ok(false)
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let e_tag = current.e_tag.clone().ok_or(StorageError::Other(
+                    "S3 didn't return an etag for the ref".to_string(),
+                ))?;
+                if current.body.collect().await?.into_bytes() != expected {
+                    return Ok(false);
+                }
+                // this version of the SDK doesn't expose If-Match for put_object
+                builder
+                    .body(new.into())
+                    .customize()
+                    .mutate_request(move |req| {
+                        req.headers_mut().insert("If-Match", e_tag.clone());
+                    })
+                    .send()
+                    .await
+            }
+        };
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(err) => {
+                let code = err.as_service_error().and_then(|e| e.code()).unwrap_or("");
+                if code.contains("PreconditionFailed")
+                    || code.contains("ConditionalRequestConflict")
+                {
+                    Ok(false)
+                } else {
+                    Err(err.into())
+                }
+            }
+        }
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        let interceptor = DateHeaderInterceptor::default();
+        // any response has a date, even errors, so we don't care about the result
+        let res = self
+            .client
+            .head_bucket()
+            .bucket(self.bucket.clone())
+            .customize()
+            .interceptor(interceptor.clone())
+            .send()
+            .await;
+        #[allow(clippy::expect_used)]
+        let date = interceptor.date.lock().expect("poison lock").take();
+        match (date, res) {
+            (Some(date), _) => DateTime::parse_from_rfc2822(date.as_str())
+                .map(SystemTime::from)
+                .map_err(|_| StorageError::Other(format!("invalid Date header: {date}"))),
+            (None, Err(err)) => Err(StorageError::Other(err.to_string())),
+            (None, Ok(_)) => Err(StorageError::Unsupported(
+                "backend_time, S3 didn't return a Date header".to_string(),
+            )),
+        }
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.client
+            .list_objects_v2()
+            .bucket(self.bucket.clone())
+            .prefix(self.prefix.clone())
+            .max_keys(1)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers every request with an empty response with the given `Date` header
+    async fn serve_date(date: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = socket.read(&mut buf).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nDate: {date}\r\nContent-Length: 0\r\n\r\n"
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_backend_time_parses_date_header() {
+        let endpoint = serve_date("Sun, 06 Nov 1994 08:49:37 GMT").await;
+        let config = S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(endpoint),
+            credentials: S3Credentials::Static(StaticS3Credentials {
+                access_key_id: "key".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+            }),
+            allow_http: true,
+        };
+        let storage =
+            S3Storage::new_s3_store("bucket", "prefix", Some(&config)).await.unwrap();
+        let time = storage.backend_time().await.unwrap();
+        assert_eq!(
+            time.duration_since(SystemTime::UNIX_EPOCH).unwrap(),
+            Duration::from_secs(784111777)
+        );
+    }
+
+    #[test]
+    fn test_range_to_header() {
+        assert_eq!(range_to_header(&ByteRange::ALL), None);
+        assert_eq!(
+            range_to_header(&ByteRange::bounded(2, 10)),
+            Some("bytes=2-9".to_string())
+        );
+        assert_eq!(range_to_header(&ByteRange::From(5)), Some("bytes=5-".to_string()));
+        assert_eq!(range_to_header(&ByteRange::Last(5)), Some("bytes=-5".to_string()));
+    }
 }
@@ -0,0 +1,366 @@
+//! A [`Storage`] decorator to make the order of operations reproducible in tests
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
+    },
+    private,
+};
+
+/// Runs backend operations one at a time, in the order they are started
+///
+/// Operations queue on a fair lock, so concurrent operations started from the same task, for
+/// example with `join_all`, execute and complete in a deterministic order, regardless of how
+/// long each one takes in the backend. Results are the same as the backend's.
+///
+/// The order of completion is recorded, see [`SerializingStorage::operations`]. Only available
+/// in tests, or with the `test-util` feature.
+#[derive(Debug)]
+pub struct SerializingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    lock: tokio::sync::Mutex<()>,
+    operations: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl SerializingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            backend,
+            lock: tokio::sync::Mutex::new(()),
+            operations: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The operations executed so far, in order, with the object id or ref key they used
+    #[allow(clippy::expect_used)]
+    pub fn operations(&self) -> Vec<(String, Vec<u8>)> {
+        self.operations.lock().expect("poison lock").clone()
+    }
+
+    #[allow(clippy::expect_used)]
+    async fn serialized<R>(
+        &self,
+        operation: &str,
+        id: &[u8],
+        fut: impl Future<Output = R>,
+    ) -> R {
+        let _guard = self.lock.lock().await;
+        let res = fut.await;
+        self.operations
+            .lock()
+            .expect("poison lock")
+            .push((operation.to_string(), id.to_vec()));
+        res
+    }
+}
+
+impl private::Sealed for SerializingStorage {}
+
+#[async_trait]
+impl Storage for SerializingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.serialized("fetch_snapshot", &id.0, self.backend.fetch_snapshot(id)).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.serialized("fetch_attributes", &id.0, self.backend.fetch_attributes(id))
+            .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.serialized("fetch_manifests", &id.0, self.backend.fetch_manifests(id)).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.serialized("fetch_chunk", &id.0, self.backend.fetch_chunk(id, range)).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.serialized(
+            "fetch_node_chunks",
+            &manifest_id.0,
+            self.backend.fetch_node_chunks(manifest_id, node),
+        )
+        .await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.serialized(
+            "fetch_chunk_info",
+            &manifest_id.0,
+            self.backend.fetch_chunk_info(manifest_id, node, coord),
+        )
+        .await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.serialized("exists", id.as_bytes(), self.backend.exists(id)).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("write_snapshot", &key, self.backend.write_snapshot(id, table))
+            .await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized(
+            "write_attributes",
+            &key,
+            self.backend.write_attributes(id, table),
+        )
+        .await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("write_manifests", &key, self.backend.write_manifests(id, table))
+            .await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("write_chunk", &key, self.backend.write_chunk(id, bytes)).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        let key = id.0;
+        self.serialized(
+            "write_chunk_if_absent",
+            &key,
+            self.backend.write_chunk_if_absent(id, bytes),
+        )
+        .await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        self.serialized("write_new_chunk", &[], self.backend.write_new_chunk(bytes)).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.serialized("get_ref", ref_key.as_bytes(), self.backend.get_ref(ref_key))
+            .await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.serialized(
+            "get_refs",
+            keys.join("\n").as_bytes(),
+            self.backend.get_refs(keys),
+        )
+        .await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.serialized(
+            "get_ref_if_changed",
+            ref_key.as_bytes(),
+            self.backend.get_ref_if_changed(ref_key, etag),
+        )
+        .await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.serialized("ref_names", &[], self.backend.ref_names()).await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        // only listing is serialized, not the consumption of the stream
+        self.serialized("list_modified", &[], self.backend.list_modified(kind, from, to))
+            .await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.serialized("list_objects", &[], self.backend.list_objects(kind)).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        // only listing is serialized, not the consumption of the stream
+        self.serialized(
+            "ref_versions",
+            ref_name.as_bytes(),
+            self.backend.ref_versions(ref_name),
+        )
+        .await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("delete_chunk", &key, self.backend.delete_chunk(id)).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("delete_manifest", &key, self.backend.delete_manifest(id)).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("delete_snapshot", &key, self.backend.delete_snapshot(id)).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        let key = id.0;
+        self.serialized("delete_attributes", &key, self.backend.delete_attributes(id))
+            .await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.serialized(
+            "delete_ref_version",
+            ref_name.as_bytes(),
+            self.backend.delete_ref_version(ref_name, version_id),
+        )
+        .await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.serialized(
+            "write_ref",
+            ref_key.as_bytes(),
+            self.backend.write_ref(ref_key, overwrite_refs, bytes),
+        )
+        .await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.serialized(
+            "compare_and_swap_ref",
+            ref_key.as_bytes(),
+            self.backend.compare_and_swap_ref(ref_key, expected, new),
+        )
+        .await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.serialized("backend_time", &[], self.backend.backend_time()).await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.serialized("ping", &[], self.backend.ping()).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::storage::faulty::{Call, Fault, FaultyStorage};
+
+    /// Delays chunk operations by a random amount
+    fn jittery_storage() -> FaultyStorage {
+        let jitter =
+            |_: &Call| Fault::Delay(Duration::from_millis(thread_rng().gen_range(0..5)));
+        FaultyStorage::in_memory().on("fetch_chunk", jitter).on("write_chunk", jitter)
+    }
+
+    /// Concurrently write and read back some chunks, returning the operation log
+    async fn concurrent_workload() -> Vec<(String, Vec<u8>)> {
+        let storage = SerializingStorage::new(Arc::new(jittery_storage()));
+        let ids: Vec<_> = (0..10u8).map(|i| ChunkId::new([i; 12])).collect();
+        let writes = ids
+            .iter()
+            .map(|id| storage.write_chunk(id.clone(), Bytes::copy_from_slice(&id.0)));
+        assert!(join_all(writes).await.iter().all(Result::is_ok));
+        let reads = ids.iter().rev().map(|id| storage.fetch_chunk(id, &ByteRange::ALL));
+        for (id, bytes) in ids.iter().rev().zip(join_all(reads).await) {
+            assert_eq!(bytes.unwrap(), Bytes::copy_from_slice(&id.0));
+        }
+        storage.operations()
+    }
+
+    #[tokio::test]
+    async fn test_operation_log_is_reproducible() {
+        let first = concurrent_workload().await;
+        for _ in 0..5 {
+            assert_eq!(concurrent_workload().await, first);
+        }
+
+        // operations complete in the order they were started
+        let expected: Vec<_> = (0..10u8)
+            .map(|i| ("write_chunk".to_string(), vec![i; 12]))
+            .chain((0..10u8).rev().map(|i| ("fetch_chunk".to_string(), vec![i; 12])))
+            .collect();
+        assert_eq!(first, expected);
+    }
+}
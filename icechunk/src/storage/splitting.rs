@@ -0,0 +1,549 @@
+use std::{collections::HashSet, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{ready, try_join_all},
+    stream::BoxStream,
+    StreamExt, TryStreamExt,
+};
+use quick_cache::sync::Cache;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    fetch_chunk_header, AnyObjectId, ObjectKind, RefFetch, Storage, StorageError,
+    StorageResult,
+};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ChunkOffset, ManifestId, NodeId,
+        SnapshotId,
+    },
+    private,
+};
+
+/// Written at the start of the objects that describe a split chunk
+const SPLIT_INDEX_MAGIC: &[u8; 8] = b"ICSPLIT1";
+
+/// How many chunks we remember as split or not split
+const SPLIT_INDEX_CACHE_SIZE: usize = 10_000;
+
+/// How many chunk headers are read at the same time when listing chunks
+const LIST_HEADERS_CONCURRENCY: usize = 16;
+
+/// Where the parts of a split chunk are stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SplitIndex {
+    total_size: ChunkOffset,
+    part_size: ChunkOffset,
+    parts: Vec<ChunkId>,
+}
+
+impl SplitIndex {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        rmp_serde::from_slice(bytes.strip_prefix(SPLIT_INDEX_MAGIC)?).ok()
+    }
+
+    fn to_bytes(&self) -> StorageResult<Bytes> {
+        let mut res = SPLIT_INDEX_MAGIC.to_vec();
+        res.extend(rmp_serde::to_vec(self)?);
+        Ok(res.into())
+    }
+}
+
+/// A [`Storage`] decorator for backends that limit the size of objects
+///
+/// Chunks larger than `max_chunk_size` are written as multiple part objects. The object for the
+/// original chunk id holds a small index pointing to the parts. Reads reassemble the chunk,
+/// fetching only the parts needed for the requested range.
+#[derive(Debug)]
+pub struct ChunkSplittingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    max_chunk_size: usize,
+    /// `None` for chunks known not to be split
+    split_indexes: Cache<ChunkId, Option<Arc<SplitIndex>>>,
+}
+
+impl ChunkSplittingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>, max_chunk_size: usize) -> Self {
+        Self {
+            backend,
+            max_chunk_size: max_chunk_size.max(1),
+            split_indexes: Cache::new(SPLIT_INDEX_CACHE_SIZE),
+        }
+    }
+
+    async fn split_index(&self, id: &ChunkId) -> StorageResult<Option<Arc<SplitIndex>>> {
+        if let Some(index) = self.split_indexes.get(id) {
+            return Ok(index);
+        }
+        let header =
+            fetch_chunk_header(self.backend.as_ref(), id, SPLIT_INDEX_MAGIC.len())
+                .await?
+                .ok_or_else(|| {
+                    StorageError::ObjectNotFound(AnyObjectId::Chunk(id.clone()))
+                })?;
+        let index = if header.as_ref() == SPLIT_INDEX_MAGIC {
+            let bytes = self.backend.fetch_chunk(id, &ByteRange::ALL).await?;
+            let index = SplitIndex::parse(&bytes).ok_or_else(|| {
+                StorageError::Other(format!("invalid split chunk index for {id}"))
+            })?;
+            Some(Arc::new(index))
+        } else {
+            None
+        };
+        self.split_indexes.insert(id.clone(), index.clone());
+        Ok(index)
+    }
+
+    /// The ids of the parts of every split chunk in the backend
+    ///
+    /// Parts have random ids, the only way to tell them from chunks is reading the header of
+    /// every chunk, to find the split indexes.
+    async fn split_parts(&self) -> StorageResult<HashSet<ChunkId>> {
+        self.backend
+            .list_objects(ObjectKind::Chunk)
+            .await?
+            .map_ok(|id| async move {
+                match id {
+                    AnyObjectId::Chunk(id) => match self.split_index(&id).await {
+                        // deleted since it was listed
+                        Err(err) if err.is_not_found() => Ok(None),
+                        res => res,
+                    },
+                    _ => Ok(None),
+                }
+            })
+            .try_buffer_unordered(LIST_HEADERS_CONCURRENCY)
+            .try_fold(HashSet::new(), |mut parts, index| {
+                if let Some(index) = index {
+                    parts.extend(index.parts.iter().cloned());
+                }
+                ready(Ok(parts))
+            })
+            .await
+    }
+
+    async fn fetch_parts(
+        &self,
+        index: &SplitIndex,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let (start, end) = match range {
+            ByteRange::Bounded(range) => (range.start, range.end.min(index.total_size)),
+            ByteRange::From(offset) => (*offset, index.total_size),
+            ByteRange::Last(n) => (index.total_size.saturating_sub(*n), index.total_size),
+        };
+        if start >= end {
+            return Ok(Bytes::new());
+        }
+
+        let first_part = start / index.part_size;
+        let last_part = (end - 1) / index.part_size;
+        let parts = (first_part..=last_part).map(|part| {
+            let part_start = part * index.part_size;
+            let from = start.max(part_start) - part_start;
+            let to = end.min(part_start + index.part_size) - part_start;
+            let part_id = index.parts.get(part as usize).cloned();
+            async move {
+                let part_id = part_id.ok_or_else(|| {
+                    StorageError::Other("split chunk index is missing parts".to_string())
+                })?;
+                self.backend.fetch_chunk(&part_id, &ByteRange::bounded(from, to)).await
+            }
+        });
+        let parts = try_join_all(parts).await?;
+        if let [part] = parts.as_slice() {
+            return Ok(part.clone());
+        }
+        let mut res = BytesMut::with_capacity((end - start) as usize);
+        for part in parts {
+            res.extend_from_slice(&part);
+        }
+        Ok(res.freeze())
+    }
+}
+
+fn is_part(parts: &HashSet<ChunkId>, id: &AnyObjectId) -> bool {
+    matches!(id, AnyObjectId::Chunk(id) if parts.contains(id))
+}
+
+impl private::Sealed for ChunkSplittingStorage {}
+
+#[async_trait]
+impl Storage for ChunkSplittingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_node_chunks(manifest_id, node).await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        self.backend.fetch_chunk_info(manifest_id, node, coord).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        match self.split_index(id).await {
+            Ok(Some(index)) => self.fetch_parts(&index, range).await,
+            Ok(None) => self.backend.fetch_chunk(id, range).await,
+            // let the backend report a missing chunk
+            Err(_) => self.backend.fetch_chunk(id, range).await,
+        }
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.backend.exists(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, snapshot).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, manifest).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        // small chunks that look like an index are split too, so they are never ambiguous
+        if bytes.len() <= self.max_chunk_size && !bytes.starts_with(SPLIT_INDEX_MAGIC) {
+            self.backend.write_chunk(id.clone(), bytes).await?;
+            self.split_indexes.insert(id, None);
+            return Ok(());
+        }
+
+        let parts: Vec<_> = bytes
+            .chunks(self.max_chunk_size)
+            .map(|part| (ChunkId::random(), bytes.slice_ref(part)))
+            .collect();
+        let index = SplitIndex {
+            total_size: bytes.len() as ChunkOffset,
+            part_size: self.max_chunk_size as ChunkOffset,
+            parts: parts.iter().map(|(id, _)| id.clone()).collect(),
+        };
+        try_join_all(
+            parts
+                .into_iter()
+                .map(|(part_id, part)| self.backend.write_chunk(part_id, part)),
+        )
+        .await?;
+        // the index is written last, so it never points to missing parts
+        self.backend.write_chunk(id.clone(), index.to_bytes()?).await?;
+        self.split_indexes.insert(id, Some(Arc::new(index)));
+        Ok(())
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.backend.get_refs(keys).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.backend.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        let listing = self.backend.list_modified(kind, from, to).await?;
+        if kind != ObjectKind::Chunk {
+            return Ok(listing);
+        }
+        let parts = self.split_parts().await?;
+        Ok(listing.try_filter(move |(id, _)| ready(!is_part(&parts, id))).boxed())
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        let listing = self.backend.list_objects(kind).await?;
+        if kind != ObjectKind::Chunk {
+            return Ok(listing);
+        }
+        // only the logical chunks are listed, garbage collection must not see the parts as
+        // unreferenced chunks
+        let parts = self.split_parts().await?;
+        Ok(listing.try_filter(move |id| ready(!is_part(&parts, id))).boxed())
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.compare_and_swap_ref(ref_key, expected, new).await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.backend.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.backend.ping().await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        match self.split_index(id).await {
+            // parts go first, if this fails the index is still there to find the rest
+            Ok(Some(index)) => {
+                try_join_all(
+                    index.parts.iter().map(|part| self.backend.delete_chunk(part)),
+                )
+                .await?;
+            }
+            Ok(None) => {}
+            Err(err) if err.is_not_found() => {}
+            // deleting the index without knowing its parts would orphan them
+            Err(err) => return Err(err),
+        }
+        self.backend.delete_chunk(id).await?;
+        self.split_indexes.remove(id);
+        Ok(())
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.backend.delete_manifest(id).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.backend.delete_snapshot(id).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.backend.delete_attributes(id).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.delete_ref_version(ref_name, version_id).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{storage::logging::LoggingStorage, ObjectStorage};
+
+    fn data() -> Bytes {
+        (0..25u8).collect::<Vec<_>>().into()
+    }
+
+    #[tokio::test]
+    async fn test_oversized_chunk_is_split() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = ChunkSplittingStorage::new(Arc::clone(&backend), 10);
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), data()).await?;
+
+        let index = SplitIndex::parse(&backend.fetch_chunk(&id, &ByteRange::ALL).await?)
+            .expect("chunk should be split");
+        assert_eq!(index.total_size, 25);
+        assert_eq!(index.parts.len(), 3);
+        for (i, part) in index.parts.iter().enumerate() {
+            let part = backend.fetch_chunk(part, &ByteRange::ALL).await?;
+            assert_eq!(part, data().slice(i * 10..(i * 10 + 10).min(25)));
+        }
+
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, data());
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::Last(3)).await?,
+            data().slice(22..)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ranged_read_fetches_only_needed_parts(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let id = ChunkId::random();
+        ChunkSplittingStorage::new(Arc::clone(&backend), 10)
+            .write_chunk(id.clone(), data())
+            .await?;
+
+        // a fresh instance has to discover the split from the stored index
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let storage = ChunkSplittingStorage::new(logging_c, 10);
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::bounded(8, 14)).await?,
+            data().slice(8..14)
+        );
+        // header, index, and two parts
+        assert_eq!(logging.fetch_operations().len(), 4);
+
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::bounded(12, 18)).await?,
+            data().slice(12..18)
+        );
+        assert_eq!(logging.fetch_operations().len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_small_chunks_are_not_split() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = ChunkSplittingStorage::new(Arc::clone(&backend), 100);
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), data()).await?;
+        assert_eq!(backend.fetch_chunk(&id, &ByteRange::ALL).await?, data());
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::bounded(3, 7)).await?,
+            data().slice(3..7)
+        );
+
+        // unless they look like a split index
+        let id = ChunkId::random();
+        let tricky = Bytes::from_static(b"ICSPLIT1 but not an index");
+        storage.write_chunk(id.clone(), tricky.clone()).await?;
+        assert_ne!(backend.fetch_chunk(&id, &ByteRange::ALL).await?, tricky);
+        let storage = ChunkSplittingStorage::new(Arc::clone(&backend), 100);
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, tricky);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listings_skip_parts() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = ChunkSplittingStorage::new(Arc::clone(&backend), 10);
+        let split = ChunkId::random();
+        let small = ChunkId::random();
+        storage.write_chunk(split.clone(), data()).await?;
+        storage.write_chunk(small.clone(), Bytes::from_static(b"small")).await?;
+        // too short for a ranged read of the header
+        let empty = ChunkId::random();
+        storage.write_chunk(empty.clone(), Bytes::new()).await?;
+        let expected = HashSet::from([
+            AnyObjectId::Chunk(split),
+            AnyObjectId::Chunk(small),
+            AnyObjectId::Chunk(empty),
+        ]);
+
+        let all: Vec<_> =
+            backend.list_objects(ObjectKind::Chunk).await?.try_collect().await?;
+        assert_eq!(all.len(), 6);
+        let listed: HashSet<_> =
+            storage.list_objects(ObjectKind::Chunk).await?.try_collect().await?;
+        assert_eq!(listed, expected);
+
+        let modified: HashSet<_> = storage
+            .list_modified(ObjectKind::Chunk, SystemTime::UNIX_EPOCH, SystemTime::now())
+            .await?
+            .map_ok(|(id, _)| id)
+            .try_collect()
+            .await?;
+        assert_eq!(modified, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_split_chunk() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let id = ChunkId::random();
+        ChunkSplittingStorage::new(Arc::clone(&backend), 10)
+            .write_chunk(id.clone(), data())
+            .await?;
+        let index = SplitIndex::parse(&backend.fetch_chunk(&id, &ByteRange::ALL).await?)
+            .expect("chunk should be split");
+
+        // a fresh instance finds the parts from the stored index
+        let storage = ChunkSplittingStorage::new(Arc::clone(&backend), 10);
+        storage.delete_chunk(&id).await?;
+        for chunk in index.parts.iter().chain([&id]) {
+            assert!(!backend.exists(&AnyObjectId::Chunk(chunk.clone())).await?);
+        }
+        assert!(storage.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+
+        // small chunks, and chunks that are already gone
+        let small = ChunkId::random();
+        storage.write_chunk(small.clone(), Bytes::from_static(b"small")).await?;
+        storage.delete_chunk(&small).await?;
+        assert!(!backend.exists(&AnyObjectId::Chunk(small.clone())).await?);
+        storage.delete_chunk(&small).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+//! A [`Storage`] decorator that emits a `tracing` span for every operation
+//!
+//! All spans are named `storage`, at `INFO` level, with these fields:
+//!
+//! * `op`: the name of the [`Storage`] method,
+//! * `id`: the object, for operations on a single object,
+//! * `ref_key`: the ref, for ref operations,
+//! * `bytes`: the size of the chunk or ref read or written,
+//! * `entries`: the number of nodes of a snapshot, or chunks of a manifest,
+//! * `error`: the error, if the operation failed.
+//!
+//! The span covers the whole operation, so its duration is the latency of the call. Events
+//! emitted by the backend, like the cache lookups of [`super::caching::MemCachingStorage`], are
+//! recorded inside the span.
+use std::{fmt::Display, future::Future, sync::Arc, time::SystemTime};
+
+use ::tracing::{field, info_span, Instrument, Span};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkInfo, Manifest},
+        snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+};
+
+#[derive(Debug)]
+pub struct TracingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+}
+
+impl TracingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { backend }
+    }
+}
+
+fn op_span(op: &'static str) -> Span {
+    info_span!(
+        "storage",
+        op,
+        id = field::Empty,
+        ref_key = field::Empty,
+        bytes = field::Empty,
+        entries = field::Empty,
+        error = field::Empty,
+    )
+}
+
+fn object_span(op: &'static str, id: &dyn Display) -> Span {
+    let span = op_span(op);
+    span.record("id", field::display(id));
+    span
+}
+
+fn ref_span(op: &'static str, ref_key: &str) -> Span {
+    let span = op_span(op);
+    span.record("ref_key", ref_key);
+    span
+}
+
+/// Run `fut` in `span`, recording in `field` the size `size` computes from the result
+async fn traced<T>(
+    span: Span,
+    fut: impl Future<Output = StorageResult<T>>,
+    field: &'static str,
+    size: impl FnOnce(&T) -> Option<usize>,
+) -> StorageResult<T> {
+    let res = fut.instrument(span.clone()).await;
+    match &res {
+        Ok(value) => {
+            if let Some(size) = size(value) {
+                span.record(field, size);
+            }
+        }
+        Err(err) => {
+            span.record("error", field::display(err));
+        }
+    }
+    res
+}
+
+/// Run `fut` in `span` without recording a size
+async fn traced_op<T>(
+    span: Span,
+    fut: impl Future<Output = StorageResult<T>>,
+) -> StorageResult<T> {
+    traced(span, fut, "bytes", |_| None).await
+}
+
+impl private::Sealed for TracingStorage {}
+
+#[async_trait]
+impl Storage for TracingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let span = object_span("fetch_snapshot", id);
+        traced(span, self.backend.fetch_snapshot(id), "entries", |snapshot| {
+            Some(snapshot.len())
+        })
+        .await
+    }
+
+    async fn fetch_snapshot_subtree(
+        &self,
+        id: &SnapshotId,
+        root_path: &Path,
+    ) -> StorageResult<Snapshot> {
+        let span = object_span("fetch_snapshot_subtree", id);
+        let fut = self.backend.fetch_snapshot_subtree(id, root_path);
+        traced(span, fut, "entries", |snapshot| Some(snapshot.len())).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        traced_op(object_span("fetch_attributes", id), self.backend.fetch_attributes(id))
+            .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let span = object_span("fetch_manifests", id);
+        traced(span, self.backend.fetch_manifests(id), "entries", |manifest| {
+            Some(manifest.len())
+        })
+        .await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let span = object_span("fetch_chunk", id);
+        traced(span, self.backend.fetch_chunk(id, range), "bytes", |bytes| {
+            Some(bytes.len())
+        })
+        .await
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        let span = object_span("fetch_chunk_info", manifest_id);
+        traced_op(span, self.backend.fetch_chunk_info(manifest_id, node, coord)).await
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        let span = object_span("fetch_node_chunks", manifest_id);
+        let fut = self.backend.fetch_node_chunks(manifest_id, node);
+        traced(span, fut, "entries", |manifest| Some(manifest.len())).await
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        let span = op_span("exists");
+        span.record("id", field::debug(id));
+        traced_op(span, self.backend.exists(id)).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let span = object_span("write_snapshot", &id);
+        span.record("entries", snapshot.len());
+        traced_op(span, self.backend.write_snapshot(id, snapshot)).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        let span = object_span("write_attributes", &id);
+        traced_op(span, self.backend.write_attributes(id, table)).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let span = object_span("write_manifests", &id);
+        span.record("entries", manifest.len());
+        traced_op(span, self.backend.write_manifests(id, manifest)).await
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        let span = object_span("write_manifests_if_not_exists", &id);
+        span.record("entries", manifest.len());
+        traced_op(span, self.backend.write_manifests_if_not_exists(id, manifest)).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let span = object_span("write_chunk", &id);
+        span.record("bytes", bytes.len());
+        traced_op(span, self.backend.write_chunk(id, bytes)).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        let span = object_span("write_chunk_if_absent", &id);
+        span.record("bytes", bytes.len());
+        traced_op(span, self.backend.write_chunk_if_absent(id, bytes)).await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        let span = op_span("write_new_chunk");
+        span.record("bytes", bytes.len());
+        let res = traced_op(span.clone(), self.backend.write_new_chunk(bytes)).await;
+        if let Ok(id) = &res {
+            span.record("id", field::display(id));
+        }
+        res
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        let span = ref_span("get_ref", ref_key);
+        traced(span, self.backend.get_ref(ref_key), "bytes", |bytes| Some(bytes.len()))
+            .await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        let span = ref_span("get_ref_if_changed", ref_key);
+        let fut = self.backend.get_ref_if_changed(ref_key, etag);
+        traced(span, fut, "bytes", |fetch| match fetch {
+            RefFetch::Unchanged => None,
+            RefFetch::Changed { bytes, .. } => Some(bytes.len()),
+        })
+        .await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        traced(op_span("get_refs"), self.backend.get_refs(keys), "entries", |refs| {
+            Some(refs.len())
+        })
+        .await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        traced(op_span("ref_names"), self.backend.ref_names(), "entries", |names| {
+            Some(names.len())
+        })
+        .await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        traced_op(op_span("list_modified"), self.backend.list_modified(kind, from, to))
+            .await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        traced_op(op_span("list_objects"), self.backend.list_objects(kind)).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        traced_op(ref_span("ref_versions", ref_name), self.backend.ref_versions(ref_name))
+            .await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        traced_op(object_span("delete_chunk", id), self.backend.delete_chunk(id)).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        traced_op(object_span("delete_manifest", id), self.backend.delete_manifest(id))
+            .await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        traced_op(object_span("delete_snapshot", id), self.backend.delete_snapshot(id))
+            .await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        let span = object_span("delete_attributes", id);
+        traced_op(span, self.backend.delete_attributes(id)).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        let span = ref_span("delete_ref_version", ref_name);
+        traced_op(span, self.backend.delete_ref_version(ref_name, version_id)).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let span = ref_span("write_ref", ref_key);
+        span.record("bytes", bytes.len());
+        traced_op(span, self.backend.write_ref(ref_key, overwrite_refs, bytes)).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        let span = ref_span("compare_and_swap_ref", ref_key);
+        span.record("bytes", new.len());
+        traced_op(span, self.backend.compare_and_swap_ref(ref_key, expected, new)).await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        traced_op(op_span("backend_time"), self.backend.backend_time()).await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        traced_op(op_span("ping"), self.backend.ping()).await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        let span = ref_span("record_ref_version", ref_name);
+        span.record("bytes", bytes.len());
+        traced_op(span, self.backend.record_ref_version(ref_name, bytes)).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use ::tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+
+    use super::*;
+    use crate::{storage::caching::MemCachingStorage, ObjectStorage};
+
+    type Fields = BTreeMap<String, String>;
+
+    #[derive(Default)]
+    struct FieldVisitor(Fields);
+
+    impl Visit for FieldVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{value:?}"));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    #[derive(Default)]
+    struct Captured {
+        spans: Mutex<Vec<Fields>>,
+        events: Mutex<Vec<(Option<usize>, Fields)>>,
+        current: Mutex<Vec<usize>>,
+    }
+
+    /// Keeps the fields of every span, and the events with the span they happened in
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Captured>);
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut visitor = FieldVisitor::default();
+            span.record(&mut visitor);
+            let mut spans = self.0.spans.lock().unwrap();
+            spans.push(visitor.0);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut visitor = FieldVisitor::default();
+            values.record(&mut visitor);
+            self.0.spans.lock().unwrap()[span.into_u64() as usize - 1].extend(visitor.0);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut visitor = FieldVisitor::default();
+            event.record(&mut visitor);
+            let span = self.0.current.lock().unwrap().last().copied();
+            self.0.events.lock().unwrap().push((span, visitor.0));
+        }
+
+        fn enter(&self, span: &Id) {
+            self.0.current.lock().unwrap().push(span.into_u64() as usize - 1);
+        }
+
+        fn exit(&self, _: &Id) {
+            self.0.current.lock().unwrap().pop();
+        }
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Fields {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_operations_emit_spans() -> Result<(), Box<dyn std::error::Error>> {
+        let capture = Capture::default();
+        let _guard = ::tracing::subscriber::set_default(capture.clone());
+
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let cache = MemCachingStorage::new(backend, 10, 10, 10, 10);
+        let storage = TracingStorage::new(Arc::new(cache));
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        storage.fetch_chunk(&id, &ByteRange::ALL).await?;
+        storage.fetch_chunk(&id, &ByteRange::ALL).await?;
+        assert!(storage.fetch_chunk(&ChunkId::random(), &ByteRange::ALL).await.is_err());
+
+        let id = id.to_string();
+        let spans = capture.0.spans.lock().unwrap().clone();
+        let fetch = fields(&[("op", "fetch_chunk"), ("id", &id), ("bytes", "5")]);
+        assert_eq!(
+            spans[..3],
+            [
+                fields(&[("op", "write_chunk"), ("id", &id), ("bytes", "5")]),
+                fetch.clone(),
+                fetch,
+            ]
+        );
+        assert_eq!(spans[3]["op"], "fetch_chunk");
+        assert!(spans[3].contains_key("error"));
+        assert!(!spans[3].contains_key("bytes"));
+
+        // the cache lookups are recorded in the spans of the fetches
+        let lookups: Vec<_> = capture
+            .0
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, event)| {
+                event.get("cache").is_some_and(|cache| cache == "chunks")
+            })
+            .map(|(span, event)| (*span, event["hit"].clone()))
+            .collect();
+        assert_eq!(
+            lookups,
+            vec![
+                (Some(1), "false".to_string()),
+                (Some(2), "true".to_string()),
+                (Some(3), "false".to_string()),
+            ]
+        );
+        Ok(())
+    }
+}
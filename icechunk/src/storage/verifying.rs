@@ -0,0 +1,542 @@
+//! A [`Storage`] decorator that checks fetched objects are the ones requested
+//!
+//! Like [`verify_content_addresses`](crate::ops::verify_content_addresses), every fetch is
+//! checked against what is known about the object:
+//!
+//! * snapshots must contain their own id,
+//! * chunk fetches cannot return more bytes than the requested range,
+//! * manifests must have a format this library reads, and match what the recently verified
+//!   snapshots record about them: their format version, and the nodes and extents pointing to
+//!   them,
+//! * chunk lookups in a manifest must return the requested node and coordinates,
+//! * attribute files listed by the recently verified snapshots must have a format this library
+//!   reads.
+//!
+//! Stack it below [`super::caching::MemCachingStorage`], so objects are verified once and the
+//! cache only holds verified objects.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use super::{AnyObjectId, ObjectKind, RefFetch, Storage, StorageError, StorageResult};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        format_constants::{
+            LATEST_ICECHUNK_ATTRIBUTES_FORMAT, LATEST_ICECHUNK_MANIFEST_FORMAT,
+        },
+        manifest::{ChunkInfo, Manifest, ManifestExtents},
+        snapshot::{AttributeFileInfo, ManifestFileInfo, NodeData, Snapshot},
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
+    },
+    private,
+};
+
+/// How many verified snapshots are remembered to check the files they reference
+const REMEMBERED_SNAPSHOTS: usize = 16;
+
+#[derive(Debug)]
+pub struct VerifyingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    /// What the most recently verified snapshots record, oldest first
+    snapshots: Mutex<VecDeque<Arc<References>>>,
+}
+
+/// What a snapshot records about the manifests and attribute files it references
+#[derive(Debug, Default)]
+struct References {
+    manifest_files: HashMap<ManifestId, ManifestFileInfo>,
+    attribute_files: HashMap<AttributesId, AttributeFileInfo>,
+    /// The nodes pointing to each manifest, with the extents of the manifest
+    manifest_refs: HashMap<ManifestId, Vec<(NodeId, ManifestExtents)>>,
+}
+
+impl References {
+    fn new(snapshot: &Snapshot) -> Self {
+        let mut refs = Self {
+            manifest_files: snapshot
+                .manifest_files
+                .iter()
+                .map(|info| (info.id.clone(), info.clone()))
+                .collect(),
+            attribute_files: snapshot
+                .attribute_files
+                .iter()
+                .map(|info| (info.id.clone(), info.clone()))
+                .collect(),
+            manifest_refs: HashMap::new(),
+        };
+        for node in snapshot.iter() {
+            if let NodeData::Array(_, manifests) = &node.node_data {
+                for manifest in manifests {
+                    refs.manifest_refs
+                        .entry(manifest.object_id.clone())
+                        .or_default()
+                        .push((node.id, manifest.extents.clone()));
+                }
+            }
+        }
+        refs
+    }
+}
+
+impl VerifyingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { backend, snapshots: Mutex::new(VecDeque::new()) }
+    }
+
+    #[allow(clippy::expect_used)]
+    fn remember(&self, snapshot: &Snapshot) {
+        let refs = Arc::new(References::new(snapshot));
+        let mut snapshots = self.snapshots.lock().expect("poison lock");
+        if snapshots.len() == REMEMBERED_SNAPSHOTS {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(refs);
+    }
+
+    #[allow(clippy::expect_used)]
+    fn recent_snapshots(&self) -> Vec<Arc<References>> {
+        self.snapshots.lock().expect("poison lock").iter().cloned().collect()
+    }
+
+    fn verify_manifest(&self, id: &ManifestId, manifest: &Manifest) -> StorageResult<()> {
+        let version = manifest.icechunk_manifest_format_version;
+        if version > LATEST_ICECHUNK_MANIFEST_FORMAT {
+            return Err(StorageError::IntegrityError {
+                expected: format!(
+                    "manifest {id} with format up to {LATEST_ICECHUNK_MANIFEST_FORMAT}"
+                ),
+                actual: format!("format {version}"),
+            });
+        }
+
+        let snapshots = self.recent_snapshots();
+        for info in snapshots.iter().filter_map(|refs| refs.manifest_files.get(id)) {
+            if info.format_version != version {
+                return Err(StorageError::IntegrityError {
+                    expected: format!(
+                        "manifest {id} with format {}",
+                        info.format_version
+                    ),
+                    actual: format!("format {version}"),
+                });
+            }
+        }
+        let pointers: Vec<_> = snapshots
+            .iter()
+            .filter_map(|refs| refs.manifest_refs.get(id))
+            .flatten()
+            .collect();
+        // without a snapshot pointing to the manifest there is nothing to compare with
+        if pointers.is_empty() {
+            return Ok(());
+        }
+        for (node, coord) in manifest.chunks().keys() {
+            if !pointers.iter().any(|(n, extents)| n == node && extents.contains(coord)) {
+                return Err(StorageError::IntegrityError {
+                    expected: format!("chunks of the nodes pointing to manifest {id}"),
+                    actual: format!("chunk {coord:?} of node {node}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The most bytes a fetch of `range` can return, `None` if it depends on the object size
+///
+/// Like in HTTP, ranges past the end of the object return fewer bytes.
+fn max_len(range: &ByteRange) -> Option<u64> {
+    match range {
+        ByteRange::Bounded(range) => Some(range.end.saturating_sub(range.start)),
+        ByteRange::Last(n) => Some(*n),
+        ByteRange::From(_) => None,
+    }
+}
+
+impl private::Sealed for VerifyingStorage {}
+
+#[async_trait]
+impl Storage for VerifyingStorage {
+    // fetch_snapshot_subtree is not forwarded, the default implementation fetches the snapshot
+    // through fetch_snapshot, so it's verified
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let snapshot = self.backend.fetch_snapshot(id).await?;
+        if &snapshot.metadata.id != id {
+            return Err(StorageError::IntegrityError {
+                expected: format!("snapshot {id}"),
+                actual: format!("snapshot {}", snapshot.metadata.id),
+            });
+        }
+        self.remember(&snapshot);
+        Ok(snapshot)
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let table = self.backend.fetch_attributes(id).await?;
+        for refs in self.recent_snapshots() {
+            if let Some(info) = refs.attribute_files.get(id) {
+                if info.format_version > LATEST_ICECHUNK_ATTRIBUTES_FORMAT {
+                    return Err(StorageError::IntegrityError {
+                        expected: format!(
+                            "attributes {id} with format up to {LATEST_ICECHUNK_ATTRIBUTES_FORMAT}"
+                        ),
+                        actual: format!("format {}", info.format_version),
+                    });
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let manifest = self.backend.fetch_manifests(id).await?;
+        self.verify_manifest(id, &manifest)?;
+        Ok(manifest)
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let bytes = self.backend.fetch_chunk(id, range).await?;
+        match max_len(range) {
+            Some(max) if bytes.len() as u64 > max => Err(StorageError::IntegrityError {
+                expected: format!("at most {max} bytes of chunk {id}"),
+                actual: format!("{} bytes", bytes.len()),
+            }),
+            _ => Ok(bytes),
+        }
+    }
+
+    async fn fetch_chunk_info(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> StorageResult<Option<ChunkInfo>> {
+        let info = self.backend.fetch_chunk_info(manifest_id, node, coord).await?;
+        match info {
+            Some(info) if info.node != node || &info.coord != coord => {
+                Err(StorageError::IntegrityError {
+                    expected: format!(
+                        "chunk {coord:?} of node {node} in manifest {manifest_id}"
+                    ),
+                    actual: format!("chunk {:?} of node {}", info.coord, info.node),
+                })
+            }
+            info => Ok(info),
+        }
+    }
+
+    async fn fetch_node_chunks(
+        &self,
+        manifest_id: &ManifestId,
+        node: NodeId,
+    ) -> StorageResult<Arc<Manifest>> {
+        let manifest = self.backend.fetch_node_chunks(manifest_id, node).await?;
+        if let Some((other, coord)) = manifest.chunks().keys().find(|(n, _)| *n != node) {
+            return Err(StorageError::IntegrityError {
+                expected: format!("chunks of node {node} in manifest {manifest_id}"),
+                actual: format!("chunk {coord:?} of node {other}"),
+            });
+        }
+        self.verify_manifest(manifest_id, &manifest)?;
+        Ok(manifest)
+    }
+
+    async fn exists(&self, id: &AnyObjectId) -> StorageResult<bool> {
+        self.backend.exists(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, snapshot).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, manifest).await
+    }
+
+    async fn write_manifests_if_not_exists(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<bool> {
+        self.backend.write_manifests_if_not_exists(id, manifest).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_chunk_if_absent(
+        &self,
+        id: ChunkId,
+        bytes: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.write_chunk_if_absent(id, bytes).await
+    }
+
+    async fn write_new_chunk(&self, bytes: Bytes) -> StorageResult<ChunkId> {
+        self.backend.write_new_chunk(bytes).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn get_ref_if_changed(
+        &self,
+        ref_key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<RefFetch> {
+        self.backend.get_ref_if_changed(ref_key, etag).await
+    }
+
+    async fn get_refs(
+        &self,
+        keys: &[&str],
+    ) -> StorageResult<Vec<(String, Option<Bytes>)>> {
+        self.backend.get_refs(keys).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn list_modified(
+        &self,
+        kind: ObjectKind,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> StorageResult<BoxStream<StorageResult<(AnyObjectId, SystemTime)>>> {
+        self.backend.list_modified(kind, from, to).await
+    }
+
+    async fn list_objects(
+        &self,
+        kind: ObjectKind,
+    ) -> StorageResult<BoxStream<StorageResult<AnyObjectId>>> {
+        self.backend.list_objects(kind).await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn delete_chunk(&self, id: &ChunkId) -> StorageResult<()> {
+        self.backend.delete_chunk(id).await
+    }
+
+    async fn delete_manifest(&self, id: &ManifestId) -> StorageResult<()> {
+        self.backend.delete_manifest(id).await
+    }
+
+    async fn delete_snapshot(&self, id: &SnapshotId) -> StorageResult<()> {
+        self.backend.delete_snapshot(id).await
+    }
+
+    async fn delete_attributes(&self, id: &AttributesId) -> StorageResult<()> {
+        self.backend.delete_attributes(id).await
+    }
+
+    async fn delete_ref_version(
+        &self,
+        ref_name: &str,
+        version_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.delete_ref_version(ref_name, version_id).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn compare_and_swap_ref(
+        &self,
+        ref_key: &str,
+        expected: Option<Bytes>,
+        new: Bytes,
+    ) -> StorageResult<bool> {
+        self.backend.compare_and_swap_ref(ref_key, expected, new).await
+    }
+
+    async fn backend_time(&self) -> StorageResult<SystemTime> {
+        self.backend.backend_time().await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.backend.ping().await
+    }
+
+    async fn record_ref_version(
+        &self,
+        ref_name: &str,
+        bytes: Bytes,
+    ) -> StorageResult<String> {
+        self.backend.record_ref_version(ref_name, bytes).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkPayload, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        storage::ObjectStorage,
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_tampered_objects_fail_verification(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = VerifyingStorage::new(Arc::clone(&backend));
+        let snapshot_id = Repository::init(Arc::clone(&backend), false)
+            .await?
+            .build()
+            .snapshot_id()
+            .clone();
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+
+        // a snapshot stored under a different id
+        let other_id = SnapshotId::random();
+        backend.write_snapshot(other_id.clone(), snapshot).await?;
+        assert!(matches!(
+            storage.fetch_snapshot(&other_id).await,
+            Err(StorageError::IntegrityError { expected, actual })
+                if expected == format!("snapshot {other_id}")
+                    && actual == format!("snapshot {snapshot_id}")
+        ));
+
+        // chunk fetches can't be longer than the range, ranges past the end are shorter
+        let chunk_id = ChunkId::random();
+        backend.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello")).await?;
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::bounded(1, 3)).await?,
+            Bytes::from_static(b"el")
+        );
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id, &ByteRange::Last(500)).await?,
+            Bytes::from_static(b"hello")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifests_must_match_the_snapshot(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let verifying = || -> Arc<dyn Storage + Send + Sync> {
+            Arc::new(VerifyingStorage::new(Arc::clone(&backend)))
+        };
+        let mut repo = Repository::init(verifying(), false)
+            .await?
+            .with_manifest_shard_size(2)
+            .with_max_manifest_deltas(1)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let large: Path = "/large".try_into()?;
+        let small: Path = "/small".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(large.clone(), zarr_meta.clone()).await?;
+        repo.add_array(small.clone(), zarr_meta).await?;
+        let inline =
+            |s: String| Some(ChunkPayload::Inline(Bytes::copy_from_slice(s.as_bytes())));
+        for i in 0..5 {
+            repo.set_chunk_ref(
+                large.clone(),
+                ChunkIndices(vec![i]),
+                inline(format!("a{i}")),
+            )
+            .await?;
+        }
+        repo.set_chunk_ref(small.clone(), ChunkIndices(vec![0]), inline("s".to_string()))
+            .await?;
+        let sharded = repo.commit("main", "sharded", None).await?;
+        repo.set_chunk_ref(
+            large.clone(),
+            ChunkIndices(vec![3]),
+            inline("b3".to_string()),
+        )
+        .await?;
+        let delta = repo.commit("main", "delta", None).await?;
+
+        // shards, shared manifests and deltas all match their snapshot
+        for snapshot_id in [&sharded, &delta] {
+            let repo = Repository::update(verifying(), snapshot_id.clone()).build();
+            assert_eq!(repo.all_chunks().await?.try_collect::<Vec<_>>().await?.len(), 6);
+            assert!(repo.get_chunk_ref(&large, &ChunkIndices(vec![4])).await?.is_some());
+        }
+
+        // a manifest of the small array stored in place of a shard of the large one
+        let snapshot = backend.fetch_snapshot(&sharded).await?;
+        let manifests = |path: &Path| match &snapshot.get_node(path).unwrap().node_data {
+            NodeData::Array(_, manifests) => manifests.clone(),
+            NodeData::Group => panic!("must be an array"),
+        };
+        let shard = manifests(&large)[0].object_id.clone();
+        let shared = backend.fetch_manifests(&manifests(&small)[0].object_id).await?;
+        backend.write_manifests(shard.clone(), shared).await?;
+
+        let storage = VerifyingStorage::new(Arc::clone(&backend));
+        // unknown manifests can only be checked for their format
+        storage.fetch_manifests(&shard).await?;
+        storage.fetch_snapshot(&sharded).await?;
+        assert!(matches!(
+            storage.fetch_manifests(&shard).await,
+            Err(StorageError::IntegrityError { expected, .. })
+                if expected == format!("chunks of the nodes pointing to manifest {shard}")
+        ));
+        Ok(())
+    }
+}
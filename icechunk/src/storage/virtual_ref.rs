@@ -9,6 +9,7 @@ use object_store::{path::Path as ObjectPath, GetOptions, GetRange, ObjectStore};
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
 use std::fmt::Debug;
+use std::sync::Arc;
 use tokio::sync::OnceCell;
 use url::{self, Url};
 
@@ -32,11 +33,38 @@ pub enum ObjectStoreVirtualChunkResolverConfig {
 pub struct ObjectStoreVirtualChunkResolver {
     s3: OnceCell<Client>,
     config: Box<Option<ObjectStoreVirtualChunkResolverConfig>>,
+    stores: Vec<(String, Arc<dyn ObjectStore>)>,
 }
 
 impl ObjectStoreVirtualChunkResolver {
     pub fn new(config: Option<ObjectStoreVirtualChunkResolverConfig>) -> Self {
-        Self { s3: Default::default(), config: Box::new(config) }
+        Self { s3: Default::default(), config: Box::new(config), stores: Vec::new() }
+    }
+
+    /// Read the locations that start with `prefix` from `store`
+    ///
+    /// The rest of the location is the path of the object in `store`, for example, with
+    /// prefix `memory://bucket/` the location `memory://bucket/data/c0` reads `data/c0`. This
+    /// allows reading from stores the resolver can't configure itself, like in memory stores.
+    /// If several prefixes match a location, the longest one is used.
+    pub fn with_store(
+        mut self,
+        prefix: impl Into<String>,
+        store: Arc<dyn ObjectStore>,
+    ) -> Self {
+        self.stores.push((prefix.into(), store));
+        self
+    }
+
+    fn registered_store(
+        &self,
+        location: &str,
+    ) -> Option<(&Arc<dyn ObjectStore>, String)> {
+        self.stores
+            .iter()
+            .filter(|(prefix, _)| location.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, store)| (store, location[prefix.len()..].to_string()))
     }
 
     async fn s3(&self) -> &Client {
@@ -58,19 +86,7 @@ impl ObjectStoreVirtualChunkResolver {
         url: &Url,
         range: &ByteRange,
     ) -> Result<Bytes, VirtualReferenceError> {
-        let store = LocalFileSystem::new();
-        let options =
-            GetOptions { range: Option::<GetRange>::from(range), ..Default::default() };
-        let path = ObjectPath::parse(url.path())
-            .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
-
-        store
-            .get_opts(&path, options)
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
-            .bytes()
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))
+        fetch_from_store(&LocalFileSystem::new(), url.path(), range).await
     }
 
     async fn fetch_s3(
@@ -105,6 +121,25 @@ impl ObjectStoreVirtualChunkResolver {
     }
 }
 
+async fn fetch_from_store(
+    store: &dyn ObjectStore,
+    path: &str,
+    range: &ByteRange,
+) -> Result<Bytes, VirtualReferenceError> {
+    let options =
+        GetOptions { range: Option::<GetRange>::from(range), ..Default::default() };
+    let path = ObjectPath::parse(path)
+        .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+
+    store
+        .get_opts(&path, options)
+        .await
+        .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
+        .bytes()
+        .await
+        .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))
+}
+
 // Converts the requested ByteRange to a valid ByteRange appropriate
 // to the chunk reference of known `offset` and `length`.
 pub fn construct_valid_byte_range(
@@ -143,6 +178,9 @@ impl VirtualChunkResolver for ObjectStoreVirtualChunkResolver {
         range: &ByteRange,
     ) -> Result<Bytes, VirtualReferenceError> {
         let VirtualChunkLocation::Absolute(location) = location;
+        if let Some((store, path)) = self.registered_store(location) {
+            return fetch_from_store(store.as_ref(), &path, range).await;
+        }
         let parsed =
             url::Url::parse(location).map_err(VirtualReferenceError::CannotParseUrl)?;
         let scheme = parsed.scheme();
@@ -0,0 +1,278 @@
+//! A read-only filesystem view of a snapshot
+//!
+//! Every group and array is a directory at its own path. Arrays have a `c` directory with a
+//! nested directory per chunk coordinate, following the default zarr chunk key encoding, so the
+//! chunk at `[0, 1]` of `/group/array` is the file `/group/array/c/0/1`. This is the adapter
+//! layer needed to mount a repository version, using FUSE or from a WASM host.
+use bytes::Bytes;
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, ChunkRef},
+        snapshot::{NodeData, NodeSnapshot, Snapshot, ZarrArrayMetadata},
+        ByteRange, ChunkIndices, Path,
+    },
+    storage::{StorageError, StorageResult},
+    Storage,
+};
+
+const CHUNK_DIR: &str = "c";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VfsEntryKind {
+    Group,
+    Array,
+    /// A directory grouping the chunks with a common prefix of coordinates
+    ChunkDir,
+    Chunk,
+}
+
+/// An entry in a directory listing
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VfsEntry {
+    /// The last component of the entry path
+    pub name: String,
+    pub kind: VfsEntryKind,
+}
+
+impl VfsEntry {
+    pub fn is_dir(&self) -> bool {
+        self.kind != VfsEntryKind::Chunk
+    }
+}
+
+/// What a logical path points to
+enum Resolved<'a> {
+    Node(&'a NodeSnapshot),
+    /// A prefix of the coordinates of the chunks in an array, it's a chunk if complete
+    Chunk {
+        node: &'a NodeSnapshot,
+        metadata: &'a ZarrArrayMetadata,
+        coords: Vec<u32>,
+    },
+}
+
+/// List the entries of the directory at `path`
+///
+/// Chunk entries are generated from the array shape, so they may not have been written.
+/// Returns an empty list if `path` is not a directory.
+pub fn list_dir(snapshot: &Snapshot, path: &str) -> Vec<VfsEntry> {
+    match resolve(snapshot, path) {
+        Some(Resolved::Node(node)) => node_entries(snapshot, node),
+        Some(Resolved::Chunk { metadata, coords, .. }) => {
+            let dims = metadata.num_chunks();
+            match dims.get(coords.len()) {
+                Some(n) => {
+                    let kind = if coords.len() + 1 == dims.len() {
+                        VfsEntryKind::Chunk
+                    } else {
+                        VfsEntryKind::ChunkDir
+                    };
+                    (0..*n).map(|i| VfsEntry { name: i.to_string(), kind }).collect()
+                }
+                None => Vec::new(),
+            }
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Read the chunk at `path`
+///
+/// Fails with [`StorageError::ChunkPathNotFound`] if `path` is not a chunk or the chunk was
+/// never written. Virtual chunks are not supported.
+pub async fn read_path(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &Snapshot,
+    path: &str,
+    range: &ByteRange,
+) -> StorageResult<Bytes> {
+    let not_found = || StorageError::ChunkPathNotFound(path.to_string());
+    let (node, coords) = match resolve(snapshot, path) {
+        Some(Resolved::Chunk { node, metadata, coords })
+            if coords.len() == metadata.shape.len() =>
+        {
+            (node, ChunkIndices(coords))
+        }
+        _ => return Err(not_found()),
+    };
+    let NodeData::Array(_, manifests) = &node.node_data else {
+        return Err(not_found());
+    };
+
+    for manifest in manifests {
+        if let Some(info) =
+            storage.fetch_chunk_info(&manifest.object_id, node.id, &coords).await?
+        {
+            return match info.payload {
+                ChunkPayload::Ref(ChunkRef { id, .. }) => {
+                    storage.fetch_chunk(&id, range).await
+                }
+                ChunkPayload::Inline(bytes) => Ok(range.slice(bytes)),
+                ChunkPayload::Virtual(_) => Err(StorageError::Unsupported(
+                    "reading virtual chunks by path".to_string(),
+                )),
+            };
+        }
+    }
+    Err(not_found())
+}
+
+fn resolve<'a>(snapshot: &'a Snapshot, path: &str) -> Option<Resolved<'a>> {
+    let path =
+        Path::new(path.strip_suffix('/').filter(|p| !p.is_empty()).unwrap_or(path))
+            .ok()?;
+    // the closest node is the array owning the chunk, if path is a chunk path
+    let node = path.ancestors().find_map(|p| snapshot.get_node(&p).ok())?;
+    let full = path.to_string();
+    let rest = full.strip_prefix(node.path.to_string().as_str())?.trim_start_matches('/');
+    if rest.is_empty() {
+        return Some(Resolved::Node(node));
+    }
+
+    let NodeData::Array(metadata, _) = &node.node_data else {
+        return None;
+    };
+    let mut components = rest.split('/');
+    if components.next() != Some(CHUNK_DIR) {
+        return None;
+    }
+    let dims = metadata.num_chunks();
+    let coords = components.map(|c| c.parse::<u32>().ok()).collect::<Option<Vec<_>>>()?;
+    let in_grid = coords.len() <= dims.len()
+        && coords.iter().zip(dims.iter()).all(|(c, n)| (*c as u64) < *n);
+    in_grid.then_some(Resolved::Chunk { node, metadata, coords })
+}
+
+fn node_entries(snapshot: &Snapshot, node: &NodeSnapshot) -> Vec<VfsEntry> {
+    match &node.node_data {
+        NodeData::Array(metadata, _) => {
+            // the chunk of a scalar array is the `c` file itself
+            let kind = if metadata.shape.is_empty() {
+                VfsEntryKind::Chunk
+            } else {
+                VfsEntryKind::ChunkDir
+            };
+            vec![VfsEntry { name: CHUNK_DIR.to_string(), kind }]
+        }
+        NodeData::Group => snapshot
+            .iter_subtree(&node.path)
+            .filter(|child| child.path.ancestors().nth(1).as_ref() == Some(&node.path))
+            .filter_map(|child| {
+                let name = child.path.to_string().rsplit('/').next()?.to_string();
+                let kind = match child.node_data {
+                    NodeData::Group => VfsEntryKind::Group,
+                    NodeData::Array(..) => VfsEntryKind::Array,
+                };
+                Some(VfsEntry { name, kind })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{num::NonZeroU64, sync::Arc};
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        ObjectStorage, Repository,
+    };
+
+    fn metadata(shape: Vec<u64>, chunk_shape: Vec<u64>) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape,
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(
+                chunk_shape.into_iter().map(|n| NonZeroU64::new(n).unwrap()).collect(),
+            ),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    fn entry(name: &str, kind: VfsEntryKind) -> VfsEntry {
+        VfsEntry { name: name.to_string(), kind }
+    }
+
+    #[tokio::test]
+    async fn test_list_and_read_hierarchy() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        repo.add_group(Path::root()).await?;
+        repo.add_group("/group".try_into()?).await?;
+        repo.add_array("/group/array".try_into()?, metadata(vec![4, 3], vec![2, 2]))
+            .await?;
+        repo.add_array("/scalar".try_into()?, metadata(vec![], vec![])).await?;
+        let large = Bytes::from(vec![42; 1_000]);
+        let payload = repo.get_chunk_writer()(large.clone()).await?;
+        repo.set_chunk_ref(
+            "/group/array".try_into()?,
+            ChunkIndices(vec![1, 0]),
+            Some(payload),
+        )
+        .await?;
+        repo.set_chunk_ref(
+            "/scalar".try_into()?,
+            ChunkIndices(vec![]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        let snapshot_id = repo.commit("main", "hierarchy", None).await?;
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+
+        assert_eq!(
+            list_dir(&snapshot, "/"),
+            vec![
+                entry("group", VfsEntryKind::Group),
+                entry("scalar", VfsEntryKind::Array)
+            ]
+        );
+        assert_eq!(
+            list_dir(&snapshot, "/group"),
+            vec![entry("array", VfsEntryKind::Array)]
+        );
+        assert_eq!(
+            list_dir(&snapshot, "/group/array/"),
+            vec![entry("c", VfsEntryKind::ChunkDir)]
+        );
+        assert_eq!(
+            list_dir(&snapshot, "/group/array/c"),
+            vec![entry("0", VfsEntryKind::ChunkDir), entry("1", VfsEntryKind::ChunkDir)]
+        );
+        assert_eq!(
+            list_dir(&snapshot, "/group/array/c/1"),
+            vec![entry("0", VfsEntryKind::Chunk), entry("1", VfsEntryKind::Chunk)]
+        );
+        assert_eq!(list_dir(&snapshot, "/scalar"), vec![entry("c", VfsEntryKind::Chunk)]);
+        assert!(list_dir(&snapshot, "/group/array/c/1/0").is_empty());
+        assert!(list_dir(&snapshot, "/group/array/c/2").is_empty());
+        assert!(list_dir(&snapshot, "/group/array/d").is_empty());
+        assert!(list_dir(&snapshot, "/missing").is_empty());
+
+        let read = |path: &'static str, range: ByteRange| {
+            let storage = Arc::clone(&storage);
+            let snapshot = Arc::clone(&snapshot);
+            async move { read_path(storage.as_ref(), &snapshot, path, &range).await }
+        };
+        assert_eq!(read("/group/array/c/1/0", ByteRange::ALL).await?, large);
+        assert_eq!(
+            read("/group/array/c/1/0", ByteRange::bounded(0, 10)).await?,
+            large.slice(0..10)
+        );
+        assert_eq!(read("/scalar/c", ByteRange::ALL).await?, Bytes::from("hello"));
+        for missing in ["/group/array/c/0/0", "/group/array/c/1", "/group", "/nope/c/0"] {
+            assert!(matches!(
+                read(missing, ByteRange::ALL).await,
+                Err(StorageError::ChunkPathNotFound(path)) if path == missing
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! A read-only view of a ref that follows it as it moves
+//!
+//! A [`Repository`] reads a fixed snapshot. Long running readers that want to see recent data,
+//! without polling the ref themselves, can use a [`SnapshotView`] instead: it resolves the ref
+//! again before a read if it was last resolved more than `max_staleness` ago. Snapshots,
+//! manifests and chunks are immutable, so switching versions doesn't invalidate anything cached
+//! by the storage.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::{
+    format::{
+        manifest::ChunkPayload, snapshot::NodeSnapshot, ByteRange, ChunkIndices, Path,
+        SnapshotId,
+    },
+    refs::fetch_ref,
+    repository::RepositoryResult,
+    Repository, Storage,
+};
+
+#[derive(Debug)]
+struct Resolved {
+    repository: Arc<Repository>,
+    at: Instant,
+}
+
+/// Reads the version pointed to by a ref, at most `max_staleness` old
+///
+/// The age of the version is the time since the ref was last resolved, the staleness check is
+/// done before each read. Tags never move, but they are resolved again all the same.
+#[derive(Debug)]
+pub struct SnapshotView {
+    storage: Arc<dyn Storage + Send + Sync>,
+    ref_name: String,
+    max_staleness: Duration,
+    resolved: Mutex<Resolved>,
+}
+
+impl SnapshotView {
+    pub async fn new(
+        storage: Arc<dyn Storage + Send + Sync>,
+        ref_name: &str,
+        max_staleness: Duration,
+    ) -> RepositoryResult<Self> {
+        let resolved = resolve(&storage, ref_name).await?;
+        Ok(Self {
+            storage,
+            ref_name: ref_name.to_string(),
+            max_staleness,
+            resolved: Mutex::new(resolved),
+        })
+    }
+
+    pub fn ref_name(&self) -> &str {
+        &self.ref_name
+    }
+
+    pub fn max_staleness(&self) -> Duration {
+        self.max_staleness
+    }
+
+    /// A repository at the current version, resolving the ref again if the version is stale
+    ///
+    /// Concurrent callers wait for a single resolution.
+    pub async fn repository(&self) -> RepositoryResult<Arc<Repository>> {
+        let mut resolved = self.resolved.lock().await;
+        if resolved.at.elapsed() > self.max_staleness {
+            *resolved = self.refresh_from(&resolved).await?;
+        }
+        Ok(Arc::clone(&resolved.repository))
+    }
+
+    /// Resolve the ref now, even if the current version is not stale
+    pub async fn refresh(&self) -> RepositoryResult<SnapshotId> {
+        let mut resolved = self.resolved.lock().await;
+        *resolved = self.refresh_from(&resolved).await?;
+        Ok(resolved.repository.snapshot_id().clone())
+    }
+
+    pub async fn snapshot_id(&self) -> RepositoryResult<SnapshotId> {
+        Ok(self.repository().await?.snapshot_id().clone())
+    }
+
+    pub async fn get_node(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
+        self.repository().await?.get_node(path).await
+    }
+
+    pub async fn get_chunk_ref(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<Option<ChunkPayload>> {
+        self.repository().await?.get_chunk_ref(path, coords).await
+    }
+
+    /// See [`Repository::read_chunk`]
+    pub async fn read_chunk(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        byte_range: &ByteRange,
+        fill_missing: bool,
+    ) -> RepositoryResult<Bytes> {
+        self.repository().await?.read_chunk(path, coords, byte_range, fill_missing).await
+    }
+
+    async fn refresh_from(&self, current: &Resolved) -> RepositoryResult<Resolved> {
+        let new = resolve(&self.storage, &self.ref_name).await?;
+        if new.repository.snapshot_id() == current.repository.snapshot_id() {
+            // keep the repository readers already hold
+            Ok(Resolved { repository: Arc::clone(&current.repository), at: new.at })
+        } else {
+            Ok(new)
+        }
+    }
+}
+
+async fn resolve(
+    storage: &Arc<dyn Storage + Send + Sync>,
+    ref_name: &str,
+) -> RepositoryResult<Resolved> {
+    // take the time before fetching, the version can't be older than this
+    let at = Instant::now();
+    let (_, ref_data) = fetch_ref(storage.as_ref(), ref_name).await?;
+    let repository = Repository::update(Arc::clone(storage), ref_data.snapshot).build();
+    Ok(Resolved { repository: Arc::new(repository), at })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_reads_follow_the_branch() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut writer = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path: Path = "/array".try_into()?;
+        let coords = ChunkIndices(vec![0]);
+        writer.add_group(Path::root()).await?;
+        writer
+            .add_array(
+                path.clone(),
+                ZarrArrayMetadata {
+                    shape: vec![1],
+                    data_type: DataType::Int32,
+                    chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                    chunk_key_encoding: ChunkKeyEncoding::Slash,
+                    fill_value: FillValue::Int32(0),
+                    codecs: vec![],
+                    storage_transformers: None,
+                    dimension_names: None,
+                },
+            )
+            .await?;
+        writer
+            .set_chunk_ref(
+                path.clone(),
+                coords.clone(),
+                Some(ChunkPayload::Inline("v1".into())),
+            )
+            .await?;
+        let first = writer.commit("main", "v1", None).await?;
+
+        let max_staleness = Duration::from_millis(200);
+        let view = SnapshotView::new(Arc::clone(&storage), "main", max_staleness).await?;
+        let read = || view.read_chunk(&path, &coords, &ByteRange::ALL, false);
+        assert_eq!(read().await?, Bytes::from("v1"));
+
+        writer
+            .set_chunk_ref(
+                path.clone(),
+                coords.clone(),
+                Some(ChunkPayload::Inline("v2".into())),
+            )
+            .await?;
+        let second = writer.commit("main", "v2", None).await?;
+
+        // within the bound the view keeps reading the version it has
+        assert_eq!(view.snapshot_id().await?, first);
+        assert_eq!(read().await?, Bytes::from("v1"));
+
+        tokio::time::sleep(max_staleness * 2).await;
+        assert_eq!(read().await?, Bytes::from("v2"));
+        assert_eq!(view.snapshot_id().await?, second);
+
+        // an unchanged ref keeps the same repository
+        let repository = view.repository().await?;
+        assert_eq!(view.refresh().await?, second);
+        assert!(Arc::ptr_eq(&repository, &view.repository().await?));
+        Ok(())
+    }
+}
@@ -4,8 +4,8 @@ use bytes::Bytes;
 use chrono::Utc;
 use icechunk::{
     format::{
-        manifest::Manifest, snapshot::Snapshot, ByteRange, ChunkId, ManifestId,
-        SnapshotId,
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
     },
     refs::{
         create_tag, fetch_branch_tip, fetch_tag, list_refs, update_branch, Ref, RefError,
@@ -58,6 +58,17 @@ pub async fn test_manifest_write_read() -> Result<(), Box<dyn std::error::Error>
     Ok(())
 }
 
+#[tokio::test]
+pub async fn test_attributes_write_read() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage().await?;
+    let id = AttributesId::random();
+    let table = Arc::new(AttributesTable {});
+    storage.write_attributes(id.clone(), table.clone()).await?;
+    let back = storage.fetch_attributes(&id).await?;
+    assert_eq!(table, back);
+    Ok(())
+}
+
 #[tokio::test]
 pub async fn test_chunk_write_read() -> Result<(), Box<dyn std::error::Error>> {
     let storage = mk_storage().await?;
@@ -79,6 +90,9 @@ pub async fn test_chunk_write_read() -> Result<(), Box<dyn std::error::Error>> {
 
     let back = storage.fetch_chunk(&id, &ByteRange::bounded(1, 4)).await?;
     assert_eq!(Bytes::from_static(b"ell"), back);
+
+    let back = storage.fetch_chunk(&id, &ByteRange::Last(2)).await?;
+    assert_eq!(Bytes::from_static(b"lo"), back);
     Ok(())
 }
 
@@ -188,3 +202,35 @@ pub async fn test_ref_names() -> Result<(), Box<dyn std::error::Error>> {
     );
     Ok(())
 }
+
+#[tokio::test]
+pub async fn test_compare_and_swap_ref_races() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = Arc::new(mk_storage().await?);
+    let key = "branch.main/ref.json";
+    assert!(storage.compare_and_swap_ref(key, None, Bytes::from("first")).await?);
+    assert!(!storage.compare_and_swap_ref(key, None, Bytes::from("again")).await?);
+
+    let handles: Vec<_> = (0..5)
+        .map(|i| {
+            let storage = Arc::clone(&storage);
+            tokio::spawn(async move {
+                storage
+                    .compare_and_swap_ref(
+                        key,
+                        Some(Bytes::from("first")),
+                        Bytes::from(format!("writer {i}")),
+                    )
+                    .await
+            })
+        })
+        .collect();
+    let mut swapped = 0;
+    for handle in handles {
+        if handle.await?? {
+            swapped += 1;
+        }
+    }
+    assert_eq!(swapped, 1);
+    assert_ne!(storage.get_ref(key).await?, Bytes::from("first"));
+    Ok(())
+}
@@ -10,7 +10,9 @@ mod tests {
         repository::{get_chunk, ChunkPayload, ZarrArrayMetadata},
         storage::{
             s3::{mk_client, S3Config, S3Credentials, S3Storage, StaticS3Credentials},
-            virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+            virtual_ref::{
+                ObjectStoreVirtualChunkResolver, ObjectStoreVirtualChunkResolverConfig,
+            },
             ObjectStorage,
         },
         zarr::AccessMode,
@@ -22,7 +24,8 @@ mod tests {
 
     use bytes::Bytes;
     use object_store::{
-        local::LocalFileSystem, ObjectStore, PutMode, PutOptions, PutPayload,
+        local::LocalFileSystem, memory::InMemory, path::Path as ObjectPath, ObjectStore,
+        PutMode, PutOptions, PutPayload,
     };
     use pretty_assertions::assert_eq;
 
@@ -126,6 +129,57 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_repository_with_in_memory_virtual_refs() -> Result<(), Box<dyn Error>> {
+        // an object in an external bucket, not owned by the repository
+        let external = Arc::new(InMemory::new());
+        let bytes = Bytes::copy_from_slice(b"header of a large zarr chunk");
+        external.put(&ObjectPath::from("data/c/0"), bytes.clone().into()).await?;
+        let resolver = ObjectStoreVirtualChunkResolver::new(None)
+            .with_store("memory://external/", external);
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_virtual_resolver(Arc::new(resolver))
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let array_path: Path = "/array".try_into().unwrap();
+        ds.add_array(array_path.clone(), zarr_meta).await?;
+        let payload = ChunkPayload::Virtual(VirtualChunkRef {
+            location: VirtualChunkLocation::from_absolute_path(
+                "memory://external/data/c/0",
+            )?,
+            offset: 12,
+            length: 5,
+        });
+        ds.set_chunk_ref(array_path.clone(), ChunkIndices(vec![0]), Some(payload))
+            .await?;
+        ds.commit("main", "virtual chunk", None).await?;
+
+        let coords = ChunkIndices(vec![0]);
+        assert_eq!(
+            ds.read_chunk(&array_path, &coords, &ByteRange::ALL, false).await?,
+            Bytes::copy_from_slice(b"large")
+        );
+        assert_eq!(
+            ds.read_chunk(&array_path, &coords, &ByteRange::bounded(1, 3), false).await?,
+            Bytes::copy_from_slice(b"ar")
+        );
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_repository_with_local_virtual_refs() -> Result<(), Box<dyn Error>> {
         let chunk_dir = TempDir::new()?;